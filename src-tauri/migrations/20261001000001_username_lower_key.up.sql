@@ -0,0 +1,4 @@
+-- Usernames compare case-insensitively everywhere, so uniqueness does too. Sign-up, the name
+-- allocated to a social sign-up (INSERT ... ON CONFLICT (lower(username))) and choosing one with
+-- a ticket all leave settling races to this index, see src/service/username.rs.
+CREATE UNIQUE INDEX IF NOT EXISTS users_username_lower_key ON users (lower(username));
@@ -0,0 +1,18 @@
+-- OAuth tokens for identities linked through Google / Microsoft sign-in.
+-- access_token and refresh_token hold nonce || ciphertext sealed by the token vault.
+CREATE TABLE IF NOT EXISTS provider_tokens (
+    provider TEXT NOT NULL,
+    provider_user_id TEXT NOT NULL,
+    access_token BYTEA NOT NULL,
+    refresh_token BYTEA,
+    expires_at TIMESTAMPTZ,
+    scopes TEXT[] NOT NULL DEFAULT '{}',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (provider, provider_user_id)
+);
+
+-- The refresher only ever looks at refreshable tokens ordered by expiry.
+CREATE INDEX IF NOT EXISTS provider_tokens_refresh_idx
+    ON provider_tokens (expires_at)
+    WHERE refresh_token IS NOT NULL;
@@ -0,0 +1,2 @@
+-- Set by operators through wyrd-admin. Password logins are refused while it is set.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS password_reset_required BOOLEAN NOT NULL DEFAULT false;
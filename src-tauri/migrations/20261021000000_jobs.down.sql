@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS job_runs;
+ALTER TABLE users DROP COLUMN IF EXISTS deleted_at;
@@ -0,0 +1,26 @@
+-- When an account was marked deleted, so purging can wait out a grace period. Accounts that are
+-- already deleted get theirs starting now.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ;
+UPDATE users SET deleted_at = now() WHERE status = 'deleted' AND deleted_at IS NULL;
+
+-- One row per run of a background job, see src/service/jobs.rs.
+CREATE TABLE IF NOT EXISTS job_runs (
+    id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    job TEXT NOT NULL,
+    -- The schedule tick the run belongs to; NULL for runs started through wyrd-admin.
+    scheduled_for TIMESTAMPTZ,
+    started_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    finished_at TIMESTAMPTZ,
+    status TEXT NOT NULL DEFAULT 'running',
+    duration_ms BIGINT,
+    -- What the job reported, or why it failed.
+    detail TEXT,
+    -- Host and process that ran it.
+    instance TEXT NOT NULL,
+
+    CONSTRAINT job_runs_status_check CHECK (status IN ('running', 'succeeded', 'failed')),
+    -- Instances whose clocks are a little behind find the tick taken instead of running it again.
+    CONSTRAINT job_runs_tick_key UNIQUE (job, scheduled_for)
+);
+
+CREATE INDEX IF NOT EXISTS job_runs_started_at_idx ON job_runs (started_at DESC);
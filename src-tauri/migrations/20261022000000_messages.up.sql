@@ -0,0 +1,43 @@
+-- Chat history. Every message belongs to a conversation and is numbered within it, so clients
+-- page through history and catch up after a reconnect by sequence number.
+CREATE TABLE IF NOT EXISTS conversations (
+    id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    kind TEXT NOT NULL,
+    -- The two members of a 1:1 conversation, lower id first; NULL for other kinds.
+    direct_low BIGINT,
+    direct_high BIGINT,
+    -- Sequence number of the latest message, bumped in the transaction that stores the next one.
+    last_seq BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+
+    CONSTRAINT conversations_kind_check CHECK (kind IN ('direct')),
+    CONSTRAINT conversations_direct_check CHECK (
+        (kind = 'direct') = (direct_low IS NOT NULL AND direct_high IS NOT NULL)
+        AND (direct_low IS NULL OR direct_low < direct_high)
+    ),
+    -- At most one 1:1 conversation per pair of users.
+    CONSTRAINT conversations_direct_key UNIQUE (direct_low, direct_high)
+);
+
+CREATE TABLE IF NOT EXISTS conversation_members (
+    conversation_id BIGINT NOT NULL REFERENCES conversations (id) ON DELETE CASCADE,
+    user_id BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    joined_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+
+    PRIMARY KEY (conversation_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS conversation_members_user_idx ON conversation_members (user_id);
+
+CREATE TABLE IF NOT EXISTS messages (
+    id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    conversation_id BIGINT NOT NULL REFERENCES conversations (id) ON DELETE CASCADE,
+    seq BIGINT NOT NULL,
+    -- NULL once the sender's account is purged; the message stays for everyone else.
+    sender_id BIGINT REFERENCES users (id) ON DELETE SET NULL,
+    body TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    edited_at TIMESTAMPTZ,
+
+    CONSTRAINT messages_seq_key UNIQUE (conversation_id, seq)
+);
@@ -0,0 +1,16 @@
+DELETE FROM conversations WHERE kind = 'group';
+
+ALTER TABLE messages DROP CONSTRAINT IF EXISTS messages_kind_check;
+ALTER TABLE messages DROP COLUMN IF EXISTS event;
+ALTER TABLE messages DROP COLUMN IF EXISTS kind;
+
+DROP INDEX IF EXISTS conversation_members_owner_key;
+ALTER TABLE conversation_members DROP CONSTRAINT IF EXISTS conversation_members_role_check;
+ALTER TABLE conversation_members DROP COLUMN IF EXISTS role;
+
+ALTER TABLE conversations DROP CONSTRAINT IF EXISTS conversations_group_name_check;
+ALTER TABLE conversations DROP CONSTRAINT IF EXISTS conversations_kind_check;
+ALTER TABLE conversations ADD CONSTRAINT conversations_kind_check CHECK (kind IN ('direct'));
+ALTER TABLE conversations DROP COLUMN IF EXISTS avatar_url;
+ALTER TABLE conversations DROP COLUMN IF EXISTS topic;
+ALTER TABLE conversations DROP COLUMN IF EXISTS name;
@@ -0,0 +1,26 @@
+-- Group conversations: named, with an owner, admins and members. Changes to a group are kept as
+-- system messages in its history.
+ALTER TABLE conversations ADD COLUMN IF NOT EXISTS name TEXT;
+ALTER TABLE conversations ADD COLUMN IF NOT EXISTS topic TEXT;
+ALTER TABLE conversations ADD COLUMN IF NOT EXISTS avatar_url TEXT;
+ALTER TABLE conversations DROP CONSTRAINT IF EXISTS conversations_kind_check;
+ALTER TABLE conversations ADD CONSTRAINT conversations_kind_check
+    CHECK (kind IN ('direct', 'group'));
+ALTER TABLE conversations ADD CONSTRAINT conversations_group_name_check
+    CHECK ((kind = 'group') = (name IS NOT NULL));
+
+-- Members of 1:1 conversations are plain members.
+ALTER TABLE conversation_members ADD COLUMN IF NOT EXISTS role TEXT NOT NULL DEFAULT 'member';
+ALTER TABLE conversation_members ADD CONSTRAINT conversation_members_role_check
+    CHECK (role IN ('owner', 'admin', 'member'));
+-- Ownership moves by demoting the old owner before promoting the new one.
+CREATE UNIQUE INDEX IF NOT EXISTS conversation_members_owner_key
+    ON conversation_members (conversation_id)
+    WHERE role = 'owner';
+
+-- System messages carry what happened as JSON in event, see repository::GroupEvent, and a plain
+-- summary in body for clients that don't know the event.
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS kind TEXT NOT NULL DEFAULT 'text';
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS event JSONB;
+ALTER TABLE messages ADD CONSTRAINT messages_kind_check
+    CHECK (kind IN ('text', 'system') AND (kind = 'system') = (event IS NOT NULL));
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS group_invite_uses;
+DROP TABLE IF EXISTS group_invites;
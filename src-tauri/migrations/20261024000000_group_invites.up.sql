@@ -0,0 +1,36 @@
+-- Invite links to groups. Anyone holding the code can join until the invite is revoked, expires
+-- or runs out of uses; every join through one is logged in group_invite_uses.
+CREATE TABLE IF NOT EXISTS group_invites (
+    id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    conversation_id BIGINT NOT NULL REFERENCES conversations (id) ON DELETE CASCADE,
+    code TEXT NOT NULL,
+    -- NULL once the creator's account is purged; the invite keeps working.
+    created_by BIGINT REFERENCES users (id) ON DELETE SET NULL,
+    -- Role given to whoever joins through it.
+    role TEXT NOT NULL DEFAULT 'member',
+    -- NULL for no limit.
+    max_uses INTEGER,
+    -- Bumped in the transaction that adds the member, so max_uses is never exceeded.
+    uses INTEGER NOT NULL DEFAULT 0,
+    expires_at TIMESTAMPTZ,
+    revoked_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+
+    CONSTRAINT group_invites_code_key UNIQUE (code),
+    CONSTRAINT group_invites_role_check CHECK (role IN ('admin', 'member')),
+    CONSTRAINT group_invites_uses_check CHECK (
+        uses >= 0 AND (max_uses IS NULL OR (max_uses > 0 AND uses <= max_uses))
+    )
+);
+
+CREATE INDEX IF NOT EXISTS group_invites_conversation_idx ON group_invites (conversation_id);
+
+CREATE TABLE IF NOT EXISTS group_invite_uses (
+    id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    invite_id BIGINT NOT NULL REFERENCES group_invites (id) ON DELETE CASCADE,
+    -- NULL once the account is purged; the use still counts.
+    user_id BIGINT REFERENCES users (id) ON DELETE SET NULL,
+    used_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS group_invite_uses_invite_idx ON group_invite_uses (invite_id);
@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS users_present_idx;
+ALTER TABLE users DROP COLUMN IF EXISTS last_seen;
+ALTER TABLE users DROP CONSTRAINT IF EXISTS users_activity_check;
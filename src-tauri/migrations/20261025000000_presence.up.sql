@@ -0,0 +1,9 @@
+-- Presence. activity is what other users see, worked out from the user's connected devices and
+-- the state they picked, see service/presence.rs; last_seen is when it last turned offline.
+UPDATE users SET activity = 'offline' WHERE activity NOT IN ('online', 'away', 'dnd', 'offline');
+ALTER TABLE users ADD CONSTRAINT users_activity_check
+    CHECK (activity IN ('online', 'away', 'dnd', 'offline'));
+ALTER TABLE users ADD COLUMN IF NOT EXISTS last_seen TIMESTAMPTZ;
+
+-- The sweep job only looks at users who aren't offline, a small share of them at any time.
+CREATE INDEX IF NOT EXISTS users_present_idx ON users (id) WHERE activity <> 'offline';
@@ -0,0 +1,21 @@
+-- Emails waiting to go out, see src/service/otp.rs. Every email is queued before the first
+-- attempt and deleted once it was sent; failed ones are retried by the retry_emails job until
+-- they expire. Bodies carry verification codes, so nothing is kept past expires_at.
+CREATE TABLE IF NOT EXISTS email_outbox (
+    id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    -- Mailbox in "Name <address>" form.
+    recipient TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    body TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    -- Why the latest attempt failed.
+    last_error TEXT,
+    next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    -- When the email is no use anymore, e.g. because its code expired.
+    expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+
+    CONSTRAINT email_outbox_attempts_check CHECK (attempts >= 0)
+);
+
+CREATE INDEX IF NOT EXISTS email_outbox_next_attempt_at_idx ON email_outbox (next_attempt_at);
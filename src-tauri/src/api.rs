@@ -0,0 +1,129 @@
+use axum::{
+    routing::{delete, get, patch, post, put},
+    Router,
+};
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+use utoipa_swagger_ui::SwaggerUi;
+
+use crate::auth_handler::{
+    choose_username_handler, login_handler, otp_verify_handler, resend_otp_handler, signup_handler,
+};
+use crate::chat_handler::{
+    accept_invite_handler, add_members_handler, conversation_handler, create_group_handler,
+    create_invite_handler, delete_group_handler, history_handler, invite_uses_handler,
+    invites_handler, leave_handler, open_direct_handler, post_message_handler, presence_handler,
+    preview_invite_handler, remove_member_handler, revoke_invite_handler, set_presence_handler,
+    set_role_handler, transfer_ownership_handler, update_group_handler,
+};
+
+/// Request and response shapes under a version prefix only change in backwards compatible ways.
+/// Anything else goes into a new version.
+pub const V1_PREFIX: &str = "/api/v1";
+
+pub const OPENAPI_PATH: &str = "/api/v1/openapi.json";
+pub const DOCS_PATH: &str = "/api/docs";
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Wyrd API"),
+    servers((url = "/api/v1")),
+    paths(
+        crate::auth_handler::signup_handler,
+        crate::auth_handler::otp_verify_handler,
+        crate::auth_handler::resend_otp_handler,
+        crate::auth_handler::login_handler,
+        crate::auth_handler::choose_username_handler,
+        crate::chat_handler::open_direct_handler,
+        crate::chat_handler::post_message_handler,
+        crate::chat_handler::history_handler,
+        crate::chat_handler::conversation_handler,
+        crate::chat_handler::create_group_handler,
+        crate::chat_handler::update_group_handler,
+        crate::chat_handler::delete_group_handler,
+        crate::chat_handler::add_members_handler,
+        crate::chat_handler::set_role_handler,
+        crate::chat_handler::remove_member_handler,
+        crate::chat_handler::leave_handler,
+        crate::chat_handler::transfer_ownership_handler,
+        crate::chat_handler::create_invite_handler,
+        crate::chat_handler::invites_handler,
+        crate::chat_handler::revoke_invite_handler,
+        crate::chat_handler::invite_uses_handler,
+        crate::chat_handler::preview_invite_handler,
+        crate::chat_handler::accept_invite_handler,
+        crate::chat_handler::presence_handler,
+        crate::chat_handler::set_presence_handler,
+    ),
+    modifiers(&SessionAuth),
+    tags(
+        (name = "auth", description = "Accounts, password login and email verification"),
+        (name = "chat", description = "Conversations, groups, invite links, message history and presence"),
+    )
+)]
+pub struct ApiDoc;
+
+/// The `session` scheme: the token from the login response, as `Authorization: Bearer <token>`.
+struct SessionAuth;
+
+impl Modify for SessionAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "session",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+    }
+}
+
+pub fn v1_routes() -> Router {
+    Router::new()
+        .route("/signup", post(signup_handler))
+        .route("/otp", post(otp_verify_handler))
+        .route("/otp/resend", post(resend_otp_handler))
+        .route("/login", post(login_handler))
+        .route("/username", post(choose_username_handler))
+        .route("/conversations/direct", post(open_direct_handler))
+        .route(
+            "/conversations/{id}/messages",
+            get(history_handler).post(post_message_handler),
+        )
+        .route("/groups", post(create_group_handler))
+        .route(
+            "/conversations/{id}",
+            get(conversation_handler)
+                .patch(update_group_handler)
+                .delete(delete_group_handler),
+        )
+        .route("/conversations/{id}/members", post(add_members_handler))
+        .route(
+            "/conversations/{id}/members/{user_id}",
+            patch(set_role_handler).delete(remove_member_handler),
+        )
+        .route("/conversations/{id}/leave", post(leave_handler))
+        .route(
+            "/conversations/{id}/owner",
+            post(transfer_ownership_handler),
+        )
+        .route(
+            "/conversations/{id}/invites",
+            get(invites_handler).post(create_invite_handler),
+        )
+        .route(
+            "/conversations/{id}/invites/{invite_id}",
+            delete(revoke_invite_handler),
+        )
+        .route(
+            "/conversations/{id}/invites/{invite_id}/uses",
+            get(invite_uses_handler),
+        )
+        .route("/invites/{code}", get(preview_invite_handler))
+        .route("/invites/{code}/accept", post(accept_invite_handler))
+        .route("/presence", get(presence_handler).put(set_presence_handler))
+    //.route("/personalize", method_router)
+}
+
+/// Swagger UI for the spec above, bundled into the binary so it works offline.
+pub fn docs() -> SwaggerUi {
+    SwaggerUi::new(DOCS_PATH).url(OPENAPI_PATH, ApiDoc::openapi())
+}
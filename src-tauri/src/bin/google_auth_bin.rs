@@ -49,8 +49,6 @@ use openidconnect::{AdditionalProviderMetadata, ProviderMetadata, RevocationUrl,
 use serde::{Deserialize, Serialize};
 
 use std::env;
-use std::io::{BufRead, BufReader, Write};
-use std::net::TcpListener;
 #[derive(Clone, Debug, Deserialize, Serialize)]
 
 struct RevocationEndpointProviderMetadata {
@@ -72,9 +70,8 @@ type GoogleProviderMetadata = ProviderMetadata<
     CoreSubjectIdentifierType,
 >;
 
-#[path = "../service/third_party_auth.rs"]
-mod tp_auth;
-use tp_auth::*;
+use wyrd_lib::settings::Settings;
+use wyrd_lib::tp_auth::*;
 
 async fn google_auth() -> Result<(), anyhow::Error> {
     //const redirect_url: &str = "http://localhost:8080";
@@ -89,7 +86,8 @@ async fn google_auth() -> Result<(), anyhow::Error> {
             unreachable!();
         });
 
-    let client_creds = create_client("GOOGLE").await?;
+    let settings = Settings::load()?;
+    let client_creds = create_client("GOOGLE", &settings).await?;
 
     let provider_metadata =
         GoogleProviderMetadata::discover_async(client_creds.issuer_url, &http_client)
@@ -102,17 +100,14 @@ async fn google_auth() -> Result<(), anyhow::Error> {
         .revocation_endpoint
         .clone();
 
+    let loopback = bind_loopback().await?;
+
     let client = CoreClient::from_provider_metadata(
         provider_metadata,
         client_creds.client_id,
         Some(client_creds.client_secret),
     )
-    .set_redirect_uri(
-        RedirectUrl::new("http://localhost:8080".to_string()).unwrap_or_else(|err| {
-            handle_error(&err, "Invalid redirect URL");
-            unreachable!();
-        }),
-    )
+    .set_redirect_uri(loopback.redirect_url.clone())
     .set_revocation_url(
         RevocationUrl::new(revocation_endpoint).unwrap_or_else(|err| {
             handle_error(&err, "Invalid revocation endpoint URL");
@@ -143,7 +138,7 @@ async fn google_auth() -> Result<(), anyhow::Error> {
 
     // Now you can exchange it for an access token and ID token.
 
-    let (code, state) = auth_code("GOOGLE");
+    let code = loopback.wait_for_code("GOOGLE", &csrf_token).await?;
 
     let token_response = client
         .exchange_code(code)
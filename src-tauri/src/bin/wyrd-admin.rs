@@ -0,0 +1,252 @@
+// Account management and background jobs for operators. Goes through the same services as the API, so the rules
+// (sessions revoked on suspension, grants revoked on unlink) can't be skipped by accident.
+use clap::{Args, Parser, Subcommand};
+use std::path::Path;
+use wyrd_lib::accounts;
+use wyrd_lib::auth_handler::AppState;
+use wyrd_lib::jobs::{self, JobRun};
+use wyrd_lib::logging;
+use wyrd_lib::repository::{User, UserSearch};
+use wyrd_lib::server;
+use wyrd_lib::settings::Settings;
+
+#[derive(Parser)]
+#[command(
+    about = "Manage Wyrd accounts and background jobs",
+    after_help = "USER can be an id, an email address or a username."
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List accounts, oldest first.
+    List(Page),
+    /// Find accounts whose name, username or email contains TEXT.
+    Search {
+        text: String,
+        #[command(flatten)]
+        page: Page,
+    },
+    /// Show everything about one account.
+    Show { user: String },
+    /// Mark the email address as verified.
+    Verify { user: String },
+    /// Block logins and end every session.
+    Suspend { user: String },
+    /// Let a suspended account log in again.
+    Unsuspend { user: String },
+    /// Refuse password logins until a new password is set, and end every session.
+    ResetPassword { user: String },
+    /// End every session.
+    RevokeSessions { user: String },
+    /// Detach the Google or Microsoft identity and revoke its stored grant.
+    Unlink { user: String },
+    /// Permanently remove accounts marked deleted. Only lists them unless --confirm is given.
+    Purge {
+        #[arg(long)]
+        confirm: bool,
+    },
+    /// Inspect and run background jobs.
+    Jobs {
+        #[command(subcommand)]
+        command: JobsCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum JobsCommand {
+    /// Every job with its schedule and latest run.
+    List,
+    /// Past runs, latest first.
+    History {
+        /// Only runs of this job.
+        #[arg(long)]
+        job: Option<String>,
+        /// Only runs with this status: running, succeeded or failed.
+        #[arg(long)]
+        status: Option<String>,
+        #[arg(long, default_value_t = 20)]
+        limit: i64,
+    },
+    /// Run a job now, unless another instance is running it.
+    Run { job: String },
+}
+
+#[derive(Args)]
+struct Page {
+    /// Only accounts with this status: active, suspended or deleted.
+    #[arg(long)]
+    status: Option<String>,
+    #[arg(long, default_value_t = 50)]
+    limit: i64,
+    #[arg(long, default_value_t = 0)]
+    offset: i64,
+}
+
+impl Page {
+    fn search(self, text: Option<String>) -> UserSearch {
+        UserSearch {
+            text,
+            status: self.status,
+            limit: self.limit,
+            offset: self.offset,
+            ..UserSearch::default()
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    let cli = Cli::parse();
+    let settings = Settings::load()?;
+    let _log_guard = logging::init(&settings.logging, Path::new("logs"))?;
+    let state = server::build_state(settings).await?;
+    run(&state, cli.command).await
+}
+
+async fn run(state: &AppState, command: Command) -> Result<(), anyhow::Error> {
+    let users = state.users.as_ref();
+    let sessions = state.sessions.as_ref();
+
+    match command {
+        Command::List(page) => print_table(&users.search(&page.search(None)).await?),
+        Command::Search { text, page } => {
+            print_table(&users.search(&page.search(Some(text))).await?)
+        }
+        Command::Show { user } => {
+            let user = accounts::find(users, &user).await?;
+            println!("id:              {}", user.id);
+            println!("name:            {}", user.name);
+            println!("username:        {}", user.username);
+            println!("email:           {}", user.email);
+            println!("status:          {}", user.status);
+            println!("verified:        {}", user.user_verified);
+            println!("has password:    {}", user.password.is_some());
+            println!("reset required:  {}", user.password_reset_required);
+            if let (Some(provider), Some(subject)) = (&user.provider, &user.provider_user_id) {
+                println!("provider:        {} ({})", provider, subject);
+            }
+        }
+        Command::Verify { user } => {
+            let user = accounts::find(users, &user).await?;
+            accounts::mark_verified(users, &user).await?;
+            println!("Marked {} as verified", user.username);
+        }
+        Command::Suspend { user } => {
+            let user = accounts::find(users, &user).await?;
+            let revoked = accounts::suspend(users, sessions, &user).await?;
+            println!("Suspended {}, revoked {} sessions", user.username, revoked);
+        }
+        Command::Unsuspend { user } => {
+            let user = accounts::find(users, &user).await?;
+            accounts::unsuspend(users, &user).await?;
+            println!("Unsuspended {}", user.username);
+        }
+        Command::ResetPassword { user } => {
+            let user = accounts::find(users, &user).await?;
+            let revoked = accounts::force_password_reset(users, sessions, &user).await?;
+            println!(
+                "{} has to reset their password, revoked {} sessions",
+                user.username, revoked
+            );
+        }
+        Command::RevokeSessions { user } => {
+            let user = accounts::find(users, &user).await?;
+            let revoked = accounts::revoke_sessions(sessions, &user).await?;
+            println!("Revoked {} sessions of {}", revoked, user.username);
+        }
+        Command::Unlink { user } => {
+            let user = accounts::find(users, &user).await?;
+            accounts::unlink_provider(state, &user).await?;
+            println!("Unlinked the provider from {}", user.username);
+        }
+        Command::Purge { confirm } => {
+            let purged = accounts::purge_deleted(users, sessions, None, !confirm).await?;
+            print_table(&purged);
+            if confirm {
+                println!("Purged {} accounts", purged.len());
+            } else {
+                println!(
+                    "Dry run: {} accounts would be purged, pass --confirm to remove them",
+                    purged.len()
+                );
+            }
+        }
+        Command::Jobs { command } => run_jobs(state, command).await?,
+    }
+    Ok(())
+}
+
+async fn run_jobs(state: &AppState, command: JobsCommand) -> Result<(), anyhow::Error> {
+    match command {
+        JobsCommand::List => {
+            for job in jobs::JOBS {
+                println!("{}", job.name);
+                println!("    {}", job.description);
+                match jobs::next_run(&state.settings.jobs, job) {
+                    Some(next) => println!("    next run: {}", next),
+                    None => println!("    next run: manual only"),
+                }
+                match jobs::history(&state.db, Some(job.name), None, 1)
+                    .await?
+                    .first()
+                {
+                    Some(run) => println!("    last run: {} {}", run.started_at, run.status),
+                    None => println!("    last run: never"),
+                }
+            }
+        }
+        JobsCommand::History { job, status, limit } => {
+            let runs = jobs::history(&state.db, job.as_deref(), status.as_deref(), limit).await?;
+            for run in &runs {
+                print_run(run);
+            }
+        }
+        JobsCommand::Run { job } => {
+            let Some(job) = jobs::find(&job) else {
+                anyhow::bail!("There is no job called {}, see `jobs list`", job);
+            };
+            match jobs::run(state, job, None).await? {
+                Some(run) => print_run(&run),
+                None => println!("{} is running on another instance", job.name),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_run(run: &JobRun) {
+    let duration = run
+        .duration_ms
+        .map_or_else(|| "-".to_string(), |ms| format!("{}ms", ms));
+    println!(
+        "{:>8}  {:<18}  {}  {:<9}  {:>9}  {}  {}",
+        run.id,
+        run.job,
+        run.started_at,
+        run.status,
+        duration,
+        run.instance,
+        run.detail.as_deref().unwrap_or_default()
+    );
+}
+
+fn print_table(users: &[User]) {
+    for user in users {
+        println!(
+            "{:>8}  {:<24}  {:<32}  {:<9}  {}",
+            user.id,
+            user.username,
+            user.email,
+            user.status,
+            if user.user_verified {
+                "verified"
+            } else {
+                "unverified"
+            }
+        );
+    }
+}
@@ -0,0 +1,102 @@
+// The backend on its own, for running on a server without the desktop shell.
+use clap::{Parser, Subcommand};
+use std::path::Path;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+use wyrd_lib::logging;
+use wyrd_lib::migrations::{self, State};
+use wyrd_lib::server;
+use wyrd_lib::settings::Settings;
+
+#[derive(Parser)]
+#[command(about = "The Wyrd backend")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Serve the API (the default).
+    Serve,
+    /// Manage the database schema.
+    Migrate {
+        #[command(subcommand)]
+        action: Migrate,
+    },
+}
+
+#[derive(Subcommand)]
+enum Migrate {
+    /// Apply every pending migration.
+    Run,
+    /// Revert the latest migration, or everything newer than --to.
+    Revert {
+        #[arg(long)]
+        to: Option<i64>,
+    },
+    /// List migrations and whether they are applied.
+    Status,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    let cli = Cli::parse();
+    let settings = Settings::load()?;
+    let _log_guard = logging::init(&settings.logging, Path::new("logs"))?;
+
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(settings).await,
+        Command::Migrate { action } => migrate(settings, action).await,
+    }
+}
+
+async fn serve(settings: Settings) -> Result<(), anyhow::Error> {
+    info!("Starting wyrd-server ({} profile)", settings.profile);
+    let state = server::build_state(settings).await?;
+    server::spawn_background_tasks(&state);
+
+    let shutdown = CancellationToken::new();
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            server::shutdown_signal().await;
+            shutdown.cancel();
+        }
+    });
+
+    let listener = server::bind(&state.settings.server).await?;
+    info!("Listening on {}", listener.local_addr()?);
+    server::serve(listener, state, shutdown).await
+}
+
+async fn migrate(mut settings: Settings, action: Migrate) -> Result<(), anyhow::Error> {
+    // Connect without applying anything, that's up to the subcommand.
+    settings.database.run_migrations = false;
+    let db = server::setup_db(&settings.database, 1).await?;
+
+    match action {
+        Migrate::Run => {
+            migrations::run(&db).await?;
+            println!("Database is up to date");
+        }
+        Migrate::Revert { to } => {
+            let version = migrations::revert(&db, to).await?;
+            println!("Reverted to version {}", version);
+        }
+        Migrate::Status => {
+            for migration in migrations::status(&db).await? {
+                let state = match migration.state {
+                    State::Applied => "applied",
+                    State::Pending => "pending",
+                    State::Modified => "MODIFIED",
+                };
+                println!(
+                    "{:<16} {:<9} {}",
+                    migration.version, state, migration.description
+                );
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,672 @@
+use axum::{
+    extract::{
+        rejection::{JsonRejection, PathRejection, QueryRejection},
+        Request,
+    },
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt;
+use tracing::{debug, error};
+use utoipa::ToSchema;
+
+use crate::auth_service::AuthenticationErrors;
+use crate::chat::{ChatError, MAX_BODY_CHARS, MAX_GROUP_NAME_CHARS, MAX_TOPIC_CHARS};
+use crate::otp::OTPErrors;
+use crate::presence::PresenceError;
+use crate::repository::StoreError;
+use crate::telemetry;
+use crate::username::{UsernameError, MAX_LENGTH, MIN_LENGTH};
+
+/// Machine-readable reason for a failed request. The serialized names are part of the API
+/// contract: clients branch on them, so existing codes are never renamed or reused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    ValidationFailed,
+    UsernameTooShort,
+    UsernameTooLong,
+    UsernameInvalidStart,
+    UsernameInvalidCharacters,
+    UsernameReserved,
+    UsernameTaken,
+    EmailInvalid,
+    EmailTaken,
+    InvalidCredentials,
+    Unauthenticated,
+    AccountSuspended,
+    PasswordResetRequired,
+    OtpInvalid,
+    OtpExpired,
+    TicketExpired,
+    UserNotFound,
+    ConversationNotFound,
+    CannotMessageSelf,
+    PermissionDenied,
+    OwnerMustTransfer,
+    GroupNameInvalid,
+    GroupTopicTooLong,
+    AvatarUrlInvalid,
+    RoleInvalid,
+    InviteNotFound,
+    InviteExpired,
+    InviteExpiryInvalid,
+    InviteMaxUsesInvalid,
+    PresenceStateInvalid,
+    MessageEmpty,
+    MessageTooLong,
+    EmailSendFailed,
+    RateLimited,
+    Unavailable,
+    SocialSignInUnavailable,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::ValidationFailed
+            | ErrorCode::UsernameTooShort
+            | ErrorCode::UsernameTooLong
+            | ErrorCode::UsernameInvalidStart
+            | ErrorCode::UsernameInvalidCharacters
+            | ErrorCode::UsernameReserved
+            | ErrorCode::EmailInvalid
+            | ErrorCode::CannotMessageSelf
+            | ErrorCode::GroupNameInvalid
+            | ErrorCode::GroupTopicTooLong
+            | ErrorCode::AvatarUrlInvalid
+            | ErrorCode::RoleInvalid
+            | ErrorCode::InviteExpiryInvalid
+            | ErrorCode::InviteMaxUsesInvalid
+            | ErrorCode::PresenceStateInvalid
+            | ErrorCode::MessageEmpty
+            | ErrorCode::MessageTooLong => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::UsernameTaken | ErrorCode::EmailTaken | ErrorCode::OwnerMustTransfer => {
+                StatusCode::CONFLICT
+            }
+            ErrorCode::InvalidCredentials
+            | ErrorCode::Unauthenticated
+            | ErrorCode::OtpInvalid
+            | ErrorCode::TicketExpired => StatusCode::UNAUTHORIZED,
+            ErrorCode::AccountSuspended
+            | ErrorCode::PasswordResetRequired
+            | ErrorCode::PermissionDenied => StatusCode::FORBIDDEN,
+            ErrorCode::UserNotFound
+            | ErrorCode::ConversationNotFound
+            | ErrorCode::InviteNotFound => StatusCode::NOT_FOUND,
+            ErrorCode::OtpExpired | ErrorCode::InviteExpired => StatusCode::GONE,
+            ErrorCode::EmailSendFailed => StatusCode::BAD_GATEWAY,
+            ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::Unavailable | ErrorCode::SocialSignInUnavailable => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn message(self, locale: Locale) -> String {
+        match locale {
+            Locale::En => english(self),
+            Locale::Es => spanish(self),
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Same text as the JSON, e.g. "username_taken".
+        let value = serde_json::to_value(self).map_err(|_| fmt::Error)?;
+        f.write_str(value.as_str().unwrap_or_default())
+    }
+}
+
+fn english(code: ErrorCode) -> String {
+    match code {
+        ErrorCode::ValidationFailed => "Some fields need your attention.".to_string(),
+        ErrorCode::UsernameTooShort => {
+            format!("Usernames must be at least {MIN_LENGTH} characters long.")
+        }
+        ErrorCode::UsernameTooLong => format!("Usernames can be at most {MAX_LENGTH} characters long."),
+        ErrorCode::UsernameInvalidStart => "Usernames have to start with a letter.".to_string(),
+        ErrorCode::UsernameInvalidCharacters => {
+            "Usernames can only contain letters, numbers, '-' and '_'.".to_string()
+        }
+        ErrorCode::UsernameReserved => {
+            "This username is reserved. Please try a different one.".to_string()
+        }
+        ErrorCode::UsernameTaken => {
+            "Username already taken. Please try a different one.".to_string()
+        }
+        ErrorCode::EmailInvalid => "Please enter a valid email address.".to_string(),
+        ErrorCode::EmailTaken => "This email address is already registered. Please try a different one or log in.".to_string(),
+        ErrorCode::InvalidCredentials => "Your username, email or password is incorrect.".to_string(),
+        ErrorCode::Unauthenticated => "Your session has ended. Please log in again.".to_string(),
+        ErrorCode::AccountSuspended => "This account has been suspended. Please contact support.".to_string(),
+        ErrorCode::PasswordResetRequired => {
+            "Your password has to be reset before you can log in.".to_string()
+        }
+        ErrorCode::OtpInvalid => "The code entered is invalid.".to_string(),
+        ErrorCode::OtpExpired => "Your one-time password has expired. Please resend the code, then check your inbox and enter the new one.".to_string(),
+        ErrorCode::TicketExpired => {
+            "This link to choose a username has expired. Please sign in again.".to_string()
+        }
+        ErrorCode::UserNotFound => "We couldn't find that user.".to_string(),
+        ErrorCode::ConversationNotFound => "We couldn't find that conversation.".to_string(),
+        ErrorCode::CannotMessageSelf => "You can't start a conversation with yourself.".to_string(),
+        ErrorCode::PermissionDenied => "You don't have permission to do that here.".to_string(),
+        ErrorCode::OwnerMustTransfer => {
+            "Make someone else the owner before leaving, or delete the group.".to_string()
+        }
+        ErrorCode::GroupNameInvalid => {
+            format!("Group names need between 1 and {MAX_GROUP_NAME_CHARS} characters.")
+        }
+        ErrorCode::GroupTopicTooLong => {
+            format!("Topics can be at most {MAX_TOPIC_CHARS} characters long.")
+        }
+        ErrorCode::AvatarUrlInvalid => "Avatars have to be an https:// link.".to_string(),
+        ErrorCode::RoleInvalid => "Members can be made admins or plain members.".to_string(),
+        ErrorCode::InviteNotFound => "We couldn't find that invite.".to_string(),
+        ErrorCode::InviteExpired => {
+            "This invite has expired or was revoked. Ask for a new one.".to_string()
+        }
+        ErrorCode::InviteExpiryInvalid => {
+            "Invites can stay valid for up to 30 days.".to_string()
+        }
+        ErrorCode::InviteMaxUsesInvalid => "Invites have to allow at least one use.".to_string(),
+        ErrorCode::PresenceStateInvalid => {
+            "Pick online, away, do not disturb or invisible.".to_string()
+        }
+        ErrorCode::MessageEmpty => "Messages can't be empty.".to_string(),
+        ErrorCode::MessageTooLong => {
+            format!("Messages can be at most {MAX_BODY_CHARS} characters long.")
+        }
+        ErrorCode::EmailSendFailed => {
+            "We couldn't send you an email. Please try again in a moment.".to_string()
+        }
+        ErrorCode::RateLimited => "Too many attempts. Please wait a moment and try again.".to_string(),
+        ErrorCode::Unavailable => {
+            "The service is temporarily unavailable. Please try again in a moment.".to_string()
+        }
+        ErrorCode::SocialSignInUnavailable => {
+            "Google and Microsoft sign-in aren't available with a remote server yet. Please sign in with your email instead.".to_string()
+        }
+        ErrorCode::Internal => "Something went wrong on our side. Please try again later.".to_string(),
+    }
+}
+
+fn spanish(code: ErrorCode) -> String {
+    match code {
+        ErrorCode::ValidationFailed => "Algunos campos necesitan tu atención.".to_string(),
+        ErrorCode::UsernameTooShort => {
+            format!("Los nombres de usuario deben tener al menos {MIN_LENGTH} caracteres.")
+        }
+        ErrorCode::UsernameTooLong => {
+            format!("Los nombres de usuario pueden tener como máximo {MAX_LENGTH} caracteres.")
+        }
+        ErrorCode::UsernameInvalidStart => {
+            "Los nombres de usuario deben empezar con una letra.".to_string()
+        }
+        ErrorCode::UsernameInvalidCharacters => {
+            "Los nombres de usuario solo pueden contener letras, números, '-' y '_'.".to_string()
+        }
+        ErrorCode::UsernameReserved => {
+            "Este nombre de usuario está reservado. Prueba con otro.".to_string()
+        }
+        ErrorCode::UsernameTaken => "Este nombre de usuario ya existe. Prueba con otro.".to_string(),
+        ErrorCode::EmailInvalid => "Introduce un correo electrónico válido.".to_string(),
+        ErrorCode::EmailTaken => "Este correo electrónico ya está registrado. Prueba con otro o inicia sesión.".to_string(),
+        ErrorCode::InvalidCredentials => {
+            "Tu nombre de usuario, correo o contraseña es incorrecto.".to_string()
+        }
+        ErrorCode::Unauthenticated => "Tu sesión ha terminado. Inicia sesión de nuevo.".to_string(),
+        ErrorCode::AccountSuspended => "Esta cuenta ha sido suspendida. Contacta con soporte.".to_string(),
+        ErrorCode::PasswordResetRequired => {
+            "Tienes que restablecer tu contraseña antes de iniciar sesión.".to_string()
+        }
+        ErrorCode::OtpInvalid => "El código introducido no es válido.".to_string(),
+        ErrorCode::OtpExpired => "Tu código de un solo uso ha caducado. Solicita uno nuevo y revisa tu bandeja de entrada.".to_string(),
+        ErrorCode::TicketExpired => {
+            "Este enlace para elegir un nombre de usuario ha caducado. Inicia sesión de nuevo.".to_string()
+        }
+        ErrorCode::UserNotFound => "No encontramos a ese usuario.".to_string(),
+        ErrorCode::ConversationNotFound => "No encontramos esa conversación.".to_string(),
+        ErrorCode::CannotMessageSelf => {
+            "No puedes iniciar una conversación contigo mismo.".to_string()
+        }
+        ErrorCode::PermissionDenied => "No tienes permiso para hacer eso aquí.".to_string(),
+        ErrorCode::OwnerMustTransfer => {
+            "Nombra a otra persona propietaria antes de salir, o elimina el grupo.".to_string()
+        }
+        ErrorCode::GroupNameInvalid => {
+            format!("Los nombres de grupo deben tener entre 1 y {MAX_GROUP_NAME_CHARS} caracteres.")
+        }
+        ErrorCode::GroupTopicTooLong => {
+            format!("Los temas pueden tener como máximo {MAX_TOPIC_CHARS} caracteres.")
+        }
+        ErrorCode::AvatarUrlInvalid => "El avatar tiene que ser un enlace https://.".to_string(),
+        ErrorCode::RoleInvalid => {
+            "Los miembros pueden ser administradores o miembros normales.".to_string()
+        }
+        ErrorCode::InviteNotFound => "No encontramos esa invitación.".to_string(),
+        ErrorCode::InviteExpired => {
+            "Esta invitación ha caducado o fue revocada. Pide una nueva.".to_string()
+        }
+        ErrorCode::InviteExpiryInvalid => {
+            "Las invitaciones pueden ser válidas durante 30 días como máximo.".to_string()
+        }
+        ErrorCode::InviteMaxUsesInvalid => {
+            "Las invitaciones tienen que permitir al menos un uso.".to_string()
+        }
+        ErrorCode::PresenceStateInvalid => {
+            "Elige conectado, ausente, no molestar o invisible.".to_string()
+        }
+        ErrorCode::MessageEmpty => "Los mensajes no pueden estar vacíos.".to_string(),
+        ErrorCode::MessageTooLong => {
+            format!("Los mensajes pueden tener como máximo {MAX_BODY_CHARS} caracteres.")
+        }
+        ErrorCode::EmailSendFailed => {
+            "No pudimos enviarte el correo. Inténtalo de nuevo en un momento.".to_string()
+        }
+        ErrorCode::RateLimited => {
+            "Demasiados intentos. Espera un momento e inténtalo de nuevo.".to_string()
+        }
+        ErrorCode::Unavailable => {
+            "El servicio no está disponible temporalmente. Inténtalo de nuevo en un momento."
+                .to_string()
+        }
+        ErrorCode::SocialSignInUnavailable => {
+            "El inicio de sesión con Google y Microsoft aún no está disponible con un servidor remoto. Inicia sesión con tu correo."
+                .to_string()
+        }
+        ErrorCode::Internal => "Algo salió mal por nuestra parte. Inténtalo más tarde.".to_string(),
+    }
+}
+
+/// Languages error messages are translated into. Anything else gets English.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Locale {
+    #[default]
+    En,
+    Es,
+}
+
+impl Locale {
+    /// Picks the supported language with the highest weight in an `Accept-Language` header.
+    pub fn from_accept_language(header: &str) -> Locale {
+        let mut best = (Locale::default(), 0.0_f32);
+        for entry in header.split(',') {
+            let mut parts = entry.trim().split(';');
+            let tag = parts.next().unwrap_or_default();
+            let weight = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            let primary = tag.split('-').next().unwrap_or_default().to_lowercase();
+            let locale = match primary.as_str() {
+                "en" => Locale::En,
+                "es" => Locale::Es,
+                _ => continue,
+            };
+            if weight > best.1 {
+                best = (locale, weight);
+            }
+        }
+        best.0
+    }
+}
+
+/// A problem with one input field, so forms can mark every bad field at once.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub code: ErrorCode,
+}
+
+impl FieldError {
+    pub fn new(field: &'static str, code: ErrorCode) -> Self {
+        FieldError { field, code }
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct FieldErrorBody {
+    pub field: String,
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ErrorBody {
+    pub code: ErrorCode,
+    /// Ready to show to the user, in the language asked for through `Accept-Language`.
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldErrorBody>,
+}
+
+/// Body of every API error response.
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ErrorResp {
+    pub error: ErrorBody,
+}
+
+/// The one error type handlers and Tauri commands return.
+///
+/// Internal causes are logged when the error is turned into a response and never sent to the
+/// client.
+#[derive(Debug)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub fields: Vec<FieldError>,
+    source: Option<anyhow::Error>,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode) -> Self {
+        ApiError {
+            code,
+            fields: Vec::new(),
+            source: None,
+        }
+    }
+
+    /// A failed form, with one entry per bad field.
+    pub fn validation(fields: Vec<FieldError>) -> Self {
+        ApiError {
+            code: ErrorCode::ValidationFailed,
+            fields,
+            source: None,
+        }
+    }
+
+    pub fn internal(source: impl Into<anyhow::Error>) -> Self {
+        ApiError {
+            code: ErrorCode::Internal,
+            fields: Vec::new(),
+            source: Some(source.into()),
+        }
+    }
+
+    pub fn with_source(mut self, source: impl Into<anyhow::Error>) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.code.status()
+    }
+
+    pub fn body(&self, locale: Locale) -> ErrorBody {
+        render(self.code, &self.fields, locale)
+    }
+}
+
+fn render(code: ErrorCode, fields: &[FieldError], locale: Locale) -> ErrorBody {
+    ErrorBody {
+        code,
+        message: code.message(locale),
+        fields: fields
+            .iter()
+            .map(|field| FieldErrorBody {
+                field: field.field.to_string(),
+                code: field.code,
+                message: field.code.message(locale),
+            })
+            .collect(),
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.code.message(Locale::En))
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+// What `localize` needs to render the body again in another language.
+#[derive(Clone)]
+struct RenderedError {
+    code: ErrorCode,
+    fields: Vec<FieldError>,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        if let Some(source) = &self.source {
+            error!("{} ({}): {:?}", self.code, self.status(), source);
+        }
+        let mut response = (
+            self.status(),
+            Json(ErrorResp {
+                error: self.body(Locale::En),
+            }),
+        )
+            .into_response();
+        response.extensions_mut().insert(RenderedError {
+            code: self.code,
+            fields: self.fields,
+        });
+        response
+    }
+}
+
+// Tauri commands hand errors to the webview as the same body the HTTP API sends.
+impl Serialize for ApiError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if let Some(source) = &self.source {
+            error!("{}: {:?}", self.code, source);
+        }
+        self.body(Locale::En).serialize(serializer)
+    }
+}
+
+/// Middleware that re-renders error bodies in the language the client asked for.
+pub async fn localize(request: Request, next: Next) -> Response {
+    let locale = request
+        .headers()
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|value| value.to_str().ok())
+        .map(Locale::from_accept_language)
+        .unwrap_or_default();
+
+    let response = next.run(request).await;
+    if locale == Locale::En {
+        return response;
+    }
+    match response.extensions().get::<RenderedError>().cloned() {
+        Some(rendered) => {
+            let (mut parts, _) = response.into_parts();
+            parts.headers.remove(header::CONTENT_LENGTH);
+            let body = Json(ErrorResp {
+                error: render(rendered.code, &rendered.fields, locale),
+            });
+            (parts, body).into_response()
+        }
+        None => response,
+    }
+}
+
+impl From<UsernameError> for ApiError {
+    fn from(err: UsernameError) -> Self {
+        match err {
+            UsernameError::TooShort => ApiError::new(ErrorCode::UsernameTooShort),
+            UsernameError::TooLong => ApiError::new(ErrorCode::UsernameTooLong),
+            UsernameError::InvalidStart => ApiError::new(ErrorCode::UsernameInvalidStart),
+            UsernameError::InvalidCharacters => ApiError::new(ErrorCode::UsernameInvalidCharacters),
+            UsernameError::Reserved => ApiError::new(ErrorCode::UsernameReserved),
+            UsernameError::Taken => ApiError::new(ErrorCode::UsernameTaken),
+            UsernameError::TicketExpired => ApiError::new(ErrorCode::TicketExpired),
+            UsernameError::StoreError(err) => ApiError::from(err),
+            UsernameError::Exhausted | UsernameError::DatabaseError(_) => ApiError::internal(err),
+        }
+    }
+}
+
+impl From<AuthenticationErrors> for ApiError {
+    fn from(err: AuthenticationErrors) -> Self {
+        match err {
+            AuthenticationErrors::InvalidUsername(err) => ApiError::from(err),
+            AuthenticationErrors::SignupErrorUsername(_) => ApiError::new(ErrorCode::UsernameTaken),
+            AuthenticationErrors::SignupErrorEmail(_) => ApiError::new(ErrorCode::EmailTaken),
+            AuthenticationErrors::SignupInvalidEmail => ApiError::new(ErrorCode::EmailInvalid),
+            AuthenticationErrors::LoginError(_) => ApiError::new(ErrorCode::InvalidCredentials),
+            AuthenticationErrors::Unauthenticated => ApiError::new(ErrorCode::Unauthenticated),
+            AuthenticationErrors::InvalidOTP(_) => ApiError::new(ErrorCode::OtpInvalid),
+            AuthenticationErrors::AccountSuspended => ApiError::new(ErrorCode::AccountSuspended),
+            AuthenticationErrors::PasswordResetRequired => {
+                ApiError::new(ErrorCode::PasswordResetRequired)
+            }
+            AuthenticationErrors::EmailSendError(_) => {
+                ApiError::new(ErrorCode::EmailSendFailed).with_source(err)
+            }
+            AuthenticationErrors::StoreError(err) => ApiError::from(err),
+            AuthenticationErrors::HashError(_)
+            | AuthenticationErrors::DatabaseError(_)
+            | AuthenticationErrors::GeneralError(_) => ApiError::internal(err),
+        }
+    }
+}
+
+/// Field errors from signup become one validation error. Anything that isn't about a field,
+/// like the database being down, wins over them.
+impl From<Vec<AuthenticationErrors>> for ApiError {
+    fn from(errors: Vec<AuthenticationErrors>) -> Self {
+        let mut fields = Vec::new();
+        for err in errors {
+            let field = match err {
+                AuthenticationErrors::InvalidUsername(_)
+                | AuthenticationErrors::SignupErrorUsername(_) => "username",
+                AuthenticationErrors::SignupErrorEmail(_)
+                | AuthenticationErrors::SignupInvalidEmail => "email",
+                _ => return ApiError::from(err),
+            };
+            fields.push(FieldError::new(field, ApiError::from(err).code));
+        }
+        ApiError::validation(fields)
+    }
+}
+
+impl From<OTPErrors> for ApiError {
+    fn from(err: OTPErrors) -> Self {
+        match err {
+            OTPErrors::Expired => ApiError::new(ErrorCode::OtpExpired),
+            OTPErrors::Unavailable(_) => ApiError::new(ErrorCode::Unavailable).with_source(err),
+            OTPErrors::EmailError(_) => ApiError::new(ErrorCode::EmailSendFailed).with_source(err),
+            OTPErrors::SendOTPError(_)
+            | OTPErrors::ResendOTPError(_)
+            | OTPErrors::GenerateOTPError(_)
+            | OTPErrors::VerifyOTPError(_) => ApiError::internal(err),
+        }
+    }
+}
+
+impl From<StoreError> for ApiError {
+    fn from(err: StoreError) -> Self {
+        match err {
+            StoreError::Conflict("username") => ApiError::new(ErrorCode::UsernameTaken),
+            StoreError::Conflict("email") => ApiError::new(ErrorCode::EmailTaken),
+            StoreError::Redis(err) => ApiError::from(err),
+            _ => ApiError::internal(err),
+        }
+    }
+}
+
+impl From<ChatError> for ApiError {
+    fn from(err: ChatError) -> Self {
+        match err {
+            ChatError::EmptyBody => ApiError::new(ErrorCode::MessageEmpty),
+            ChatError::BodyTooLong => ApiError::new(ErrorCode::MessageTooLong),
+            ChatError::UnknownRecipient(_) => ApiError::new(ErrorCode::UserNotFound),
+            ChatError::MessagingSelf => ApiError::new(ErrorCode::CannotMessageSelf),
+            ChatError::ConversationNotFound(_) => ApiError::new(ErrorCode::ConversationNotFound),
+            ChatError::NotPermitted => ApiError::new(ErrorCode::PermissionDenied),
+            ChatError::NotAMember(_) => ApiError::new(ErrorCode::UserNotFound),
+            ChatError::OwnerMustTransfer => ApiError::new(ErrorCode::OwnerMustTransfer),
+            ChatError::InvalidGroupName => {
+                ApiError::validation(vec![FieldError::new("name", ErrorCode::GroupNameInvalid)])
+            }
+            ChatError::TopicTooLong => {
+                ApiError::validation(vec![FieldError::new("topic", ErrorCode::GroupTopicTooLong)])
+            }
+            ChatError::InvalidAvatarUrl => ApiError::validation(vec![FieldError::new(
+                "avatar_url",
+                ErrorCode::AvatarUrlInvalid,
+            )]),
+            ChatError::InvalidRole => {
+                ApiError::validation(vec![FieldError::new("role", ErrorCode::RoleInvalid)])
+            }
+            ChatError::InviteNotFound => ApiError::new(ErrorCode::InviteNotFound),
+            ChatError::InviteExpired => ApiError::new(ErrorCode::InviteExpired),
+            ChatError::InvalidInviteExpiry => ApiError::validation(vec![FieldError::new(
+                "expires_in",
+                ErrorCode::InviteExpiryInvalid,
+            )]),
+            ChatError::InvalidInviteMaxUses => ApiError::validation(vec![FieldError::new(
+                "max_uses",
+                ErrorCode::InviteMaxUsesInvalid,
+            )]),
+            ChatError::Store(err) => ApiError::from(err),
+        }
+    }
+}
+
+impl From<PresenceError> for ApiError {
+    fn from(err: PresenceError) -> Self {
+        match err {
+            PresenceError::InvalidState => ApiError::validation(vec![FieldError::new(
+                "state",
+                ErrorCode::PresenceStateInvalid,
+            )]),
+            PresenceError::Store(err) => ApiError::from(err),
+        }
+    }
+}
+
+// Requests the extractors in `extract` couldn't make sense of. What exactly was wrong only goes
+// to the debug log; clients sent it, so it's no server error.
+fn rejected(rejection: impl fmt::Display) -> ApiError {
+    debug!("Rejected request: {}", rejection);
+    ApiError::new(ErrorCode::ValidationFailed)
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        rejected(rejection)
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> Self {
+        rejected(rejection)
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        rejected(rejection)
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(err: sqlx::Error) -> Self {
+        ApiError::internal(err)
+    }
+}
+
+/// Redis going away is worth a retry, so it's told apart from bugs.
+impl From<redis::RedisError> for ApiError {
+    fn from(err: redis::RedisError) -> Self {
+        ApiError::new(ErrorCode::Unavailable).with_source(err)
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        ApiError::internal(err)
+    }
+}
@@ -1,53 +1,81 @@
 use anyhow::Error;
 use axum::{
-    http::{response, StatusCode},
-    response::IntoResponse,
+    extract::{FromRequestParts, Request, State as AxumState},
+    http::{header, request::Parts, response, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
     routing::{get, post},
-    Extension, Json, Router,
+    Extension, Router,
 };
 use axum_macros::debug_handler;
 use fast_chemail;
 use hex_literal::hex;
-use redis::aio::MultiplexedConnection;
-use redis::AsyncCommands;
+use metrics_exporter_prometheus::PrometheusHandle;
 use rustls::client;
 use serde::{ser::SerializeStruct, Deserialize, Serialize};
 use serde_json::{json, Value};
 use sha2::{Digest, Sha256, Sha512};
+use socketioxide::SocketIo;
 use sqlx::{PgPool, Pool, Postgres};
 use sqlx_postgres::PgPoolOptions;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 
 use tauri::{AppHandle, Emitter, State};
 use totp_rs::Secret;
-use tracing::{debug, error, instrument};
+use tracing::{debug, error, instrument, warn};
 use tracing_subscriber::field::display;
+use utoipa::ToSchema;
 
 use crate::{
-    auth_service::{login, signup, AuthenticationErrors},
-    otp::{generate_otp, send_otp, verify_otp},
-    password::encrypt,
+    auth_service::{authenticate, login, signup, AuthenticationErrors},
+    chat::{self, ChatError},
+    ephemeral::EphemeralStore,
+    error::{ApiError, ErrorCode, ErrorResp},
+    extract::Json,
+    gateway,
+    oidc_cache::OidcCache,
+    otp::{send_otp, start_verification, verify_otp, OTPErrors},
+    rate_limit::{check_command, RateLimitSubject, RateLimiter},
+    repository::{
+        verification_key, ConversationStore, EmailOutbox, PendingVerification, PresenceStore,
+        SessionStore, User, UserRepository, VerificationStore,
+    },
+    settings::Settings,
+    telemetry,
+    token_vault::TokenVault,
+    username::{choose_username, UsernameError},
 };
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct SignupReq {
     pub name: String,
     pub username: String,
     pub email: String,
     pub password: String,
+    /// Code of a group invite. The new account joins the group once its email is verified.
+    pub invite: Option<String>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct OTPVerReq {
+    /// The email the sign-up used, which the code was sent to.
+    pub email: String,
     pub entered_code: String,
 }
 
-#[derive(Deserialize, Debug)]
-struct AuthInitiateRequest {
-    provider: String,
+#[derive(Deserialize, ToSchema)]
+pub struct ResendOtpReq {
+    /// The email the sign-up used.
+    pub email: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ChooseUsernameReq {
+    pub ticket: String,
+    pub username: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct LoginReq {
     //The user can either log in with a username or email
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -57,8 +85,42 @@ pub struct LoginReq {
     pub password: String,
 }
 
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct MessageResp {
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct LoginResp {
+    pub message: String,
+    /// Identifies the session on later requests.
+    pub session: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct OTPVerResp {
+    pub valid: bool,
+    pub message: String,
+    /// The group joined through the invite the sign-up came with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conversation_id: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct UsernameResp {
+    pub username: String,
+}
+
+fn message(message: impl Into<String>) -> Json<MessageResp> {
+    Json(MessageResp {
+        message: message.into(),
+    })
+}
+
 #[derive(Deserialize)]
 pub struct Theme {
+    /// Email of the sign-up being personalized.
+    pub email: String,
     pub mode: String,
     pub rbg: (u8, u8, u8),
 }
@@ -71,186 +133,254 @@ pub type Db = Pool<Postgres>;
 
 pub struct AppState {
     pub db: Db,
-    pub red: MultiplexedConnection,
+    pub ephemeral: Arc<dyn EphemeralStore>,
+    pub users: Arc<dyn UserRepository>,
+    pub verifications: Arc<dyn VerificationStore>,
+    pub sessions: Arc<dyn SessionStore>,
+    pub conversations: Arc<dyn ConversationStore>,
+    pub presence: Arc<dyn PresenceStore>,
+    pub outbox: Arc<dyn EmailOutbox>,
+    pub vault: TokenVault,
+    pub http: openidconnect::reqwest::Client,
+    pub oidc: OidcCache,
+    pub settings: Settings,
+    pub metrics: PrometheusHandle,
+    pub rate_limit: RateLimiter,
+    /// This instance's gateway, once `gateway::layer` set it up. Background jobs publish
+    /// through it.
+    pub gateway: OnceLock<SocketIo>,
 }
 
-const EXPIRY_TIME: u64 = 90;
+/// The token from an `Authorization: Bearer` header.
+pub fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let token = value.strip_prefix("Bearer ")?.trim();
+    (!token.is_empty()).then(|| token.to_string())
+}
 
-pub async fn signup_handler(
-    Extension(state): Extension<Arc<AppState>>,
-    Json(payload): Json<SignupReq>,
-) -> impl IntoResponse {
-    let pool = &state.db;
-
-    let signup_response = signup(pool, &payload).await;
-
-    let ref_client_email = payload.email.as_str();
-    let ref_client_name = payload.name.as_str();
-
-    match signup_response {
-        Ok(_) => {
-            let mut connection = state.red.clone();
-            let (_, _): ((), ()) = redis::pipe()
-                .set("client_email_signup", ref_client_email)
-                .set("client_name_signup", ref_client_name)
-                .query_async(&mut connection)
-                .await
-                .unwrap();
-            let token = match generate_otp(&pool, ref_client_email, ref_client_name).await {
-                Ok(t) => t.generate_current().unwrap(),
-                Err(err) => {
-                    error!("Failed to generate OTP: {:?}", err);
-                    return (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        Json(json!({"error": "Failed to generate OTP"})),
-                    );
-                }
-            };
-
-            let shared_token = token.as_str();
-
-            let encrypted_token = encrypt(shared_token).await.unwrap();
-
-            let _: () = redis::pipe()
-                .atomic()
-                .set_ex("token", encrypted_token, EXPIRY_TIME)
-                .ignore()
-                .query_async(&mut connection)
-                .await
-                .unwrap();
-
-            send_otp(shared_token, ref_client_email, ref_client_name).await;
-
-            (
-                StatusCode::OK,
-                Json(json!({"message": "Signup successful"})),
-            )
+/// The user whose session token came with the request. Handlers taking it answer
+/// `unauthenticated` to everyone else.
+#[derive(Clone)]
+pub struct CurrentUser(pub User);
+
+impl<S: Send + Sync> FromRequestParts<S> for CurrentUser {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, ApiError> {
+        // `authenticate_request` already looked the session up.
+        if let Some(user) = parts.extensions.get::<CurrentUser>() {
+            return Ok(user.clone());
         }
-        Err(errors) => {
-            // Iterate over the Vec<AuthenticationErrors> and return the first matching error
-            for error in errors {
-                match error {
-                    AuthenticationErrors::SignupErrorUsername(_) => {
-                        return (
-                            StatusCode::BAD_REQUEST,
-                            Json(json!({"error": format!("Username: {:?}", error)})),
-                        );
-                    }
-                    AuthenticationErrors::SignupErrorEmail(_) => {
-                        return (
-                            StatusCode::BAD_REQUEST,
-                            Json(json!({"error": format!("Email: {:?}", error)})),
-                        );
-                    }
-
-                    AuthenticationErrors::DatabaseError(_) => {
-                        return (
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                            Json(json!({"error": format!("DatabaseError: {:?}", error)})),
-                        );
-                    }
-
-                    AuthenticationErrors::HashError(_) => {
-                        return (
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                            Json(json!({"error": format!("HashingError: {:?}", error)})),
-                        );
-                    }
-                    _ => {
-                        return (
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                            Json(json!({"error": "An unexpected error occurred."})),
-                        );
-                    }
-                }
-            }
+        let Extension(state) = Extension::<Arc<AppState>>::from_request_parts(parts, state)
+            .await
+            .map_err(ApiError::internal)?;
+        let token = bearer_token(&parts.headers)
+            .ok_or_else(|| ApiError::new(ErrorCode::Unauthenticated))?;
+        let user = authenticate(state.users.as_ref(), state.sessions.as_ref(), &token).await?;
+        Ok(CurrentUser(user))
+    }
+}
 
-            (
-                StatusCode::BAD_REQUEST,
-                Json(json!({"error": "Unhandled error occurred."})),
-            )
+/// Looks up the session a request comes with before it gets rate limited, so rules keyed by
+/// user count it under the account rather than the IP. Requests without a valid session pass
+/// through unchanged; handlers that need one reject them.
+pub async fn authenticate_request(
+    AxumState(state): AxumState<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if let Some(token) = bearer_token(request.headers()) {
+        if let Ok(user) = authenticate(state.users.as_ref(), state.sessions.as_ref(), &token).await
+        {
+            request
+                .extensions_mut()
+                .insert(RateLimitSubject(format!("user:{}", user.id)));
+            request.extensions_mut().insert(CurrentUser(user));
         }
     }
+    next.run(request).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/signup",
+    tag = "auth",
+    request_body = SignupReq,
+    responses(
+        (status = 200, description = "Account created and verification code sent, or queued to be sent shortly", body = MessageResp),
+        (status = 422, description = "One entry in `fields` per rejected field, e.g. `username_taken`", body = ErrorResp),
+        (status = 502, description = "The verification email could not be queued", body = ErrorResp),
+        (status = 500, body = ErrorResp),
+    )
+)]
+pub async fn signup_handler(
+    Extension(state): Extension<Arc<AppState>>,
+    Json(payload): Json<SignupReq>,
+) -> Result<Json<MessageResp>, ApiError> {
+    signup(state.users.as_ref(), &payload).await?;
+
+    let pending = PendingVerification {
+        email: payload.email,
+        name: payload.name,
+        invite: payload.invite.filter(|code| !code.trim().is_empty()),
+    };
+    let token = start_verification(
+        state.users.as_ref(),
+        state.verifications.as_ref(),
+        &pending,
+        state.settings.otp.expiry(),
+    )
+    .await?;
+
+    send_otp(
+        state.outbox.as_ref(),
+        &token,
+        &pending.email,
+        &pending.name,
+        state.settings.otp.expiry(),
+    )
+    .await?;
+
+    Ok(message("Signup successful"))
 }
 //        let token = email_verification::generate_otp(&pool, payload.email, payload.name);
 
+#[utoipa::path(
+    post,
+    path = "/otp",
+    tag = "auth",
+    request_body = OTPVerReq,
+    responses(
+        (status = 200, description = "The code matched and the email is verified. A sign-up with an invite is in its group now", body = OTPVerResp),
+        (status = 401, description = "`otp_invalid`: the code did not match", body = ErrorResp),
+        (status = 410, description = "`otp_expired`: a new code has to be sent", body = ErrorResp),
+    )
+)]
 pub async fn otp_verify_handler(
     Extension(state): Extension<Arc<AppState>>,
+    Extension(io): Extension<SocketIo>,
     Json(payload): Json<OTPVerReq>,
-) -> impl IntoResponse {
-    let pool = &state.db;
-
-    let mut connection = state.red.clone();
-    let (client_email, client_name): (String, String) = redis::pipe()
-        .get("client_email_signup".to_string())
-        .get("client_name_signup".to_string())
-        .query_async(&mut connection)
-        .await
-        .unwrap();
-
-    let entered_code = payload.entered_code.as_str();
-    let mut con = state.red.clone();
-
-    let mut exists = con.exists::<&str, i64>("token").await.unwrap();
-    if exists == 0 {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(json!({
-                "message": "Your one-time password has expired. Please resend the otp, then check your inbox and enter the new code to continue."
-            })),
-        );
+) -> Result<Json<OTPVerResp>, ApiError> {
+    let email = &payload.email;
+    if !verify_otp(state.verifications.as_ref(), email, &payload.entered_code).await? {
+        return Err(ApiError::new(ErrorCode::OtpInvalid));
     }
 
-    let code: String = redis::cmd("GET")
-        .arg("token")
-        .query_async(&mut con)
-        .await
-        .unwrap();
-
-    let email_verification_response = verify_otp(Extension(state), entered_code).await;
-    let encrypted_code = encrypt(entered_code).await.unwrap();
-
-    match email_verification_response {
-        Ok(true) => {
-            connection.del::<&str, i64>("token").await.unwrap();
-            connection
-                .del::<&str, String>("client_email_signup")
-                .await
-                .unwrap();
-            sqlx::query!(
-                "UPDATE users SET user_verified = true WHERE username = $1",
-                client_name,
-            );
-            return (
-                StatusCode::OK,
-                Json(json!({"Valid": "The code entered is valid."})),
-            );
+    let mut conversation_id = None;
+    if let Some(pending) = state.verifications.pending(email).await? {
+        state.users.mark_verified(&pending.email).await?;
+        if let Some(code) = &pending.invite {
+            conversation_id = join_invited_group(&state, &io, &pending.email, code).await;
         }
-        Ok(false) => (
-            StatusCode::UNAUTHORIZED,
-            Json(json!({"Invalid": "The code entered is invalid."})),
-        ),
-        Err(e) => (StatusCode::BAD_REQUEST, Json(json!(e.to_string()))),
     }
+    state.verifications.finish(email).await?;
+    Ok(Json(OTPVerResp {
+        valid: true,
+        message: "The code entered is valid.".to_string(),
+        conversation_id,
+    }))
 }
 
-#[path = "../service/google_auth.rs"]
-mod google;
-#[path = "../service/ms_auth.rs"]
-mod microsoft;
-/*#[path = "../service/google_auth.rs"]
-mod github;
-*/
-pub async fn oauth_callback_handler(
+#[utoipa::path(
+    post,
+    path = "/otp/resend",
+    tag = "auth",
+    request_body = ResendOtpReq,
+    responses(
+        (status = 200, description = "A new code was sent or queued, the earlier one no longer works", body = MessageResp),
+        (status = 410, description = "`otp_expired`: no sign-up is waiting on this email, it has to start over", body = ErrorResp),
+        (status = 429, description = "`rate_limited`: too many codes for this email", body = ErrorResp),
+        (status = 502, description = "The verification email could not be queued", body = ErrorResp),
+    )
+)]
+pub async fn resend_otp_handler(
     Extension(state): Extension<Arc<AppState>>,
-    Json(payload): Json<AuthInitiateRequest>,
-) -> Result<impl IntoResponse, anyhow::Error> {
-    match payload.provider.as_str() {
-        "GOOGLE" => google::google_auth().await?,
-        "MS" => microsoft::ms_auth()
-        _ =>
+    Json(payload): Json<ResendOtpReq>,
+) -> Result<Json<MessageResp>, ApiError> {
+    // Counted per address, whether or not a sign-up is waiting on it.
+    check_command(&state, "resend_otp", &verification_key(&payload.email)).await?;
+    let pending = state
+        .verifications
+        .pending(&payload.email)
+        .await?
+        .ok_or(ApiError::new(ErrorCode::OtpExpired))?;
+
+    // Replaces the earlier code, so only the newest one works.
+    let token = start_verification(
+        state.users.as_ref(),
+        state.verifications.as_ref(),
+        &pending,
+        state.settings.otp.expiry(),
+    )
+    .await?;
+    send_otp(
+        state.outbox.as_ref(),
+        &token,
+        &pending.email,
+        &pending.name,
+        state.settings.otp.expiry(),
+    )
+    .await?;
+
+    Ok(message("Code sent"))
+}
+
+// Joins the group a sign-up was invited to. The email is verified either way, so an invite that
+// stopped working in the meantime only means the new user isn't in the group.
+async fn join_invited_group(
+    state: &AppState,
+    io: &SocketIo,
+    email: &str,
+    code: &str,
+) -> Option<i64> {
+    let result = match state.users.find_by_email(email).await {
+        Ok(Some(user)) => chat::accept_invite(state.conversations.as_ref(), &user, code).await,
+        Ok(None) => return None,
+        Err(err) => Err(ChatError::Store(err)),
+    };
+    match result {
+        Ok((group, change)) => {
+            if let Some(change) = change {
+                gateway::publish(io, &change.recipients, &change.message);
+            }
+            Some(group.id)
+        }
+        Err(ChatError::InviteNotFound | ChatError::InviteExpired) => {
+            debug!("Invite given at sign-up no longer works");
+            None
+        }
+        Err(err) => {
+            warn!("Sign-up could not join its invited group: {:?}", err);
+            None
+        }
     }
-    Ok((StatusCode::OK, Json(json!({"message": "Login successful"}))))
+}
+
+// Lets a new social sign-up replace the username that was allocated for them.
+#[utoipa::path(
+    post,
+    path = "/username",
+    tag = "auth",
+    request_body = ChooseUsernameReq,
+    responses(
+        (status = 200, body = UsernameResp),
+        (status = 422, description = "The username breaks the naming rules", body = ErrorResp),
+        (status = 401, description = "The ticket expired", body = ErrorResp),
+        (status = 409, description = "The username is taken", body = ErrorResp),
+    )
+)]
+pub async fn choose_username_handler(
+    Extension(state): Extension<Arc<AppState>>,
+    Json(payload): Json<ChooseUsernameReq>,
+) -> Result<Json<UsernameResp>, ApiError> {
+    let username = choose_username(
+        state.users.as_ref(),
+        state.ephemeral.as_ref(),
+        &payload.ticket,
+        &payload.username,
+    )
+    .await?;
+    Ok(Json(UsernameResp { username }))
 }
 
 //Personalized handlers just store the info in the Database.
@@ -258,16 +388,19 @@ pub async fn oauth_callback_handler(
 pub async fn personalize_theme(
     Extension(state): Extension<Arc<AppState>>,
     Json(payload): Json<Theme>,
-) -> Result<impl IntoResponse, anyhow::Error> {
-    let pool = &state.db;
-    let mut con = state.red.clone();
-    let client_name = con.get::<&str, String>("client_name_signup").await.unwrap();
+) -> Result<impl IntoResponse, ApiError> {
+    // Only a sign-up that finished recently can still be personalized without a session.
+    let user = match state.verifications.signup_name(&payload.email).await? {
+        Some(_) => state.users.find_by_email(&payload.email).await?,
+        None => None,
+    }
+    .ok_or_else(|| ApiError::internal(anyhow::anyhow!("No sign-up to personalize")))?;
     sqlx::query!(
-        "UPDATE users SET personalization = personalization || $1::jsonb WHERE name = $2",
+        "UPDATE users SET personalization = personalization || $1::jsonb WHERE id = $2",
         serde_json::json!({"theme": payload.mode,"color":payload.rbg}),
-        client_name
+        user.id
     )
-    .fetch_optional(pool)
+    .execute(&state.db)
     .await
     .map_err(|e| AuthenticationErrors::DatabaseError(e))?;
     Ok(StatusCode::OK)
@@ -291,16 +424,31 @@ pub async fn personalize_avatar(
 ) -> impl IntoResponse {
 }
 
+#[utoipa::path(
+    post,
+    path = "/login",
+    tag = "auth",
+    request_body = LoginReq,
+    responses(
+        (status = 200, body = LoginResp),
+        (status = 401, body = ErrorResp),
+        (status = 403, description = "`account_suspended` or `password_reset_required`", body = ErrorResp),
+    )
+)]
 pub async fn login_handler(
     Extension(state): Extension<Arc<AppState>>,
     Json(payload): Json<LoginReq>,
-) -> impl IntoResponse {
-    let pool = &state.db;
-    match login(&pool, payload).await {
-        Ok(_) => (StatusCode::OK, Json(json!({"message": "Login successful"}))),
-        Err(err) => (
-            StatusCode::UNAUTHORIZED,
-            Json(json!({"error": format!("Login failed: {}", err)})),
-        ),
-    }
+) -> Result<Json<LoginResp>, ApiError> {
+    let result = login(
+        state.users.as_ref(),
+        state.sessions.as_ref(),
+        payload,
+        state.settings.security.session_ttl(),
+    )
+    .await;
+    telemetry::record_login("password", result.is_ok());
+    Ok(Json(LoginResp {
+        message: "Login successful".to_string(),
+        session: result?,
+    }))
 }
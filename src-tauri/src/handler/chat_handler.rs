@@ -0,0 +1,805 @@
+// REST side of chat: opening conversations, managing groups and their invite links, posting and
+// reading history, presence. Posted messages, the system messages recording changes to groups
+// and picked presence states are also published through the gateway.
+use axum::{http::StatusCode, Extension};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use socketioxide::SocketIo;
+use std::sync::Arc;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::auth_handler::{AppState, CurrentUser};
+use crate::chat::{self, ChatMessage, GroupChange, HistoryPage, InvitePreview};
+use crate::error::{ApiError, ErrorCode, ErrorResp};
+use crate::extract::{Json, Path, Query};
+use crate::gateway;
+use crate::presence::{self, PresenceUpdate};
+use crate::repository::{Conversation, HistoryCursor, Invite, User};
+
+#[derive(Deserialize, ToSchema)]
+pub struct OpenDirectReq {
+    /// Who to talk to.
+    pub username: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateGroupReq {
+    pub name: String,
+    pub topic: Option<String>,
+    /// An https URL.
+    pub avatar_url: Option<String>,
+    /// Usernames of the first members, besides the creator, who becomes the owner.
+    #[serde(default)]
+    pub members: Vec<String>,
+}
+
+/// Fields left out stay as they are. An empty `topic` or `avatar_url` removes it.
+#[derive(Deserialize, ToSchema)]
+pub struct UpdateGroupReq {
+    pub name: Option<String>,
+    pub topic: Option<String>,
+    pub avatar_url: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct AddMembersReq {
+    pub usernames: Vec<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct SetRoleReq {
+    /// `admin` or `member`.
+    pub role: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct SetPresenceReq {
+    /// `online`, `away`, `dnd` or `invisible`. `online` follows the user's devices again, which
+    /// show as away once they've all been idle for a while.
+    pub state: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct TransferOwnershipReq {
+    /// The member who becomes the owner.
+    pub user_id: i64,
+}
+
+/// Without `max_uses` or `expires_in` the invite works until it is revoked.
+#[derive(Deserialize, ToSchema)]
+pub struct CreateInviteReq {
+    /// `member`, the default, or `admin`, which only the owner can hand out.
+    pub role: Option<String>,
+    /// How many people can join through it.
+    pub max_uses: Option<i64>,
+    /// Seconds until it expires, at most 30 days.
+    pub expires_in: Option<i64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct MemberResp {
+    pub id: i64,
+    pub username: String,
+    pub name: String,
+    /// `owner`, `admin` or `member`.
+    pub role: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ConversationResp {
+    pub id: i64,
+    /// `direct` for 1:1 conversations, `group` for groups.
+    pub kind: String,
+    /// Groups only, like `topic` and `avatar_url`.
+    pub name: Option<String>,
+    pub topic: Option<String>,
+    pub avatar_url: Option<String>,
+    /// `seq` of the latest message, 0 if there is none yet.
+    pub last_seq: i64,
+    /// RFC 3339, in UTC.
+    pub created_at: String,
+    pub members: Vec<MemberResp>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct InviteResp {
+    pub id: i64,
+    /// Goes into the link, and into `invite` at sign-up.
+    pub code: String,
+    /// `admin` or `member`, given to whoever joins through it.
+    pub role: String,
+    /// Absent for no limit.
+    pub max_uses: Option<i32>,
+    pub uses: i32,
+    /// Absent once the creator's account is gone.
+    pub created_by: Option<i64>,
+    /// RFC 3339, in UTC, like the other times.
+    pub created_at: String,
+    pub expires_at: Option<String>,
+    pub revoked_at: Option<String>,
+    /// Not revoked, expired or used up.
+    pub usable: bool,
+}
+
+impl From<Invite> for InviteResp {
+    fn from(invite: Invite) -> Self {
+        InviteResp {
+            usable: invite.usable(Utc::now()),
+            id: invite.id,
+            code: invite.code,
+            role: invite.role,
+            max_uses: invite.max_uses,
+            uses: invite.uses,
+            created_by: invite.created_by,
+            created_at: chat::timestamp(invite.created_at),
+            expires_at: invite.expires_at.map(chat::timestamp),
+            revoked_at: invite.revoked_at.map(chat::timestamp),
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct InviteUseResp {
+    /// Absent, like `username`, once their account is gone.
+    pub user_id: Option<i64>,
+    pub username: Option<String>,
+    /// RFC 3339, in UTC.
+    pub used_at: String,
+}
+
+/// The group behind an invite, for whoever holds the link, member or not.
+#[derive(Serialize, ToSchema)]
+pub struct InvitePreviewResp {
+    pub conversation_id: i64,
+    pub name: Option<String>,
+    pub topic: Option<String>,
+    pub avatar_url: Option<String>,
+    pub member_count: usize,
+    /// The role joining through the invite gives.
+    pub role: String,
+    /// RFC 3339, in UTC.
+    pub expires_at: Option<String>,
+}
+
+impl From<InvitePreview> for InvitePreviewResp {
+    fn from(preview: InvitePreview) -> Self {
+        InvitePreviewResp {
+            conversation_id: preview.group.id,
+            name: preview.group.name,
+            topic: preview.group.topic,
+            avatar_url: preview.group.avatar_url,
+            member_count: preview.member_count,
+            role: preview.invite.role,
+            expires_at: preview.invite.expires_at.map(chat::timestamp),
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct PostMessageReq {
+    pub body: String,
+    /// Echoed back in the message, see `ChatMessage`.
+    pub nonce: Option<String>,
+}
+
+/// At most one of `before` and `after`. Without either, the latest messages.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct HistoryQuery {
+    /// Messages older than this `seq`.
+    pub before: Option<i64>,
+    /// Messages newer than this `seq`.
+    pub after: Option<i64>,
+    /// Page size, 50 if not given and at most 100.
+    pub limit: Option<i64>,
+}
+
+async fn conversation_resp(
+    state: &AppState,
+    conversation: Conversation,
+) -> Result<ConversationResp, ApiError> {
+    let mut members = Vec::new();
+    for member in state.conversations.members(conversation.id).await? {
+        if let Some(user) = state.users.find_by_id(member.user_id).await? {
+            members.push(MemberResp {
+                id: user.id,
+                username: user.username,
+                name: user.name,
+                role: member.role,
+            });
+        }
+    }
+    Ok(ConversationResp {
+        id: conversation.id,
+        kind: conversation.kind,
+        name: conversation.name,
+        topic: conversation.topic,
+        avatar_url: conversation.avatar_url,
+        last_seq: conversation.last_seq,
+        created_at: chat::timestamp(conversation.created_at),
+        members,
+    })
+}
+
+// The group as it is after a change, once the change has gone out through the gateway.
+async fn changed(
+    state: &AppState,
+    io: &SocketIo,
+    user: &User,
+    conversation_id: i64,
+    change: Option<GroupChange>,
+) -> Result<Json<ConversationResp>, ApiError> {
+    if let Some(change) = change {
+        gateway::publish(io, &change.recipients, &change.message);
+    }
+    let (conversation, _) =
+        chat::membership(state.conversations.as_ref(), user, conversation_id).await?;
+    Ok(Json(conversation_resp(state, conversation).await?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/conversations/direct",
+    tag = "chat",
+    request_body = OpenDirectReq,
+    security(("session" = [])),
+    responses(
+        (status = 200, description = "The 1:1 conversation with the user, created if there was none", body = ConversationResp),
+        (status = 401, body = ErrorResp),
+        (status = 404, description = "`user_not_found`", body = ErrorResp),
+        (status = 422, description = "`cannot_message_self`", body = ErrorResp),
+    )
+)]
+pub async fn open_direct_handler(
+    Extension(state): Extension<Arc<AppState>>,
+    CurrentUser(user): CurrentUser,
+    Json(payload): Json<OpenDirectReq>,
+) -> Result<Json<ConversationResp>, ApiError> {
+    let conversation = chat::open_direct(
+        state.users.as_ref(),
+        state.conversations.as_ref(),
+        &user,
+        &payload.username,
+    )
+    .await?;
+    Ok(Json(conversation_resp(&state, conversation).await?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/conversations/{id}/messages",
+    tag = "chat",
+    params(("id" = i64, Path, description = "Conversation id")),
+    request_body = PostMessageReq,
+    security(("session" = [])),
+    responses(
+        (status = 201, description = "Stored, and published to the members through the gateway", body = ChatMessage),
+        (status = 401, body = ErrorResp),
+        (status = 404, description = "`conversation_not_found`, also for conversations the user isn't in", body = ErrorResp),
+        (status = 422, description = "`message_empty` or `message_too_long`", body = ErrorResp),
+    )
+)]
+pub async fn post_message_handler(
+    Extension(state): Extension<Arc<AppState>>,
+    Extension(io): Extension<SocketIo>,
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i64>,
+    Json(payload): Json<PostMessageReq>,
+) -> Result<(StatusCode, Json<ChatMessage>), ApiError> {
+    let (message, members) = chat::post_message(
+        state.conversations.as_ref(),
+        &user,
+        id,
+        &payload.body,
+        payload.nonce,
+    )
+    .await?;
+    gateway::publish(&io, &members, &message);
+    Ok((StatusCode::CREATED, Json(message)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/conversations/{id}/messages",
+    tag = "chat",
+    params(("id" = i64, Path, description = "Conversation id"), HistoryQuery),
+    security(("session" = [])),
+    responses(
+        (status = 200, body = HistoryPage),
+        (status = 401, body = ErrorResp),
+        (status = 404, description = "`conversation_not_found`, also for conversations the user isn't in", body = ErrorResp),
+        (status = 422, description = "Both `before` and `after` were given", body = ErrorResp),
+    )
+)]
+pub async fn history_handler(
+    Extension(state): Extension<Arc<AppState>>,
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i64>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<HistoryPage>, ApiError> {
+    let cursor = match (query.before, query.after) {
+        (None, None) => HistoryCursor::Latest,
+        (Some(before), None) => HistoryCursor::Before(before),
+        (None, Some(after)) => HistoryCursor::After(after),
+        (Some(_), Some(_)) => return Err(ApiError::new(ErrorCode::ValidationFailed)),
+    };
+    let page = chat::history(state.conversations.as_ref(), &user, id, cursor, query.limit).await?;
+    Ok(Json(page))
+}
+
+#[utoipa::path(
+    get,
+    path = "/conversations/{id}",
+    tag = "chat",
+    params(("id" = i64, Path, description = "Conversation id")),
+    security(("session" = [])),
+    responses(
+        (status = 200, body = ConversationResp),
+        (status = 401, body = ErrorResp),
+        (status = 404, description = "`conversation_not_found`, also for conversations the user isn't in", body = ErrorResp),
+    )
+)]
+pub async fn conversation_handler(
+    Extension(state): Extension<Arc<AppState>>,
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i64>,
+) -> Result<Json<ConversationResp>, ApiError> {
+    let (conversation, _) = chat::membership(state.conversations.as_ref(), &user, id).await?;
+    Ok(Json(conversation_resp(&state, conversation).await?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/groups",
+    tag = "chat",
+    request_body = CreateGroupReq,
+    security(("session" = [])),
+    responses(
+        (status = 201, description = "The group, owned by the creator", body = ConversationResp),
+        (status = 401, body = ErrorResp),
+        (status = 404, description = "`user_not_found`: one of the members doesn't exist", body = ErrorResp),
+        (status = 422, description = "One entry in `fields` per rejected field", body = ErrorResp),
+    )
+)]
+pub async fn create_group_handler(
+    Extension(state): Extension<Arc<AppState>>,
+    Extension(io): Extension<SocketIo>,
+    CurrentUser(user): CurrentUser,
+    Json(payload): Json<CreateGroupReq>,
+) -> Result<(StatusCode, Json<ConversationResp>), ApiError> {
+    let details = chat::validate_group(
+        &payload.name,
+        payload.topic.as_deref(),
+        payload.avatar_url.as_deref(),
+    )?;
+    let (conversation, change) = chat::create_group(
+        state.users.as_ref(),
+        state.conversations.as_ref(),
+        &user,
+        &details,
+        &payload.members,
+    )
+    .await?;
+    gateway::publish(&io, &change.recipients, &change.message);
+    Ok((
+        StatusCode::CREATED,
+        Json(conversation_resp(&state, conversation).await?),
+    ))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/conversations/{id}",
+    tag = "chat",
+    params(("id" = i64, Path, description = "Conversation id")),
+    request_body = UpdateGroupReq,
+    security(("session" = [])),
+    responses(
+        (status = 200, body = ConversationResp),
+        (status = 401, body = ErrorResp),
+        (status = 403, description = "`permission_denied`: admins and the owner only, and groups only", body = ErrorResp),
+        (status = 404, body = ErrorResp),
+        (status = 422, description = "One entry in `fields` per rejected field", body = ErrorResp),
+    )
+)]
+pub async fn update_group_handler(
+    Extension(state): Extension<Arc<AppState>>,
+    Extension(io): Extension<SocketIo>,
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i64>,
+    Json(payload): Json<UpdateGroupReq>,
+) -> Result<Json<ConversationResp>, ApiError> {
+    let change = chat::update_group(
+        state.conversations.as_ref(),
+        &user,
+        id,
+        payload.name.as_deref(),
+        payload.topic.as_deref(),
+        payload.avatar_url.as_deref(),
+    )
+    .await?;
+    changed(&state, &io, &user, id, Some(change)).await
+}
+
+#[utoipa::path(
+    delete,
+    path = "/conversations/{id}",
+    tag = "chat",
+    params(("id" = i64, Path, description = "Conversation id")),
+    security(("session" = [])),
+    responses(
+        (status = 204, description = "The group and its history are gone"),
+        (status = 401, body = ErrorResp),
+        (status = 403, description = "`permission_denied`: the owner only, and groups only", body = ErrorResp),
+        (status = 404, body = ErrorResp),
+    )
+)]
+pub async fn delete_group_handler(
+    Extension(state): Extension<Arc<AppState>>,
+    Extension(io): Extension<SocketIo>,
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, ApiError> {
+    let members = chat::delete_group(state.conversations.as_ref(), &user, id).await?;
+    gateway::publish_deleted(&io, &members, id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/conversations/{id}/members",
+    tag = "chat",
+    params(("id" = i64, Path, description = "Conversation id")),
+    request_body = AddMembersReq,
+    security(("session" = [])),
+    responses(
+        (status = 200, description = "The group with its new members", body = ConversationResp),
+        (status = 401, body = ErrorResp),
+        (status = 403, description = "`permission_denied`: admins and the owner only, and groups only", body = ErrorResp),
+        (status = 404, description = "`conversation_not_found`, or `user_not_found` for a username", body = ErrorResp),
+    )
+)]
+pub async fn add_members_handler(
+    Extension(state): Extension<Arc<AppState>>,
+    Extension(io): Extension<SocketIo>,
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i64>,
+    Json(payload): Json<AddMembersReq>,
+) -> Result<Json<ConversationResp>, ApiError> {
+    let change = chat::add_members(
+        state.users.as_ref(),
+        state.conversations.as_ref(),
+        &user,
+        id,
+        &payload.usernames,
+    )
+    .await?;
+    changed(&state, &io, &user, id, change).await
+}
+
+#[utoipa::path(
+    patch,
+    path = "/conversations/{id}/members/{user_id}",
+    tag = "chat",
+    params(
+        ("id" = i64, Path, description = "Conversation id"),
+        ("user_id" = i64, Path, description = "The member"),
+    ),
+    request_body = SetRoleReq,
+    security(("session" = [])),
+    responses(
+        (status = 200, body = ConversationResp),
+        (status = 401, body = ErrorResp),
+        (status = 403, description = "`permission_denied`: the owner only, and not on themselves", body = ErrorResp),
+        (status = 404, description = "`conversation_not_found`, or `user_not_found` for someone who isn't a member", body = ErrorResp),
+        (status = 422, description = "`role_invalid`", body = ErrorResp),
+    )
+)]
+pub async fn set_role_handler(
+    Extension(state): Extension<Arc<AppState>>,
+    Extension(io): Extension<SocketIo>,
+    CurrentUser(user): CurrentUser,
+    Path((id, member_id)): Path<(i64, i64)>,
+    Json(payload): Json<SetRoleReq>,
+) -> Result<Json<ConversationResp>, ApiError> {
+    let change = chat::set_role(
+        state.conversations.as_ref(),
+        &user,
+        id,
+        member_id,
+        &payload.role,
+    )
+    .await?;
+    changed(&state, &io, &user, id, change).await
+}
+
+#[utoipa::path(
+    delete,
+    path = "/conversations/{id}/members/{user_id}",
+    tag = "chat",
+    params(
+        ("id" = i64, Path, description = "Conversation id"),
+        ("user_id" = i64, Path, description = "The member"),
+    ),
+    security(("session" = [])),
+    responses(
+        (status = 200, description = "The group without the member", body = ConversationResp),
+        (status = 401, body = ErrorResp),
+        (status = 403, description = "`permission_denied`: the owner removes anyone, admins plain members; nobody removes themselves", body = ErrorResp),
+        (status = 404, description = "`conversation_not_found`, or `user_not_found` for someone who isn't a member", body = ErrorResp),
+    )
+)]
+pub async fn remove_member_handler(
+    Extension(state): Extension<Arc<AppState>>,
+    Extension(io): Extension<SocketIo>,
+    CurrentUser(user): CurrentUser,
+    Path((id, member_id)): Path<(i64, i64)>,
+) -> Result<Json<ConversationResp>, ApiError> {
+    let change = chat::remove_member(state.conversations.as_ref(), &user, id, member_id).await?;
+    changed(&state, &io, &user, id, Some(change)).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/conversations/{id}/leave",
+    tag = "chat",
+    params(("id" = i64, Path, description = "Conversation id")),
+    security(("session" = [])),
+    responses(
+        (status = 204, description = "No longer a member"),
+        (status = 401, body = ErrorResp),
+        (status = 403, description = "`permission_denied`: 1:1 conversations can't be left", body = ErrorResp),
+        (status = 404, body = ErrorResp),
+        (status = 409, description = "`owner_must_transfer`", body = ErrorResp),
+    )
+)]
+pub async fn leave_handler(
+    Extension(state): Extension<Arc<AppState>>,
+    Extension(io): Extension<SocketIo>,
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, ApiError> {
+    let change = chat::leave(state.conversations.as_ref(), &user, id).await?;
+    gateway::publish(&io, &change.recipients, &change.message);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/conversations/{id}/owner",
+    tag = "chat",
+    params(("id" = i64, Path, description = "Conversation id")),
+    request_body = TransferOwnershipReq,
+    security(("session" = [])),
+    responses(
+        (status = 200, description = "The group under its new owner; the previous one is an admin now", body = ConversationResp),
+        (status = 401, body = ErrorResp),
+        (status = 403, description = "`permission_denied`: the owner only", body = ErrorResp),
+        (status = 404, description = "`conversation_not_found`, or `user_not_found` for someone who isn't a member", body = ErrorResp),
+    )
+)]
+pub async fn transfer_ownership_handler(
+    Extension(state): Extension<Arc<AppState>>,
+    Extension(io): Extension<SocketIo>,
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i64>,
+    Json(payload): Json<TransferOwnershipReq>,
+) -> Result<Json<ConversationResp>, ApiError> {
+    let change =
+        chat::transfer_ownership(state.conversations.as_ref(), &user, id, payload.user_id).await?;
+    changed(&state, &io, &user, id, Some(change)).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/conversations/{id}/invites",
+    tag = "chat",
+    params(("id" = i64, Path, description = "Conversation id")),
+    request_body = CreateInviteReq,
+    security(("session" = [])),
+    responses(
+        (status = 201, body = InviteResp),
+        (status = 401, body = ErrorResp),
+        (status = 403, description = "`permission_denied`: admins invite members, the owner also admins; groups only", body = ErrorResp),
+        (status = 404, body = ErrorResp),
+        (status = 422, description = "One entry in `fields` per rejected field", body = ErrorResp),
+    )
+)]
+pub async fn create_invite_handler(
+    Extension(state): Extension<Arc<AppState>>,
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i64>,
+    Json(payload): Json<CreateInviteReq>,
+) -> Result<(StatusCode, Json<InviteResp>), ApiError> {
+    let invite = chat::create_invite(
+        state.conversations.as_ref(),
+        &user,
+        id,
+        payload.role.as_deref(),
+        payload.max_uses,
+        payload.expires_in,
+    )
+    .await?;
+    Ok((StatusCode::CREATED, Json(invite.into())))
+}
+
+#[utoipa::path(
+    get,
+    path = "/conversations/{id}/invites",
+    tag = "chat",
+    params(("id" = i64, Path, description = "Conversation id")),
+    security(("session" = [])),
+    responses(
+        (status = 200, description = "Newest first, revoked and expired ones included", body = [InviteResp]),
+        (status = 401, body = ErrorResp),
+        (status = 403, description = "`permission_denied`: admins and the owner only, and groups only", body = ErrorResp),
+        (status = 404, body = ErrorResp),
+    )
+)]
+pub async fn invites_handler(
+    Extension(state): Extension<Arc<AppState>>,
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i64>,
+) -> Result<Json<Vec<InviteResp>>, ApiError> {
+    let invites = chat::invites(state.conversations.as_ref(), &user, id).await?;
+    Ok(Json(invites.into_iter().map(InviteResp::from).collect()))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/conversations/{id}/invites/{invite_id}",
+    tag = "chat",
+    params(
+        ("id" = i64, Path, description = "Conversation id"),
+        ("invite_id" = i64, Path, description = "The invite"),
+    ),
+    security(("session" = [])),
+    responses(
+        (status = 204, description = "Revoked; people who already joined stay"),
+        (status = 401, body = ErrorResp),
+        (status = 403, description = "`permission_denied`: admins and the owner only", body = ErrorResp),
+        (status = 404, description = "`conversation_not_found` or `invite_not_found`", body = ErrorResp),
+    )
+)]
+pub async fn revoke_invite_handler(
+    Extension(state): Extension<Arc<AppState>>,
+    CurrentUser(user): CurrentUser,
+    Path((id, invite_id)): Path<(i64, i64)>,
+) -> Result<StatusCode, ApiError> {
+    chat::revoke_invite(state.conversations.as_ref(), &user, id, invite_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/conversations/{id}/invites/{invite_id}/uses",
+    tag = "chat",
+    params(
+        ("id" = i64, Path, description = "Conversation id"),
+        ("invite_id" = i64, Path, description = "The invite"),
+    ),
+    security(("session" = [])),
+    responses(
+        (status = 200, description = "Who joined through the invite, latest first", body = [InviteUseResp]),
+        (status = 401, body = ErrorResp),
+        (status = 403, description = "`permission_denied`: admins and the owner only", body = ErrorResp),
+        (status = 404, description = "`conversation_not_found` or `invite_not_found`", body = ErrorResp),
+    )
+)]
+pub async fn invite_uses_handler(
+    Extension(state): Extension<Arc<AppState>>,
+    CurrentUser(user): CurrentUser,
+    Path((id, invite_id)): Path<(i64, i64)>,
+) -> Result<Json<Vec<InviteUseResp>>, ApiError> {
+    let mut uses = Vec::new();
+    for used in chat::invite_uses(state.conversations.as_ref(), &user, id, invite_id).await? {
+        let username = match used.user_id {
+            Some(user_id) => state
+                .users
+                .find_by_id(user_id)
+                .await?
+                .map(|user| user.username),
+            None => None,
+        };
+        uses.push(InviteUseResp {
+            user_id: used.user_id,
+            username,
+            used_at: chat::timestamp(used.used_at),
+        });
+    }
+    Ok(Json(uses))
+}
+
+#[utoipa::path(
+    get,
+    path = "/invites/{code}",
+    tag = "chat",
+    params(("code" = String, Path, description = "Code from the invite link")),
+    responses(
+        (status = 200, body = InvitePreviewResp),
+        (status = 404, description = "`invite_not_found`", body = ErrorResp),
+        (status = 410, description = "`invite_expired`: revoked, expired or used up", body = ErrorResp),
+    )
+)]
+pub async fn preview_invite_handler(
+    Extension(state): Extension<Arc<AppState>>,
+    Path(code): Path<String>,
+) -> Result<Json<InvitePreviewResp>, ApiError> {
+    let preview = chat::preview_invite(state.conversations.as_ref(), &code).await?;
+    Ok(Json(preview.into()))
+}
+
+#[utoipa::path(
+    post,
+    path = "/invites/{code}/accept",
+    tag = "chat",
+    params(("code" = String, Path, description = "Code from the invite link")),
+    security(("session" = [])),
+    responses(
+        (status = 200, description = "The group, joined with the invite's role unless already a member", body = ConversationResp),
+        (status = 401, body = ErrorResp),
+        (status = 404, description = "`invite_not_found`", body = ErrorResp),
+        (status = 410, description = "`invite_expired`: revoked, expired or used up", body = ErrorResp),
+    )
+)]
+pub async fn accept_invite_handler(
+    Extension(state): Extension<Arc<AppState>>,
+    Extension(io): Extension<SocketIo>,
+    CurrentUser(user): CurrentUser,
+    Path(code): Path<String>,
+) -> Result<Json<ConversationResp>, ApiError> {
+    let (group, change) = chat::accept_invite(state.conversations.as_ref(), &user, &code).await?;
+    changed(&state, &io, &user, group.id, change).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/presence",
+    tag = "chat",
+    security(("session" = [])),
+    responses(
+        (status = 200, description = "Everyone sharing a conversation with the user, by id", body = [PresenceUpdate]),
+        (status = 401, body = ErrorResp),
+    )
+)]
+pub async fn presence_handler(
+    Extension(state): Extension<Arc<AppState>>,
+    CurrentUser(user): CurrentUser,
+) -> Result<Json<Vec<PresenceUpdate>>, ApiError> {
+    let contacts =
+        presence::contacts(state.users.as_ref(), state.conversations.as_ref(), user.id).await?;
+    Ok(Json(contacts))
+}
+
+#[utoipa::path(
+    put,
+    path = "/presence",
+    tag = "chat",
+    request_body = SetPresenceReq,
+    security(("session" = [])),
+    responses(
+        (status = 204, description = "Picked. Shows once the user has a device connected to the gateway"),
+        (status = 401, body = ErrorResp),
+        (status = 422, description = "`presence_state_invalid`", body = ErrorResp),
+    )
+)]
+pub async fn set_presence_handler(
+    Extension(state): Extension<Arc<AppState>>,
+    Extension(io): Extension<SocketIo>,
+    CurrentUser(user): CurrentUser,
+    Json(payload): Json<SetPresenceReq>,
+) -> Result<StatusCode, ApiError> {
+    let update = presence::set_state(
+        state.users.as_ref(),
+        state.presence.as_ref(),
+        &state.settings.presence,
+        user.id,
+        &payload.state,
+    )
+    .await?;
+    if let Some(update) = update {
+        let recipients = presence::recipients(state.conversations.as_ref(), user.id).await?;
+        gateway::publish_presence(&io, &recipients, &update);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,409 @@
+// Socket.IO gateway, the real-time side of chat. Clients connect to `NAMESPACE` with their session
+// token and join a room of their own that all their devices share, so reaching a user is one
+// emit to that room. Messages are stored first, whether they come in here or through the REST
+// API, and what gets published is the stored message. Each connection is also a device for
+// presence, counted for as long as it sends heartbeats.
+use axum::http::{header, HeaderMap};
+use serde::Deserialize;
+use serde_json::json;
+use socketioxide::extract::{AckSender, Extension, SocketRef, State, TryData};
+use socketioxide::handler::ConnectHandler;
+use socketioxide::layer::SocketIoLayer;
+use socketioxide::SocketIo;
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+use crate::auth_handler::{bearer_token, AppState};
+use crate::auth_service::authenticate;
+use crate::chat::{self, ChatMessage};
+use crate::error::{ApiError, ErrorCode, Locale};
+use crate::presence::{self, PresenceError, PresenceUpdate};
+use crate::telemetry;
+
+pub const NAMESPACE: &str = "/chat";
+
+/// Client to server. Acknowledged with `{"message": ChatMessage}` or `{"error": ErrorBody}`.
+pub const SEND_MESSAGE: &str = "message:send";
+/// Server to client, with a `ChatMessage`. Sent to everyone in the conversation, including the
+/// sender's other devices, but not to the socket that sent it, which has the ack. Messages posted
+/// through the REST API reach every device, and so do the system messages recording changes to
+/// a group, which also go to whoever was just removed from it.
+pub const NEW_MESSAGE: &str = "message:new";
+/// Server to client, with `{"conversation_id": ...}`, to the members of a group that was deleted.
+pub const CONVERSATION_DELETED: &str = "conversation:deleted";
+/// Client to server, with `{"active": bool}`, more often than `presence.heartbeat_timeout_secs`.
+/// `active` says the user did something on this device since the last one. Connections that
+/// stop sending them stop counting as a device, and those whose session ended are closed.
+pub const PRESENCE_HEARTBEAT: &str = "presence:heartbeat";
+/// Client to server, with `{"state": ...}`, one of `presence::STATES`. Acknowledged with `{}` or
+/// `{"error": ErrorBody}`.
+pub const SET_PRESENCE: &str = "presence:set";
+/// Server to client, with a `PresenceUpdate`, to everyone sharing a conversation with the user
+/// and to all the user's devices.
+pub const PRESENCE_UPDATE: &str = "presence:update";
+
+/// The `auth` object Socket.IO clients send with the handshake.
+#[derive(Debug, Default, Deserialize)]
+pub struct Handshake {
+    /// Session token from the login response.
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SendMessage {
+    pub conversation_id: Option<i64>,
+    /// Username, for the 1:1 conversation with them, when there's no `conversation_id`.
+    pub to: Option<String>,
+    pub body: String,
+    pub nonce: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Heartbeat {
+    #[serde(default)]
+    pub active: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetPresence {
+    pub state: String,
+}
+
+// Attached to the socket once the handshake checks out.
+#[derive(Clone)]
+struct Identity {
+    user_id: i64,
+    token: String,
+    locale: Locale,
+}
+
+pub fn user_room(user_id: i64) -> String {
+    format!("user:{}", user_id)
+}
+
+fn member_rooms(member_ids: &[i64]) -> Vec<String> {
+    member_ids.iter().map(|id| user_room(*id)).collect()
+}
+
+/// Sends a stored message to every connected device of the conversation's members. Members
+/// that miss it catch up from history, so a failure is only logged.
+pub fn publish(io: &SocketIo, member_ids: &[i64], message: &ChatMessage) {
+    let Some(chat) = io.of(NAMESPACE) else {
+        return;
+    };
+    if let Err(err) = chat.to(member_rooms(member_ids)).emit(NEW_MESSAGE, message) {
+        warn!("Message {} not published: {:?}", message.id, err);
+    }
+}
+
+/// Tells the former members of a deleted conversation.
+pub fn publish_deleted(io: &SocketIo, member_ids: &[i64], conversation_id: i64) {
+    let Some(chat) = io.of(NAMESPACE) else {
+        return;
+    };
+    let payload = json!({ "conversation_id": conversation_id });
+    if let Err(err) = chat
+        .to(member_rooms(member_ids))
+        .emit(CONVERSATION_DELETED, &payload)
+    {
+        warn!(
+            "Deletion of conversation {} not published: {:?}",
+            conversation_id, err
+        );
+    }
+}
+
+/// Tells everyone who should hear about a presence state the user picked elsewhere.
+pub fn publish_presence(io: &SocketIo, recipients: &[i64], update: &PresenceUpdate) {
+    let Some(chat) = io.of(NAMESPACE) else {
+        return;
+    };
+    if let Err(err) = chat
+        .to(member_rooms(recipients))
+        .emit(PRESENCE_UPDATE, update)
+    {
+        warn!(
+            "Presence of user {} not published: {:?}",
+            update.user_id, err
+        );
+    }
+}
+
+/// The token from the handshake's `auth` object, or else from an `Authorization: Bearer` header
+/// for clients that can't send one.
+pub fn handshake_token(handshake: &Handshake, headers: &HeaderMap) -> Option<String> {
+    handshake
+        .token
+        .clone()
+        .filter(|token| !token.is_empty())
+        .or_else(|| bearer_token(headers))
+}
+
+/// The layer answering Socket.IO requests, and the handle to emit through from elsewhere.
+pub fn layer(state: Arc<AppState>) -> (SocketIoLayer, SocketIo) {
+    let (layer, io) = SocketIo::builder().with_state(state.clone()).build_layer();
+    io.ns(NAMESPACE, on_connect.with(check_handshake));
+    // A state serves one gateway; should another be built on it, jobs keep the first.
+    let _ = state.gateway.set(io.clone());
+    (layer, io)
+}
+
+// Runs before the connection is accepted. A refused client gets a connect_error whose message is
+// the error code, e.g. "unauthenticated".
+async fn check_handshake(
+    socket: SocketRef,
+    State(state): State<Arc<AppState>>,
+    TryData(handshake): TryData<Handshake>,
+) -> Result<(), ErrorCode> {
+    let headers = &socket.req_parts().headers;
+    let locale = headers
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|value| value.to_str().ok())
+        .map(Locale::from_accept_language)
+        .unwrap_or_default();
+
+    let result = match handshake_token(&handshake.unwrap_or_default(), headers) {
+        Some(token) => authenticate(state.users.as_ref(), state.sessions.as_ref(), &token)
+            .await
+            .map(|user| (user, token))
+            .map_err(ApiError::from),
+        None => Err(ApiError::new(ErrorCode::Unauthenticated)),
+    };
+    telemetry::record_gateway_connect(result.is_ok());
+
+    let (user, token) = result.map_err(|err| {
+        if err.status().is_server_error() {
+            warn!("Gateway handshake failed: {:?}", err);
+        }
+        err.code
+    })?;
+    socket.extensions.insert(Identity {
+        user_id: user.id,
+        token,
+        locale,
+    });
+    Ok(())
+}
+
+async fn on_connect(
+    socket: SocketRef,
+    State(state): State<Arc<AppState>>,
+    Extension(identity): Extension<Identity>,
+) {
+    debug!("User {} connected to the gateway", identity.user_id);
+    socket.join(user_room(identity.user_id));
+    socket.on(SEND_MESSAGE, send_message);
+    socket.on(PRESENCE_HEARTBEAT, heartbeat);
+    socket.on(SET_PRESENCE, set_presence);
+    socket.on_disconnect(on_disconnect);
+
+    let result = presence::connected(
+        state.users.as_ref(),
+        state.presence.as_ref(),
+        &state.settings.presence,
+        identity.user_id,
+        &socket.id.to_string(),
+    )
+    .await;
+    announce(&socket, &state, identity.user_id, result).await;
+}
+
+async fn on_disconnect(
+    socket: SocketRef,
+    State(state): State<Arc<AppState>>,
+    Extension(identity): Extension<Identity>,
+) {
+    debug!("User {} disconnected from the gateway", identity.user_id);
+    let result = presence::disconnected(
+        state.users.as_ref(),
+        state.presence.as_ref(),
+        &state.settings.presence,
+        identity.user_id,
+        &socket.id.to_string(),
+    )
+    .await;
+    announce(&socket, &state, identity.user_id, result).await;
+}
+
+// Publishes a change in the user's presence, this socket included. Contacts that miss it see the
+// stored state when they next ask, so failures are only logged.
+async fn announce(
+    socket: &SocketRef,
+    state: &AppState,
+    user_id: i64,
+    result: Result<Option<PresenceUpdate>, PresenceError>,
+) {
+    let update = match result {
+        Ok(Some(update)) => update,
+        Ok(None) => return,
+        Err(err) => {
+            warn!("Presence of user {} not updated: {:?}", user_id, err);
+            return;
+        }
+    };
+    let recipients = match presence::recipients(state.conversations.as_ref(), user_id).await {
+        Ok(recipients) => recipients,
+        Err(err) => {
+            warn!("Presence of user {} not published: {:?}", user_id, err);
+            return;
+        }
+    };
+    if let Err(err) = socket
+        .within(member_rooms(&recipients))
+        .emit(PRESENCE_UPDATE, &update)
+    {
+        warn!("Presence of user {} not published: {:?}", user_id, err);
+    }
+}
+
+async fn heartbeat(
+    socket: SocketRef,
+    State(state): State<Arc<AppState>>,
+    Extension(identity): Extension<Identity>,
+    TryData(payload): TryData<Heartbeat>,
+) {
+    // The socket outlives the check at the handshake. Once the session is revoked or the account
+    // suspended, the connection is dropped rather than kept counting as a device.
+    if let Err(err) = authenticate(
+        state.users.as_ref(),
+        state.sessions.as_ref(),
+        &identity.token,
+    )
+    .await
+    {
+        let err = ApiError::from(err);
+        if err.status().is_server_error() {
+            warn!(
+                "Session of user {} not checked on heartbeat: {:?}",
+                identity.user_id, err
+            );
+        } else {
+            debug!(
+                "Dropping gateway connection of user {}: {:?}",
+                identity.user_id, err.code
+            );
+            if let Err(err) = socket.disconnect() {
+                debug!("Gateway connection not dropped: {:?}", err);
+            }
+            return;
+        }
+    }
+
+    let result = presence::heartbeat(
+        state.users.as_ref(),
+        state.presence.as_ref(),
+        &state.settings.presence,
+        identity.user_id,
+        &socket.id.to_string(),
+        payload.unwrap_or_default().active,
+    )
+    .await;
+    announce(&socket, &state, identity.user_id, result).await;
+}
+
+async fn set_presence(
+    socket: SocketRef,
+    State(state): State<Arc<AppState>>,
+    Extension(identity): Extension<Identity>,
+    TryData(payload): TryData<SetPresence>,
+    ack: AckSender,
+) {
+    let result = match payload {
+        Ok(payload) => presence::set_state(
+            state.users.as_ref(),
+            state.presence.as_ref(),
+            &state.settings.presence,
+            identity.user_id,
+            &payload.state,
+        )
+        .await
+        .map_err(ApiError::from),
+        Err(_) => Err(ApiError::new(ErrorCode::ValidationFailed)),
+    };
+    let reply = match result {
+        Ok(update) => {
+            announce(&socket, &state, identity.user_id, Ok(update)).await;
+            json!({})
+        }
+        Err(err) => {
+            if err.status().is_server_error() {
+                warn!(
+                    "Presence change of user {} failed: {:?}",
+                    identity.user_id, err
+                );
+            }
+            json!({ "error": err.body(identity.locale) })
+        }
+    };
+    if let Err(err) = ack.send(&reply) {
+        debug!("Presence change not acknowledged: {:?}", err);
+    }
+}
+
+async fn send_message(
+    socket: SocketRef,
+    State(state): State<Arc<AppState>>,
+    Extension(identity): Extension<Identity>,
+    TryData(payload): TryData<SendMessage>,
+    ack: AckSender,
+) {
+    let reply = match deliver(&socket, &state, &identity, payload).await {
+        Ok(message) => json!({ "message": message }),
+        Err(err) => {
+            if err.status().is_server_error() {
+                warn!("Message from user {} failed: {:?}", identity.user_id, err);
+            }
+            json!({ "error": err.body(identity.locale) })
+        }
+    };
+    if let Err(err) = ack.send(&reply) {
+        debug!("Message not acknowledged: {:?}", err);
+    }
+}
+
+async fn deliver(
+    socket: &SocketRef,
+    state: &AppState,
+    identity: &Identity,
+    payload: Result<SendMessage, serde_json::Error>,
+) -> Result<ChatMessage, ApiError> {
+    let payload = payload.map_err(|_| ApiError::new(ErrorCode::ValidationFailed))?;
+    // The socket outlives the check at the handshake, the session may be gone by now.
+    let sender = authenticate(
+        state.users.as_ref(),
+        state.sessions.as_ref(),
+        &identity.token,
+    )
+    .await?;
+    let conversation_id = match (payload.conversation_id, &payload.to) {
+        (Some(id), _) => id,
+        (None, Some(to)) => {
+            chat::open_direct(
+                state.users.as_ref(),
+                state.conversations.as_ref(),
+                &sender,
+                to,
+            )
+            .await?
+            .id
+        }
+        (None, None) => return Err(ApiError::new(ErrorCode::ValidationFailed)),
+    };
+    let (message, members) = chat::post_message(
+        state.conversations.as_ref(),
+        &sender,
+        conversation_id,
+        &payload.body,
+        payload.nonce,
+    )
+    .await?;
+
+    // Not `publish`, which would echo it to this socket as well.
+    if let Err(err) = socket
+        .to(member_rooms(&members))
+        .emit(NEW_MESSAGE, &message)
+    {
+        warn!("Message {} not published: {:?}", message.id, err);
+    }
+    Ok(message)
+}
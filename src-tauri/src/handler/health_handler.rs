@@ -0,0 +1,66 @@
+use axum::{http::StatusCode, response::IntoResponse, Extension, Json};
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::auth_handler::AppState;
+
+// A dependency that takes longer than this to answer counts as down.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Liveness: the process is up and serving requests.
+pub async fn healthz_handler() -> impl IntoResponse {
+    (StatusCode::OK, Json(json!({"status": "ok"})))
+}
+
+// Readiness: Postgres and the ephemeral store both answer, so requests can actually be handled.
+// The store is reported as "redis" even when it lives in memory, where it's always up.
+pub async fn readyz_handler(Extension(state): Extension<Arc<AppState>>) -> impl IntoResponse {
+    let (database, redis) = tokio::join!(check_database(&state), check_ephemeral(&state));
+    let ready = database && redis;
+
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(json!({
+            "status": if ready { "ready" } else { "unavailable" },
+            "checks": {
+                "database": if database { "ok" } else { "unreachable" },
+                "redis": if redis { "ok" } else { "unreachable" },
+            },
+        })),
+    )
+}
+
+async fn check_database(state: &AppState) -> bool {
+    match tokio::time::timeout(CHECK_TIMEOUT, sqlx::query("SELECT 1").execute(&state.db)).await {
+        Ok(Ok(_)) => true,
+        Ok(Err(err)) => {
+            warn!("Readiness check: database error: {:?}", err);
+            false
+        }
+        Err(_) => {
+            warn!("Readiness check: database timed out");
+            false
+        }
+    }
+}
+
+async fn check_ephemeral(state: &AppState) -> bool {
+    match tokio::time::timeout(CHECK_TIMEOUT, state.ephemeral.ping()).await {
+        Ok(Ok(())) => true,
+        Ok(Err(err)) => {
+            warn!("Readiness check: redis error: {:?}", err);
+            false
+        }
+        Err(_) => {
+            warn!("Readiness check: redis timed out");
+            false
+        }
+    }
+}
@@ -3,12 +3,52 @@
 #![allow(unused)]
 #![allow(warnings)]
 
+pub mod api;
+pub mod error;
+pub mod migrations;
+pub mod server;
+pub mod settings;
+pub mod tls;
+
 #[path = "handler/auth_handler.rs"]
 pub mod auth_handler;
 
+#[path = "handler/health_handler.rs"]
+pub mod health_handler;
+
+#[path = "handler/gateway.rs"]
+pub mod gateway;
+
+#[path = "handler/chat_handler.rs"]
+pub mod chat_handler;
+
+#[path = "handler/extract.rs"]
+pub mod extract;
+
 #[path = "service/auth_service.rs"]
 pub mod auth_service;
 
+#[path = "service/ephemeral.rs"]
+pub mod ephemeral;
+
+#[path = "service/repository/mod.rs"]
+pub mod repository;
+
+#[path = "service/memory_store.rs"]
+pub mod memory_store;
+
+#[path = "service/accounts.rs"]
+pub mod accounts;
+
+#[path = "service/jobs.rs"]
+pub mod jobs;
+
+#[path = "service/chat.rs"]
+pub mod chat;
+
+#[path = "service/presence.rs"]
+pub mod presence;
+
 #[path = "service/otp.rs"]
 pub mod otp;
 
@@ -17,4 +57,28 @@ pub mod password;
 
 #[path = "service/third_party_auth.rs"]
 pub mod tp_auth;
+
+#[path = "service/username.rs"]
+pub mod username;
+
+#[path = "service/token_vault.rs"]
+pub mod token_vault;
+
+#[path = "service/oidc_cache.rs"]
+pub mod oidc_cache;
+
+#[path = "service/telemetry.rs"]
+pub mod telemetry;
+
+#[path = "service/logging.rs"]
+pub mod logging;
+
+#[path = "service/rate_limit.rs"]
+pub mod rate_limit;
+
+#[path = "service/google_auth.rs"]
+pub mod google;
+
+#[path = "service/ms_auth.rs"]
+pub mod microsoft;
 //use auth_handler::{otp_verify_handler, resend_otp_handler, signup_handler, AppState};
@@ -15,7 +15,6 @@ use axum::{
 use core::error;
 use dotenvy::dotenv;
 use http::HeaderValue;
-use log::info;
 use redis::Commands;
 use redis::{self, aio::MultiplexedConnection, AsyncCommands};
 use serde_json::Value;
@@ -26,105 +25,55 @@ use socketioxide::{
 };
 use sqlx::{Pool, Postgres};
 use sqlx_postgres::PgPoolOptions;
-use std::{env, error::Error, fmt::format, sync::Arc, thread};
+use std::{
+    env,
+    error::Error,
+    fmt::format,
+    sync::{Arc, Mutex},
+    thread,
+};
 use sysinfo::{ProcessExt, System, SystemExt};
 use tauri::{self, Listener, State};
-use tauri::{async_runtime::block_on, Emitter};
-use tauri::{generate_context, generate_handler, AppHandle, Builder, Manager, Wry};
+use tauri::{
+    async_runtime::{block_on, JoinHandle},
+    Emitter,
+};
+use tauri::{generate_context, generate_handler, AppHandle, Builder, Manager, RunEvent, Wry};
 use tauri_plugin_autostart::MacosLauncher;
 use thiserror::Error;
+use tokio_util::sync::CancellationToken;
 use tower::ServiceBuilder;
-use tower_http::{cors::Any, services::ServeDir};
 use tower_http::{cors::CorsLayer, trace::TraceLayer};
+use tower_http::{
+    cors::{AllowOrigin, Any},
+    services::ServeDir,
+};
 use tower_sessions::{
     cookie::time::Duration, session_store::ExpiredDeletion, Expiry, Session, SessionManagerLayer,
 };
 use tower_sessions_sqlx_store::{sqlx::PgPool, PostgresStore};
-use tracing::Level;
-use tracing_appender::rolling::{RollingFileAppender, Rotation};
-use tracing_subscriber::fmt::{self, layer};
+use tracing::{info, Level};
+use tracing_appender::non_blocking::WorkerGuard;
 use wyrd_lib::password::encrypt;
 
 use wyrd_lib::auth_service::AuthenticationErrors;
-use wyrd_lib::otp::{generate_otp, send_otp, verify_otp, OTPErrors, OTPInfo};
+use wyrd_lib::error::{ApiError, ErrorCode};
+use wyrd_lib::logging;
+use wyrd_lib::otp::{send_otp, start_verification, OTPErrors};
+use wyrd_lib::rate_limit;
+use wyrd_lib::server;
+use wyrd_lib::settings::Settings;
+use wyrd_lib::telemetry;
+use wyrd_lib::tp_auth::{OAuthResult, OAUTH_RESULT_EVENT};
 use wyrd_lib::{
-    auth_handler::{login_handler, otp_verify_handler, signup_handler, AppState, Db},
-    auth_service,
+    auth_handler::{
+        choose_username_handler, login_handler, otp_verify_handler, signup_handler, AppState, Db,
+    },
+    auth_service, google, microsoft,
 };
 
-const EXPIRY_TIME: u64 = 90;
-
-async fn setup_db() -> Result<(Db), anyhow::Error> {
-    dotenv().ok();
-    let database_url = dotenvy::var("DATABASE_URL")
-        .context("DATABASE_URL must be set")
-        .unwrap();
-
-    let db = PgPoolOptions::new()
-        .max_connections(20)
-        .connect(&database_url)
-        .await
-        .unwrap();
-
-    sqlx::migrate!("./migrations").run(&db).await.unwrap();
-
-    Ok(db)
-}
-
-async fn setup_reddis() -> Result<(MultiplexedConnection), anyhow::Error> {
-    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
-    let mut con = client.get_multiplexed_tokio_connection().await?;
-    Ok((con))
-}
-
-async fn setup_app(app: &AppHandle, state: Arc<AppState>) -> Result<(), anyhow::Error> {
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000").await?;
-    rustls::crypto::ring::default_provider()
-        .install_default()
-        .expect("Failed to install rustls crypto provider");
-    let cors_layer = CorsLayer::new()
-        .allow_origin(
-            Any, /*[
-                    "http://localhost:5173".parse::<HeaderValue>().unwrap(),
-                    "tauri://localhost".parse::<HeaderValue>().unwrap(),
-                ]*/
-        ) //
-        .allow_methods(Any /*[Method::GET, Method::POST]*/) //
-        .allow_credentials(false)
-        .allow_headers(
-            Any, /*[
-                    http::header::AUTHORIZATION,
-                    http::header::ACCEPT,
-                    http::header::CONTENT_TYPE,
-                ]*/
-        );
-    /* */
-
-    let start_app = Router::new()
-        .route("/signup", post(signup_handler))
-        .route("/otp", post(otp_verify_handler))
-        //.route("personalize-1", post())
-        //.route("personalize-2", post())
-        //.route("personalize-3", post(func3))
-        //.route("personalize-4", post(func4))
-        //.route("/personalize", method_router)
-        .layer(
-            ServiceBuilder::new()
-                .layer(TraceLayer::new_for_http())
-                .layer(Extension(state))
-                .layer(cors_layer),
-        )
-        // Add explicit separation between API and frontend
-        .nest_service("/app", ServeDir::new("dist"));
-
-    tauri::async_runtime::spawn(async {
-        axum::serve(listener, start_app)
-            .await
-            .expect("Server failed");
-    });
-
-    Ok(())
-}
+// Where the webview sends its API requests: either a remote wyrd-server or the one started here.
+struct ServerUrl(String);
 
 /*
 fn verify_local_process()  {
@@ -135,87 +84,113 @@ fn verify_local_process()  {
 }*/
 
 #[tauri::command]
-async fn client_info_otp(state: State<'_, Arc<AppState>>) -> Result<OTPInfo, ()> {
-    let mut connection = state.red.clone();
-
-    let ((client_email, client_name)): (String, String) = redis::pipe()
-        .get("client_email_signup".to_string())
-        .get("client_name_signup".to_string())
-        .query_async(&mut connection)
-        .await
-        .unwrap();
-
-    let otp_info = OTPInfo {
-        name: client_name,
-        email: client_email,
-    };
-
-    Ok(otp_info)
+fn server_url(server_url: State<'_, ServerUrl>) -> String {
+    server_url.0.clone()
 }
 
+// Social sign-in runs its OAuth flow against the embedded server. A remote server keeps its
+// state to itself, so the sign-in page shows the options disabled and `oauth_sign_in` says why.
 #[tauri::command]
-async fn resend_otp_handler(state: State<'_, Arc<AppState>>) -> Result<(), OTPErrors> {
-    let pool = &state.db;
-    let mut connection = state.red.clone();
-    let code_exists = connection.exists::<&str, i64>("token").await.unwrap();
-    if code_exists == 1 {
-        connection.del::<&str, i64>("token").await.unwrap();
-    }
-    let ((client_email, client_name)): (String, String) = redis::pipe()
-        .get("client_email_signup".to_string())
-        .get("client_name_signup".to_string())
-        .query_async(&mut connection)
-        .await
-        .unwrap();
-
-    let ref_client_email = client_email.as_str();
-    let ref_client_name = client_name.as_str();
-
-    let token = match generate_otp(&pool, ref_client_email, ref_client_name).await {
-        Ok(t) => t.generate_current().unwrap(),
-        Err(err) => return Err(OTPErrors::GenerateOTPError(err.to_string())),
-    };
-
-    let shared_token = token.as_str();
-
-    let encrypted_token = encrypt(shared_token).await.unwrap();
-
-    let _: () = redis::pipe()
-        .atomic()
-        .set_ex("token", encrypted_token, EXPIRY_TIME)
-        .ignore()
-        .query_async(&mut connection)
-        .await
-        .unwrap();
-
-    send_otp(shared_token, ref_client_email, ref_client_name).await?;
+fn social_sign_in_available(app: AppHandle) -> bool {
+    app.try_state::<Arc<AppState>>().is_some()
+}
 
+// Runs the sign-in in the background and reports back through `OAUTH_RESULT_EVENT`, since the
+// user may take minutes in the browser and the invoke call shouldn't block on that.
+#[tauri::command]
+async fn oauth_sign_in(app: AppHandle, provider: String) -> Result<(), ApiError> {
+    let state = app
+        .try_state::<Arc<AppState>>()
+        .ok_or_else(|| ApiError::new(ErrorCode::SocialSignInUnavailable))?
+        .inner()
+        .clone();
+    rate_limit::check_command(&state, "oauth_start", "all").await?;
+    tauri::async_runtime::spawn(async move {
+        let result = match provider.as_str() {
+            "GOOGLE" => google::google_auth(&app, &state).await,
+            "MS" => microsoft::ms_auth(&app, &state).await,
+            other => Err(anyhow::anyhow!("Unsupported provider: {}", other)),
+        };
+        telemetry::record_oauth(&provider, result.is_ok());
+        if let Err(err) = &result {
+            tracing::error!("{} sign-in failed: {:?}", provider, err);
+        }
+        if let Err(err) = app.emit(
+            OAUTH_RESULT_EVENT,
+            OAuthResult::from_result(&provider, result),
+        ) {
+            tracing::error!("Failed to emit sign-in result: {:?}", err);
+        }
+    });
     Ok(())
 }
 
 fn main() {
+    // Fail before any window opens if the configuration is unusable.
+    let settings = Settings::load().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+
+    let shutdown = CancellationToken::new();
+    // The embedded server, awaited on exit so open requests can drain.
+    let server_task: Arc<Mutex<Option<JoinHandle<()>>>> = Arc::default();
+    // Dropped last on exit so buffered log lines make it to disk.
+    let log_guard: Arc<Mutex<Option<WorkerGuard>>> = Arc::default();
+
     tauri::Builder::default()
         .invoke_handler(tauri::generate_handler![
-            client_info_otp,
-            resend_otp_handler
+            server_url,
+            social_sign_in_available,
+            oauth_sign_in
         ])
-        .setup(|app| {
-            let handle = app.handle().clone();
-
-            tauri::async_runtime::block_on({
-                let handle = handle;
-                async move {
-                    let db = setup_db().await?;
-                    let red = setup_reddis().await?;
-                    let state = Arc::new(AppState { db, red });
-                    app.manage(state.clone());
-                    setup_app(&handle, state).await
+        .setup({
+            let shutdown = shutdown.clone();
+            let server_task = server_task.clone();
+            let log_guard = log_guard.clone();
+            move |app| {
+                let log_dir = app.path().app_data_dir()?.join("logs");
+                *log_guard.lock().unwrap() = Some(logging::init(&settings.logging, &log_dir)?);
+                info!("Starting Wyrd ({} profile)", settings.profile);
+
+                if let Some(remote_url) = settings.desktop.server_url.clone() {
+                    // Everything goes through the remote server, nothing to start locally.
+                    app.manage(ServerUrl(remote_url));
+                    return Ok(());
                 }
-            });
 
-            Ok(())
+                app.manage(ServerUrl(settings.server.local_url(settings.tls.enabled)));
+                let handle = app.handle().clone();
+                let task = tauri::async_runtime::block_on(async move {
+                    let state = server::build_state(settings).await?;
+                    handle.manage(state.clone());
+                    server::spawn_background_tasks(&state);
+
+                    let listener = server::bind(&state.settings.server).await?;
+                    Ok::<_, anyhow::Error>(tauri::async_runtime::spawn(async move {
+                        if let Err(err) = server::serve(listener, state, shutdown).await {
+                            tracing::error!("Server failed: {:?}", err);
+                        }
+                    }))
+                })?;
+                *server_task.lock().unwrap() = Some(task);
+
+                Ok(())
+            }
         })
         .plugin(tauri_plugin_window_state::Builder::default().build())
-        .run(tauri::generate_context!())
-        .expect("Error while running Tauri application");
+        .plugin(tauri_plugin_opener::init())
+        .build(tauri::generate_context!())
+        .expect("Error while building Tauri application")
+        .run(move |app, event| {
+            if let RunEvent::Exit = event {
+                // Let the embedded server finish what it's doing before the process goes away.
+                shutdown.cancel();
+                let task = server_task.lock().unwrap().take();
+                if let Some(task) = task {
+                    let _ = tauri::async_runtime::block_on(task);
+                }
+                log_guard.lock().unwrap().take();
+            }
+        });
 }
@@ -0,0 +1,71 @@
+use sqlx::migrate::{Migrate, Migrator};
+use std::collections::HashMap;
+
+use crate::auth_handler::Db;
+
+/// Everything in `migrations/`, compiled into the binary.
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum State {
+    Applied,
+    Pending,
+    /// Applied, but the file changed since. The database no longer matches the source.
+    Modified,
+}
+
+#[derive(Debug, Clone)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub state: State,
+}
+
+pub async fn run(db: &Db) -> Result<(), anyhow::Error> {
+    MIGRATOR.run(db).await?;
+    Ok(())
+}
+
+/// Reverts every applied migration newer than `target`. Without a target only the latest
+/// migration is reverted. Returns the version the database is at afterwards.
+pub async fn revert(db: &Db, target: Option<i64>) -> Result<i64, anyhow::Error> {
+    let mut applied: Vec<i64> = applied(db).await?.into_keys().collect();
+    applied.sort_unstable();
+
+    let target = match target {
+        Some(target) => target,
+        None if applied.len() >= 2 => applied[applied.len() - 2],
+        None => 0,
+    };
+    MIGRATOR.undo(db, target).await?;
+    Ok(target)
+}
+
+pub async fn status(db: &Db) -> Result<Vec<MigrationStatus>, anyhow::Error> {
+    let applied = applied(db).await?;
+    Ok(MIGRATOR
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration())
+        .map(|migration| MigrationStatus {
+            version: migration.version,
+            description: migration.description.to_string(),
+            state: match applied.get(&migration.version) {
+                None => State::Pending,
+                Some(checksum) if *checksum == *migration.checksum => State::Applied,
+                Some(_) => State::Modified,
+            },
+        })
+        .collect())
+}
+
+// Versions applied so far, with the checksum of the file at the time.
+async fn applied(db: &Db) -> Result<HashMap<i64, Vec<u8>>, anyhow::Error> {
+    let mut connection = db.acquire().await?;
+    connection.ensure_migrations_table().await?;
+    Ok(connection
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|migration| (migration.version, migration.checksum.into_owned()))
+        .collect())
+}
@@ -0,0 +1,520 @@
+use anyhow::Context;
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method},
+    middleware::{self, Next},
+    response::Response,
+    routing::get,
+    Extension, Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use socketioxide::SocketIo;
+use sqlx_postgres::PgPoolOptions;
+use std::future::{Future, IntoFuture};
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tower::ServiceBuilder;
+use tower_http::{
+    cors::{AllowOrigin, CorsLayer},
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    sensitive_headers::{SetSensitiveRequestHeadersLayer, SetSensitiveResponseHeadersLayer},
+    services::ServeDir,
+    set_header::SetResponseHeaderLayer,
+    trace::TraceLayer,
+};
+use tracing::{error, info, warn};
+
+use crate::api;
+use crate::auth_handler::{authenticate_request, AppState, Db};
+use crate::ephemeral::{EphemeralStore, RedisStore};
+use crate::error;
+use crate::gateway;
+use crate::health_handler::{healthz_handler, readyz_handler};
+use crate::jobs;
+use crate::logging;
+use crate::memory_store::MemoryStore;
+use crate::migrations;
+use crate::oidc_cache::{self, OidcCache};
+use crate::rate_limit::{self, RateLimiter};
+use crate::repository::{
+    EphemeralPresenceStore, EphemeralSessionStore, EphemeralVerificationStore, PgConversationStore,
+    PgEmailOutbox, PgUserRepository,
+};
+use crate::settings::{CorsSettings, DatabaseSettings, EphemeralBackend, ServerSettings, Settings};
+use crate::telemetry::{self, metrics_handler, track_http};
+use crate::tls::{self, ClientAuth};
+use crate::token_vault::TokenVault;
+
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Runs `connect` until it succeeds, sleeping with exponential backoff in between, so the app
+/// survives starting before its database or Redis.
+async fn retry<T, F, Fut>(what: &str, attempts: u32, mut connect: F) -> Result<T, anyhow::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, anyhow::Error>>,
+{
+    let mut delay = Duration::from_millis(500);
+    let mut attempt = 1;
+    loop {
+        match connect().await {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt < attempts => {
+                warn!(
+                    "{} unavailable (attempt {}/{}): {:#}, retrying in {:?}",
+                    what, attempt, attempts, err, delay
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_BACKOFF);
+                attempt += 1;
+            }
+            Err(err) => {
+                return Err(err.context(format!("{} unavailable after {} attempts", what, attempts)))
+            }
+        }
+    }
+}
+
+pub async fn setup_db(settings: &DatabaseSettings, attempts: u32) -> Result<Db, anyhow::Error> {
+    let db = retry("Postgres", attempts, || async move {
+        Ok(PgPoolOptions::new()
+            .max_connections(settings.max_connections)
+            .connect(&settings.url)
+            .await?)
+    })
+    .await?;
+
+    if settings.run_migrations {
+        migrations::run(&db)
+            .await
+            .context("Failed to run database migrations")?;
+    }
+
+    Ok(db)
+}
+
+pub async fn setup_ephemeral(
+    settings: &Settings,
+    attempts: u32,
+) -> Result<Arc<dyn EphemeralStore>, anyhow::Error> {
+    match settings.ephemeral.backend {
+        EphemeralBackend::Memory => Ok(Arc::new(MemoryStore::new())),
+        EphemeralBackend::Redis => {
+            let redis = &settings.redis;
+            let store = retry("Redis", attempts, || async move {
+                RedisStore::connect(redis).await
+            })
+            .await?;
+            Ok(Arc::new(store))
+        }
+    }
+}
+
+/// Connects to Postgres and Redis and assembles the state every handler shares.
+pub async fn build_state(settings: Settings) -> Result<Arc<AppState>, anyhow::Error> {
+    let attempts = settings.server.startup_attempts;
+    let db = setup_db(&settings.database, attempts).await?;
+    let ephemeral = setup_ephemeral(&settings, attempts).await?;
+    let vault = TokenVault::from_settings(&settings)?;
+    let rate_limit = RateLimiter::new(ephemeral.clone(), settings.rate_limit.clone());
+    Ok(Arc::new(AppState {
+        users: Arc::new(PgUserRepository::new(db.clone())),
+        verifications: Arc::new(EphemeralVerificationStore::new(
+            ephemeral.clone(),
+            settings.jobs.unverified_grace(),
+        )),
+        sessions: Arc::new(EphemeralSessionStore::new(ephemeral.clone())),
+        conversations: Arc::new(PgConversationStore::new(db.clone())),
+        presence: Arc::new(EphemeralPresenceStore::new(ephemeral.clone())),
+        outbox: Arc::new(PgEmailOutbox::new(db.clone())),
+        db,
+        ephemeral,
+        vault,
+        http: oidc_cache::build_http_client()?,
+        oidc: OidcCache::new(&settings.oidc),
+        settings,
+        metrics: telemetry::recorder()?,
+        rate_limit,
+        gateway: OnceLock::new(),
+    }))
+}
+
+pub fn spawn_background_tasks(state: &Arc<AppState>) {
+    jobs::spawn_scheduler(state.clone());
+    oidc_cache::spawn_refresher(state.clone());
+}
+
+fn cors_layer(settings: &CorsSettings) -> Result<CorsLayer, anyhow::Error> {
+    let allowed_origins = settings
+        .allowed_origins
+        .iter()
+        .map(|origin| origin.parse::<HeaderValue>())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Credentials let the webview send the session cookie, which rules out wildcards everywhere.
+    Ok(CorsLayer::new()
+        .allow_origin(AllowOrigin::list(allowed_origins))
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::PATCH,
+            Method::DELETE,
+        ])
+        .allow_headers([
+            header::AUTHORIZATION,
+            header::ACCEPT,
+            header::CONTENT_TYPE,
+            X_REQUEST_ID,
+        ])
+        .expose_headers([X_REQUEST_ID])
+        .allow_credentials(true)
+        .max_age(Duration::from_secs(60 * 60)))
+}
+
+/// The API with everything in front of it. Also returns the gateway, which `serve` closes on
+/// shutdown.
+pub fn router(state: Arc<AppState>) -> Result<(Router, SocketIo), anyhow::Error> {
+    let cors_layer = cors_layer(&state.settings.cors)?;
+    let (socket_layer, io) = gateway::layer(state.clone());
+
+    let hsts = strict_transport(&state.settings)?;
+
+    let security_headers = ServiceBuilder::new()
+        .layer(SetResponseHeaderLayer::if_not_present(
+            header::X_FRAME_OPTIONS,
+            HeaderValue::from_static("DENY"),
+        ))
+        .layer(SetResponseHeaderLayer::if_not_present(
+            header::X_CONTENT_TYPE_OPTIONS,
+            HeaderValue::from_static("nosniff"),
+        ))
+        .layer(SetResponseHeaderLayer::if_not_present(
+            header::REFERRER_POLICY,
+            HeaderValue::from_static("no-referrer"),
+        ))
+        .layer(middleware::from_fn_with_state(
+            hsts,
+            strict_transport_security,
+        ));
+
+    // The admin endpoints are never served here; they get a listener of their own, see `serve`.
+    let app = Router::new()
+        .route("/healthz", get(healthz_handler))
+        .route("/readyz", get(readyz_handler))
+        .nest(api::V1_PREFIX, api::v1_routes())
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit::limit_requests,
+        ))
+        // Runs before the rate limiter, which counts signed-in requests per user.
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            authenticate_request,
+        ))
+        // A route layer so the route template is known when the request gets counted.
+        .route_layer(middleware::from_fn(track_http))
+        .route_layer(middleware::from_fn(error::localize))
+        .layer(
+            ServiceBuilder::new()
+                // Credentials never show up in the request and response logs.
+                .layer(SetSensitiveRequestHeadersLayer::new([
+                    header::AUTHORIZATION,
+                    header::COOKIE,
+                ]))
+                // Keeps an id sent by the client, otherwise generates one, and echoes it back.
+                .layer(SetRequestIdLayer::new(X_REQUEST_ID, MakeRequestUuid))
+                .layer(TraceLayer::new_for_http().make_span_with(logging::request_span))
+                .layer(SetSensitiveResponseHeadersLayer::new([header::SET_COOKIE]))
+                .layer(PropagateRequestIdLayer::new(X_REQUEST_ID))
+                .layer(Extension(state))
+                // Lets handlers publish to the gateway.
+                .layer(Extension(io.clone()))
+                .layer(cors_layer)
+                // Answers /socket.io/ itself, so those requests never reach the routes.
+                .layer(socket_layer)
+                // The API never renders pages, so nothing may frame it and nothing may run in it.
+                .layer(SetResponseHeaderLayer::if_not_present(
+                    header::CONTENT_SECURITY_POLICY,
+                    HeaderValue::from_static("default-src 'none'; frame-ancestors 'none'"),
+                )),
+        )
+        // The docs page runs scripts, so it sits outside the API's content security policy.
+        .merge(api::docs())
+        // Add explicit separation between API and frontend
+        .nest_service("/app", ServeDir::new("dist"))
+        .layer(security_headers);
+    Ok((app, io))
+}
+
+#[derive(Clone)]
+struct StrictTransport {
+    header: Option<HeaderValue>,
+    tls: bool,
+    trust_forwarded_proto: bool,
+}
+
+fn strict_transport(settings: &Settings) -> Result<StrictTransport, anyhow::Error> {
+    let security = &settings.security;
+    let header = (security.hsts_max_age_secs > 0)
+        .then(|| {
+            format!("max-age={}; includeSubDomains", security.hsts_max_age_secs)
+                .parse::<HeaderValue>()
+        })
+        .transpose()?;
+    if header.is_some() && !settings.tls.enabled && !security.trust_forwarded_proto {
+        warn!(
+            "security.hsts_max_age_secs is set, but without tls.enabled or \
+             security.trust_forwarded_proto no request counts as HTTPS, so HSTS is never sent"
+        );
+    }
+    Ok(StrictTransport {
+        header,
+        tls: settings.tls.enabled,
+        trust_forwarded_proto: security.trust_forwarded_proto,
+    })
+}
+
+// Browsers ignore HSTS over plain HTTP, and a client that did take it from there would be pinned
+// to an HTTPS endpoint that might not exist. So the header only goes out on HTTPS responses.
+async fn strict_transport_security(
+    State(hsts): State<StrictTransport>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let https = hsts.tls || (hsts.trust_forwarded_proto && forwarded_https(request.headers()));
+    let mut response = next.run(request).await;
+    if let (true, Some(value)) = (https, hsts.header) {
+        response
+            .headers_mut()
+            .entry(header::STRICT_TRANSPORT_SECURITY)
+            .or_insert(value);
+    }
+    response
+}
+
+// Proxies in a chain append their scheme; the left-most one is what the client used.
+fn forwarded_https(headers: &HeaderMap) -> bool {
+    headers
+        .get("x-forwarded-proto")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .is_some_and(|proto| proto.trim().eq_ignore_ascii_case("https"))
+}
+
+pub fn admin_router(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics_handler))
+        .route_layer(middleware::from_fn(track_http))
+        .layer(TraceLayer::new_for_http())
+        .layer(Extension(state))
+}
+
+pub async fn bind(settings: &ServerSettings) -> Result<TcpListener, anyhow::Error> {
+    let address = settings.bind_address();
+    TcpListener::bind(address)
+        .await
+        .with_context(|| format!("Failed to bind {}", address))
+}
+
+/// Serves the API on `listener` until `shutdown` is cancelled. In-flight requests then get
+/// `server.shutdown_grace_secs` to finish before the remaining connections are dropped.
+///
+/// The admin endpoints get a loopback listener on `server.admin_port`. With `tls.enabled` the
+/// API is served over HTTPS, next to the optional HTTP redirect listener, and mutual TLS moves
+/// the admin endpoints to `tls.admin_port`.
+pub async fn serve(
+    listener: TcpListener,
+    state: Arc<AppState>,
+    shutdown: CancellationToken,
+) -> Result<(), anyhow::Error> {
+    let (app, io) = router(state.clone())?;
+    let gateway_shutdown = shutdown.child_token();
+    let gateway = tokio::spawn(close_gateway(io, gateway_shutdown.clone()));
+
+    let result = serve_app(listener, app, state, shutdown).await;
+    // Also ends the task when the server failed rather than being shut down.
+    gateway_shutdown.cancel();
+    gateway.await?;
+    result
+}
+
+// Gateway clients stay connected until told otherwise, which would hold up the drain for the
+// whole grace period. They get disconnected as soon as shutdown starts instead.
+async fn close_gateway(io: SocketIo, shutdown: CancellationToken) {
+    shutdown.cancelled().await;
+    info!("Closing gateway connections");
+    io.close().await;
+}
+
+async fn serve_app(
+    listener: TcpListener,
+    app: Router,
+    state: Arc<AppState>,
+    shutdown: CancellationToken,
+) -> Result<(), anyhow::Error> {
+    // Only the first caller gets to install the provider, which is all we need.
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    let grace = state.settings.server.shutdown_grace();
+    let tls = state.settings.tls.clone();
+    if let (false, Some(address)) = (tls.mutual_tls(), state.settings.server.admin_address()) {
+        // Metrics are optional, a port taken by another install shouldn't keep the API down.
+        match TcpListener::bind(address).await {
+            Ok(admin) => {
+                info!("Serving admin endpoints on {}", address);
+                tokio::spawn(log_failure(
+                    "Admin listener",
+                    serve_plain(admin, admin_router(state.clone()), grace, shutdown.clone()),
+                ));
+            }
+            Err(err) => warn!(
+                "Admin endpoints unavailable, failed to bind {}: {}",
+                address, err
+            ),
+        }
+    }
+    if !tls.enabled {
+        return serve_plain(listener, app, grace, shutdown).await;
+    }
+
+    let server = &state.settings.server;
+    if let Some(port) = tls.redirect_port {
+        let redirect = bind_port(server, port).await?;
+        info!("Redirecting HTTP on {} to HTTPS", redirect.local_addr()?);
+        let redirect_app = tls::redirect_router(server.port);
+        tokio::spawn(log_failure(
+            "HTTP redirect listener",
+            serve_plain(redirect, redirect_app, grace, shutdown.clone()),
+        ));
+    }
+
+    if let (true, Some(port)) = (tls.mutual_tls(), tls.admin_port) {
+        let admin = bind_port(server, port).await?;
+        let config = tls::rustls_config(&tls, ClientAuth::Required)?;
+        tls::spawn_reloader(
+            config.clone(),
+            tls.clone(),
+            ClientAuth::Required,
+            shutdown.clone(),
+        );
+        info!(
+            "Serving admin endpoints with mutual TLS on {}",
+            admin.local_addr()?
+        );
+        tokio::spawn(log_failure(
+            "Admin listener",
+            serve_tls(
+                admin,
+                admin_router(state.clone()),
+                config,
+                grace,
+                shutdown.clone(),
+            ),
+        ));
+    }
+
+    let config = tls::rustls_config(&tls, ClientAuth::None)?;
+    tls::spawn_reloader(config.clone(), tls, ClientAuth::None, shutdown.clone());
+    serve_tls(listener, app, config, grace, shutdown).await
+}
+
+async fn bind_port(settings: &ServerSettings, port: u16) -> Result<TcpListener, anyhow::Error> {
+    let address = settings.address_with_port(port);
+    TcpListener::bind(address)
+        .await
+        .with_context(|| format!("Failed to bind {}", address))
+}
+
+async fn log_failure(what: &'static str, server: impl Future<Output = Result<(), anyhow::Error>>) {
+    if let Err(err) = server.await {
+        error!("{} failed: {:?}", what, err);
+    }
+}
+
+async fn serve_plain(
+    listener: TcpListener,
+    app: Router,
+    grace: Duration,
+    shutdown: CancellationToken,
+) -> Result<(), anyhow::Error> {
+    // Connection info gives the rate limiter the client address.
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    let server = axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown.clone().cancelled_owned())
+        .into_future();
+    tokio::pin!(server);
+
+    tokio::select! {
+        result = &mut server => return Ok(result?),
+        _ = shutdown.cancelled() => info!("Shutting down, draining open requests"),
+    }
+
+    match tokio::time::timeout(grace, server).await {
+        Ok(result) => Ok(result?),
+        Err(_) => {
+            warn!("Requests still open after {:?}, dropping them", grace);
+            Ok(())
+        }
+    }
+}
+
+async fn serve_tls(
+    listener: TcpListener,
+    app: Router,
+    config: RustlsConfig,
+    grace: Duration,
+    shutdown: CancellationToken,
+) -> Result<(), anyhow::Error> {
+    let handle = axum_server::Handle::new();
+    tokio::spawn({
+        let handle = handle.clone();
+        async move {
+            shutdown.cancelled().await;
+            info!("Shutting down, draining open requests");
+            // Connections still open once the grace period is over are closed.
+            handle.graceful_shutdown(Some(grace));
+        }
+    });
+
+    axum_server::from_tcp_rustls(listener.into_std()?, config)
+        .handle(handle)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await?;
+    Ok(())
+}
+
+/// Resolves on Ctrl+C, or SIGTERM on Unix, which is what service managers send.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for Ctrl+C: {:?}", err);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(err) => {
+                warn!("Failed to listen for SIGTERM: {:?}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
@@ -0,0 +1,152 @@
+// Account management for operators, driven by the wyrd-admin binary and the background jobs.
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::auth_handler::AppState;
+use crate::repository::{
+    SessionStore, StoreError, User, UserRepository, UserSearch, ACTIVE, DELETED, SUSPENDED,
+};
+use crate::tp_auth;
+
+const PURGE_BATCH: i64 = 500;
+
+#[derive(Debug, Error)]
+pub enum AccountError {
+    #[error("No account matches {0}")]
+    NotFound(String),
+
+    #[error("The account is {actual}, expected {expected}")]
+    WrongStatus {
+        expected: &'static str,
+        actual: String,
+    },
+
+    #[error("The account has no linked provider")]
+    NoProvider,
+
+    #[error(transparent)]
+    Store(#[from] StoreError),
+
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+/// Looks an account up by id, email or username, whichever `who` looks like.
+pub async fn find(users: &dyn UserRepository, who: &str) -> Result<User, AccountError> {
+    let who = who.trim();
+    let user = if let Ok(id) = who.trim_start_matches('#').parse::<i64>() {
+        users.find_by_id(id).await?
+    } else if who.contains('@') {
+        users.find_by_email(who).await?
+    } else {
+        users.find_by_username(who).await?
+    };
+    user.ok_or_else(|| AccountError::NotFound(who.to_string()))
+}
+
+pub async fn mark_verified(users: &dyn UserRepository, user: &User) -> Result<(), AccountError> {
+    Ok(users.mark_verified(&user.email).await?)
+}
+
+/// Blocks logins and signs the user out everywhere. Returns how many sessions were revoked.
+pub async fn suspend(
+    users: &dyn UserRepository,
+    sessions: &dyn SessionStore,
+    user: &User,
+) -> Result<u64, AccountError> {
+    expect_status(user, ACTIVE)?;
+    users.set_status(user.id, SUSPENDED).await?;
+    Ok(sessions.revoke_all(user.id).await?)
+}
+
+pub async fn unsuspend(users: &dyn UserRepository, user: &User) -> Result<(), AccountError> {
+    expect_status(user, SUSPENDED)?;
+    Ok(users.set_status(user.id, ACTIVE).await?)
+}
+
+/// Refuses password logins until a new password is set, and ends the current sessions so a
+/// leaked password stops working right away.
+pub async fn force_password_reset(
+    users: &dyn UserRepository,
+    sessions: &dyn SessionStore,
+    user: &User,
+) -> Result<u64, AccountError> {
+    users.set_password_reset_required(user.id, true).await?;
+    Ok(sessions.revoke_all(user.id).await?)
+}
+
+pub async fn revoke_sessions(
+    sessions: &dyn SessionStore,
+    user: &User,
+) -> Result<u64, AccountError> {
+    Ok(sessions.revoke_all(user.id).await?)
+}
+
+/// Detaches the user's provider identity, revoking the stored grant with the provider.
+pub async fn unlink_provider(state: &AppState, user: &User) -> Result<(), AccountError> {
+    let (Some(provider), Some(provider_user_id)) = (&user.provider, &user.provider_user_id) else {
+        return Err(AccountError::NoProvider);
+    };
+    tp_auth::unlink_identity(state, provider, provider_user_id).await?;
+    Ok(())
+}
+
+/// Permanently removes the accounts marked deleted, only those deleted at least `deleted_for`
+/// ago when given. With `dry_run` nothing is touched and the accounts that would go are returned.
+pub async fn purge_deleted(
+    users: &dyn UserRepository,
+    sessions: &dyn SessionStore,
+    deleted_for: Option<Duration>,
+    dry_run: bool,
+) -> Result<Vec<User>, AccountError> {
+    let mut purged = Vec::new();
+    let mut search = UserSearch {
+        status: Some(DELETED.to_string()),
+        deleted_for,
+        limit: PURGE_BATCH,
+        ..UserSearch::default()
+    };
+    loop {
+        let batch = users.search(&search).await?;
+        let last_batch = (batch.len() as i64) < search.limit;
+        for user in batch {
+            if !dry_run {
+                sessions.revoke_all(user.id).await?;
+                users.delete(user.id).await?;
+            }
+            purged.push(user);
+        }
+        if last_batch {
+            return Ok(purged);
+        }
+        // Deleted rows drop out of the results by themselves.
+        if dry_run {
+            search.offset += search.limit;
+        }
+    }
+}
+
+/// Removes password sign-ups nobody verified within `older_than`, freeing their username and
+/// email. Returns how many went.
+pub async fn purge_unverified(
+    users: &dyn UserRepository,
+    sessions: &dyn SessionStore,
+    older_than: Duration,
+) -> Result<usize, AccountError> {
+    let purged = users.delete_unverified(older_than).await?;
+    for id in &purged {
+        sessions.revoke_all(*id).await?;
+    }
+    Ok(purged.len())
+}
+
+fn expect_status(user: &User, expected: &'static str) -> Result<(), AccountError> {
+    if user.status == expected {
+        Ok(())
+    } else {
+        Err(AccountError::WrongStatus {
+            expected,
+            actual: user.status.clone(),
+        })
+    }
+}
@@ -1,4 +1,8 @@
 use crate::otp::{generate_otp, send_otp};
+use crate::repository::{
+    NewUser, SessionStore, StoreError, User, UserRepository, ACTIVE, DELETED, SUSPENDED,
+};
+use crate::username;
 use anyhow::Error;
 use axum::{http::StatusCode, response::IntoResponse, Router};
 use chrono::{DateTime, Local, Utc};
@@ -7,7 +11,7 @@ use sqlx::{
     types::{time::PrimitiveDateTime, Json},
     PgPool,
 };
-use std::{fmt::Display, path, ptr::null, vec};
+use std::{fmt::Display, path, ptr::null, time::Duration, vec};
 use thiserror::Error;
 use totp_rs::Secret;
 
@@ -24,56 +28,84 @@ pub enum AuthenticationErrors {
     #[error("Database error: {0}")]
     DatabaseError(#[from] sqlx::Error),
 
+    #[error("{0}")]
+    StoreError(#[from] StoreError),
+
     #[error("Incorrect Username or Password {0}")]
     LoginError(String),
 
+    #[error("{0}")]
+    InvalidUsername(#[from] username::UsernameError),
+
     #[error("Username already taken. Please try a different one.")]
     SignupErrorUsername(String),
 
+    #[error("Please enter a valid email address.")]
+    SignupInvalidEmail,
+
     #[error("This email address has already been regestred with a different account. Please try a different one or log in.")]
     SignupErrorEmail(String),
 
     #[error("Incorrect code")]
     InvalidOTP(String),
 
+    #[error("The session expired or was revoked.")]
+    Unauthenticated,
+
+    #[error("This account has been suspended.")]
+    AccountSuspended,
+
+    #[error("A new password has to be set before logging in.")]
+    PasswordResetRequired,
+
     #[error("An error has ocurred. Please try again later.")]
     GeneralError(String),
 
-    #[error("")]
+    #[error("Sending the email failed: {0}")]
     EmailSendError(String),
 }
 
-struct Record {
-    password: String,
-}
-
-pub async fn signup(pool: &PgPool, payload: &SignupReq) -> Result<(), Vec<AuthenticationErrors>> {
+/// Signs up with a password. The account stays unverified until the emailed code is entered.
+pub async fn signup(
+    users: &dyn UserRepository,
+    payload: &SignupReq,
+) -> Result<(), Vec<AuthenticationErrors>> {
     let mut errors: Vec<AuthenticationErrors> = Vec::new();
     /*match fetch_email_data(&payload.email) {
         Ok(response) => println!("{:?}", response),
         Err(e) => errors.push(AuthenticationErrors::SignupInvalidEmail(e.to_string())),
     }*/
 
-    let existing_username = sqlx::query!(
-        "SELECT id FROM users WHERE username = $1",
-        &payload.username,
-    )
-    .fetch_optional(pool)
-    .await
-    .map_err(|e| vec![AuthenticationErrors::DatabaseError(e)])?;
+    // Same rules as usernames allocated for social sign-ups, which are always lowercase. Typed
+    // ones keep their case, uniqueness ignores it. Every field is checked before returning so
+    // the form can show all of its problems at once.
+    let requested_username = payload.username.trim();
+    let valid_username = match username::validate(&requested_username.to_lowercase()) {
+        Ok(()) => true,
+        Err(err) => {
+            errors.push(AuthenticationErrors::InvalidUsername(err));
+            false
+        }
+    };
+    let valid_email = fast_chemail::is_valid_email(&payload.email);
+    if !valid_email {
+        errors.push(AuthenticationErrors::SignupInvalidEmail);
+    }
 
-    let existing_email = sqlx::query!("SELECT id FROM users WHERE email = $1", &payload.email,)
-        .fetch_optional(pool)
+    let existing_username = users
+        .find_by_username(requested_username)
+        .await
+        .map_err(|e| vec![AuthenticationErrors::from(e)])?;
+    let existing_email = users
+        .find_by_email(&payload.email)
         .await
-        .map_err(|e| vec![AuthenticationErrors::DatabaseError(e)])?;
+        .map_err(|e| vec![AuthenticationErrors::from(e)])?;
 
-    if existing_username.is_some() {
-        errors.push(AuthenticationErrors::SignupErrorUsername(
-            ("Username already taken. Please try a different one.".to_string()),
-        ));
+    if valid_username && existing_username.is_some() {
+        errors.push(username_taken());
     }
-    if existing_email.is_some() {
-        errors.push(AuthenticationErrors::SignupErrorEmail(("This email address has already been regestred with a different account. Please try a different one or log in.".to_string())));
+    if valid_email && existing_email.is_some() {
+        errors.push(email_taken());
     }
     if !errors.is_empty() {
         return Err(errors);
@@ -86,50 +118,43 @@ pub async fn signup(pool: &PgPool, payload: &SignupReq) -> Result<(), Vec<Authen
     //Used for secret key generations for the totp-rs crate.
     let secret_key = Secret::generate_secret().to_string();
 
-    sqlx::query!(
-          "INSERT INTO users (name, username, email, password, last_login, status, activity, user_verified, totp_secret, personalization, profile_url, provider, provider_user_id)
-           VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)",
-          &payload.name,                  // $1: User's name
-          &payload.username,              // $2: User's username
-          &payload.email,                 // $3: User's email
-          hashed_password,               // $4: Hashed password
-          PrimitiveDateTime::MAX,        // $5: last_login (None for new users)
-          "active",                      // $6: status (default to "active")
-          "offline",                     // $7: activity (default to "offline")
-          false,                         // $8: user_verified (default to false)
-          secret_key,                    //$9 secret key for otp and other verification (DO NOT DELETE)
-          serde_json::json!({}),        // $10: personalization
-          None::<String>,               // $11: profile_url
-          None::<String>,               // $12: provider
-          None::<String>,               // $13: provider_user_id
-      )
-    .execute(pool)
-    .await
-    .map_err(|e| vec![AuthenticationErrors::DatabaseError(e)])?;
-
-    Ok(())
+    let new_user = NewUser {
+        name: payload.name.clone(),
+        username: requested_username.to_string(),
+        email: payload.email.clone(),
+        password: Some(hashed_password),
+        user_verified: false,
+        totp_secret: secret_key, // secret key for otp and other verification (DO NOT DELETE)
+    };
+    // The lookups above only make for friendlier errors, the unique indexes settle races.
+    match users.create(&new_user).await {
+        Ok(_) => Ok(()),
+        Err(StoreError::Conflict("username")) => Err(vec![username_taken()]),
+        Err(StoreError::Conflict(_)) => Err(vec![email_taken()]),
+        Err(err) => Err(vec![err.into()]),
+    }
 }
 
-pub async fn login(pool: &PgPool, payload: LoginReq) -> Result<(), AuthenticationErrors> {
+fn username_taken() -> AuthenticationErrors {
+    AuthenticationErrors::SignupErrorUsername(
+        "Username already taken. Please try a different one.".to_string(),
+    )
+}
+
+fn email_taken() -> AuthenticationErrors {
+    AuthenticationErrors::SignupErrorEmail("This email address has already been regestred with a different account. Please try a different one or log in.".to_string())
+}
+
+/// Checks the password and opens a session. Returns the session token.
+pub async fn login(
+    users: &dyn UserRepository,
+    sessions: &dyn SessionStore,
+    payload: LoginReq,
+    session_ttl: Duration,
+) -> Result<String, AuthenticationErrors> {
     let user = match (payload.username.as_ref(), payload.email.as_ref()) {
-        (Some(username), None) => {
-            sqlx::query_as!(
-                Record,
-                "SELECT password FROM users WHERE username = $1",
-                username,
-            )
-            .fetch_optional(pool)
-            .await?
-        }
-        (None, Some(email)) => {
-            sqlx::query_as!(
-                Record,
-                "SELECT password FROM users WHERE email = $1",
-                payload.email.unwrap(),
-            )
-            .fetch_optional(pool)
-            .await?
-        }
+        (Some(username), None) => users.find_by_username(username).await?,
+        (None, Some(email)) => users.find_by_email(email).await?,
         _ => {
             return (Err(AuthenticationErrors::LoginError(
                 ("Error retrieving username and email.").to_string(),
@@ -138,12 +163,31 @@ pub async fn login(pool: &PgPool, payload: LoginReq) -> Result<(), Authenticatio
     };
 
     match user {
-        Some(record) => {
-            if password::verify(&pool, payload.password, record.password)
+        // Deleted accounts wait for the purge and behave as if they were gone already.
+        Some(User { status, .. }) if status == DELETED => Err(AuthenticationErrors::LoginError(
+            "User not found".to_string(),
+        )),
+        // Accounts created through a provider have no password to log in with.
+        Some(User {
+            id,
+            password: Some(hash),
+            status,
+            password_reset_required,
+            ..
+        }) => {
+            if password::verify(payload.password, hash)
                 .await
                 .unwrap_or(false)
             {
-                Ok(())
+                // Only told after the password checked out, so these don't reveal anything.
+                if status == SUSPENDED {
+                    return Err(AuthenticationErrors::AccountSuspended);
+                }
+                if password_reset_required {
+                    return Err(AuthenticationErrors::PasswordResetRequired);
+                }
+                users.record_login(id).await?;
+                Ok(sessions.create(id, session_ttl).await?)
             } else {
                 if payload.username.is_some() {
                     Err(AuthenticationErrors::LoginError(
@@ -156,8 +200,25 @@ pub async fn login(pool: &PgPool, payload: LoginReq) -> Result<(), Authenticatio
                 }
             }
         }
-        None => Err(AuthenticationErrors::LoginError(
+        Some(User { password: None, .. }) | None => Err(AuthenticationErrors::LoginError(
             "User not found".to_string(),
         )),
     }
 }
+
+/// The user a session token belongs to. Checked on every use, so revoking the session or
+/// suspending the account takes effect right away.
+pub async fn authenticate(
+    users: &dyn UserRepository,
+    sessions: &dyn SessionStore,
+    token: &str,
+) -> Result<User, AuthenticationErrors> {
+    let Some(user_id) = sessions.user_id(token).await? else {
+        return Err(AuthenticationErrors::Unauthenticated);
+    };
+    match users.find_by_id(user_id).await? {
+        Some(user) if user.status == ACTIVE => Ok(user),
+        Some(user) if user.status == SUSPENDED => Err(AuthenticationErrors::AccountSuspended),
+        _ => Err(AuthenticationErrors::Unauthenticated),
+    }
+}
@@ -0,0 +1,683 @@
+// Chat messages: what makes a valid one, who may read and post where, and the shape clients
+// receive. Messages are stored before anyone hears of them; the gateway and the REST API only
+// deliver what comes back from here. Groups are managed here too, with their permissions:
+// owners can do anything, admins can change the details, add or remove plain members and hand
+// out invite links that make plain members.
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use serde::Serialize;
+use thiserror::Error;
+use utoipa::ToSchema;
+
+use crate::repository::{
+    Conversation, ConversationStore, GroupDetails, GroupEvent, HistoryCursor, Invite, InviteUse,
+    Member, Message, NewInvite, StoreError, User, UserRepository, ACTIVE, ADMIN, GROUP, MEMBER,
+    OWNER,
+};
+
+/// Longest message body, in characters.
+pub const MAX_BODY_CHARS: usize = 4000;
+
+/// Messages in a page of history when the client doesn't say.
+pub const DEFAULT_PAGE: i64 = 50;
+pub const MAX_PAGE: i64 = 100;
+
+/// Longest group name and topic, in characters.
+pub const MAX_GROUP_NAME_CHARS: usize = 80;
+pub const MAX_TOPIC_CHARS: usize = 500;
+
+/// Longest an invite can stay valid, in seconds: 30 days.
+pub const MAX_INVITE_EXPIRY_SECS: i64 = 30 * 24 * 60 * 60;
+
+#[derive(Debug, Error)]
+pub enum ChatError {
+    #[error("Messages can't be empty")]
+    EmptyBody,
+
+    #[error("Messages can be at most {MAX_BODY_CHARS} characters long")]
+    BodyTooLong,
+
+    #[error("No user called {0}")]
+    UnknownRecipient(String),
+
+    #[error("Users can't open a conversation with themselves")]
+    MessagingSelf,
+
+    /// Also what non-members get, so conversation ids can't be probed.
+    #[error("No conversation {0}")]
+    ConversationNotFound(i64),
+
+    /// The user's role doesn't allow it, or it only makes sense in a group.
+    #[error("Not permitted in this conversation")]
+    NotPermitted,
+
+    #[error("User {0} is not a member")]
+    NotAMember(i64),
+
+    #[error("The owner has to transfer the group before leaving it")]
+    OwnerMustTransfer,
+
+    #[error("Group names need 1 to {MAX_GROUP_NAME_CHARS} characters")]
+    InvalidGroupName,
+
+    #[error("Topics can be at most {MAX_TOPIC_CHARS} characters long")]
+    TopicTooLong,
+
+    #[error("Avatars have to be https URLs")]
+    InvalidAvatarUrl,
+
+    #[error("Members can only be made admins or members")]
+    InvalidRole,
+
+    /// Also for invites to other groups than the one asked about.
+    #[error("No such invite")]
+    InviteNotFound,
+
+    #[error("The invite was revoked, expired or used up")]
+    InviteExpired,
+
+    #[error("Invites can stay valid for 1 to {MAX_INVITE_EXPIRY_SECS} seconds")]
+    InvalidInviteExpiry,
+
+    #[error("Invites allow at least one use")]
+    InvalidInviteMaxUses,
+
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
+
+/// A message as clients receive it.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ChatMessage {
+    pub id: i64,
+    pub conversation_id: i64,
+    /// Position in the conversation, the cursor for paging through history.
+    pub seq: i64,
+    /// Absent once the sender's account is gone. For system messages, who made the change.
+    pub sender_id: Option<i64>,
+    /// `text`, or `system` for changes to a group.
+    pub kind: String,
+    /// Plain text. For system messages an English summary of `event`.
+    pub body: String,
+    /// What changed, on system messages.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<GroupEvent>,
+    /// RFC 3339, in UTC.
+    pub created_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<String>,
+    /// Whatever the sender attached, echoed back so it can match the message to the one it
+    /// shows optimistically.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+}
+
+impl ChatMessage {
+    pub fn new(message: Message, nonce: Option<String>) -> Self {
+        ChatMessage {
+            id: message.id,
+            conversation_id: message.conversation_id,
+            seq: message.seq,
+            sender_id: message.sender_id,
+            kind: message.kind,
+            body: message.body,
+            event: message
+                .event
+                .and_then(|event| serde_json::from_value(event).ok()),
+            created_at: timestamp(message.created_at),
+            edited_at: message.edited_at.map(timestamp),
+            nonce,
+        }
+    }
+}
+
+pub fn timestamp(at: DateTime<Utc>) -> String {
+    at.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Trims surrounding whitespace and checks what is left.
+pub fn validate_body(body: &str) -> Result<String, ChatError> {
+    let body = body.trim();
+    if body.is_empty() {
+        return Err(ChatError::EmptyBody);
+    }
+    if body.chars().count() > MAX_BODY_CHARS {
+        return Err(ChatError::BodyTooLong);
+    }
+    Ok(body.to_string())
+}
+
+/// The 1:1 conversation between `user` and the user called `username`. Only active accounts
+/// can be messaged.
+pub async fn open_direct(
+    users: &dyn UserRepository,
+    conversations: &dyn ConversationStore,
+    user: &User,
+    username: &str,
+) -> Result<Conversation, ChatError> {
+    let other = users
+        .find_by_username(username)
+        .await?
+        .filter(|other| other.status == ACTIVE)
+        .ok_or_else(|| ChatError::UnknownRecipient(username.to_string()))?;
+    if other.id == user.id {
+        return Err(ChatError::MessagingSelf);
+    }
+    Ok(conversations.open_direct(user.id, other.id).await?)
+}
+
+/// The conversation and its members' ids, as long as `user` is one of them.
+pub async fn membership(
+    conversations: &dyn ConversationStore,
+    user: &User,
+    conversation_id: i64,
+) -> Result<(Conversation, Vec<i64>), ChatError> {
+    let not_found = || ChatError::ConversationNotFound(conversation_id);
+    let conversation = conversations
+        .find(conversation_id)
+        .await?
+        .ok_or_else(not_found)?;
+    let members = conversations.member_ids(conversation_id).await?;
+    if !members.contains(&user.id) {
+        return Err(not_found());
+    }
+    Ok((conversation, members))
+}
+
+/// Stores a message from `sender`. Comes back with the ids of the members to deliver it to.
+pub async fn post_message(
+    conversations: &dyn ConversationStore,
+    sender: &User,
+    conversation_id: i64,
+    body: &str,
+    nonce: Option<String>,
+) -> Result<(ChatMessage, Vec<i64>), ChatError> {
+    let body = validate_body(body)?;
+    let (_, members) = membership(conversations, sender, conversation_id).await?;
+    let message = conversations
+        .post(conversation_id, sender.id, &body)
+        .await?;
+    Ok((ChatMessage::new(message, nonce), members))
+}
+
+/// One page of a conversation, oldest message first.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct HistoryPage {
+    pub messages: Vec<ChatMessage>,
+    /// Pass as `before` for the page before this one. Absent when this page starts at the first
+    /// message.
+    pub older: Option<i64>,
+    /// Pass as `after` for the page after this one. Absent when this page ends at the latest
+    /// message.
+    pub newer: Option<i64>,
+}
+
+/// Up to `limit` messages next to `cursor`, `DEFAULT_PAGE` if not given and at most `MAX_PAGE`.
+pub async fn history(
+    conversations: &dyn ConversationStore,
+    user: &User,
+    conversation_id: i64,
+    cursor: HistoryCursor,
+    limit: Option<i64>,
+) -> Result<HistoryPage, ChatError> {
+    membership(conversations, user, conversation_id).await?;
+    let limit = limit.unwrap_or(DEFAULT_PAGE).clamp(1, MAX_PAGE);
+
+    // One more than asked for tells whether the page is the last one in its direction.
+    let mut messages = conversations
+        .history(conversation_id, cursor, limit + 1)
+        .await?;
+    let more = messages.len() as i64 > limit;
+    if more {
+        match cursor {
+            HistoryCursor::After(_) => {
+                messages.pop();
+            }
+            HistoryCursor::Latest | HistoryCursor::Before(_) => {
+                messages.remove(0);
+            }
+        }
+    }
+
+    let first = messages.first().map(|message| message.seq);
+    let last = messages.last().map(|message| message.seq);
+    // Whoever holds a cursor got it from a page on its other side.
+    let (older, newer) = match cursor {
+        HistoryCursor::Latest => (first.filter(|_| more), None),
+        HistoryCursor::Before(_) => (first.filter(|_| more), last),
+        HistoryCursor::After(after) => (first.filter(|_| after > 0), last.filter(|_| more)),
+    };
+    Ok(HistoryPage {
+        messages: messages
+            .into_iter()
+            .map(|message| ChatMessage::new(message, None))
+            .collect(),
+        older,
+        newer,
+    })
+}
+
+/// Trims the name, topic and avatar of a group and checks them. An empty topic or avatar means
+/// none.
+pub fn validate_group(
+    name: &str,
+    topic: Option<&str>,
+    avatar_url: Option<&str>,
+) -> Result<GroupDetails, ChatError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_GROUP_NAME_CHARS {
+        return Err(ChatError::InvalidGroupName);
+    }
+    let topic = topic.map(str::trim).filter(|topic| !topic.is_empty());
+    if topic.is_some_and(|topic| topic.chars().count() > MAX_TOPIC_CHARS) {
+        return Err(ChatError::TopicTooLong);
+    }
+    let avatar_url = avatar_url.map(str::trim).filter(|url| !url.is_empty());
+    if let Some(url) = avatar_url {
+        let https = url::Url::parse(url).is_ok_and(|url| url.scheme() == "https");
+        if !https {
+            return Err(ChatError::InvalidAvatarUrl);
+        }
+    }
+    Ok(GroupDetails {
+        name: name.to_string(),
+        topic: topic.map(str::to_string),
+        avatar_url: avatar_url.map(str::to_string),
+    })
+}
+
+/// A recorded change to a group and who to tell about it: the members, and anyone who just
+/// stopped being one.
+#[derive(Debug)]
+pub struct GroupChange {
+    pub message: ChatMessage,
+    pub recipients: Vec<i64>,
+}
+
+// Higher ranks can do everything lower ones can.
+fn rank(role: &str) -> u8 {
+    match role {
+        OWNER => 2,
+        ADMIN => 1,
+        _ => 0,
+    }
+}
+
+fn role_of(members: &[Member], user_id: i64) -> Option<&str> {
+    members
+        .iter()
+        .find(|member| member.user_id == user_id)
+        .map(|member| member.role.as_str())
+}
+
+// The group and its members, as long as `user` is one of them and holds at least `role`.
+async fn group_membership(
+    conversations: &dyn ConversationStore,
+    user: &User,
+    conversation_id: i64,
+    role: &str,
+) -> Result<(Conversation, Vec<Member>), ChatError> {
+    let not_found = || ChatError::ConversationNotFound(conversation_id);
+    let conversation = conversations
+        .find(conversation_id)
+        .await?
+        .ok_or_else(not_found)?;
+    let members = conversations.members(conversation_id).await?;
+    let user_role = role_of(&members, user.id).ok_or_else(not_found)?;
+    if conversation.kind != GROUP || rank(user_role) < rank(role) {
+        return Err(ChatError::NotPermitted);
+    }
+    Ok((conversation, members))
+}
+
+async fn record(
+    conversations: &dyn ConversationStore,
+    actor: &User,
+    conversation_id: i64,
+    event: GroupEvent,
+) -> Result<GroupChange, ChatError> {
+    let message = conversations
+        .apply(conversation_id, actor.id, &event)
+        .await?;
+    let mut recipients = conversations.member_ids(conversation_id).await?;
+    if let GroupEvent::MemberRemoved { user_id } | GroupEvent::MemberLeft { user_id } = event {
+        recipients.push(user_id);
+    }
+    Ok(GroupChange {
+        message: ChatMessage::new(message, None),
+        recipients,
+    })
+}
+
+// Active users by username, in order and without repeats.
+async fn active_users(
+    users: &dyn UserRepository,
+    usernames: &[String],
+) -> Result<Vec<User>, ChatError> {
+    let mut found: Vec<User> = Vec::new();
+    for username in usernames {
+        let user = users
+            .find_by_username(username)
+            .await?
+            .filter(|user| user.status == ACTIVE)
+            .ok_or_else(|| ChatError::UnknownRecipient(username.clone()))?;
+        if !found.iter().any(|other| other.id == user.id) {
+            found.push(user);
+        }
+    }
+    Ok(found)
+}
+
+/// A new group owned by `owner`, with the users called `usernames` as members.
+pub async fn create_group(
+    users: &dyn UserRepository,
+    conversations: &dyn ConversationStore,
+    owner: &User,
+    details: &GroupDetails,
+    usernames: &[String],
+) -> Result<(Conversation, GroupChange), ChatError> {
+    let member_ids: Vec<i64> = active_users(users, usernames)
+        .await?
+        .iter()
+        .map(|user| user.id)
+        .filter(|id| *id != owner.id)
+        .collect();
+    let (conversation, message) = conversations
+        .create_group(owner.id, details, &member_ids)
+        .await?;
+    let recipients = conversations.member_ids(conversation.id).await?;
+    Ok((
+        conversation,
+        GroupChange {
+            message: ChatMessage::new(message, None),
+            recipients,
+        },
+    ))
+}
+
+/// Changes the name, topic or avatar, whichever are given. An empty topic or avatar removes it.
+/// Admins and the owner only.
+pub async fn update_group(
+    conversations: &dyn ConversationStore,
+    user: &User,
+    conversation_id: i64,
+    name: Option<&str>,
+    topic: Option<&str>,
+    avatar_url: Option<&str>,
+) -> Result<GroupChange, ChatError> {
+    let (group, _) = group_membership(conversations, user, conversation_id, ADMIN).await?;
+    let details = validate_group(
+        name.or(group.name.as_deref()).unwrap_or_default(),
+        topic.or(group.topic.as_deref()),
+        avatar_url.or(group.avatar_url.as_deref()),
+    )?;
+    let event = GroupEvent::Updated {
+        name: details.name,
+        topic: details.topic,
+        avatar_url: details.avatar_url,
+    };
+    record(conversations, user, conversation_id, event).await
+}
+
+/// Adds the users called `usernames` as members. Admins and the owner only. `None` when they
+/// were all members already.
+pub async fn add_members(
+    users: &dyn UserRepository,
+    conversations: &dyn ConversationStore,
+    user: &User,
+    conversation_id: i64,
+    usernames: &[String],
+) -> Result<Option<GroupChange>, ChatError> {
+    let (_, members) = group_membership(conversations, user, conversation_id, ADMIN).await?;
+    let user_ids: Vec<i64> = active_users(users, usernames)
+        .await?
+        .iter()
+        .map(|user| user.id)
+        .filter(|id| role_of(&members, *id).is_none())
+        .collect();
+    if user_ids.is_empty() {
+        return Ok(None);
+    }
+    let event = GroupEvent::MembersAdded { user_ids };
+    Ok(Some(
+        record(conversations, user, conversation_id, event).await?,
+    ))
+}
+
+/// Takes someone else out of the group. The owner can remove anyone, admins only plain members.
+pub async fn remove_member(
+    conversations: &dyn ConversationStore,
+    user: &User,
+    conversation_id: i64,
+    member_id: i64,
+) -> Result<GroupChange, ChatError> {
+    let (_, members) = group_membership(conversations, user, conversation_id, ADMIN).await?;
+    let role = role_of(&members, member_id).ok_or(ChatError::NotAMember(member_id))?;
+    let user_role = role_of(&members, user.id).unwrap_or(MEMBER);
+    if member_id == user.id || rank(role) >= rank(user_role) {
+        return Err(ChatError::NotPermitted);
+    }
+    let event = GroupEvent::MemberRemoved { user_id: member_id };
+    record(conversations, user, conversation_id, event).await
+}
+
+/// Makes a member an admin or the other way around. The owner only. `None` when the member
+/// already has that role.
+pub async fn set_role(
+    conversations: &dyn ConversationStore,
+    user: &User,
+    conversation_id: i64,
+    member_id: i64,
+    role: &str,
+) -> Result<Option<GroupChange>, ChatError> {
+    if role != ADMIN && role != MEMBER {
+        return Err(ChatError::InvalidRole);
+    }
+    let (_, members) = group_membership(conversations, user, conversation_id, OWNER).await?;
+    let current = role_of(&members, member_id).ok_or(ChatError::NotAMember(member_id))?;
+    if member_id == user.id {
+        return Err(ChatError::NotPermitted);
+    }
+    if current == role {
+        return Ok(None);
+    }
+    let event = GroupEvent::RoleChanged {
+        user_id: member_id,
+        role: role.to_string(),
+    };
+    Ok(Some(
+        record(conversations, user, conversation_id, event).await?,
+    ))
+}
+
+/// Hands the group to another member. The owner stays on as an admin.
+pub async fn transfer_ownership(
+    conversations: &dyn ConversationStore,
+    user: &User,
+    conversation_id: i64,
+    member_id: i64,
+) -> Result<GroupChange, ChatError> {
+    let (_, members) = group_membership(conversations, user, conversation_id, OWNER).await?;
+    role_of(&members, member_id).ok_or(ChatError::NotAMember(member_id))?;
+    if member_id == user.id {
+        return Err(ChatError::NotPermitted);
+    }
+    let event = GroupEvent::OwnershipTransferred {
+        from: user.id,
+        to: member_id,
+    };
+    record(conversations, user, conversation_id, event).await
+}
+
+/// Leaves a group. Owners transfer it first, or delete it.
+pub async fn leave(
+    conversations: &dyn ConversationStore,
+    user: &User,
+    conversation_id: i64,
+) -> Result<GroupChange, ChatError> {
+    let (_, members) = group_membership(conversations, user, conversation_id, MEMBER).await?;
+    if role_of(&members, user.id) == Some(OWNER) {
+        return Err(ChatError::OwnerMustTransfer);
+    }
+    let event = GroupEvent::MemberLeft { user_id: user.id };
+    record(conversations, user, conversation_id, event).await
+}
+
+/// Deletes a group with its history. The owner only. Returns who was in it.
+pub async fn delete_group(
+    conversations: &dyn ConversationStore,
+    user: &User,
+    conversation_id: i64,
+) -> Result<Vec<i64>, ChatError> {
+    let (_, members) = group_membership(conversations, user, conversation_id, OWNER).await?;
+    conversations.delete(conversation_id).await?;
+    Ok(members.iter().map(|member| member.user_id).collect())
+}
+
+/// A new invite link to the group. Admins can invite plain members, only the owner can invite
+/// admins. Without `max_uses` or `expires_in`, in seconds, it stays valid until revoked.
+pub async fn create_invite(
+    conversations: &dyn ConversationStore,
+    user: &User,
+    conversation_id: i64,
+    role: Option<&str>,
+    max_uses: Option<i64>,
+    expires_in: Option<i64>,
+) -> Result<Invite, ChatError> {
+    let (_, members) = group_membership(conversations, user, conversation_id, ADMIN).await?;
+    let role = role.unwrap_or(MEMBER);
+    if role != ADMIN && role != MEMBER {
+        return Err(ChatError::InvalidRole);
+    }
+    if rank(role) >= rank(role_of(&members, user.id).unwrap_or(MEMBER)) {
+        return Err(ChatError::NotPermitted);
+    }
+    let max_uses = match max_uses {
+        Some(max) => Some(
+            i32::try_from(max)
+                .ok()
+                .filter(|max| *max > 0)
+                .ok_or(ChatError::InvalidInviteMaxUses)?,
+        ),
+        None => None,
+    };
+    if expires_in.is_some_and(|secs| !(1..=MAX_INVITE_EXPIRY_SECS).contains(&secs)) {
+        return Err(ChatError::InvalidInviteExpiry);
+    }
+    let invite = NewInvite {
+        created_by: user.id,
+        role: role.to_string(),
+        max_uses,
+        expires_at: expires_in.map(|secs| Utc::now() + Duration::seconds(secs)),
+    };
+    Ok(conversations
+        .create_invite(conversation_id, &invite)
+        .await?)
+}
+
+/// The group's invites, newest first. Admins and the owner only.
+pub async fn invites(
+    conversations: &dyn ConversationStore,
+    user: &User,
+    conversation_id: i64,
+) -> Result<Vec<Invite>, ChatError> {
+    group_membership(conversations, user, conversation_id, ADMIN).await?;
+    Ok(conversations.invites(conversation_id).await?)
+}
+
+// One of the group's invites, for its admins.
+async fn group_invite(
+    conversations: &dyn ConversationStore,
+    user: &User,
+    conversation_id: i64,
+    invite_id: i64,
+) -> Result<Invite, ChatError> {
+    invites(conversations, user, conversation_id)
+        .await?
+        .into_iter()
+        .find(|invite| invite.id == invite_id)
+        .ok_or(ChatError::InviteNotFound)
+}
+
+/// Stops an invite from letting anyone else in. Admins and the owner only.
+pub async fn revoke_invite(
+    conversations: &dyn ConversationStore,
+    user: &User,
+    conversation_id: i64,
+    invite_id: i64,
+) -> Result<(), ChatError> {
+    group_invite(conversations, user, conversation_id, invite_id).await?;
+    Ok(conversations.revoke_invite(invite_id).await?)
+}
+
+/// Who joined through an invite, latest first. Admins and the owner only.
+pub async fn invite_uses(
+    conversations: &dyn ConversationStore,
+    user: &User,
+    conversation_id: i64,
+    invite_id: i64,
+) -> Result<Vec<InviteUse>, ChatError> {
+    group_invite(conversations, user, conversation_id, invite_id).await?;
+    Ok(conversations.invite_uses(invite_id).await?)
+}
+
+/// What the holder of an invite code gets to see before joining.
+#[derive(Debug)]
+pub struct InvitePreview {
+    pub invite: Invite,
+    pub group: Conversation,
+    pub member_count: usize,
+}
+
+/// The invite behind `code` and its group, as long as it can still be used.
+pub async fn preview_invite(
+    conversations: &dyn ConversationStore,
+    code: &str,
+) -> Result<InvitePreview, ChatError> {
+    let invite = conversations
+        .find_invite(code)
+        .await?
+        .ok_or(ChatError::InviteNotFound)?;
+    if !invite.usable(Utc::now()) {
+        return Err(ChatError::InviteExpired);
+    }
+    let group = conversations
+        .find(invite.conversation_id)
+        .await?
+        .ok_or(ChatError::InviteNotFound)?;
+    let member_count = conversations.member_ids(group.id).await?.len();
+    Ok(InvitePreview {
+        invite,
+        group,
+        member_count,
+    })
+}
+
+/// Joins the group behind `code` with the invite's role. Comes back with the group, and the
+/// change unless `user` was a member already.
+pub async fn accept_invite(
+    conversations: &dyn ConversationStore,
+    user: &User,
+    code: &str,
+) -> Result<(Conversation, Option<GroupChange>), ChatError> {
+    let InvitePreview { invite, group, .. } = preview_invite(conversations, code).await?;
+    if conversations.member_ids(group.id).await?.contains(&user.id) {
+        return Ok((group, None));
+    }
+    // Someone else took the last use, or it expired, since the check above.
+    let message = conversations
+        .redeem_invite(invite.id, user.id)
+        .await?
+        .ok_or(ChatError::InviteExpired)?;
+    let recipients = conversations.member_ids(group.id).await?;
+    let group = conversations
+        .find(group.id)
+        .await?
+        .ok_or(ChatError::ConversationNotFound(group.id))?;
+    Ok((
+        group,
+        Some(GroupChange {
+            message: ChatMessage::new(message, None),
+            recipients,
+        }),
+    ))
+}
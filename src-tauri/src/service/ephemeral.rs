@@ -0,0 +1,211 @@
+use async_trait::async_trait;
+use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+use redis::{AsyncCommands, IntoConnectionInfo, Script};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::repository::StoreError;
+use crate::settings::RedisSettings;
+use crate::telemetry;
+
+/// Short-lived state: verification codes, sessions, username tickets and rate limit windows.
+/// Redis when several servers share it, process memory for a single desktop install.
+#[async_trait]
+pub trait EphemeralStore: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<String>, StoreError>;
+    /// Without a `ttl` the value stays until deleted.
+    async fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> Result<(), StoreError>;
+    /// Gets and deletes in one step.
+    async fn take(&self, key: &str) -> Result<Option<String>, StoreError>;
+    /// Returns how many of `keys` existed.
+    async fn delete(&self, keys: &[String]) -> Result<u64, StoreError>;
+    async fn set_add(&self, key: &str, member: &str) -> Result<(), StoreError>;
+    async fn set_remove(&self, key: &str, member: &str) -> Result<(), StoreError>;
+    async fn set_members(&self, key: &str) -> Result<Vec<String>, StoreError>;
+    /// Every key starting with `prefix`. Walks the whole keyspace, so it's meant for
+    /// maintenance jobs rather than requests.
+    async fn scan(&self, prefix: &str) -> Result<Vec<String>, StoreError>;
+    /// Counts a hit in the sliding `window` behind `key`. Returns how long to wait when `limit`
+    /// hits are in the window already, in which case this one isn't counted.
+    async fn hit(
+        &self,
+        key: &str,
+        window: Duration,
+        limit: u64,
+    ) -> Result<Option<Duration>, StoreError>;
+    async fn ping(&self) -> Result<(), StoreError>;
+}
+
+// Sliding window log: one sorted set member per allowed request, scored by its time in ms.
+// Returns 0 when the request is allowed, otherwise the ms until the oldest entry leaves the
+// window. Running it as a script keeps check-and-record atomic across server instances.
+const SLIDING_WINDOW: &str = r"
+local key = KEYS[1]
+local now = tonumber(ARGV[1])
+local window = tonumber(ARGV[2])
+local limit = tonumber(ARGV[3])
+redis.call('ZREMRANGEBYSCORE', key, 0, now - window)
+if redis.call('ZCARD', key) < limit then
+    redis.call('ZADD', key, now, ARGV[4])
+    redis.call('PEXPIRE', key, window)
+    return 0
+end
+local oldest = redis.call('ZRANGE', key, 0, 0, 'WITHSCORES')
+return math.max(tonumber(oldest[2]) + window - now, 1)
+";
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Redis through a `ConnectionManager`: a dropped connection fails the commands in flight and
+/// is re-established in the background with exponential backoff, so a Redis restart costs a
+/// few errors rather than the process.
+#[derive(Clone)]
+pub struct RedisStore {
+    connection: ConnectionManager,
+    sliding_window: Script,
+}
+
+impl RedisStore {
+    pub async fn connect(settings: &RedisSettings) -> Result<Self, anyhow::Error> {
+        // rediss:// URLs get TLS. Credentials set separately win over the ones in the URL so the
+        // password can come from the environment.
+        let mut info = settings.url.as_str().into_connection_info()?;
+        if let Some(username) = &settings.username {
+            info.redis.username = Some(username.clone());
+        }
+        if let Some(password) = &settings.password {
+            info.redis.password = Some(password.clone());
+        }
+
+        // rustls can't pick a crypto provider on its own when several are compiled in. Same one
+        // as the HTTPS listener; whichever installs first wins.
+        let _ = rustls::crypto::ring::default_provider().install_default();
+
+        let config = ConnectionManagerConfig::new()
+            .set_connection_timeout(settings.connect_timeout())
+            .set_response_timeout(settings.response_timeout())
+            .set_number_of_retries(settings.reconnect_attempts)
+            .set_max_delay(settings.max_reconnect_delay_ms);
+        let connection =
+            ConnectionManager::new_with_config(redis::Client::open(info)?, config).await?;
+        Ok(RedisStore {
+            connection,
+            sliding_window: Script::new(SLIDING_WINDOW),
+        })
+    }
+}
+
+fn redis_error(operation: &'static str) -> impl Fn(redis::RedisError) -> StoreError {
+    move |err| {
+        telemetry::record_redis_error(operation);
+        StoreError::Redis(err)
+    }
+}
+
+#[async_trait]
+impl EphemeralStore for RedisStore {
+    async fn get(&self, key: &str) -> Result<Option<String>, StoreError> {
+        let mut connection = self.connection.clone();
+        connection.get(key).await.map_err(redis_error("get"))
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> Result<(), StoreError> {
+        let mut connection = self.connection.clone();
+        match ttl {
+            // PSETEX rejects 0.
+            Some(ttl) => connection
+                .pset_ex::<_, _, ()>(key, value, ttl.as_millis().max(1) as u64)
+                .await
+                .map_err(redis_error("set")),
+            None => connection
+                .set::<_, _, ()>(key, value)
+                .await
+                .map_err(redis_error("set")),
+        }
+    }
+
+    async fn take(&self, key: &str) -> Result<Option<String>, StoreError> {
+        let mut connection = self.connection.clone();
+        connection.get_del(key).await.map_err(redis_error("getdel"))
+    }
+
+    async fn delete(&self, keys: &[String]) -> Result<u64, StoreError> {
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        let mut connection = self.connection.clone();
+        connection.del(keys).await.map_err(redis_error("del"))
+    }
+
+    async fn set_add(&self, key: &str, member: &str) -> Result<(), StoreError> {
+        let mut connection = self.connection.clone();
+        connection
+            .sadd(key, member)
+            .await
+            .map_err(redis_error("sadd"))
+    }
+
+    async fn set_remove(&self, key: &str, member: &str) -> Result<(), StoreError> {
+        let mut connection = self.connection.clone();
+        connection
+            .srem(key, member)
+            .await
+            .map_err(redis_error("srem"))
+    }
+
+    async fn set_members(&self, key: &str) -> Result<Vec<String>, StoreError> {
+        let mut connection = self.connection.clone();
+        connection
+            .smembers(key)
+            .await
+            .map_err(redis_error("smembers"))
+    }
+
+    async fn scan(&self, prefix: &str) -> Result<Vec<String>, StoreError> {
+        let mut connection = self.connection.clone();
+        let mut keys = Vec::new();
+        let mut iter = connection
+            .scan_match::<_, String>(format!("{}*", prefix))
+            .await
+            .map_err(redis_error("scan"))?;
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+        Ok(keys)
+    }
+
+    async fn hit(
+        &self,
+        key: &str,
+        window: Duration,
+        limit: u64,
+    ) -> Result<Option<Duration>, StoreError> {
+        let now = now_millis();
+        let member = format!("{}-{}", now, rand::random::<u64>());
+        let mut connection = self.connection.clone();
+        let wait_ms: u64 = self
+            .sliding_window
+            .key(key)
+            .arg(now)
+            .arg(window.as_millis() as u64)
+            .arg(limit)
+            .arg(member)
+            .invoke_async(&mut connection)
+            .await
+            .map_err(redis_error("sliding_window"))?;
+        Ok((wait_ms > 0).then(|| Duration::from_millis(wait_ms)))
+    }
+
+    async fn ping(&self) -> Result<(), StoreError> {
+        let mut connection = self.connection.clone();
+        redis::cmd("PING")
+            .query_async::<String>(&mut connection)
+            .await
+            .map_err(redis_error("ping"))?;
+        Ok(())
+    }
+}
@@ -11,9 +11,9 @@ use oauth2::basic::{
 };
 use oauth2::{
     helpers, AsyncHttpClient, AuthUrl, CodeTokenRequest, ConfigurationError, EmptyExtraTokenFields,
-    EndpointNotSet, EndpointState, ErrorResponse, PkceCodeVerifier, RevocableToken,
-    RevocationRequest, StandardErrorResponse, StandardRevocableToken, StandardTokenResponse,
-    TokenIntrospectionResponse,
+    EndpointMaybeSet, EndpointNotSet, EndpointSet, EndpointState, ErrorResponse, PkceCodeVerifier,
+    RevocableToken, RevocationRequest, StandardErrorResponse, StandardRevocableToken,
+    StandardTokenResponse, TokenIntrospectionResponse,
 };
 use openidconnect::core::{
     CoreAuthPrompt, CoreAuthenticationFlow, CoreClient, CoreErrorResponseType, CoreGenderClaim,
@@ -49,15 +49,14 @@ use openidconnect::{AdditionalProviderMetadata, ProviderMetadata, RevocationUrl,
 use serde::{Deserialize, Serialize};
 
 use std::env;
-use std::io::{BufRead, BufReader, Write};
-use std::net::TcpListener;
+use tauri::AppHandle;
 #[derive(Clone, Debug, Deserialize, Serialize)]
 
-struct RevocationEndpointProviderMetadata {
-    revocation_endpoint: String,
+pub struct RevocationEndpointProviderMetadata {
+    pub revocation_endpoint: String,
 }
 impl AdditionalProviderMetadata for RevocationEndpointProviderMetadata {}
-type GoogleProviderMetadata = ProviderMetadata<
+pub type GoogleProviderMetadata = ProviderMetadata<
     RevocationEndpointProviderMetadata,
     CoreAuthDisplay,
     CoreClientAuthMethod,
@@ -72,51 +71,76 @@ type GoogleProviderMetadata = ProviderMetadata<
     CoreSubjectIdentifierType,
 >;
 
+use crate::auth_handler::AppState;
+use crate::oidc_cache::is_unknown_key;
+use crate::token_vault::ProviderTokens;
 use crate::tp_auth::*;
 
-pub async fn google_auth() -> Result<(), anyhow::Error> {
-    //const redirect_url: &str = "http://localhost:8080";
-    //let google_additional_md = GoogleProviderMetadata::additional_metadata();
-    //let cm = CoreProviderMetadata::new(issuer, authorization_endpoint, jwks_uri, response_types_supported, subject_types_supported, id_token_signing_alg_values_supported, additional_metadata)
-
-    let http_client = reqwest::ClientBuilder::new()
-        .redirect(reqwest::redirect::Policy::none())
-        .build()
-        .unwrap_or_else(|err| {
-            handle_error(&err, "Failed to build HTTP client");
-            unreachable!();
-        });
-
-    let client_creds = create_client("GOOGLE").await?;
+type GoogleClient = CoreClient<
+    EndpointSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointSet,
+    EndpointMaybeSet,
+    EndpointMaybeSet,
+>;
 
-    let provider_metadata =
-        GoogleProviderMetadata::discover_async(client_creds.issuer_url, &http_client)
-            .await?
-            .set_scopes_supported(client_creds.scopes)
-            .set_claims_supported(client_creds.claims);
+async fn google_client(state: &AppState) -> Result<GoogleClient, anyhow::Error> {
+    let client_creds = create_client("GOOGLE", &state.settings).await?;
+    let provider_metadata = state
+        .oidc
+        .google
+        .get(&state.http, &client_creds.issuer_url)
+        .await?;
+    client_from_metadata(&provider_metadata, client_creds)
+}
 
+fn client_from_metadata(
+    provider_metadata: &GoogleProviderMetadata,
+    client_creds: ClientCredentials,
+) -> Result<GoogleClient, anyhow::Error> {
     let revocation_endpoint = provider_metadata
         .additional_metadata()
         .revocation_endpoint
         .clone();
 
-    let client = CoreClient::from_provider_metadata(
+    let provider_metadata = provider_metadata
+        .clone()
+        .set_scopes_supported(client_creds.scopes)
+        .set_claims_supported(client_creds.claims);
+
+    Ok(CoreClient::from_provider_metadata(
         provider_metadata,
         client_creds.client_id,
         Some(client_creds.client_secret),
     )
-    .set_redirect_uri(
-        RedirectUrl::new("http://localhost:8080".to_string()).unwrap_or_else(|err| {
-            handle_error(&err, "Invalid redirect URL");
-            unreachable!();
-        }),
+    .set_revocation_url(RevocationUrl::new(revocation_endpoint)?))
+}
+
+fn provider_tokens(token_response: &CoreTokenResponse) -> ProviderTokens {
+    ProviderTokens::new(
+        token_response.access_token().clone(),
+        token_response.refresh_token().cloned(),
+        token_response.expires_in(),
+        token_response
+            .scopes()
+            .map(|scopes| {
+                scopes
+                    .iter()
+                    .map(|scope| scope.as_str().to_owned())
+                    .collect()
+            })
+            .unwrap_or_default(),
     )
-    .set_revocation_url(
-        RevocationUrl::new(revocation_endpoint).unwrap_or_else(|err| {
-            handle_error(&err, "Invalid revocation endpoint URL");
-            unreachable!();
-        }),
-    );
+}
+
+pub async fn google_auth(app: &AppHandle, state: &AppState) -> Result<SignIn, anyhow::Error> {
+    // A fresh loopback port per attempt; Google accepts any port on 127.0.0.1 for desktop clients.
+    let loopback = bind_loopback().await?;
+
+    let client = google_client(state)
+        .await?
+        .set_redirect_uri(loopback.redirect_url.clone());
 
     let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
 
@@ -129,72 +153,93 @@ pub async fn google_auth() -> Result<(), anyhow::Error> {
         )
         // Set the PKCE code challenge.
         .set_pkce_challenge(pkce_challenge)
+        // Google only issues a refresh token for offline access, and only on the consent screen.
+        .add_extra_param("access_type", "offline")
+        .add_extra_param("prompt", "consent")
         .url();
 
-    // This is the URL you should redirect the user to, in order to trigger the authorization
-    // process.
-    println!("Browse to: {}", auth_url);
-
-    // Once the user has been redirected to the redirect URL, you'll have access to the
-    // authorization code. For security reasons, your code should verify that the `state`
-    // parameter returned by the server matches `csrf_state`.
-
-    // Now you can exchange it for an access token and ID token.
+    open_in_browser(app, &auth_url)?;
 
-    let (code, state) = auth_code("GOOGLE");
+    // The listener checks that the returned `state` matches `csrf_token` before handing back the code.
+    let code = loopback.wait_for_code("GOOGLE", &csrf_token).await?;
 
     let token_response = client
-        .exchange_code(code)
-        // This needs to be done before exchange_code returns the Result
-        .unwrap_or_else(|err| {
-            handle_error(&err, "No user info endpoint");
-            unreachable!();
-        })
+        .exchange_code(code)?
         .set_pkce_verifier(pkce_verifier)
-        .request_async(&http_client)
+        .request_async(&state.http)
         .await
-        .unwrap_or_else(|err| {
-            handle_error(&err, "Failed to contact token endpoint");
-            unreachable!();
-        });
+        .map_err(|err| anyhow!("Failed to contact token endpoint: {}", err))?;
 
-    let id_token_verifier: CoreIdTokenVerifier = client.id_token_verifier();
-    let id_token_claims: &CoreIdTokenClaims = token_response
+    let id_token = token_response
         .extra_fields()
         .id_token()
-        .expect("Server did not return an ID token")
-        .claims(&id_token_verifier, &nonce)
-        .unwrap_or_else(|err| {
-            handle_error(&err, "Failed to verify ID token");
-            unreachable!();
-        });
-
-    println!("{:?}", id_token_claims);
-
-    // Revoke the obtained token
-    let token_to_revoke: CoreRevocableToken = match token_response.refresh_token() {
-        Some(token) => token.into(),
-        None => token_response.access_token().into(),
-    };
+        .ok_or_else(|| anyhow!("Server did not return an ID token"))?;
+
+    if let Err(err) = id_token.claims(&client.id_token_verifier(), &nonce) {
+        if !is_unknown_key(&err) {
+            return Err(anyhow!("Failed to verify ID token: {}", err));
+        }
+        // Google rotated its signing keys since we cached them; refetch the set and try once more.
+        let provider_metadata = state.oidc.google.refresh_jwks(&state.http).await?;
+        let client = client_from_metadata(
+            &provider_metadata,
+            create_client("GOOGLE", &state.settings).await?,
+        )?;
+        id_token
+            .claims(&client.id_token_verifier(), &nonce)
+            .map_err(|err| anyhow!("Failed to verify ID token: {}", err))?;
+    }
 
     let userinfo: CoreUserInfoClaims = client
         .user_info(token_response.access_token().to_owned(), None)?
-        .request_async(&http_client)
+        .request_async(&state.http)
         .await
         .map_err(|err| anyhow!("Failed requesting user info: {}", err))?;
 
+    let sign_in = sign_in_or_sign_up(userinfo, "GOOGLE", state).await?;
+
+    state
+        .vault
+        .store(
+            &state.db,
+            "GOOGLE",
+            &sign_in.provider_user_id,
+            &provider_tokens(&token_response),
+        )
+        .await?;
+
+    Ok(sign_in)
+}
+
+pub async fn refresh_tokens(
+    state: &AppState,
+    refresh_token: &RefreshToken,
+) -> Result<ProviderTokens, anyhow::Error> {
+    let client = google_client(state).await?;
+
+    let token_response = client
+        .exchange_refresh_token(refresh_token)?
+        .request_async(&state.http)
+        .await
+        .map_err(|err| anyhow!("Failed to refresh token: {}", err))?;
+
+    Ok(provider_tokens(&token_response))
+}
+
+pub async fn revoke_tokens(state: &AppState, tokens: &ProviderTokens) -> Result<(), anyhow::Error> {
+    let client = google_client(state).await?;
+
+    // Revoking the refresh token also invalidates every access token issued from it.
+    let token_to_revoke: CoreRevocableToken = match &tokens.refresh_token {
+        Some(token) => token.into(),
+        None => (&tokens.access_token).into(),
+    };
+
     client
-        .revoke_token(token_to_revoke)
-        .unwrap_or_else(|err| {
-            handle_error(&err, "Failed to revoke token");
-            unreachable!();
-        })
-        .request_async(&http_client)
+        .revoke_token(token_to_revoke)?
+        .request_async(&state.http)
         .await
-        .unwrap_or_else(|err| {
-            handle_error(&err, "Failed to revoke token");
-            unreachable!();
-        });
+        .map_err(|err| anyhow!("Failed to revoke token: {}", err))?;
 
     Ok(())
 }
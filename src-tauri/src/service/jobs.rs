@@ -0,0 +1,330 @@
+// Periodic maintenance. Every instance runs the scheduler, and a Postgres advisory lock held for
+// the length of a run makes sure only one of them runs a given job at a time. Runs are recorded
+// in job_runs, which is unique per job and schedule tick, so an instance whose clock is a little
+// behind finds the tick taken instead of running it a second time.
+use chrono::{DateTime, Utc};
+use sqlx::types::time::OffsetDateTime;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{debug, error, info, warn};
+
+use crate::accounts;
+use crate::auth_handler::{AppState, Db};
+use crate::gateway;
+use crate::otp;
+use crate::presence;
+use crate::settings::JobsSettings;
+use crate::telemetry;
+use crate::token_vault;
+
+// First half of every job's advisory lock key, so they can't collide with other advisory locks
+// taken in the same database. The second half is hashtext(job name).
+const LOCK_NAMESPACE: i32 = 0x5759_5244;
+
+pub const RUNNING: &str = "running";
+pub const SUCCEEDED: &str = "succeeded";
+pub const FAILED: &str = "failed";
+
+type JobFuture<'a> = Pin<Box<dyn Future<Output = Result<String, anyhow::Error>> + Send + 'a>>;
+
+pub struct Job {
+    pub name: &'static str,
+    pub description: &'static str,
+    // Returns a summary of what was done, kept in the run's detail.
+    run: fn(&AppState) -> JobFuture<'_>,
+}
+
+pub const JOBS: &[Job] = &[
+    Job {
+        name: "prune_sessions",
+        description: "Forget sessions that have expired",
+        run: prune_sessions,
+    },
+    Job {
+        name: "purge_unverified",
+        description: "Remove password sign-ups left unverified past jobs.unverified_grace_hours",
+        run: purge_unverified,
+    },
+    Job {
+        name: "purge_deleted",
+        description: "Remove accounts deleted more than jobs.deletion_grace_days ago",
+        run: purge_deleted,
+    },
+    Job {
+        name: "refresh_tokens",
+        description: "Refresh stored provider tokens that are about to expire",
+        run: refresh_tokens,
+    },
+    Job {
+        name: "prune_job_runs",
+        description: "Forget job runs older than jobs.history_days",
+        run: prune_job_runs,
+    },
+    Job {
+        name: "sweep_presence",
+        description: "Mark users offline or away whose gateway devices stopped sending heartbeats",
+        run: sweep_presence,
+    },
+    Job {
+        name: "retry_emails",
+        description: "Send queued emails that failed to go out, and drop the ones that expired",
+        run: retry_emails,
+    },
+];
+
+pub fn find(name: &str) -> Option<&'static Job> {
+    JOBS.iter().find(|job| job.name == name)
+}
+
+/// When `job` is due next, `None` if it has no schedule.
+pub fn next_run(settings: &JobsSettings, job: &Job) -> Option<DateTime<Utc>> {
+    settings.schedule(job.name)?.upcoming(Utc).next()
+}
+
+#[derive(Debug, Clone)]
+pub struct JobRun {
+    pub id: i64,
+    pub job: String,
+    pub scheduled_for: Option<OffsetDateTime>,
+    pub started_at: OffsetDateTime,
+    pub finished_at: Option<OffsetDateTime>,
+    /// One of `RUNNING`, `SUCCEEDED` or `FAILED`.
+    pub status: String,
+    pub duration_ms: Option<i64>,
+    pub detail: Option<String>,
+    pub instance: String,
+}
+
+/// Starts a loop for every job with a schedule. A tick that comes while the previous run is
+/// still going is skipped rather than queued.
+pub fn spawn_scheduler(state: Arc<AppState>) {
+    if !state.settings.jobs.enabled {
+        info!("Job scheduler disabled");
+        return;
+    }
+    for job in JOBS {
+        let Some(schedule) = state.settings.jobs.schedule(job.name) else {
+            continue;
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            while let Some(tick) = schedule.upcoming(Utc).next() {
+                let wait = (tick - Utc::now()).to_std().unwrap_or_default();
+                tokio::time::sleep(wait).await;
+                let Ok(scheduled_for) = OffsetDateTime::from_unix_timestamp(tick.timestamp())
+                else {
+                    continue;
+                };
+
+                // Its own task, so a panicking job doesn't take the schedule down with it.
+                let state = state.clone();
+                match tokio::spawn(async move { run(&state, job, Some(scheduled_for)).await }).await
+                {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(err)) => error!("Job {} could not run: {:?}", job.name, err),
+                    Err(err) => error!("Job {} panicked: {:?}", job.name, err),
+                }
+            }
+        });
+    }
+}
+
+/// Runs `job` and records the run, unless another instance is running it or already ran this
+/// tick, in which case `None` comes back. `scheduled_for` is the schedule tick, `None` for runs
+/// started by hand.
+pub async fn run(
+    state: &AppState,
+    job: &Job,
+    scheduled_for: Option<OffsetDateTime>,
+) -> Result<Option<JobRun>, anyhow::Error> {
+    // Transaction scoped, so the lock goes away with the connection's transaction however this
+    // ends, even when the task is dropped halfway through.
+    let mut lock = state.db.begin().await?;
+    let locked = sqlx::query_scalar!(
+        "SELECT pg_try_advisory_xact_lock($1, hashtext($2))",
+        LOCK_NAMESPACE,
+        job.name,
+    )
+    .fetch_one(&mut *lock)
+    .await?
+    .unwrap_or(false);
+    if !locked {
+        debug!("Job {} is running elsewhere", job.name);
+        return Ok(None);
+    }
+
+    // Nobody else can be running it now, so runs still marked running died with their process.
+    sqlx::query!(
+        "UPDATE job_runs SET status = 'failed', finished_at = now(), detail = 'interrupted'
+         WHERE job = $1 AND status = 'running'",
+        job.name,
+    )
+    .execute(&state.db)
+    .await?;
+
+    let Some(id) = sqlx::query_scalar!(
+        "INSERT INTO job_runs (job, scheduled_for, instance) VALUES ($1, $2, $3)
+         ON CONFLICT (job, scheduled_for) DO NOTHING
+         RETURNING id",
+        job.name,
+        scheduled_for,
+        instance(),
+    )
+    .fetch_optional(&state.db)
+    .await?
+    else {
+        debug!("Job {} already ran for this tick", job.name);
+        return Ok(None);
+    };
+
+    let started = Instant::now();
+    let result = (job.run)(state).await;
+    let duration = started.elapsed();
+
+    let (status, detail) = match result {
+        Ok(summary) => {
+            info!("Job {} finished in {:?}: {}", job.name, duration, summary);
+            (SUCCEEDED, summary)
+        }
+        Err(err) => {
+            warn!("Job {} failed after {:?}: {:?}", job.name, duration, err);
+            (FAILED, format!("{:#}", err))
+        }
+    };
+    telemetry::record_job(job.name, status, duration);
+
+    let run = sqlx::query_as!(
+        JobRun,
+        "UPDATE job_runs SET status = $1, finished_at = now(), duration_ms = $2, detail = $3
+         WHERE id = $4
+         RETURNING id, job, scheduled_for, started_at, finished_at, status, duration_ms, detail,
+                   instance",
+        status,
+        duration.as_millis() as i64,
+        detail,
+        id,
+    )
+    .fetch_one(&state.db)
+    .await?;
+    lock.commit().await?;
+    Ok(Some(run))
+}
+
+/// Latest runs first, optionally only those of one job or with one status.
+pub async fn history(
+    db: &Db,
+    job: Option<&str>,
+    status: Option<&str>,
+    limit: i64,
+) -> Result<Vec<JobRun>, sqlx::Error> {
+    sqlx::query_as!(
+        JobRun,
+        "SELECT id, job, scheduled_for, started_at, finished_at, status, duration_ms, detail,
+                instance
+         FROM job_runs
+         WHERE ($1::text IS NULL OR job = $1) AND ($2::text IS NULL OR status = $2)
+         ORDER BY started_at DESC, id DESC
+         LIMIT $3",
+        job,
+        status,
+        limit,
+    )
+    .fetch_all(db)
+    .await
+}
+
+fn instance() -> String {
+    let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "localhost".to_string());
+    format!("{}:{}", host, std::process::id())
+}
+
+fn prune_sessions(state: &AppState) -> JobFuture<'_> {
+    Box::pin(async move {
+        let pruned = state.sessions.prune_expired().await?;
+        Ok(format!("{} expired sessions pruned", pruned))
+    })
+}
+
+fn purge_unverified(state: &AppState) -> JobFuture<'_> {
+    Box::pin(async move {
+        let purged = accounts::purge_unverified(
+            state.users.as_ref(),
+            state.sessions.as_ref(),
+            state.settings.jobs.unverified_grace(),
+        )
+        .await?;
+        Ok(format!("{} unverified sign-ups removed", purged))
+    })
+}
+
+fn purge_deleted(state: &AppState) -> JobFuture<'_> {
+    Box::pin(async move {
+        let purged = accounts::purge_deleted(
+            state.users.as_ref(),
+            state.sessions.as_ref(),
+            Some(state.settings.jobs.deletion_grace()),
+            false,
+        )
+        .await?;
+        Ok(format!("{} deleted accounts purged", purged.len()))
+    })
+}
+
+fn refresh_tokens(state: &AppState) -> JobFuture<'_> {
+    Box::pin(async move {
+        let counts = token_vault::refresh_due_tokens(state).await?;
+        Ok(format!(
+            "{} provider tokens refreshed, {} failed",
+            counts.refreshed, counts.failed
+        ))
+    })
+}
+
+fn prune_job_runs(state: &AppState) -> JobFuture<'_> {
+    Box::pin(async move {
+        let pruned = sqlx::query!(
+            "DELETE FROM job_runs
+             WHERE status <> 'running' AND started_at < now() - make_interval(secs => $1)",
+            state.settings.jobs.history().as_secs_f64(),
+        )
+        .execute(&state.db)
+        .await?
+        .rows_affected();
+        Ok(format!("{} job runs forgotten", pruned))
+    })
+}
+
+// Gateways catch the usual cases themselves, this is for connections that died with a server.
+// Changes go out through this instance's gateway; contacts connected elsewhere, or to an instance
+// without one, see them when they next load presence.
+fn sweep_presence(state: &AppState) -> JobFuture<'_> {
+    Box::pin(async move {
+        let changed = presence::sweep(
+            state.users.as_ref(),
+            state.presence.as_ref(),
+            &state.settings.presence,
+        )
+        .await?;
+        if let Some(io) = state.gateway.get() {
+            presence::announce(
+                state.conversations.as_ref(),
+                &changed,
+                |recipients, update| gateway::publish_presence(io, recipients, update),
+            )
+            .await;
+        }
+        Ok(format!("{} users' presence updated", changed.len()))
+    })
+}
+
+fn retry_emails(state: &AppState) -> JobFuture<'_> {
+    Box::pin(async move {
+        let counts = otp::retry_emails(state.outbox.as_ref(), otp::deliver).await?;
+        Ok(format!(
+            "{} queued emails sent, {} failed, {} expired",
+            counts.sent, counts.failed, counts.expired
+        ))
+    })
+}
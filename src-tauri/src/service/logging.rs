@@ -0,0 +1,93 @@
+use axum::http::Request;
+use std::fmt;
+use std::path::Path;
+use tower_http::request_id::RequestId;
+use tracing::Span;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::{fmt as subscriber_fmt, layer::SubscriberExt, util::SubscriberInitExt};
+
+use crate::settings::LoggingSettings;
+
+const LOG_FILE_PREFIX: &str = "wyrd";
+const LOG_FILE_SUFFIX: &str = "log";
+
+/// Installs the global subscriber: JSON lines to a file under `directory` that rotates daily,
+/// plus human readable output on stdout when `logging.stdout` is set. Also picks up `log`
+/// records from dependencies.
+///
+/// Buffered lines are only guaranteed to reach the file while the returned guard is alive.
+pub fn init(settings: &LoggingSettings, directory: &Path) -> Result<WorkerGuard, anyhow::Error> {
+    let directory = settings.directory.as_deref().unwrap_or(directory);
+    std::fs::create_dir_all(directory)?;
+
+    let appender = RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix(LOG_FILE_PREFIX)
+        .filename_suffix(LOG_FILE_SUFFIX)
+        .max_log_files(settings.max_files)
+        .build(directory)?;
+    let (writer, guard) = tracing_appender::non_blocking(appender);
+
+    let file = subscriber_fmt::layer()
+        .json()
+        .with_current_span(true)
+        .with_span_list(false)
+        .with_writer(writer);
+
+    let stdout = settings
+        .stdout
+        .then(|| subscriber_fmt::layer().with_target(false));
+
+    tracing_subscriber::registry()
+        .with(EnvFilter::try_new(&settings.level)?)
+        .with(file)
+        .with(stdout)
+        .try_init()?;
+
+    Ok(guard)
+}
+
+/// Span wrapping every API request. Only the path is recorded, query strings can carry codes.
+pub fn request_span<B>(request: &Request<B>) -> Span {
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .unwrap_or_default();
+
+    tracing::info_span!(
+        "request",
+        method = %request.method(),
+        path = %request.uri().path(),
+        request_id = %request_id,
+    )
+}
+
+/// Keeps the first character and the domain, e.g. `j***@example.com`, which is enough to tell
+/// accounts apart in a log without storing the address.
+pub fn redact_email(email: &str) -> String {
+    match email.split_once('@') {
+        Some((local, domain)) => match local.chars().next() {
+            Some(first) => format!("{}***@{}", first, domain),
+            None => format!("***@{}", domain),
+        },
+        None => "***".to_string(),
+    }
+}
+
+/// Wraps a value that must never end up in a log line: tokens, codes, passwords.
+pub struct Redacted<T>(pub T);
+
+impl<T> fmt::Debug for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+impl<T> fmt::Display for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
@@ -0,0 +1,1066 @@
+// In-memory stores with the same behaviour as the Postgres and Redis ones, for tests and for
+// running the auth flows without either service.
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::ephemeral::EphemeralStore;
+use crate::repository::{
+    invite_code, session_token, verification_key, Conversation, ConversationStore, EmailOutbox,
+    GroupDetails, GroupEvent, HistoryCursor, Invite, InviteUse, Member, Message, NewEmail,
+    NewInvite, NewUser, PendingVerification, Presence, QueuedEmail, SessionStore, StoreError, User,
+    UserRepository, UserSearch, VerificationStore, ACTIVE, ADMIN, DELETED, DIRECT, GROUP, MEMBER,
+    OFFLINE, OWNER, SYSTEM, TEXT,
+};
+
+#[derive(Default)]
+pub struct MemoryUserRepository {
+    users: Mutex<Vec<MemoryUser>>,
+    // Ids are never reused, even after a purge.
+    last_id: AtomicI64,
+}
+
+struct MemoryUser {
+    user: User,
+    created: Instant,
+    last_login: Option<Instant>,
+    deleted: Option<Instant>,
+    activity: String,
+    last_seen: Option<DateTime<Utc>>,
+}
+
+impl MemoryUser {
+    fn presence(&self) -> Presence {
+        Presence {
+            user_id: self.user.id,
+            activity: self.activity.clone(),
+            last_seen: self.last_seen,
+        }
+    }
+}
+
+impl MemoryUserRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// When `id` last logged in, `None` if never.
+    pub fn last_login(&self, id: i64) -> Option<Instant> {
+        self.users
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|entry| entry.user.id == id)
+            .and_then(|entry| entry.last_login)
+    }
+
+    /// Links a provider identity to `id`, like a social sign-up would.
+    pub fn link_provider(&self, id: i64, provider: &str, provider_user_id: &str) {
+        self.update(id, |user| {
+            user.provider = Some(provider.to_string());
+            user.provider_user_id = Some(provider_user_id.to_string());
+        });
+    }
+
+    fn update(&self, id: i64, change: impl FnOnce(&mut User)) {
+        let mut users = self.users.lock().unwrap();
+        if let Some(entry) = users.iter_mut().find(|entry| entry.user.id == id) {
+            change(&mut entry.user);
+        }
+    }
+
+    fn find(&self, matches: impl Fn(&User) -> bool) -> Option<User> {
+        self.users
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|entry| matches(&entry.user))
+            .map(|entry| entry.user.clone())
+    }
+}
+
+#[async_trait]
+impl UserRepository for MemoryUserRepository {
+    async fn find_by_id(&self, id: i64) -> Result<Option<User>, StoreError> {
+        Ok(self.find(|user| user.id == id))
+    }
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<User>, StoreError> {
+        Ok(self.find(|user| user.username.eq_ignore_ascii_case(username)))
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>, StoreError> {
+        Ok(self.find(|user| user.email.eq_ignore_ascii_case(email)))
+    }
+
+    async fn create(&self, new: &NewUser) -> Result<i64, StoreError> {
+        let mut users = self.users.lock().unwrap();
+        for entry in users.iter() {
+            if entry.user.username.eq_ignore_ascii_case(&new.username) {
+                return Err(StoreError::Conflict("username"));
+            }
+            if entry.user.email.eq_ignore_ascii_case(&new.email) {
+                return Err(StoreError::Conflict("email"));
+            }
+        }
+
+        let id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+        users.push(MemoryUser {
+            user: User {
+                id,
+                name: new.name.clone(),
+                username: new.username.clone(),
+                email: new.email.clone(),
+                password: new.password.clone(),
+                status: ACTIVE.to_string(),
+                user_verified: new.user_verified,
+                totp_secret: new.totp_secret.clone(),
+                provider: None,
+                provider_user_id: None,
+                password_reset_required: false,
+            },
+            created: Instant::now(),
+            last_login: None,
+            deleted: None,
+            activity: OFFLINE.to_string(),
+            last_seen: None,
+        });
+        Ok(id)
+    }
+
+    async fn set_username(&self, id: i64, username: &str) -> Result<(), StoreError> {
+        let mut users = self.users.lock().unwrap();
+        if users
+            .iter()
+            .any(|entry| entry.user.id != id && entry.user.username.eq_ignore_ascii_case(username))
+        {
+            return Err(StoreError::Conflict("username"));
+        }
+        if let Some(entry) = users.iter_mut().find(|entry| entry.user.id == id) {
+            entry.user.username = username.to_string();
+        }
+        Ok(())
+    }
+
+    async fn record_login(&self, id: i64) -> Result<(), StoreError> {
+        let mut users = self.users.lock().unwrap();
+        if let Some(entry) = users.iter_mut().find(|entry| entry.user.id == id) {
+            entry.last_login = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    async fn mark_verified(&self, email: &str) -> Result<(), StoreError> {
+        let mut users = self.users.lock().unwrap();
+        if let Some(entry) = users
+            .iter_mut()
+            .find(|entry| entry.user.email.eq_ignore_ascii_case(email))
+        {
+            entry.user.user_verified = true;
+        }
+        Ok(())
+    }
+
+    async fn search(&self, search: &UserSearch) -> Result<Vec<User>, StoreError> {
+        let text = search.text.as_deref().map(str::to_lowercase);
+        Ok(self
+            .users
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|entry| match search.deleted_for {
+                Some(deleted_for) => entry
+                    .deleted
+                    .is_some_and(|deleted| deleted.elapsed() > deleted_for),
+                None => true,
+            })
+            .map(|entry| &entry.user)
+            .filter(|user| match &text {
+                Some(text) => [&user.name, &user.username, &user.email]
+                    .iter()
+                    .any(|field| field.to_lowercase().contains(text.as_str())),
+                None => true,
+            })
+            .filter(|user| {
+                search
+                    .status
+                    .as_ref()
+                    .map_or(true, |status| &user.status == status)
+            })
+            .skip(search.offset.max(0) as usize)
+            .take(search.limit.max(0) as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn set_status(&self, id: i64, status: &str) -> Result<(), StoreError> {
+        let mut users = self.users.lock().unwrap();
+        if let Some(entry) = users.iter_mut().find(|entry| entry.user.id == id) {
+            entry.user.status = status.to_string();
+            entry.deleted = match status {
+                DELETED => entry.deleted.or(Some(Instant::now())),
+                _ => None,
+            };
+        }
+        Ok(())
+    }
+
+    async fn set_password_reset_required(&self, id: i64, required: bool) -> Result<(), StoreError> {
+        self.update(id, |user| user.password_reset_required = required);
+        Ok(())
+    }
+
+    async fn delete(&self, id: i64) -> Result<(), StoreError> {
+        self.users
+            .lock()
+            .unwrap()
+            .retain(|entry| entry.user.id != id);
+        Ok(())
+    }
+
+    async fn delete_unverified(&self, older_than: Duration) -> Result<Vec<i64>, StoreError> {
+        let mut users = self.users.lock().unwrap();
+        let mut deleted = Vec::new();
+        users.retain(|entry| {
+            let expired = !entry.user.user_verified
+                && entry.user.password.is_some()
+                && entry.user.status == ACTIVE
+                && entry.created.elapsed() > older_than;
+            if expired {
+                deleted.push(entry.user.id);
+            }
+            !expired
+        });
+        Ok(deleted)
+    }
+
+    async fn set_activity(&self, id: i64, activity: &str) -> Result<Option<Presence>, StoreError> {
+        let mut users = self.users.lock().unwrap();
+        let Some(entry) = users.iter_mut().find(|entry| entry.user.id == id) else {
+            return Ok(None);
+        };
+        if entry.activity == activity {
+            return Ok(None);
+        }
+        entry.activity = activity.to_string();
+        if activity == OFFLINE {
+            entry.last_seen = Some(Utc::now());
+        }
+        Ok(Some(entry.presence()))
+    }
+
+    async fn presence(&self, ids: &[i64]) -> Result<Vec<Presence>, StoreError> {
+        let mut presence: Vec<Presence> = self
+            .users
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|entry| ids.contains(&entry.user.id))
+            .map(MemoryUser::presence)
+            .collect();
+        presence.sort_unstable_by_key(|presence| presence.user_id);
+        Ok(presence)
+    }
+
+    async fn present_ids(&self) -> Result<Vec<i64>, StoreError> {
+        let mut ids: Vec<i64> = self
+            .users
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|entry| entry.activity != OFFLINE)
+            .map(|entry| entry.user.id)
+            .collect();
+        ids.sort_unstable();
+        Ok(ids)
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryVerificationStore {
+    // By `verification_key`, each with its code hash and when that expires.
+    pending: Mutex<HashMap<String, (PendingVerification, Option<(String, Instant)>)>>,
+    // Names by `verification_key`, kept after `finish`.
+    names: Mutex<HashMap<String, String>>,
+}
+
+impl MemoryVerificationStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl VerificationStore for MemoryVerificationStore {
+    async fn start(
+        &self,
+        pending: &PendingVerification,
+        code_hash: &str,
+        expiry: Duration,
+    ) -> Result<(), StoreError> {
+        let code = (code_hash.to_string(), Instant::now() + expiry);
+        self.pending.lock().unwrap().insert(
+            verification_key(&pending.email),
+            (pending.clone(), Some(code)),
+        );
+        self.names
+            .lock()
+            .unwrap()
+            .insert(verification_key(&pending.email), pending.name.clone());
+        Ok(())
+    }
+
+    async fn pending(&self, email: &str) -> Result<Option<PendingVerification>, StoreError> {
+        Ok(self
+            .pending
+            .lock()
+            .unwrap()
+            .get(&verification_key(email))
+            .map(|(pending, _)| pending.clone()))
+    }
+
+    async fn code_hash(&self, email: &str) -> Result<Option<String>, StoreError> {
+        Ok(self
+            .pending
+            .lock()
+            .unwrap()
+            .get(&verification_key(email))
+            .and_then(|(_, code)| code.as_ref())
+            .filter(|(_, expires)| Instant::now() < *expires)
+            .map(|(hash, _)| hash.clone()))
+    }
+
+    async fn finish(&self, email: &str) -> Result<(), StoreError> {
+        self.pending
+            .lock()
+            .unwrap()
+            .remove(&verification_key(email));
+        Ok(())
+    }
+
+    async fn signup_name(&self, email: &str) -> Result<Option<String>, StoreError> {
+        Ok(self
+            .names
+            .lock()
+            .unwrap()
+            .get(&verification_key(email))
+            .cloned())
+    }
+}
+
+#[derive(Default)]
+pub struct MemorySessionStore {
+    sessions: Mutex<HashMap<String, (i64, Instant)>>,
+}
+
+impl MemorySessionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl SessionStore for MemorySessionStore {
+    async fn create(&self, user_id: i64, ttl: Duration) -> Result<String, StoreError> {
+        let token = session_token();
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(token.clone(), (user_id, Instant::now() + ttl));
+        Ok(token)
+    }
+
+    async fn user_id(&self, token: &str) -> Result<Option<i64>, StoreError> {
+        Ok(self
+            .sessions
+            .lock()
+            .unwrap()
+            .get(token)
+            .filter(|(_, expires)| Instant::now() < *expires)
+            .map(|(user_id, _)| *user_id))
+    }
+
+    async fn revoke(&self, token: &str) -> Result<(), StoreError> {
+        self.sessions.lock().unwrap().remove(token);
+        Ok(())
+    }
+
+    async fn revoke_all(&self, user_id: i64) -> Result<u64, StoreError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        sessions.retain(|_, (owner, expires)| *owner != user_id || Instant::now() >= *expires);
+        Ok((before - sessions.len()) as u64)
+    }
+
+    async fn prune_expired(&self) -> Result<u64, StoreError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        sessions.retain(|_, (_, expires)| Instant::now() < *expires);
+        Ok((before - sessions.len()) as u64)
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryConversationStore {
+    conversations: Mutex<Vec<MemoryConversation>>,
+    last_conversation_id: AtomicI64,
+    last_message_id: AtomicI64,
+    last_invite_id: AtomicI64,
+}
+
+struct MemoryConversation {
+    conversation: Conversation,
+    // Lower id first, like the direct_low and direct_high columns.
+    direct: Option<(i64, i64)>,
+    // In the order they joined.
+    members: Vec<Member>,
+    messages: Vec<Message>,
+    // Oldest first, each with its uses, latest first.
+    invites: Vec<(Invite, Vec<InviteUse>)>,
+}
+
+impl MemoryConversation {
+    fn join(&mut self, user_id: i64, role: &str) {
+        if !self.members.iter().any(|member| member.user_id == user_id) {
+            self.members.push(Member {
+                user_id,
+                role: role.to_string(),
+                joined_at: Utc::now(),
+            });
+        }
+    }
+
+    fn set_role(&mut self, user_id: i64, role: &str) {
+        if let Some(member) = self
+            .members
+            .iter_mut()
+            .find(|member| member.user_id == user_id)
+        {
+            member.role = role.to_string();
+        }
+    }
+
+    fn push_message(
+        &mut self,
+        id: i64,
+        sender_id: i64,
+        body: &str,
+        event: Option<&GroupEvent>,
+    ) -> Message {
+        self.conversation.last_seq += 1;
+        let message = Message {
+            id,
+            conversation_id: self.conversation.id,
+            seq: self.conversation.last_seq,
+            sender_id: Some(sender_id),
+            kind: if event.is_some() { SYSTEM } else { TEXT }.to_string(),
+            body: body.to_string(),
+            event: event.map(|event| serde_json::to_value(event).unwrap()),
+            created_at: Utc::now(),
+            edited_at: None,
+        };
+        self.messages.push(message.clone());
+        message
+    }
+}
+
+impl MemoryConversationStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn next_conversation(&self, kind: &str) -> Conversation {
+        Conversation {
+            id: self.last_conversation_id.fetch_add(1, Ordering::Relaxed) + 1,
+            kind: kind.to_string(),
+            name: None,
+            topic: None,
+            avatar_url: None,
+            last_seq: 0,
+            created_at: Utc::now(),
+        }
+    }
+
+    fn next_message_id(&self) -> i64 {
+        self.last_message_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    // Like a missing row in Postgres.
+    fn with<T>(
+        &self,
+        id: i64,
+        change: impl FnOnce(&mut MemoryConversation) -> T,
+    ) -> Result<T, StoreError> {
+        let mut conversations = self.conversations.lock().unwrap();
+        conversations
+            .iter_mut()
+            .find(|entry| entry.conversation.id == id)
+            .map(change)
+            .ok_or(StoreError::Database(sqlx::Error::RowNotFound))
+    }
+}
+
+#[async_trait]
+impl ConversationStore for MemoryConversationStore {
+    async fn open_direct(&self, user_id: i64, other_id: i64) -> Result<Conversation, StoreError> {
+        let pair = (user_id.min(other_id), user_id.max(other_id));
+        let mut conversations = self.conversations.lock().unwrap();
+        if let Some(entry) = conversations
+            .iter()
+            .find(|entry| entry.direct == Some(pair))
+        {
+            return Ok(entry.conversation.clone());
+        }
+
+        let mut entry = MemoryConversation {
+            conversation: self.next_conversation(DIRECT),
+            direct: Some(pair),
+            members: Vec::new(),
+            messages: Vec::new(),
+            invites: Vec::new(),
+        };
+        entry.join(pair.0, MEMBER);
+        entry.join(pair.1, MEMBER);
+        let conversation = entry.conversation.clone();
+        conversations.push(entry);
+        Ok(conversation)
+    }
+
+    async fn create_group(
+        &self,
+        owner_id: i64,
+        details: &GroupDetails,
+        member_ids: &[i64],
+    ) -> Result<(Conversation, Message), StoreError> {
+        let mut conversation = self.next_conversation(GROUP);
+        conversation.name = Some(details.name.clone());
+        conversation.topic = details.topic.clone();
+        conversation.avatar_url = details.avatar_url.clone();
+        let mut entry = MemoryConversation {
+            conversation,
+            direct: None,
+            members: Vec::new(),
+            messages: Vec::new(),
+            invites: Vec::new(),
+        };
+        entry.join(owner_id, OWNER);
+        for member_id in member_ids {
+            entry.join(*member_id, MEMBER);
+        }
+
+        let event = GroupEvent::Created {
+            name: details.name.clone(),
+        };
+        let message = entry.push_message(
+            self.next_message_id(),
+            owner_id,
+            &event.summary(),
+            Some(&event),
+        );
+        let conversation = entry.conversation.clone();
+        self.conversations.lock().unwrap().push(entry);
+        Ok((conversation, message))
+    }
+
+    async fn find(&self, id: i64) -> Result<Option<Conversation>, StoreError> {
+        Ok(self
+            .conversations
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|entry| entry.conversation.id == id)
+            .map(|entry| entry.conversation.clone()))
+    }
+
+    async fn delete(&self, id: i64) -> Result<(), StoreError> {
+        self.conversations
+            .lock()
+            .unwrap()
+            .retain(|entry| entry.conversation.id != id);
+        Ok(())
+    }
+
+    async fn member_ids(&self, conversation_id: i64) -> Result<Vec<i64>, StoreError> {
+        let mut ids: Vec<i64> = self
+            .members(conversation_id)
+            .await?
+            .iter()
+            .map(|member| member.user_id)
+            .collect();
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    async fn members(&self, conversation_id: i64) -> Result<Vec<Member>, StoreError> {
+        Ok(self
+            .conversations
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|entry| entry.conversation.id == conversation_id)
+            .map(|entry| entry.members.clone())
+            .unwrap_or_default())
+    }
+
+    async fn contact_ids(&self, user_id: i64) -> Result<Vec<i64>, StoreError> {
+        let mut ids: Vec<i64> = self
+            .conversations
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|entry| entry.members.iter().any(|member| member.user_id == user_id))
+            .flat_map(|entry| entry.members.iter().map(|member| member.user_id))
+            .filter(|&id| id != user_id)
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    async fn apply(
+        &self,
+        conversation_id: i64,
+        actor_id: i64,
+        event: &GroupEvent,
+    ) -> Result<Message, StoreError> {
+        let id = self.next_message_id();
+        self.with(conversation_id, |entry| {
+            match event {
+                GroupEvent::Created { .. } | GroupEvent::MemberJoined { .. } => {}
+                GroupEvent::Updated {
+                    name,
+                    topic,
+                    avatar_url,
+                } => {
+                    entry.conversation.name = Some(name.clone());
+                    entry.conversation.topic = topic.clone();
+                    entry.conversation.avatar_url = avatar_url.clone();
+                }
+                GroupEvent::MembersAdded { user_ids } => {
+                    for user_id in user_ids {
+                        entry.join(*user_id, MEMBER);
+                    }
+                }
+                GroupEvent::MemberRemoved { user_id } | GroupEvent::MemberLeft { user_id } => {
+                    entry.members.retain(|member| member.user_id != *user_id);
+                }
+                GroupEvent::RoleChanged { user_id, role } => entry.set_role(*user_id, role),
+                GroupEvent::OwnershipTransferred { from, to } => {
+                    entry.set_role(*from, ADMIN);
+                    entry.set_role(*to, OWNER);
+                }
+            }
+            entry.push_message(id, actor_id, &event.summary(), Some(event))
+        })
+    }
+
+    async fn post(
+        &self,
+        conversation_id: i64,
+        sender_id: i64,
+        body: &str,
+    ) -> Result<Message, StoreError> {
+        let id = self.next_message_id();
+        self.with(conversation_id, |entry| {
+            entry.push_message(id, sender_id, body, None)
+        })
+    }
+
+    async fn history(
+        &self,
+        conversation_id: i64,
+        cursor: HistoryCursor,
+        limit: i64,
+    ) -> Result<Vec<Message>, StoreError> {
+        let conversations = self.conversations.lock().unwrap();
+        let Some(entry) = conversations
+            .iter()
+            .find(|entry| entry.conversation.id == conversation_id)
+        else {
+            return Ok(Vec::new());
+        };
+        let limit = limit.max(0) as usize;
+        // Messages are kept in sequence order.
+        let messages = &entry.messages;
+        Ok(match cursor {
+            HistoryCursor::Latest => messages[messages.len().saturating_sub(limit)..].to_vec(),
+            HistoryCursor::Before(seq) => {
+                let end = messages.partition_point(|message| message.seq < seq);
+                messages[end.saturating_sub(limit)..end].to_vec()
+            }
+            HistoryCursor::After(seq) => messages
+                .iter()
+                .filter(|message| message.seq > seq)
+                .take(limit)
+                .cloned()
+                .collect(),
+        })
+    }
+    async fn create_invite(
+        &self,
+        conversation_id: i64,
+        invite: &NewInvite,
+    ) -> Result<Invite, StoreError> {
+        let invite = Invite {
+            id: self.last_invite_id.fetch_add(1, Ordering::Relaxed) + 1,
+            conversation_id,
+            code: invite_code(),
+            created_by: Some(invite.created_by),
+            role: invite.role.clone(),
+            max_uses: invite.max_uses,
+            uses: 0,
+            expires_at: invite.expires_at,
+            revoked_at: None,
+            created_at: Utc::now(),
+        };
+        self.with(conversation_id, |entry| {
+            entry.invites.push((invite.clone(), Vec::new()));
+            invite
+        })
+    }
+
+    async fn invites(&self, conversation_id: i64) -> Result<Vec<Invite>, StoreError> {
+        Ok(self
+            .with(conversation_id, |entry| {
+                entry
+                    .invites
+                    .iter()
+                    .rev()
+                    .map(|(invite, _)| invite.clone())
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn find_invite(&self, code: &str) -> Result<Option<Invite>, StoreError> {
+        Ok(self
+            .conversations
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|entry| &entry.invites)
+            .find(|(invite, _)| invite.code == code)
+            .map(|(invite, _)| invite.clone()))
+    }
+
+    async fn revoke_invite(&self, invite_id: i64) -> Result<(), StoreError> {
+        let mut conversations = self.conversations.lock().unwrap();
+        if let Some((invite, _)) = conversations
+            .iter_mut()
+            .flat_map(|entry| &mut entry.invites)
+            .find(|(invite, _)| invite.id == invite_id)
+        {
+            invite.revoked_at.get_or_insert_with(Utc::now);
+        }
+        Ok(())
+    }
+
+    async fn invite_uses(&self, invite_id: i64) -> Result<Vec<InviteUse>, StoreError> {
+        Ok(self
+            .conversations
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|entry| &entry.invites)
+            .find(|(invite, _)| invite.id == invite_id)
+            .map(|(_, uses)| uses.clone())
+            .unwrap_or_default())
+    }
+
+    async fn redeem_invite(
+        &self,
+        invite_id: i64,
+        user_id: i64,
+    ) -> Result<Option<Message>, StoreError> {
+        let id = self.next_message_id();
+        let mut conversations = self.conversations.lock().unwrap();
+        let Some(entry) = conversations.iter_mut().find(|entry| {
+            entry
+                .invites
+                .iter()
+                .any(|(invite, _)| invite.id == invite_id)
+        }) else {
+            return Ok(None);
+        };
+        let member = entry.members.iter().any(|member| member.user_id == user_id);
+        let (invite, uses) = entry
+            .invites
+            .iter_mut()
+            .find(|(invite, _)| invite.id == invite_id)
+            .unwrap();
+        if member || !invite.usable(Utc::now()) {
+            return Ok(None);
+        }
+        invite.uses += 1;
+        uses.insert(
+            0,
+            InviteUse {
+                user_id: Some(user_id),
+                used_at: Utc::now(),
+            },
+        );
+        let role = invite.role.clone();
+        entry.join(user_id, &role);
+
+        let event = GroupEvent::MemberJoined { user_id, invite_id };
+        Ok(Some(entry.push_message(
+            id,
+            user_id,
+            &event.summary(),
+            Some(&event),
+        )))
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryEmailOutbox {
+    // By id, each with when it is due next and when it expires.
+    emails: Mutex<Vec<(QueuedEmail, DateTime<Utc>, DateTime<Utc>)>>,
+    last_id: AtomicI64,
+}
+
+impl MemoryEmailOutbox {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl EmailOutbox for MemoryEmailOutbox {
+    async fn enqueue(
+        &self,
+        email: &NewEmail,
+        expires_at: DateTime<Utc>,
+    ) -> Result<QueuedEmail, StoreError> {
+        let queued = QueuedEmail {
+            id: self.last_id.fetch_add(1, Ordering::Relaxed) + 1,
+            recipient: email.recipient.clone(),
+            subject: email.subject.clone(),
+            body: email.body.clone(),
+            attempts: 0,
+        };
+        self.emails
+            .lock()
+            .unwrap()
+            .push((queued.clone(), Utc::now(), expires_at));
+        Ok(queued)
+    }
+
+    async fn due(&self, limit: i64) -> Result<Vec<QueuedEmail>, StoreError> {
+        let now = Utc::now();
+        let mut due: Vec<_> = self
+            .emails
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, next_attempt, expires)| *next_attempt <= now && now < *expires)
+            .map(|(email, next_attempt, _)| (*next_attempt, email.clone()))
+            .collect();
+        due.sort_by_key(|(next_attempt, email)| (*next_attempt, email.id));
+        Ok(due
+            .into_iter()
+            .take(limit.max(0) as usize)
+            .map(|(_, email)| email)
+            .collect())
+    }
+
+    async fn sent(&self, id: i64) -> Result<(), StoreError> {
+        self.emails
+            .lock()
+            .unwrap()
+            .retain(|(email, _, _)| email.id != id);
+        Ok(())
+    }
+
+    async fn failed(
+        &self,
+        id: i64,
+        _error: &str,
+        retry_at: DateTime<Utc>,
+    ) -> Result<(), StoreError> {
+        let mut emails = self.emails.lock().unwrap();
+        if let Some((email, next_attempt, _)) =
+            emails.iter_mut().find(|(email, _, _)| email.id == id)
+        {
+            email.attempts += 1;
+            *next_attempt = retry_at;
+        }
+        Ok(())
+    }
+
+    async fn prune_expired(&self) -> Result<u64, StoreError> {
+        let now = Utc::now();
+        let mut emails = self.emails.lock().unwrap();
+        let before = emails.len();
+        emails.retain(|(_, _, expires)| now < *expires);
+        Ok((before - emails.len()) as u64)
+    }
+}
+
+/// `EphemeralStore` in process memory. Nothing survives a restart and nothing is shared
+/// between processes, which is all a single-user desktop install needs.
+#[derive(Default)]
+pub struct MemoryStore {
+    state: Mutex<Ephemeral>,
+}
+
+#[derive(Default)]
+struct Ephemeral {
+    values: HashMap<String, (String, Option<Instant>)>,
+    sets: HashMap<String, HashSet<String>>,
+    windows: HashMap<String, VecDeque<Instant>>,
+    // Expired values are dropped on access and, for keys nobody reads again, whenever the
+    // map has doubled since the last sweep.
+    sweep_at: usize,
+}
+
+impl Ephemeral {
+    fn value(&mut self, key: &str) -> Option<&String> {
+        if let Some((_, Some(expires))) = self.values.get(key) {
+            if Instant::now() >= *expires {
+                self.values.remove(key);
+            }
+        }
+        self.values.get(key).map(|(value, _)| value)
+    }
+
+    fn sweep(&mut self) {
+        if self.values.len() < self.sweep_at {
+            return;
+        }
+        let now = Instant::now();
+        self.values
+            .retain(|_, (_, expires)| expires.map_or(true, |expires| now < expires));
+        self.windows.retain(|_, hits| !hits.is_empty());
+        self.sweep_at = (self.values.len() * 2).max(1024);
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl EphemeralStore for MemoryStore {
+    async fn get(&self, key: &str) -> Result<Option<String>, StoreError> {
+        Ok(self.state.lock().unwrap().value(key).cloned())
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> Result<(), StoreError> {
+        let mut state = self.state.lock().unwrap();
+        state.sweep();
+        let expires = ttl.map(|ttl| Instant::now() + ttl);
+        state
+            .values
+            .insert(key.to_string(), (value.to_string(), expires));
+        Ok(())
+    }
+
+    async fn take(&self, key: &str) -> Result<Option<String>, StoreError> {
+        let mut state = self.state.lock().unwrap();
+        let value = state.value(key).cloned();
+        state.values.remove(key);
+        Ok(value)
+    }
+
+    async fn delete(&self, keys: &[String]) -> Result<u64, StoreError> {
+        let mut state = self.state.lock().unwrap();
+        let mut deleted = 0;
+        for key in keys {
+            let existed = state.value(key).is_some();
+            state.values.remove(key);
+            let was_set = state.sets.remove(key).is_some();
+            let was_window = state.windows.remove(key).is_some();
+            if existed || was_set || was_window {
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+
+    async fn set_add(&self, key: &str, member: &str) -> Result<(), StoreError> {
+        self.state
+            .lock()
+            .unwrap()
+            .sets
+            .entry(key.to_string())
+            .or_default()
+            .insert(member.to_string());
+        Ok(())
+    }
+
+    async fn set_remove(&self, key: &str, member: &str) -> Result<(), StoreError> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(set) = state.sets.get_mut(key) {
+            set.remove(member);
+            if set.is_empty() {
+                state.sets.remove(key);
+            }
+        }
+        Ok(())
+    }
+
+    async fn set_members(&self, key: &str) -> Result<Vec<String>, StoreError> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .sets
+            .get(key)
+            .map(|set| set.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn scan(&self, prefix: &str) -> Result<Vec<String>, StoreError> {
+        let now = Instant::now();
+        let state = self.state.lock().unwrap();
+        let values = state
+            .values
+            .iter()
+            .filter(|(_, (_, expires))| expires.map_or(true, |expires| now < expires))
+            .map(|(key, _)| key);
+        Ok(values
+            .chain(state.sets.keys())
+            .chain(state.windows.keys())
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+
+    async fn hit(
+        &self,
+        key: &str,
+        window: Duration,
+        limit: u64,
+    ) -> Result<Option<Duration>, StoreError> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let hits = state.windows.entry(key.to_string()).or_default();
+        while hits
+            .front()
+            .is_some_and(|hit| now.duration_since(*hit) >= window)
+        {
+            hits.pop_front();
+        }
+        if (hits.len() as u64) < limit {
+            hits.push_back(now);
+            return Ok(None);
+        }
+        let oldest = *hits.front().unwrap_or(&now);
+        Ok(Some(
+            (oldest + window)
+                .saturating_duration_since(now)
+                .max(Duration::from_millis(1)),
+        ))
+    }
+
+    async fn ping(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
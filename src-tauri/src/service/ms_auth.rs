@@ -11,9 +11,9 @@ use oauth2::basic::{
 };
 use oauth2::{
     helpers, AsyncHttpClient, AuthUrl, CodeTokenRequest, ConfigurationError, EmptyExtraTokenFields,
-    EndpointNotSet, EndpointState, ErrorResponse, PkceCodeVerifier, RevocableToken,
-    RevocationRequest, StandardErrorResponse, StandardRevocableToken, StandardTokenResponse,
-    TokenIntrospectionResponse,
+    EndpointMaybeSet, EndpointNotSet, EndpointSet, EndpointState, ErrorResponse, PkceCodeVerifier,
+    RevocableToken, RevocationRequest, StandardErrorResponse, StandardRevocableToken,
+    StandardTokenResponse, TokenIntrospectionResponse,
 };
 use openidconnect::core::{
     CoreAuthPrompt, CoreAuthenticationFlow, CoreClient, CoreErrorResponseType, CoreGenderClaim,
@@ -45,15 +45,66 @@ use openidconnect::core::{
     CoreJweKeyManagementAlgorithm, CoreResponseMode, CoreRevocableToken, CoreSubjectIdentifierType,
     CoreUserInfoClaims,
 };
-use openidconnect::{AdditionalProviderMetadata, ProviderMetadata, RevocationUrl, UserInfoClaims};
+use openidconnect::{
+    AdditionalProviderMetadata, IdTokenClaims, ProviderMetadata, RevocationUrl, UserInfoClaims,
+};
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
 use serde::de::DeserializeOwned;
 use std::env;
 use std::fmt::{Debug, Display, Formatter};
-use std::io::{BufRead, BufReader, Write};
-use std::net::TcpListener;
+use tauri::AppHandle;
+
+// Microsoft issues tokens from one issuer per tenant, but multi-tenant authorities only
+// advertise this template in their discovery document.
+const ISSUER_TEMPLATE: &str = "https://login.microsoftonline.com/{tenantid}/v2.0";
+
+// Personal Microsoft accounts all live in this tenant.
+const CONSUMERS_TENANT_ID: &str = "9188040d-6c67-4c5b-b112-36a304b66dad";
+
+/// Claims Azure adds to its ID tokens that we need for the issuer check.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AzureClaims {
+    // Absent from userinfo responses, only ID tokens carry it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tid: Option<String>,
+}
+impl AdditionalClaims for AzureClaims {}
+
+pub type AzureIdTokenFields = IdTokenFields<
+    AzureClaims,
+    EmptyExtraTokenFields,
+    CoreGenderClaim,
+    CoreJweContentEncryptionAlgorithm,
+    CoreJwsSigningAlgorithm,
+>;
+
+pub type AzureIdTokenClaims = IdTokenClaims<AzureClaims, CoreGenderClaim>;
+
+pub type AzureUserInfoClaims = UserInfoClaims<AzureClaims, CoreGenderClaim>;
+
+// Azure has sent `expires_in` both as a number and as a string, accept either.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ExpiresIn {
+    Seconds(u64),
+    Text(String),
+}
+
+fn deserialize_expires_in<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match Option::<ExpiresIn>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(ExpiresIn::Seconds(secs)) => Ok(Some(secs)),
+        Some(ExpiresIn::Text(text)) => text
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+    }
+}
 
 //Create a custom Token Reciever to correctly parse the 'expires_in' value
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -67,7 +118,8 @@ where
     #[serde(deserialize_with = "helpers::deserialize_untagged_enum_case_insensitive")]
     token_type: TT,
     #[serde(skip_serializing_if = "Option::is_none")]
-    expires_in: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_expires_in")]
+    expires_in: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     refresh_token: Option<RefreshToken>,
     #[serde(rename = "scope")]
@@ -107,7 +159,7 @@ where
     }
 
     pub fn set_expires_in(&mut self, expires_in: Option<&Duration>) {
-        self.expires_in = expires_in.map(|duration| (duration.as_secs() as u64).to_string());
+        self.expires_in = expires_in.map(Duration::as_secs);
     }
 
     pub fn set_refresh_token(&mut self, refresh_token: Option<RefreshToken>) {
@@ -143,17 +195,7 @@ where
     }
 
     fn expires_in(&self) -> Option<Duration> {
-        self.expires_in.as_ref().map(|dur| {
-            let secs = dur.as_str().parse::<u64>();
-            let secs_success = match secs {
-                Ok(value) => value,
-                Err(error) => {
-                    println!("Unsuccessfully parsed string to u64: {:?}", error);
-                    0
-                }
-            };
-            Duration::new(secs_success, 0)
-        })
+        self.expires_in.map(Duration::from_secs)
     }
 
     fn refresh_token(&self) -> Option<&RefreshToken> {
@@ -180,7 +222,7 @@ where
     }
 }
 
-pub type AzureCoreTokenResponse = AzureTokenResponse<CoreIdTokenFields, CoreTokenType>;
+pub type AzureCoreTokenResponse = AzureTokenResponse<AzureIdTokenFields, CoreTokenType>;
 
 pub type AzureCoreClient<
     HasAuthUrl = EndpointNotSet,
@@ -190,7 +232,7 @@ pub type AzureCoreClient<
     HasTokenUrl = EndpointNotSet,
     HasUserInfoUrl = EndpointNotSet,
 > = Client<
-    EmptyAdditionalClaims,
+    AzureClaims,
     CoreAuthDisplay,
     CoreGenderClaim,
     CoreJweContentEncryptionAlgorithm,
@@ -208,38 +250,111 @@ pub type AzureCoreClient<
     HasTokenUrl,
     HasUserInfoUrl,
 >;
+use crate::auth_handler::AppState;
+use crate::oidc_cache::is_unknown_key;
+use crate::settings::MicrosoftSettings;
+use crate::token_vault::ProviderTokens;
 use crate::tp_auth::*;
 
-async fn ms_auth() -> Result<(), anyhow::Error> {
-    dotenv::dotenv().ok();
-
-    let client_creds = create_client("MS").await?;
-    let authority_url = format!(
-        "https://sts.windows.net/{}/",
-        client_creds.tenant_id.unwrap()
-    );
-
-    let http_client = reqwest::ClientBuilder::new()
-        .redirect(reqwest::redirect::Policy::none())
-        .build()
-        .expect("Client should build");
+type MsClient = AzureCoreClient<
+    EndpointSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointMaybeSet,
+    EndpointMaybeSet,
+>;
 
-    let provider_metadata =
-        CoreProviderMetadata::discover_async(client_creds.issuer_url, &http_client).await?;
+async fn ms_client(
+    state: &AppState,
+    client_creds: ClientCredentials,
+) -> Result<MsClient, anyhow::Error> {
+    let provider_metadata = state
+        .oidc
+        .microsoft
+        .get(&state.http, &client_creds.issuer_url)
+        .await?;
+    Ok(client_from_metadata(&provider_metadata, client_creds))
+}
 
-    // Use CoreClient instead of AzureCoreClient for now
-    // We'll need to handle the custom token response in a different way
-    let client = AzureCoreClient::from_provider_metadata(
-        provider_metadata,
+fn client_from_metadata(
+    provider_metadata: &CoreProviderMetadata,
+    client_creds: ClientCredentials,
+) -> MsClient {
+    AzureCoreClient::from_provider_metadata(
+        provider_metadata.clone(),
         client_creds.client_id,
         Some(client_creds.client_secret),
     )
-    .set_redirect_uri(
-        RedirectUrl::new("http://localhost:8080".to_string()).unwrap_or_else(|err| {
-            handle_error(&err, "Invalid redirect URL");
-            unreachable!();
-        }),
-    );
+}
+
+// The issuer depends on the tenant the account belongs to, `check_tenant` takes care of it.
+fn id_token_verifier(client: &MsClient) -> CoreIdTokenVerifier<'_> {
+    client.id_token_verifier().require_issuer_match(false)
+}
+
+fn check_tenant(
+    claims: &AzureIdTokenClaims,
+    settings: &MicrosoftSettings,
+) -> Result<(), anyhow::Error> {
+    let tid = claims
+        .additional_claims()
+        .tid
+        .as_deref()
+        .ok_or_else(|| anyhow!("ID token is missing the tenant id"))?
+        .to_lowercase();
+
+    let expected_issuer = ISSUER_TEMPLATE.replace("{tenantid}", &tid);
+    if claims.issuer().as_str() != expected_issuer {
+        return Err(anyhow!(
+            "Unexpected ID token issuer {}",
+            claims.issuer().as_str()
+        ));
+    }
+
+    let authority_allows = match settings.tenant.to_lowercase().as_str() {
+        "common" => true,
+        "organizations" => tid != CONSUMERS_TENANT_ID,
+        "consumers" => tid == CONSUMERS_TENANT_ID,
+        tenant => tid.eq_ignore_ascii_case(tenant),
+    };
+    let allowed_tenants = &settings.allowed_tenants;
+    let tenant_allowed = allowed_tenants.is_empty()
+        || allowed_tenants
+            .iter()
+            .any(|tenant| tenant.eq_ignore_ascii_case(&tid));
+    if !authority_allows || !tenant_allowed {
+        return Err(anyhow!("Accounts from tenant {} can't sign in", tid));
+    }
+
+    Ok(())
+}
+
+fn provider_tokens(token_response: &AzureCoreTokenResponse) -> ProviderTokens {
+    ProviderTokens::new(
+        token_response.access_token().clone(),
+        token_response.refresh_token().cloned(),
+        token_response.expires_in(),
+        token_response
+            .scopes()
+            .map(|scopes| {
+                scopes
+                    .iter()
+                    .map(|scope| scope.as_str().to_owned())
+                    .collect()
+            })
+            .unwrap_or_default(),
+    )
+}
+
+pub async fn ms_auth(app: &AppHandle, state: &AppState) -> Result<SignIn, anyhow::Error> {
+    // Azure only matches loopback redirect URIs on the path, so the ephemeral port is accepted.
+    let loopback = bind_loopback().await?;
+
+    let client_creds = create_client("MS", &state.settings).await?;
+    let client = ms_client(state, client_creds)
+        .await?
+        .set_redirect_uri(loopback.redirect_url.clone());
 
     let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
 
@@ -252,49 +367,166 @@ async fn ms_auth() -> Result<(), anyhow::Error> {
         )
         .add_scope(Scope::new("email".to_string()))
         .add_scope(Scope::new("profile".to_string()))
+        // Without offline_access Azure doesn't return a refresh token.
+        .add_scope(Scope::new("offline_access".to_string()))
         .set_pkce_challenge(pkce_challenge)
         .url();
 
-    println!("Browse to: {}", auth_url);
+    open_in_browser(app, &auth_url)?;
 
-    let (code, state) = auth_code("MS");
+    let code = loopback.wait_for_code("MS", &csrf_token).await?;
 
     // Exchange the authorization code for a token
     let token_response = client
-        .exchange_code(code)
-        .unwrap_or_else(|err| {
-            handle_error(&err, "No user info endpoint");
-            unreachable!();
-        })
+        .exchange_code(code)?
         .set_pkce_verifier(pkce_verifier)
-        .request_async(&http_client)
+        .request_async(&state.http)
         .await
-        .unwrap_or_else(|err| {
-            handle_error(&err, "Failed to contact token endpoint");
-            unreachable!();
-        });
+        .map_err(|err| anyhow!("Failed to contact token endpoint: {}", err))?;
 
-    let id_token_verifier: CoreIdTokenVerifier = client.id_token_verifier();
-    let id_token_claims: &CoreIdTokenClaims = token_response
+    let id_token = token_response
         .extra_fields()
         .id_token()
-        .expect("Server did not return an ID token")
-        .claims(&id_token_verifier, &nonce)
-        .unwrap_or_else(|err| {
-            handle_error(&err, "Failed to verify ID token");
-            unreachable!();
-        });
-
-    println!("{:?}", id_token_claims);
+        .ok_or_else(|| anyhow!("Server did not return an ID token"))?;
+
+    let id_token_claims = match id_token.claims(&id_token_verifier(&client), &nonce) {
+        Ok(claims) => claims,
+        // Azure rolls its signing keys regularly; refetch the set and try once more.
+        Err(err) if is_unknown_key(&err) => {
+            let provider_metadata = state.oidc.microsoft.refresh_jwks(&state.http).await?;
+            let client = client_from_metadata(
+                &provider_metadata,
+                create_client("MS", &state.settings).await?,
+            );
+            id_token
+                .claims(&id_token_verifier(&client), &nonce)
+                .map_err(|err| anyhow!("Failed to verify ID token: {}", err))?
+        }
+        Err(err) => return Err(anyhow!("Failed to verify ID token: {}", err)),
+    };
+    check_tenant(id_token_claims, &state.settings.microsoft)?;
 
     // Get user info
-    let user_info: CoreUserInfoClaims = client
+    let user_info: AzureUserInfoClaims = client
         .user_info(token_response.access_token().to_owned(), None)?
-        .request_async(&http_client)
+        .request_async(&state.http)
         .await
         .map_err(|err| anyhow!("Failed requesting user info: {}", err))?;
 
-    sign_in_or_sign_up(user_info, pool);
+    let sign_in = sign_in_or_sign_up(user_info, "MS", state).await?;
 
-    Ok(())
+    state
+        .vault
+        .store(
+            &state.db,
+            "MS",
+            &sign_in.provider_user_id,
+            &provider_tokens(&token_response),
+        )
+        .await?;
+
+    Ok(sign_in)
+}
+
+pub async fn refresh_tokens(
+    state: &AppState,
+    refresh_token: &RefreshToken,
+) -> Result<ProviderTokens, anyhow::Error> {
+    let client = ms_client(state, create_client("MS", &state.settings).await?).await?;
+
+    let token_response = client
+        .exchange_refresh_token(refresh_token)?
+        .request_async(&state.http)
+        .await
+        .map_err(|err| anyhow!("Failed to refresh token: {}", err))?;
+
+    Ok(provider_tokens(&token_response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTOSO: &str = "72f988bf-86f1-41af-91ab-2d7cd011db47";
+    const FABRIKAM: &str = "0a1b2c3d-4e5f-4a6b-8c7d-9e0f1a2b3c4d";
+
+    fn claims(tid: Option<&str>, issuer_tenant: &str) -> AzureIdTokenClaims {
+        let mut claims = serde_json::json!({
+            "iss": ISSUER_TEMPLATE.replace("{tenantid}", issuer_tenant),
+            "aud": "client-id",
+            "exp": 4_102_444_800u64,
+            "iat": 1_700_000_000u64,
+            "sub": "subject",
+        });
+        if let Some(tid) = tid {
+            claims["tid"] = tid.into();
+        }
+        serde_json::from_value(claims).unwrap()
+    }
+
+    fn account(tid: &str) -> AzureIdTokenClaims {
+        claims(Some(tid), tid)
+    }
+
+    fn settings(tenant: &str, allowed_tenants: &[&str]) -> MicrosoftSettings {
+        MicrosoftSettings {
+            tenant: tenant.to_string(),
+            allowed_tenants: allowed_tenants.iter().map(|t| t.to_string()).collect(),
+            client_id: None,
+            client_secret: None,
+        }
+    }
+
+    #[test]
+    fn common_takes_work_and_personal_accounts() {
+        let common = settings("common", &[]);
+        assert!(check_tenant(&account(CONTOSO), &common).is_ok());
+        assert!(check_tenant(&account(CONSUMERS_TENANT_ID), &common).is_ok());
+    }
+
+    #[test]
+    fn organizations_turn_personal_accounts_away() {
+        let organizations = settings("organizations", &[]);
+        assert!(check_tenant(&account(CONTOSO), &organizations).is_ok());
+        assert!(check_tenant(&account(CONSUMERS_TENANT_ID), &organizations).is_err());
+    }
+
+    #[test]
+    fn consumers_only_take_personal_accounts() {
+        let consumers = settings("Consumers", &[]);
+        assert!(check_tenant(&account(CONSUMERS_TENANT_ID), &consumers).is_ok());
+        assert!(check_tenant(&account(CONTOSO), &consumers).is_err());
+    }
+
+    #[test]
+    fn a_specific_tenant_only_takes_its_own_accounts() {
+        let contoso = settings(&CONTOSO.to_uppercase(), &[]);
+        assert!(check_tenant(&account(CONTOSO), &contoso).is_ok());
+        assert!(check_tenant(&account(FABRIKAM), &contoso).is_err());
+        assert!(check_tenant(&account(CONSUMERS_TENANT_ID), &contoso).is_err());
+    }
+
+    #[test]
+    fn the_allow_list_narrows_the_authority() {
+        let allowed = settings("common", &[CONTOSO]);
+        assert!(check_tenant(&account(CONTOSO), &allowed).is_ok());
+        assert!(check_tenant(&account(FABRIKAM), &allowed).is_err());
+        assert!(check_tenant(&account(CONSUMERS_TENANT_ID), &allowed).is_err());
+
+        // Both have to agree.
+        let consumers = settings("consumers", &[CONTOSO]);
+        assert!(check_tenant(&account(CONTOSO), &consumers).is_err());
+    }
+
+    #[test]
+    fn the_issuer_has_to_match_the_tenant_id() {
+        let common = settings("common", &[CONTOSO]);
+        // An allowed tid can't vouch for a token another tenant issued, nor the other way round.
+        assert!(check_tenant(&claims(Some(CONTOSO), FABRIKAM), &common).is_err());
+        assert!(check_tenant(&claims(Some(FABRIKAM), CONTOSO), &common).is_err());
+        assert!(check_tenant(&claims(Some(CONTOSO), "{tenantid}"), &common).is_err());
+        assert!(check_tenant(&claims(None, CONTOSO), &common).is_err());
+        // Tenant ids compare case-insensitively, issuers carry them in lowercase.
+        assert!(check_tenant(&claims(Some(&CONTOSO.to_uppercase()), CONTOSO), &common).is_ok());
+    }
 }
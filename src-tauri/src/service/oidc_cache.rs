@@ -0,0 +1,258 @@
+use anyhow::anyhow;
+use openidconnect::core::{
+    CoreAuthDisplay, CoreClaimName, CoreClaimType, CoreClientAuthMethod, CoreGrantType,
+    CoreJsonWebKey, CoreJweContentEncryptionAlgorithm, CoreJweKeyManagementAlgorithm,
+    CoreResponseMode, CoreResponseType, CoreSubjectIdentifierType,
+};
+use openidconnect::{
+    reqwest, AdditionalProviderMetadata, ClaimsVerificationError, EmptyAdditionalProviderMetadata,
+    IssuerUrl, JsonWebKeySet, ProviderMetadata, SignatureVerificationError,
+};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, RwLock};
+use tracing::{debug, warn};
+
+use crate::auth_handler::AppState;
+use crate::google::RevocationEndpointProviderMetadata;
+use crate::settings::OidcSettings;
+
+pub type CachedProviderMetadata<A> = ProviderMetadata<
+    A,
+    CoreAuthDisplay,
+    CoreClientAuthMethod,
+    CoreClaimName,
+    CoreClaimType,
+    CoreGrantType,
+    CoreJweContentEncryptionAlgorithm,
+    CoreJweKeyManagementAlgorithm,
+    CoreJsonWebKey,
+    CoreResponseMode,
+    CoreResponseType,
+    CoreSubjectIdentifierType,
+>;
+
+/// The one HTTP client shared by every provider call, so connections to the issuers are pooled.
+pub fn build_http_client() -> Result<reqwest::Client, anyhow::Error> {
+    Ok(reqwest::ClientBuilder::new()
+        // Following redirects opens the client up to SSRF vulnerabilities.
+        .redirect(reqwest::redirect::Policy::none())
+        .build()?)
+}
+
+struct CacheEntry<A: AdditionalProviderMetadata> {
+    issuer: IssuerUrl,
+    metadata: Arc<CachedProviderMetadata<A>>,
+    fetched_at: Instant,
+    // Discovery fetches the keys too, so this is at least as recent as `fetched_at`.
+    keys_fetched_at: Instant,
+}
+
+/// Discovery document and JWKS for one issuer.
+///
+/// Entries are served for `ttl` and refreshed in the background after that. If the issuer can't
+/// be reached the last good document keeps being served instead of failing the login.
+pub struct MetadataCache<A: AdditionalProviderMetadata> {
+    ttl: Duration,
+    templated_issuer: bool,
+    min_jwks_refetch: Duration,
+    entry: RwLock<Option<CacheEntry<A>>>,
+    // Held while the key set is refetched, so a burst of unknown keys makes one request.
+    jwks_refetch: Mutex<()>,
+}
+
+impl<A: AdditionalProviderMetadata> MetadataCache<A> {
+    pub fn new(ttl: Duration) -> Self {
+        MetadataCache {
+            ttl,
+            templated_issuer: false,
+            min_jwks_refetch: Duration::ZERO,
+            entry: RwLock::new(None),
+            jwks_refetch: Mutex::new(()),
+        }
+    }
+
+    /// For multi-tenant authorities whose document advertises a placeholder issuer such as
+    /// `https://login.microsoftonline.com/{tenantid}/v2.0`. Discovery then can't insist on the
+    /// issuer matching, so ID tokens have to be checked against the template by the caller.
+    pub fn with_templated_issuer(ttl: Duration) -> Self {
+        MetadataCache {
+            templated_issuer: true,
+            ..MetadataCache::new(ttl)
+        }
+    }
+
+    /// Keeps `refresh_jwks` from fetching the key set more often than every `interval`, so ID
+    /// tokens naming made-up keys can't have us hammer the provider.
+    pub fn with_min_jwks_refetch(self, interval: Duration) -> Self {
+        MetadataCache {
+            min_jwks_refetch: interval,
+            ..self
+        }
+    }
+
+    pub async fn get(
+        &self,
+        http_client: &reqwest::Client,
+        issuer: &IssuerUrl,
+    ) -> Result<Arc<CachedProviderMetadata<A>>, anyhow::Error> {
+        if let Some(entry) = self.entry.read().await.as_ref() {
+            if entry.issuer == *issuer && entry.fetched_at.elapsed() < self.ttl {
+                return Ok(entry.metadata.clone());
+            }
+        }
+
+        match self.refresh(http_client, issuer).await {
+            Ok(metadata) => Ok(metadata),
+            Err(err) => match self.entry.read().await.as_ref() {
+                Some(entry) if entry.issuer == *issuer => {
+                    warn!(
+                        "Serving stale discovery document for {}: {:?}",
+                        issuer.as_str(),
+                        err
+                    );
+                    Ok(entry.metadata.clone())
+                }
+                _ => Err(err),
+            },
+        }
+    }
+
+    pub async fn refresh(
+        &self,
+        http_client: &reqwest::Client,
+        issuer: &IssuerUrl,
+    ) -> Result<Arc<CachedProviderMetadata<A>>, anyhow::Error> {
+        let metadata = Arc::new(if self.templated_issuer {
+            discover_templated(http_client, issuer).await?
+        } else {
+            // discover_async also fetches the JWKS the document points to.
+            CachedProviderMetadata::<A>::discover_async(issuer.clone(), http_client).await?
+        });
+
+        let now = Instant::now();
+        *self.entry.write().await = Some(CacheEntry {
+            issuer: issuer.clone(),
+            metadata: metadata.clone(),
+            fetched_at: now,
+            keys_fetched_at: now,
+        });
+        debug!("Refreshed discovery document for {}", issuer.as_str());
+
+        Ok(metadata)
+    }
+
+    /// Refetches only the key set, for ID tokens signed with a `kid` we haven't seen yet. Within
+    /// the minimum interval of the last fetch the cached keys are returned as they are.
+    pub async fn refresh_jwks(
+        &self,
+        http_client: &reqwest::Client,
+    ) -> Result<Arc<CachedProviderMetadata<A>>, anyhow::Error> {
+        let _refetch = self.jwks_refetch.lock().await;
+        let (current, keys_fetched_at) = self
+            .entry
+            .read()
+            .await
+            .as_ref()
+            .map(|entry| (entry.metadata.clone(), entry.keys_fetched_at))
+            .ok_or_else(|| anyhow!("No discovery document cached yet"))?;
+
+        if keys_fetched_at.elapsed() < self.min_jwks_refetch {
+            debug!(
+                "Signing keys for {} were fetched {:?} ago, not refetching",
+                current.issuer().as_str(),
+                keys_fetched_at.elapsed()
+            );
+            return Ok(current);
+        }
+
+        let jwks = JsonWebKeySet::fetch_async(current.jwks_uri(), http_client).await?;
+        let metadata = Arc::new((*current).clone().set_jwks(jwks));
+
+        if let Some(entry) = self.entry.write().await.as_mut() {
+            entry.metadata = metadata.clone();
+            entry.keys_fetched_at = Instant::now();
+        }
+
+        Ok(metadata)
+    }
+
+    async fn refresh_if_stale(&self, http_client: &reqwest::Client, max_age: Duration) {
+        let issuer = match self.entry.read().await.as_ref() {
+            Some(entry) if entry.fetched_at.elapsed() >= max_age => entry.issuer.clone(),
+            _ => return,
+        };
+        if let Err(err) = self.refresh(http_client, &issuer).await {
+            warn!(
+                "Background refresh for {} failed: {:?}",
+                issuer.as_str(),
+                err
+            );
+        }
+    }
+}
+
+// Same as discover_async, minus the check that the document's issuer equals the one we asked for.
+async fn discover_templated<A: AdditionalProviderMetadata>(
+    http_client: &reqwest::Client,
+    issuer: &IssuerUrl,
+) -> Result<CachedProviderMetadata<A>, anyhow::Error> {
+    let discovery_url = issuer.join(".well-known/openid-configuration")?;
+    let body = http_client
+        .get(discovery_url)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    let metadata: CachedProviderMetadata<A> = serde_json::from_slice(&body)?;
+
+    let jwks = JsonWebKeySet::fetch_async(metadata.jwks_uri(), http_client).await?;
+    Ok(metadata.set_jwks(jwks))
+}
+
+/// Discovery caches for every OpenID provider we sign in with.
+pub struct OidcCache {
+    pub google: MetadataCache<RevocationEndpointProviderMetadata>,
+    pub microsoft: MetadataCache<EmptyAdditionalProviderMetadata>,
+}
+
+impl OidcCache {
+    pub fn new(settings: &OidcSettings) -> Self {
+        let (ttl, min_refetch) = (settings.discovery_ttl(), settings.jwks_min_refetch());
+        OidcCache {
+            google: MetadataCache::new(ttl).with_min_jwks_refetch(min_refetch),
+            microsoft: MetadataCache::with_templated_issuer(ttl).with_min_jwks_refetch(min_refetch),
+        }
+    }
+}
+
+/// True when an ID token names a signing key that isn't in the cached JWKS, which usually means
+/// the provider rotated its keys since the last discovery.
+pub fn is_unknown_key(err: &ClaimsVerificationError) -> bool {
+    matches!(
+        err,
+        ClaimsVerificationError::SignatureVerification(SignatureVerificationError::NoMatchingKey)
+    )
+}
+
+/// Refreshes cached documents halfway through their TTL, so logins rarely wait on discovery.
+pub fn spawn_refresher(state: Arc<AppState>) {
+    let ttl = state.settings.oidc.discovery_ttl();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(ttl / 2);
+        loop {
+            interval.tick().await;
+            state
+                .oidc
+                .google
+                .refresh_if_stale(&state.http, ttl / 2)
+                .await;
+            state
+                .oidc
+                .microsoft
+                .refresh_if_stale(&state.http, ttl / 2)
+                .await;
+        }
+    });
+}
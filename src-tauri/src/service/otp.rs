@@ -5,51 +5,43 @@ use axum::extract::FromRef;
 use tauri::ipc::Invoke;
 use thiserror::Error;
 
+use crate::logging::redact_email;
 use crate::password::encrypt;
+use crate::repository::{
+    EmailOutbox, NewEmail, PendingVerification, QueuedEmail, StoreError, UserRepository,
+    VerificationStore,
+};
+use crate::telemetry;
 use axum::http::header::FROM;
 use axum::Extension;
 use axum::{
     http::StatusCode,
     response::{Html, IntoResponse},
 };
+use chrono::Utc;
 use lettre::message::header::ContentType;
 use lettre::message::MessageBuilder;
 use lettre::transport::smtp::authentication::Credentials;
 use lettre::{Message, SmtpTransport, Transport};
-use redis::AsyncCommands;
 use serde::{ser::SerializeStruct, Deserialize, Serialize};
 use sqlx::{
     types::{time::PrimitiveDateTime, Json},
     PgPool,
 };
 use std::sync::Arc;
-use std::time::{SystemTime, SystemTimeError};
+use std::time::{Duration, SystemTime, SystemTimeError};
 use tauri::{State, StateManager};
 use totp_rs::{Algorithm, Secret, TOTP};
-use tracing::{debug, error, instrument};
-use tracing_appender::rolling::{RollingFileAppender, Rotation};
-use tracing_subscriber::fmt;
+use tracing::{debug, error, instrument, warn};
 
 const FULL_COMPANY_EMAIL: &str = "Wyrd <thewyrdteam@gmail.com>";
 const COMPANY_EMAIL: &str = "thewyrdteam@gmail.com";
 
-#[derive(Debug)]
-pub struct OTPInfo {
-    pub name: String,
-    pub email: String,
-}
-
-impl serde::Serialize for OTPInfo {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        let mut state = serializer.serialize_struct("OTPInfo", 2)?;
-        state.serialize_field("name", &self.name)?;
-        state.serialize_field("email", &self.email)?;
-        state.end()
-    }
-}
+// Failed emails are tried again after a minute, then less and less often.
+const FIRST_RETRY: Duration = Duration::from_secs(60);
+const LAST_RETRY: Duration = Duration::from_secs(60 * 60);
+// How many queued emails one retry run goes through.
+const RETRY_BATCH: i64 = 100;
 
 #[derive(Debug, Deserialize, Serialize, Error)]
 pub enum OTPErrors {
@@ -61,37 +53,24 @@ pub enum OTPErrors {
     EmailError(String),
     #[error("Generating OTP Failed: {0}")]
     GenerateOTPError(String),
-}
-
-//impl Serialize for OTPErrors {}
-
-impl IntoResponse for OTPErrors {
-    fn into_response(self) -> axum::response::Response {
-        let body = match self {
-            OTPErrors::SendOTPError(..) => "Error Sending OTP",
-            OTPErrors::ResendOTPError(..) => "Error Resending OTP",
-            OTPErrors::EmailError(..) => "Error sending email",
-            OTPErrors::GenerateOTPError(..) => "Error generating OTP",
-        };
-        (StatusCode::INTERNAL_SERVER_ERROR, body).into_response()
-    }
+    #[error("Verifying OTP Failed: {0}")]
+    VerifyOTPError(String),
+    #[error("The one-time password has expired")]
+    Expired,
+    #[error("Codes can't be stored or read right now: {0}")]
+    Unavailable(String),
 }
 
 pub async fn generate_otp(
-    pool: &PgPool,
+    users: &dyn UserRepository,
     client_email: &str,
     client_name: &str,
 ) -> Result<TOTP, anyhow::Error> {
-    let sk = sqlx::query!(
-        "SELECT totp_secret FROM users WHERE email = $1",
-        client_email
-    )
-    .fetch_optional(pool) // Fetch an optional record from the database
-    .await
-    .map_err(|e| (e))? // Map database errors
-    .unwrap()
-    .totp_secret // Access the totp_secret field
-    .unwrap();
+    let sk = users
+        .find_by_email(client_email)
+        .await?
+        .ok_or_else(|| anyhow!("No account found for the email address"))?
+        .totp_secret;
 
     let totp = TOTP::new(
         Algorithm::SHA512,
@@ -106,25 +85,60 @@ pub async fn generate_otp(
 
     let token = totp.generate_current().unwrap();
 
-    debug!("Generated OTP token: {token}");
+    debug!("Generated OTP for {}", redact_email(client_email));
 
     Ok((totp))
 }
 
-pub async fn send_otp(token: &str, client_email: &str, client_name: &str) -> Result<(), OTPErrors> {
-    let full_client_email: String = format!("{} <{}>", client_name, client_email);
+/// Queues the verification email and tries to send it straight away. One that can't go out
+/// right now stays queued for the `retry_emails` job until its code expires, so only failing to
+/// queue it is an error.
+pub async fn send_otp(
+    outbox: &dyn EmailOutbox,
+    token: &str,
+    client_email: &str,
+    client_name: &str,
+    expiry: Duration,
+) -> Result<(), OTPErrors> {
+    let email = NewEmail {
+        recipient: format!("{} <{}>", client_name, client_email),
+        subject: "Please verify your account".to_string(),
+        body: format!(
+            "Welcome {}! Thank you for joining Wyrd! Please enter the following code shown: {}",
+            client_name, token,
+        ),
+    };
+    let expires_at = Utc::now() + chrono::Duration::from_std(expiry).unwrap_or_default();
+    let queued = outbox
+        .enqueue(&email, expires_at)
+        .await
+        .map_err(|e| OTPErrors::EmailError(format!("Failed to queue email: {e:?}")))?;
 
+    match attempt(outbox, &queued, &deliver).await {
+        Ok(true) => debug!("OTP email sent to {}", redact_email(client_email)),
+        Ok(false) => warn!(
+            "OTP email to {} queued for another attempt",
+            redact_email(client_email)
+        ),
+        // At worst it goes out twice, once now and once on retry.
+        Err(e) => warn!("Could not record the OTP email's attempt: {e:?}"),
+    }
+    Ok(())
+}
+
+/// Sends `email` over SMTP.
+pub fn deliver(email: &QueuedEmail) -> Result<(), String> {
     let send_email = Message::builder()
         .from(FULL_COMPANY_EMAIL.parse().unwrap())
         .reply_to(FULL_COMPANY_EMAIL.parse().unwrap())
-        .to(full_client_email.parse().unwrap())
-        .subject("Please verify your account")
+        .to(email
+            .recipient
+            .parse()
+            .map_err(|e| format!("Invalid recipient: {e}"))?)
+        .subject(email.subject.as_str())
         .header(ContentType::TEXT_PLAIN)
-        .body(String::from(format!(
-            "Welcome {}! Thank you for joining Wyrd! Please enter the following code shown: {}",
-            client_name, token,
-        )))
-        .unwrap();
+        .body(email.body.clone())
+        .map_err(|e| format!("Invalid email: {e}"))?;
 
     let gmail_creds = Credentials::new(
         std::env::var("SMTP_USERNAME").expect("SMTP_USERNAME must be set"),
@@ -136,42 +150,122 @@ pub async fn send_otp(token: &str, client_email: &str, client_name: &str) -> Res
         .credentials(gmail_creds)
         .build();
 
-    match mailer.send(&send_email) {
-        Ok(_) => {
-            debug!("Email sent successfully to {client_email}");
-            Ok(())
+    mailer
+        .send(&send_email)
+        .map(|_| ())
+        .map_err(|e| format!("Failed to send email: {e:?}"))
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DeliveryCounts {
+    pub sent: u64,
+    pub failed: u64,
+    /// Dropped because they expired before they could be sent.
+    pub expired: u64,
+}
+
+/// Sends the queued emails that are due through `send`, and drops the ones that expired.
+pub async fn retry_emails<F>(
+    outbox: &dyn EmailOutbox,
+    send: F,
+) -> Result<DeliveryCounts, StoreError>
+where
+    F: Fn(&QueuedEmail) -> Result<(), String>,
+{
+    let mut counts = DeliveryCounts {
+        expired: outbox.prune_expired().await?,
+        ..DeliveryCounts::default()
+    };
+    for email in outbox.due(RETRY_BATCH).await? {
+        match attempt(outbox, &email, &send).await? {
+            true => counts.sent += 1,
+            false => counts.failed += 1,
+        }
+    }
+    Ok(counts)
+}
+
+// One attempt at sending `email`: gone from the outbox once sent, backed off otherwise. Returns
+// whether it went out.
+async fn attempt<F>(
+    outbox: &dyn EmailOutbox,
+    email: &QueuedEmail,
+    send: &F,
+) -> Result<bool, StoreError>
+where
+    F: Fn(&QueuedEmail) -> Result<(), String>,
+{
+    let sent = send(email);
+    telemetry::record_otp_sent(sent.is_ok());
+    match sent {
+        Ok(()) => {
+            outbox.sent(email.id).await?;
+            Ok(true)
         }
         Err(e) => {
-            error!("Failed to send email: {e:?}");
-            Err(OTPErrors::EmailError(format!(
-                "Failed to send email: {e:?}"
-            )))
+            error!("Failed to send email: {e}");
+            let retry_at = Utc::now()
+                + chrono::Duration::from_std(retry_delay(email.attempts + 1)).unwrap_or_default();
+            outbox.failed(email.id, &e, retry_at).await?;
+            Ok(false)
         }
     }
 }
 
+// Doubles with every failed attempt, from FIRST_RETRY up to LAST_RETRY.
+fn retry_delay(attempts: i32) -> Duration {
+    let doublings = attempts.saturating_sub(1).clamp(0, 16) as u32;
+    (FIRST_RETRY * 2u32.pow(doublings)).min(LAST_RETRY)
+}
+
 /*
 
 */
 
+/// Generates a code for the account behind `pending` and stores its hash for `verify_otp`.
+/// Returns the code itself, which only ever goes out by email.
+pub async fn start_verification(
+    users: &dyn UserRepository,
+    verifications: &dyn VerificationStore,
+    pending: &PendingVerification,
+    expiry: Duration,
+) -> Result<String, OTPErrors> {
+    let token = generate_otp(users, &pending.email, &pending.name)
+        .await
+        .and_then(|totp| Ok(totp.generate_current()?))
+        .map_err(|err| OTPErrors::GenerateOTPError(err.to_string()))?;
+    let encrypted_token = encrypt(&token)
+        .await
+        .map_err(|err| OTPErrors::GenerateOTPError(err.to_string()))?;
+    verifications
+        .start(pending, &encrypted_token, expiry)
+        .await
+        .map_err(|err| OTPErrors::Unavailable(err.to_string()))?;
+    Ok(token)
+}
+
+/// Checks the code entered for the sign-up with this email.
 pub async fn verify_otp(
-    Extension(state): Extension<Arc<AppState>>,
+    verifications: &dyn VerificationStore,
+    email: &str,
     entered_code: &str,
-) -> Result<(bool), anyhow::Error> {
-    let mut con = state.red.clone();
+) -> Result<(bool), OTPErrors> {
+    let code = verifications.code_hash(email).await.map_err(|err| {
+        telemetry::record_otp_verification("error");
+        OTPErrors::Unavailable(err.to_string())
+    })?;
 
     //Check if otp exists (already expired)
-    let mut exists = con.exists::<&str, i64>("token").await?;
-    match exists {
-        0 => {
-            return Err(anyhow!("Your one-time password has expired. Please resend the otp, then check your inbox and enter the new code to continue."));
-        }
-        1 => {
-            // If the code does exist (not expired) check if it's valid
-            let code: String = redis::cmd("GET").arg("token").query_async(&mut con).await?;
-            let encrypted_code = encrypt(entered_code).await?;
-            return Ok(encrypted_code.trim() == code.trim());
-        }
-        _ => return Err(anyhow!("An error has occured with verifying the code.")),
-    }
+    let Some(code) = code else {
+        telemetry::record_otp_verification("expired");
+        return Err(OTPErrors::Expired);
+    };
+
+    // If the code does exist (not expired) check if it's valid
+    let encrypted_code = encrypt(entered_code)
+        .await
+        .map_err(|err| OTPErrors::VerifyOTPError(err.to_string()))?;
+    let valid = encrypted_code.trim() == code.trim();
+    telemetry::record_otp_verification(if valid { "valid" } else { "invalid" });
+    Ok(valid)
 }
@@ -0,0 +1,210 @@
+// Presence: whether a user is around, worked out from their gateway connections. Every connected
+// device counts until it stops sending heartbeats; heartbeats say whether the user did anything
+// on it, and once none of their devices has seen activity in a while they show as away. Users
+// can also pick a state, which wins over what their devices say while they're connected. The
+// result goes to `users.activity`, and changes only reach people sharing a conversation with
+// the user.
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use thiserror::Error;
+use tracing::warn;
+use utoipa::ToSchema;
+
+use crate::chat::timestamp;
+use crate::repository::{
+    ConversationStore, Presence, PresenceStore, StoreError, UserRepository, AWAY, DND, INVISIBLE,
+    OFFLINE, ONLINE,
+};
+use crate::settings::PresenceSettings;
+
+/// States users can pick. `ONLINE` goes back to following their devices.
+pub const STATES: [&str; 4] = [ONLINE, AWAY, DND, INVISIBLE];
+
+#[derive(Debug, Error)]
+pub enum PresenceError {
+    #[error("States are online, away, dnd or invisible")]
+    InvalidState,
+
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
+
+/// A user's presence as other users receive it.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct PresenceUpdate {
+    pub user_id: i64,
+    /// `online`, `away`, `dnd` or `offline`. Invisible users show as offline.
+    pub state: String,
+    /// When they last went offline, absent if they never have.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<String>,
+}
+
+impl From<Presence> for PresenceUpdate {
+    fn from(presence: Presence) -> Self {
+        PresenceUpdate {
+            user_id: presence.user_id,
+            state: presence.activity,
+            last_seen: presence.last_seen.map(timestamp),
+        }
+    }
+}
+
+/// What others see of a user with these devices, given when each was last active.
+pub fn aggregate(
+    choice: Option<&str>,
+    devices: &[DateTime<Utc>],
+    now: DateTime<Utc>,
+    idle_after: Duration,
+) -> &'static str {
+    if devices.is_empty() {
+        return OFFLINE;
+    }
+    match choice {
+        Some(INVISIBLE) => OFFLINE,
+        Some(DND) => DND,
+        Some(AWAY) => AWAY,
+        _ if devices.iter().all(|&active| now - active >= idle_after) => AWAY,
+        _ => ONLINE,
+    }
+}
+
+/// Who hears about the user's presence: everyone sharing a conversation with them, and their
+/// own other devices.
+pub async fn recipients(
+    conversations: &dyn ConversationStore,
+    user_id: i64,
+) -> Result<Vec<i64>, PresenceError> {
+    let mut recipients = conversations.contact_ids(user_id).await?;
+    recipients.push(user_id);
+    Ok(recipients)
+}
+
+/// Hands each change to `publish` along with its recipients, for changes worked out away from
+/// any socket, like the sweep's. A change whose recipients can't be looked up is logged and
+/// left out.
+pub async fn announce<F>(
+    conversations: &dyn ConversationStore,
+    updates: &[PresenceUpdate],
+    publish: F,
+) where
+    F: Fn(&[i64], &PresenceUpdate),
+{
+    for update in updates {
+        match recipients(conversations, update.user_id).await {
+            Ok(recipients) => publish(&recipients, update),
+            Err(err) => warn!(
+                "Presence of user {} not published: {:?}",
+                update.user_id, err
+            ),
+        }
+    }
+}
+
+/// Works out the user's presence again and stores it. Returns it when it changed.
+pub async fn refresh(
+    users: &dyn UserRepository,
+    store: &dyn PresenceStore,
+    settings: &PresenceSettings,
+    user_id: i64,
+) -> Result<Option<PresenceUpdate>, PresenceError> {
+    let choice = store.choice(user_id).await?;
+    let devices = store.devices(user_id).await?;
+    let idle_after = Duration::seconds(settings.idle_after_secs as i64);
+    let activity = aggregate(choice.as_deref(), &devices, Utc::now(), idle_after);
+    Ok(users
+        .set_activity(user_id, activity)
+        .await?
+        .map(PresenceUpdate::from))
+}
+
+/// A device of the user connected to the gateway.
+pub async fn connected(
+    users: &dyn UserRepository,
+    store: &dyn PresenceStore,
+    settings: &PresenceSettings,
+    user_id: i64,
+    device: &str,
+) -> Result<Option<PresenceUpdate>, PresenceError> {
+    store
+        .touch(user_id, device, true, settings.heartbeat_timeout())
+        .await?;
+    refresh(users, store, settings, user_id).await
+}
+
+/// Keeps the device counted. `active` says the user did something on it since the last one.
+pub async fn heartbeat(
+    users: &dyn UserRepository,
+    store: &dyn PresenceStore,
+    settings: &PresenceSettings,
+    user_id: i64,
+    device: &str,
+    active: bool,
+) -> Result<Option<PresenceUpdate>, PresenceError> {
+    store
+        .touch(user_id, device, active, settings.heartbeat_timeout())
+        .await?;
+    refresh(users, store, settings, user_id).await
+}
+
+pub async fn disconnected(
+    users: &dyn UserRepository,
+    store: &dyn PresenceStore,
+    settings: &PresenceSettings,
+    user_id: i64,
+    device: &str,
+) -> Result<Option<PresenceUpdate>, PresenceError> {
+    store.remove(user_id, device).await?;
+    refresh(users, store, settings, user_id).await
+}
+
+/// The user picks one of `STATES`. It sticks across their devices and reconnects.
+pub async fn set_state(
+    users: &dyn UserRepository,
+    store: &dyn PresenceStore,
+    settings: &PresenceSettings,
+    user_id: i64,
+    state: &str,
+) -> Result<Option<PresenceUpdate>, PresenceError> {
+    if !STATES.contains(&state) {
+        return Err(PresenceError::InvalidState);
+    }
+    store.set_choice(user_id, state).await?;
+    refresh(users, store, settings, user_id).await
+}
+
+/// Presence of the users sharing a conversation with `user_id`.
+pub async fn contacts(
+    users: &dyn UserRepository,
+    conversations: &dyn ConversationStore,
+    user_id: i64,
+) -> Result<Vec<PresenceUpdate>, PresenceError> {
+    let ids = conversations.contact_ids(user_id).await?;
+    Ok(users
+        .presence(&ids)
+        .await?
+        .into_iter()
+        .map(PresenceUpdate::from)
+        .collect())
+}
+
+/// Catches the users whose devices went quiet, or idle, without the gateway noticing: a server
+/// that went away, a client that stopped sending heartbeats. Returns what changed. A user whose
+/// presence can't be worked out is logged and left for the next sweep.
+pub async fn sweep(
+    users: &dyn UserRepository,
+    store: &dyn PresenceStore,
+    settings: &PresenceSettings,
+) -> Result<Vec<PresenceUpdate>, PresenceError> {
+    let mut changed = Vec::new();
+    for user_id in users.present_ids().await? {
+        match refresh(users, store, settings, user_id).await {
+            Ok(update) => changed.extend(update),
+            Err(err) => warn!(
+                "Failed to refresh the presence of user {}: {:?}",
+                user_id, err
+            ),
+        }
+    }
+    Ok(changed)
+}
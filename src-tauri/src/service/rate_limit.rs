@@ -0,0 +1,148 @@
+use axum::{
+    extract::{ConnectInfo, MatchedPath, Request, State},
+    http::{header, HeaderMap, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::auth_handler::AppState;
+use crate::ephemeral::EphemeralStore;
+use crate::error::{ApiError, ErrorCode};
+use crate::settings::{RateLimitKey, RateLimitRule, RateLimitSettings};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Allowed,
+    Limited { retry_after: Duration },
+}
+
+/// Identity a request is counted under when its rule is keyed by user. `authenticate_request`
+/// inserts it for requests with a valid session; the others fall back to their IP.
+#[derive(Debug, Clone)]
+pub struct RateLimitSubject(pub String);
+
+pub struct RateLimiter {
+    store: Arc<dyn EphemeralStore>,
+    settings: RateLimitSettings,
+}
+
+impl RateLimiter {
+    pub fn new(store: Arc<dyn EphemeralStore>, settings: RateLimitSettings) -> Self {
+        RateLimiter { store, settings }
+    }
+
+    /// The rule guarding `route`, a route template like "/api/v1/signup".
+    pub fn rule_for_route(&self, route: &str) -> Option<(&str, &RateLimitRule)> {
+        self.settings
+            .rules
+            .iter()
+            .find(|(_, rule)| rule.route.as_deref() == Some(route))
+            .map(|(name, rule)| (name.as_str(), rule))
+    }
+
+    /// Counts one hit against `rule` for `subject`. Unknown rules and store failures allow the
+    /// request: a Redis outage shouldn't lock every user out.
+    pub async fn check(&self, rule_name: &str, subject: &str) -> Decision {
+        if !self.settings.enabled {
+            return Decision::Allowed;
+        }
+        let Some(rule) = self.settings.rules.get(rule_name) else {
+            return Decision::Allowed;
+        };
+
+        let key = format!("rate_limit:{}:{}", rule_name, subject);
+        let window = Duration::from_secs(rule.window_secs);
+        match self.store.hit(&key, window, u64::from(rule.limit)).await {
+            Ok(None) => Decision::Allowed,
+            Ok(Some(retry_after)) => Decision::Limited { retry_after },
+            Err(err) => {
+                warn!(
+                    "Rate limit check for {} failed, allowing: {:?}",
+                    rule_name, err
+                );
+                Decision::Allowed
+            }
+        }
+    }
+}
+
+/// Limits requests on routes that have a rule in `rate_limit.rules`. Added as a route layer so
+/// the route template is known.
+pub async fn limit_requests(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let limiter = &state.rate_limit;
+    let Some(route) = request.extensions().get::<MatchedPath>() else {
+        return next.run(request).await;
+    };
+    let Some((name, rule)) = limiter.rule_for_route(route.as_str()) else {
+        return next.run(request).await;
+    };
+
+    let ip = client_ip(&request, state.settings.rate_limit.trust_forwarded_for);
+    let subject = match rule.key {
+        RateLimitKey::Ip => ip,
+        RateLimitKey::User => request
+            .extensions()
+            .get::<RateLimitSubject>()
+            .map(|subject| subject.0.clone())
+            .unwrap_or(ip),
+        RateLimitKey::Route => "all".to_string(),
+    };
+
+    match limiter.check(name, &subject).await {
+        Decision::Allowed => next.run(request).await,
+        Decision::Limited { retry_after } => too_many_requests(retry_after),
+    }
+}
+
+/// Counts a hit against `rule` for a subject the caller picks: for Tauri commands, which never
+/// pass through the HTTP stack, and for handlers that count per email rather than per request.
+pub async fn check_command(state: &AppState, rule: &str, subject: &str) -> Result<(), ApiError> {
+    match state.rate_limit.check(rule, subject).await {
+        Decision::Allowed => Ok(()),
+        Decision::Limited { .. } => Err(ApiError::new(ErrorCode::RateLimited)),
+    }
+}
+
+fn too_many_requests(retry_after: Duration) -> Response {
+    let mut response = ApiError::new(ErrorCode::RateLimited).into_response();
+    // Rounded up so clients never retry a moment too early.
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+    response
+}
+
+fn client_ip(request: &Request, trust_forwarded_for: bool) -> String {
+    if trust_forwarded_for {
+        if let Some(ip) = forwarded_for(request.headers()) {
+            return ip.to_string();
+        }
+    }
+    request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|info| info.0.ip().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+// The left-most address is the original client; proxies append theirs.
+fn forwarded_for(headers: &HeaderMap) -> Option<IpAddr> {
+    headers
+        .get("x-forwarded-for")?
+        .to_str()
+        .ok()?
+        .split(',')
+        .next()?
+        .trim()
+        .parse()
+        .ok()
+}
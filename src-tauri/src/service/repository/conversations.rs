@@ -0,0 +1,731 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use utoipa::ToSchema;
+
+use super::StoreError;
+use crate::auth_handler::Db;
+
+/// Conversation between exactly two users.
+pub const DIRECT: &str = "direct";
+/// Named conversation with an owner, see `GroupEvent` for what can happen to one.
+pub const GROUP: &str = "group";
+
+/// Member roles. Every group has exactly one `OWNER`; members of 1:1 conversations are `MEMBER`s.
+pub const OWNER: &str = "owner";
+pub const ADMIN: &str = "admin";
+pub const MEMBER: &str = "member";
+
+/// Message kinds. `SYSTEM` messages record a `GroupEvent`, sent by whoever caused it.
+pub const TEXT: &str = "text";
+pub const SYSTEM: &str = "system";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conversation {
+    pub id: i64,
+    /// `DIRECT` or `GROUP`.
+    pub kind: String,
+    /// Set for groups only, like `topic` and `avatar_url`.
+    pub name: Option<String>,
+    pub topic: Option<String>,
+    pub avatar_url: Option<String>,
+    /// Sequence number of the latest message, 0 before the first one.
+    pub last_seq: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub user_id: i64,
+    /// `OWNER`, `ADMIN` or `MEMBER`.
+    pub role: String,
+    pub joined_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub id: i64,
+    pub conversation_id: i64,
+    /// Numbers the conversation's messages from 1 without gaps, in the order they were stored.
+    pub seq: i64,
+    /// `None` once the sender's account has been purged.
+    pub sender_id: Option<i64>,
+    /// `TEXT` or `SYSTEM`.
+    pub kind: String,
+    pub body: String,
+    /// The `GroupEvent` of a system message.
+    pub event: Option<serde_json::Value>,
+    pub created_at: DateTime<Utc>,
+    pub edited_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupDetails {
+    pub name: String,
+    pub topic: Option<String>,
+    pub avatar_url: Option<String>,
+}
+
+/// Something that happened to a group. Stores apply it together with the system message that
+/// records it, so a group's history shows every change made to it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GroupEvent {
+    Created {
+        name: String,
+    },
+    /// New name, topic and avatar, whichever of them changed.
+    Updated {
+        name: String,
+        topic: Option<String>,
+        avatar_url: Option<String>,
+    },
+    MembersAdded {
+        user_ids: Vec<i64>,
+    },
+    MemberRemoved {
+        user_id: i64,
+    },
+    MemberLeft {
+        user_id: i64,
+    },
+    RoleChanged {
+        user_id: i64,
+        role: String,
+    },
+    /// The previous owner stays on as an admin.
+    OwnershipTransferred {
+        from: i64,
+        to: i64,
+    },
+    /// Joined through an invite link, with the invite's role.
+    MemberJoined {
+        user_id: i64,
+        invite_id: i64,
+    },
+}
+
+impl GroupEvent {
+    /// Body of the system message, for clients that don't know the event.
+    pub fn summary(&self) -> String {
+        match self {
+            GroupEvent::Created { name } => format!("Group \"{}\" created", name),
+            GroupEvent::Updated { .. } => "Group details changed".to_string(),
+            GroupEvent::MembersAdded { user_ids } => match user_ids.len() {
+                1 => "1 member added".to_string(),
+                count => format!("{} members added", count),
+            },
+            GroupEvent::MemberRemoved { .. } => "A member was removed".to_string(),
+            GroupEvent::MemberLeft { .. } => "A member left".to_string(),
+            GroupEvent::RoleChanged { role, .. } => format!("A member is now {}", role),
+            GroupEvent::OwnershipTransferred { .. } => "Ownership transferred".to_string(),
+            GroupEvent::MemberJoined { .. } => "A member joined through an invite".to_string(),
+        }
+    }
+}
+
+/// Where a page of history starts, by sequence number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryCursor {
+    /// The newest messages.
+    Latest,
+    /// Messages older than the given one.
+    Before(i64),
+    /// Messages newer than the given one.
+    After(i64),
+}
+
+/// A link to join a group, shared as its `code`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invite {
+    pub id: i64,
+    pub conversation_id: i64,
+    pub code: String,
+    /// `None` once the creator's account has been purged.
+    pub created_by: Option<i64>,
+    /// `ADMIN` or `MEMBER`, given to whoever joins through it.
+    pub role: String,
+    /// `None` for no limit.
+    pub max_uses: Option<i32>,
+    pub uses: i32,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Invite {
+    /// Not revoked, expired or used up.
+    pub fn usable(&self, now: DateTime<Utc>) -> bool {
+        self.revoked_at.is_none()
+            && self.expires_at.is_none_or(|expires| now < expires)
+            && self.max_uses.is_none_or(|max| self.uses < max)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewInvite {
+    pub created_by: i64,
+    pub role: String,
+    pub max_uses: Option<i32>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Someone joining through an invite.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InviteUse {
+    /// `None` once their account has been purged.
+    pub user_id: Option<i64>,
+    pub used_at: DateTime<Utc>,
+}
+
+pub fn invite_code() -> String {
+    hex::encode(rand::thread_rng().gen::<[u8; 12]>())
+}
+
+/// Conversations, who is in them and their messages.
+#[async_trait]
+pub trait ConversationStore: Send + Sync {
+    /// The 1:1 conversation between two users, created the first time either asks for it.
+    async fn open_direct(&self, user_id: i64, other_id: i64) -> Result<Conversation, StoreError>;
+    /// A group owned by `owner_id` with the others as members, and its `Created` message.
+    async fn create_group(
+        &self,
+        owner_id: i64,
+        details: &GroupDetails,
+        member_ids: &[i64],
+    ) -> Result<(Conversation, Message), StoreError>;
+    async fn find(&self, id: i64) -> Result<Option<Conversation>, StoreError>;
+    /// Removes the conversation with its members and messages.
+    async fn delete(&self, id: i64) -> Result<(), StoreError>;
+    /// Ids of the members, ordered.
+    async fn member_ids(&self, conversation_id: i64) -> Result<Vec<i64>, StoreError>;
+    /// Members in the order they joined.
+    async fn members(&self, conversation_id: i64) -> Result<Vec<Member>, StoreError>;
+    /// Everyone sharing a conversation with the user, 1:1 or group, ordered by id.
+    async fn contact_ids(&self, user_id: i64) -> Result<Vec<i64>, StoreError>;
+    /// Changes the group as `event` says and stores the system message recording it, from
+    /// `actor_id`. Either both happen or neither does.
+    async fn apply(
+        &self,
+        conversation_id: i64,
+        actor_id: i64,
+        event: &GroupEvent,
+    ) -> Result<Message, StoreError>;
+    /// Stores a message under the conversation's next sequence number.
+    async fn post(
+        &self,
+        conversation_id: i64,
+        sender_id: i64,
+        body: &str,
+    ) -> Result<Message, StoreError>;
+    /// Up to `limit` messages next to `cursor`, oldest first.
+    async fn history(
+        &self,
+        conversation_id: i64,
+        cursor: HistoryCursor,
+        limit: i64,
+    ) -> Result<Vec<Message>, StoreError>;
+    /// A new invite to the group under a fresh code.
+    async fn create_invite(
+        &self,
+        conversation_id: i64,
+        invite: &NewInvite,
+    ) -> Result<Invite, StoreError>;
+    /// The group's invites, newest first, revoked and expired ones included.
+    async fn invites(&self, conversation_id: i64) -> Result<Vec<Invite>, StoreError>;
+    async fn find_invite(&self, code: &str) -> Result<Option<Invite>, StoreError>;
+    /// Stops the invite from being used again. Revoking it twice keeps the first time.
+    async fn revoke_invite(&self, invite_id: i64) -> Result<(), StoreError>;
+    /// Who joined through the invite, latest first.
+    async fn invite_uses(&self, invite_id: i64) -> Result<Vec<InviteUse>, StoreError>;
+    /// Adds `user_id` to the invite's group with its role, counts and logs the use and stores
+    /// the `MemberJoined` message, all or nothing. `None`, with nothing changed, when the invite
+    /// can't be used any more or the user is already a member.
+    async fn redeem_invite(
+        &self,
+        invite_id: i64,
+        user_id: i64,
+    ) -> Result<Option<Message>, StoreError>;
+}
+
+pub struct PgConversationStore {
+    db: Db,
+}
+
+impl PgConversationStore {
+    pub fn new(db: Db) -> Self {
+        PgConversationStore { db }
+    }
+}
+
+// Takes the conversation's next sequence number and stores the message under it. The row lock
+// taken by the update lines up concurrent senders until their transaction ends, so numbers are
+// handed out once and in commit order.
+async fn insert_message(
+    conn: &mut PgConnection,
+    conversation_id: i64,
+    sender_id: i64,
+    body: &str,
+    event: Option<&GroupEvent>,
+) -> Result<Message, StoreError> {
+    let seq = sqlx::query_scalar!(
+        "UPDATE conversations SET last_seq = last_seq + 1 WHERE id = $1 RETURNING last_seq",
+        conversation_id,
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    let (kind, event) = match event {
+        Some(event) => (
+            SYSTEM,
+            Some(serde_json::to_value(event).map_err(|err| sqlx::Error::Encode(err.into()))?),
+        ),
+        None => (TEXT, None),
+    };
+    Ok(sqlx::query_as!(
+        Message,
+        r#"INSERT INTO messages (conversation_id, seq, sender_id, kind, body, event)
+           VALUES ($1, $2, $3, $4, $5, $6)
+           RETURNING id, conversation_id, seq, sender_id, kind, body, event,
+                     created_at AS "created_at: DateTime<Utc>",
+                     edited_at AS "edited_at: DateTime<Utc>""#,
+        conversation_id,
+        seq,
+        sender_id,
+        kind,
+        body,
+        event,
+    )
+    .fetch_one(&mut *conn)
+    .await?)
+}
+
+#[async_trait]
+impl ConversationStore for PgConversationStore {
+    async fn open_direct(&self, user_id: i64, other_id: i64) -> Result<Conversation, StoreError> {
+        let (low, high) = (user_id.min(other_id), user_id.max(other_id));
+        let mut tx = self.db.begin().await?;
+        // Whoever loses a race for the same pair finds the winner's row below.
+        let created = sqlx::query_scalar!(
+            "INSERT INTO conversations (kind, direct_low, direct_high) VALUES ('direct', $1, $2)
+             ON CONFLICT (direct_low, direct_high) DO NOTHING
+             RETURNING id",
+            low,
+            high,
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if let Some(id) = created {
+            sqlx::query!(
+                "INSERT INTO conversation_members (conversation_id, user_id)
+                 VALUES ($1, $2), ($1, $3)",
+                id,
+                low,
+                high,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        let conversation = sqlx::query_as!(
+            Conversation,
+            r#"SELECT id, kind, name, topic, avatar_url, last_seq,
+                      created_at AS "created_at: DateTime<Utc>"
+               FROM conversations WHERE direct_low = $1 AND direct_high = $2"#,
+            low,
+            high,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(conversation)
+    }
+
+    async fn create_group(
+        &self,
+        owner_id: i64,
+        details: &GroupDetails,
+        member_ids: &[i64],
+    ) -> Result<(Conversation, Message), StoreError> {
+        let mut tx = self.db.begin().await?;
+        let id = sqlx::query_scalar!(
+            "INSERT INTO conversations (kind, name, topic, avatar_url) VALUES ('group', $1, $2, $3)
+             RETURNING id",
+            &details.name,
+            details.topic.as_deref(),
+            details.avatar_url.as_deref(),
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "INSERT INTO conversation_members (conversation_id, user_id, role)
+             VALUES ($1, $2, 'owner')",
+            id,
+            owner_id,
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "INSERT INTO conversation_members (conversation_id, user_id)
+             SELECT $1, member_id FROM unnest($2::int8[]) AS member_id
+             ON CONFLICT DO NOTHING",
+            id,
+            member_ids,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let event = GroupEvent::Created {
+            name: details.name.clone(),
+        };
+        let message = insert_message(&mut tx, id, owner_id, &event.summary(), Some(&event)).await?;
+        let conversation = sqlx::query_as!(
+            Conversation,
+            r#"SELECT id, kind, name, topic, avatar_url, last_seq,
+                      created_at AS "created_at: DateTime<Utc>"
+               FROM conversations WHERE id = $1"#,
+            id,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok((conversation, message))
+    }
+
+    async fn find(&self, id: i64) -> Result<Option<Conversation>, StoreError> {
+        Ok(sqlx::query_as!(
+            Conversation,
+            r#"SELECT id, kind, name, topic, avatar_url, last_seq,
+                      created_at AS "created_at: DateTime<Utc>"
+               FROM conversations WHERE id = $1"#,
+            id,
+        )
+        .fetch_optional(&self.db)
+        .await?)
+    }
+
+    async fn delete(&self, id: i64) -> Result<(), StoreError> {
+        sqlx::query!("DELETE FROM conversations WHERE id = $1", id)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    async fn member_ids(&self, conversation_id: i64) -> Result<Vec<i64>, StoreError> {
+        Ok(sqlx::query_scalar!(
+            "SELECT user_id FROM conversation_members WHERE conversation_id = $1 ORDER BY user_id",
+            conversation_id,
+        )
+        .fetch_all(&self.db)
+        .await?)
+    }
+
+    async fn members(&self, conversation_id: i64) -> Result<Vec<Member>, StoreError> {
+        Ok(sqlx::query_as!(
+            Member,
+            r#"SELECT user_id, role, joined_at AS "joined_at: DateTime<Utc>"
+               FROM conversation_members
+               WHERE conversation_id = $1
+               ORDER BY joined_at, user_id"#,
+            conversation_id,
+        )
+        .fetch_all(&self.db)
+        .await?)
+    }
+
+    async fn contact_ids(&self, user_id: i64) -> Result<Vec<i64>, StoreError> {
+        Ok(sqlx::query_scalar!(
+            "SELECT DISTINCT other.user_id
+             FROM conversation_members mine
+             JOIN conversation_members other ON other.conversation_id = mine.conversation_id
+             WHERE mine.user_id = $1 AND other.user_id <> $1
+             ORDER BY other.user_id",
+            user_id,
+        )
+        .fetch_all(&self.db)
+        .await?)
+    }
+
+    async fn apply(
+        &self,
+        conversation_id: i64,
+        actor_id: i64,
+        event: &GroupEvent,
+    ) -> Result<Message, StoreError> {
+        let mut tx = self.db.begin().await?;
+        match event {
+            // Only create_group makes groups, and only redeem_invite lets people join.
+            GroupEvent::Created { .. } | GroupEvent::MemberJoined { .. } => {}
+            GroupEvent::Updated {
+                name,
+                topic,
+                avatar_url,
+            } => {
+                sqlx::query!(
+                    "UPDATE conversations SET name = $2, topic = $3, avatar_url = $4
+                     WHERE id = $1",
+                    conversation_id,
+                    name,
+                    topic.as_deref(),
+                    avatar_url.as_deref(),
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+            GroupEvent::MembersAdded { user_ids } => {
+                sqlx::query!(
+                    "INSERT INTO conversation_members (conversation_id, user_id)
+                     SELECT $1, member_id FROM unnest($2::int8[]) AS member_id
+                     ON CONFLICT DO NOTHING",
+                    conversation_id,
+                    user_ids,
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+            GroupEvent::MemberRemoved { user_id } | GroupEvent::MemberLeft { user_id } => {
+                sqlx::query!(
+                    "DELETE FROM conversation_members WHERE conversation_id = $1 AND user_id = $2",
+                    conversation_id,
+                    user_id,
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+            GroupEvent::RoleChanged { user_id, role } => {
+                sqlx::query!(
+                    "UPDATE conversation_members SET role = $3
+                     WHERE conversation_id = $1 AND user_id = $2",
+                    conversation_id,
+                    user_id,
+                    role,
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+            GroupEvent::OwnershipTransferred { from, to } => {
+                // Demoted first, there can only be one owner at a time.
+                sqlx::query!(
+                    "UPDATE conversation_members SET role = 'admin'
+                     WHERE conversation_id = $1 AND user_id = $2",
+                    conversation_id,
+                    from,
+                )
+                .execute(&mut *tx)
+                .await?;
+                sqlx::query!(
+                    "UPDATE conversation_members SET role = 'owner'
+                     WHERE conversation_id = $1 AND user_id = $2",
+                    conversation_id,
+                    to,
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+        let message = insert_message(
+            &mut tx,
+            conversation_id,
+            actor_id,
+            &event.summary(),
+            Some(event),
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(message)
+    }
+
+    async fn post(
+        &self,
+        conversation_id: i64,
+        sender_id: i64,
+        body: &str,
+    ) -> Result<Message, StoreError> {
+        let mut tx = self.db.begin().await?;
+        let message = insert_message(&mut tx, conversation_id, sender_id, body, None).await?;
+        tx.commit().await?;
+        Ok(message)
+    }
+
+    async fn history(
+        &self,
+        conversation_id: i64,
+        cursor: HistoryCursor,
+        limit: i64,
+    ) -> Result<Vec<Message>, StoreError> {
+        let before = match cursor {
+            HistoryCursor::Latest => None,
+            HistoryCursor::Before(seq) => Some(seq),
+            HistoryCursor::After(after) => {
+                return Ok(sqlx::query_as!(
+                    Message,
+                    r#"SELECT id, conversation_id, seq, sender_id, kind, body, event,
+                              created_at AS "created_at: DateTime<Utc>",
+                              edited_at AS "edited_at: DateTime<Utc>"
+                       FROM messages
+                       WHERE conversation_id = $1 AND seq > $2
+                       ORDER BY seq
+                       LIMIT $3"#,
+                    conversation_id,
+                    after,
+                    limit,
+                )
+                .fetch_all(&self.db)
+                .await?);
+            }
+        };
+
+        // Newest first, so the page is the one right before the cursor, then put back in order.
+        let mut messages = sqlx::query_as!(
+            Message,
+            r#"SELECT id, conversation_id, seq, sender_id, kind, body, event,
+                      created_at AS "created_at: DateTime<Utc>",
+                      edited_at AS "edited_at: DateTime<Utc>"
+               FROM messages
+               WHERE conversation_id = $1 AND ($2::int8 IS NULL OR seq < $2)
+               ORDER BY seq DESC
+               LIMIT $3"#,
+            conversation_id,
+            before,
+            limit,
+        )
+        .fetch_all(&self.db)
+        .await?;
+        messages.reverse();
+        Ok(messages)
+    }
+
+    async fn create_invite(
+        &self,
+        conversation_id: i64,
+        invite: &NewInvite,
+    ) -> Result<Invite, StoreError> {
+        Ok(sqlx::query_as!(
+            Invite,
+            r#"INSERT INTO group_invites
+                   (conversation_id, code, created_by, role, max_uses, expires_at)
+               VALUES ($1, $2, $3, $4, $5, $6)
+               RETURNING id, conversation_id, code, created_by, role, max_uses, uses,
+                         expires_at AS "expires_at: DateTime<Utc>",
+                         revoked_at AS "revoked_at: DateTime<Utc>",
+                         created_at AS "created_at: DateTime<Utc>""#,
+            conversation_id,
+            invite_code(),
+            invite.created_by,
+            &invite.role,
+            invite.max_uses,
+            invite.expires_at,
+        )
+        .fetch_one(&self.db)
+        .await?)
+    }
+
+    async fn invites(&self, conversation_id: i64) -> Result<Vec<Invite>, StoreError> {
+        Ok(sqlx::query_as!(
+            Invite,
+            r#"SELECT id, conversation_id, code, created_by, role, max_uses, uses,
+                      expires_at AS "expires_at: DateTime<Utc>",
+                      revoked_at AS "revoked_at: DateTime<Utc>",
+                      created_at AS "created_at: DateTime<Utc>"
+               FROM group_invites
+               WHERE conversation_id = $1
+               ORDER BY id DESC"#,
+            conversation_id,
+        )
+        .fetch_all(&self.db)
+        .await?)
+    }
+
+    async fn find_invite(&self, code: &str) -> Result<Option<Invite>, StoreError> {
+        Ok(sqlx::query_as!(
+            Invite,
+            r#"SELECT id, conversation_id, code, created_by, role, max_uses, uses,
+                      expires_at AS "expires_at: DateTime<Utc>",
+                      revoked_at AS "revoked_at: DateTime<Utc>",
+                      created_at AS "created_at: DateTime<Utc>"
+               FROM group_invites WHERE code = $1"#,
+            code,
+        )
+        .fetch_optional(&self.db)
+        .await?)
+    }
+
+    async fn revoke_invite(&self, invite_id: i64) -> Result<(), StoreError> {
+        sqlx::query!(
+            "UPDATE group_invites SET revoked_at = now() WHERE id = $1 AND revoked_at IS NULL",
+            invite_id,
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    async fn invite_uses(&self, invite_id: i64) -> Result<Vec<InviteUse>, StoreError> {
+        Ok(sqlx::query_as!(
+            InviteUse,
+            r#"SELECT user_id, used_at AS "used_at: DateTime<Utc>"
+               FROM group_invite_uses
+               WHERE invite_id = $1
+               ORDER BY id DESC"#,
+            invite_id,
+        )
+        .fetch_all(&self.db)
+        .await?)
+    }
+
+    async fn redeem_invite(
+        &self,
+        invite_id: i64,
+        user_id: i64,
+    ) -> Result<Option<Message>, StoreError> {
+        let mut tx = self.db.begin().await?;
+        // The row lock lines up concurrent joins, so the last use is only handed out once.
+        let Some(invite) = sqlx::query!(
+            "UPDATE group_invites SET uses = uses + 1
+             WHERE id = $1
+               AND revoked_at IS NULL
+               AND (expires_at IS NULL OR expires_at > now())
+               AND (max_uses IS NULL OR uses < max_uses)
+             RETURNING conversation_id, role",
+            invite_id,
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(None);
+        };
+        let joined = sqlx::query!(
+            "INSERT INTO conversation_members (conversation_id, user_id, role) VALUES ($1, $2, $3)
+             ON CONFLICT DO NOTHING",
+            invite.conversation_id,
+            user_id,
+            invite.role,
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if joined == 0 {
+            // Dropping the transaction takes the use back.
+            return Ok(None);
+        }
+        sqlx::query!(
+            "INSERT INTO group_invite_uses (invite_id, user_id) VALUES ($1, $2)",
+            invite_id,
+            user_id,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let event = GroupEvent::MemberJoined { user_id, invite_id };
+        let message = insert_message(
+            &mut tx,
+            invite.conversation_id,
+            user_id,
+            &event.summary(),
+            Some(&event),
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(Some(message))
+    }
+}
@@ -0,0 +1,30 @@
+// Where accounts, sign-ups, sessions, presence, conversations and outgoing emails are kept, one
+// module per store. Everything is re-exported here, so callers only name `repository::...`.
+use thiserror::Error;
+
+mod conversations;
+mod outbox;
+mod presence;
+mod sessions;
+mod users;
+mod verification;
+
+pub use conversations::*;
+pub use outbox::*;
+pub use presence::*;
+pub use sessions::*;
+pub use users::*;
+pub use verification::*;
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+
+    #[error("Redis error: {0}")]
+    Redis(#[from] redis::RedisError),
+
+    /// A unique username or email already belongs to someone else.
+    #[error("{0} already taken")]
+    Conflict(&'static str),
+}
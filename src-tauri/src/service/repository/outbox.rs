@@ -0,0 +1,126 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use super::StoreError;
+use crate::auth_handler::Db;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewEmail {
+    /// Mailbox in "Name <address>" form.
+    pub recipient: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// An email in the outbox, not sent yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueuedEmail {
+    pub id: i64,
+    pub recipient: String,
+    pub subject: String,
+    pub body: String,
+    /// Failed attempts so far.
+    pub attempts: i32,
+}
+
+/// Emails waiting to be sent. Each one is queued before its first attempt, so one that couldn't
+/// go out right away is retried later instead of being lost.
+#[async_trait]
+pub trait EmailOutbox: Send + Sync {
+    /// Queues `email` until it is sent or `expires_at` passes.
+    async fn enqueue(
+        &self,
+        email: &NewEmail,
+        expires_at: DateTime<Utc>,
+    ) -> Result<QueuedEmail, StoreError>;
+    /// Unexpired emails whose next attempt is due, oldest first.
+    async fn due(&self, limit: i64) -> Result<Vec<QueuedEmail>, StoreError>;
+    /// Removes an email that went out.
+    async fn sent(&self, id: i64) -> Result<(), StoreError>;
+    /// Records a failed attempt, the next one waits until `retry_at`.
+    async fn failed(&self, id: i64, error: &str, retry_at: DateTime<Utc>)
+        -> Result<(), StoreError>;
+    /// Drops emails that expired before they could be sent. Returns how many there were.
+    async fn prune_expired(&self) -> Result<u64, StoreError>;
+}
+
+pub struct PgEmailOutbox {
+    db: Db,
+}
+
+impl PgEmailOutbox {
+    pub fn new(db: Db) -> Self {
+        PgEmailOutbox { db }
+    }
+}
+
+#[async_trait]
+impl EmailOutbox for PgEmailOutbox {
+    async fn enqueue(
+        &self,
+        email: &NewEmail,
+        expires_at: DateTime<Utc>,
+    ) -> Result<QueuedEmail, StoreError> {
+        Ok(sqlx::query_as!(
+            QueuedEmail,
+            "INSERT INTO email_outbox (recipient, subject, body, expires_at)
+             VALUES ($1, $2, $3, $4)
+             RETURNING id, recipient, subject, body, attempts",
+            email.recipient,
+            email.subject,
+            email.body,
+            expires_at,
+        )
+        .fetch_one(&self.db)
+        .await?)
+    }
+
+    async fn due(&self, limit: i64) -> Result<Vec<QueuedEmail>, StoreError> {
+        Ok(sqlx::query_as!(
+            QueuedEmail,
+            "SELECT id, recipient, subject, body, attempts
+             FROM email_outbox
+             WHERE next_attempt_at <= now() AND expires_at > now()
+             ORDER BY next_attempt_at, id
+             LIMIT $1",
+            limit,
+        )
+        .fetch_all(&self.db)
+        .await?)
+    }
+
+    async fn sent(&self, id: i64) -> Result<(), StoreError> {
+        sqlx::query!("DELETE FROM email_outbox WHERE id = $1", id)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    async fn failed(
+        &self,
+        id: i64,
+        error: &str,
+        retry_at: DateTime<Utc>,
+    ) -> Result<(), StoreError> {
+        sqlx::query!(
+            "UPDATE email_outbox
+             SET attempts = attempts + 1, last_error = $2, next_attempt_at = $3
+             WHERE id = $1",
+            id,
+            error,
+            retry_at,
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    async fn prune_expired(&self) -> Result<u64, StoreError> {
+        Ok(
+            sqlx::query!("DELETE FROM email_outbox WHERE expires_at <= now()")
+                .execute(&self.db)
+                .await?
+                .rows_affected(),
+        )
+    }
+}
@@ -0,0 +1,103 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::StoreError;
+use crate::ephemeral::EphemeralStore;
+
+/// Gateway connections by user, each one a device. Entries expire unless heartbeats keep them
+/// alive, so the devices of a server that went away without disconnecting them don't linger.
+#[async_trait]
+pub trait PresenceStore: Send + Sync {
+    /// Registers the device or keeps it alive for another `ttl`. `active` records that the user
+    /// did something on it just now.
+    async fn touch(
+        &self,
+        user_id: i64,
+        device: &str,
+        active: bool,
+        ttl: Duration,
+    ) -> Result<(), StoreError>;
+    async fn remove(&self, user_id: i64, device: &str) -> Result<(), StoreError>;
+    /// When the user was last active on each of their live devices.
+    async fn devices(&self, user_id: i64) -> Result<Vec<DateTime<Utc>>, StoreError>;
+    /// The state the user picked, `None` if they never did.
+    async fn choice(&self, user_id: i64) -> Result<Option<String>, StoreError>;
+    async fn set_choice(&self, user_id: i64, state: &str) -> Result<(), StoreError>;
+}
+
+/// `PresenceStore` on top of whichever `EphemeralStore` is configured.
+pub struct EphemeralPresenceStore {
+    store: Arc<dyn EphemeralStore>,
+}
+
+impl EphemeralPresenceStore {
+    pub fn new(store: Arc<dyn EphemeralStore>) -> Self {
+        EphemeralPresenceStore { store }
+    }
+}
+
+// Ids of the user's devices. Entries whose device key expired are dropped by `devices`.
+fn devices_key(user_id: i64) -> String {
+    format!("presence_devices:{}", user_id)
+}
+
+// When the user was last active on the device, in ms since the epoch.
+fn device_key(user_id: i64, device: &str) -> String {
+    format!("presence_device:{}:{}", user_id, device)
+}
+
+fn choice_key(user_id: i64) -> String {
+    format!("presence_choice:{}", user_id)
+}
+
+#[async_trait]
+impl PresenceStore for EphemeralPresenceStore {
+    async fn touch(
+        &self,
+        user_id: i64,
+        device: &str,
+        active: bool,
+        ttl: Duration,
+    ) -> Result<(), StoreError> {
+        let key = device_key(user_id, device);
+        let last_active = match active {
+            true => None,
+            false => self.store.get(&key).await?,
+        };
+        let last_active = last_active.unwrap_or_else(|| Utc::now().timestamp_millis().to_string());
+        self.store.set_add(&devices_key(user_id), device).await?;
+        self.store.set(&key, &last_active, Some(ttl)).await
+    }
+
+    async fn remove(&self, user_id: i64, device: &str) -> Result<(), StoreError> {
+        self.store.delete(&[device_key(user_id, device)]).await?;
+        self.store.set_remove(&devices_key(user_id), device).await
+    }
+
+    async fn devices(&self, user_id: i64) -> Result<Vec<DateTime<Utc>>, StoreError> {
+        let index = devices_key(user_id);
+        let mut devices = Vec::new();
+        for device in self.store.set_members(&index).await? {
+            match self.store.get(&device_key(user_id, &device)).await? {
+                Some(millis) => devices.extend(
+                    millis
+                        .parse()
+                        .ok()
+                        .and_then(DateTime::<Utc>::from_timestamp_millis),
+                ),
+                None => self.store.set_remove(&index, &device).await?,
+            }
+        }
+        Ok(devices)
+    }
+
+    async fn choice(&self, user_id: i64) -> Result<Option<String>, StoreError> {
+        self.store.get(&choice_key(user_id)).await
+    }
+
+    async fn set_choice(&self, user_id: i64, state: &str) -> Result<(), StoreError> {
+        self.store.set(&choice_key(user_id), state, None).await
+    }
+}
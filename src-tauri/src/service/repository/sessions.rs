@@ -0,0 +1,107 @@
+use async_trait::async_trait;
+use rand::Rng;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::StoreError;
+use crate::ephemeral::EphemeralStore;
+
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    /// Returns the new session token.
+    async fn create(&self, user_id: i64, ttl: Duration) -> Result<String, StoreError>;
+    async fn user_id(&self, token: &str) -> Result<Option<i64>, StoreError>;
+    async fn revoke(&self, token: &str) -> Result<(), StoreError>;
+    /// Returns how many sessions were revoked.
+    async fn revoke_all(&self, user_id: i64) -> Result<u64, StoreError>;
+    /// Forgets sessions that have expired. Returns how many there were.
+    async fn prune_expired(&self) -> Result<u64, StoreError>;
+}
+
+pub fn session_token() -> String {
+    hex::encode(rand::thread_rng().gen::<[u8; 32]>())
+}
+
+/// `SessionStore` on top of whichever `EphemeralStore` is configured.
+pub struct EphemeralSessionStore {
+    store: Arc<dyn EphemeralStore>,
+}
+
+impl EphemeralSessionStore {
+    pub fn new(store: Arc<dyn EphemeralStore>) -> Self {
+        EphemeralSessionStore { store }
+    }
+}
+
+fn session_key(token: &str) -> String {
+    format!("session:{}", token)
+}
+
+const USER_SESSIONS_PREFIX: &str = "user_sessions:";
+
+// Tokens of every session a user has, so they can all be revoked at once. Entries whose
+// session already expired are harmless and get cleared by revoke_all or prune_expired.
+fn user_sessions_key(user_id: i64) -> String {
+    format!("{}{}", USER_SESSIONS_PREFIX, user_id)
+}
+
+#[async_trait]
+impl SessionStore for EphemeralSessionStore {
+    async fn create(&self, user_id: i64, ttl: Duration) -> Result<String, StoreError> {
+        let token = session_token();
+        // Indexed first: a session that can't be found by revoke_all must not exist.
+        self.store
+            .set_add(&user_sessions_key(user_id), &token)
+            .await?;
+        self.store
+            .set(&session_key(&token), &user_id.to_string(), Some(ttl))
+            .await?;
+        Ok(token)
+    }
+
+    async fn user_id(&self, token: &str) -> Result<Option<i64>, StoreError> {
+        Ok(self
+            .store
+            .get(&session_key(token))
+            .await?
+            .and_then(|user_id| user_id.parse().ok()))
+    }
+
+    async fn revoke(&self, token: &str) -> Result<(), StoreError> {
+        let user_id = self.store.take(&session_key(token)).await?;
+        if let Some(user_id) = user_id.and_then(|user_id| user_id.parse::<i64>().ok()) {
+            self.store
+                .set_remove(&user_sessions_key(user_id), token)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn revoke_all(&self, user_id: i64) -> Result<u64, StoreError> {
+        let index = user_sessions_key(user_id);
+        let sessions: Vec<String> = self
+            .store
+            .set_members(&index)
+            .await?
+            .iter()
+            .map(|token| session_key(token))
+            .collect();
+        let revoked = self.store.delete(&sessions).await?;
+        self.store.delete(&[index]).await?;
+        Ok(revoked)
+    }
+
+    async fn prune_expired(&self) -> Result<u64, StoreError> {
+        // The sessions themselves expire with their keys, only the index entries stay behind.
+        let mut pruned = 0;
+        for index in self.store.scan(USER_SESSIONS_PREFIX).await? {
+            for token in self.store.set_members(&index).await? {
+                if self.store.get(&session_key(&token)).await?.is_none() {
+                    self.store.set_remove(&index, &token).await?;
+                    pruned += 1;
+                }
+            }
+        }
+        Ok(pruned)
+    }
+}
@@ -0,0 +1,318 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::time::Duration;
+
+use super::StoreError;
+use crate::auth_handler::Db;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct User {
+    pub id: i64,
+    pub name: String,
+    pub username: String,
+    pub email: String,
+    /// Argon2 hash, `None` for accounts that only sign in through a provider.
+    pub password: Option<String>,
+    /// One of `ACTIVE`, `SUSPENDED` or `DELETED`.
+    pub status: String,
+    pub user_verified: bool,
+    pub totp_secret: String,
+    pub provider: Option<String>,
+    pub provider_user_id: Option<String>,
+    /// Password logins are refused until the user sets a new password.
+    pub password_reset_required: bool,
+}
+
+pub const ACTIVE: &str = "active";
+pub const SUSPENDED: &str = "suspended";
+/// Deleted accounts keep their row until they are purged.
+pub const DELETED: &str = "deleted";
+
+/// Activities: what other users see of someone, see `presence`. `INVISIBLE` is only ever picked,
+/// it shows as `OFFLINE`.
+pub const ONLINE: &str = "online";
+pub const AWAY: &str = "away";
+pub const DND: &str = "dnd";
+pub const INVISIBLE: &str = "invisible";
+pub const OFFLINE: &str = "offline";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Presence {
+    pub user_id: i64,
+    /// `ONLINE`, `AWAY`, `DND` or `OFFLINE`.
+    pub activity: String,
+    /// When they last went offline, `None` if they never have.
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+/// Filters for `UserRepository::search`, ordered by id.
+#[derive(Debug, Clone)]
+pub struct UserSearch {
+    /// Matched case-insensitively against the name, username and email.
+    pub text: Option<String>,
+    pub status: Option<String>,
+    /// Only accounts deleted at least this long ago.
+    pub deleted_for: Option<Duration>,
+    pub limit: i64,
+    pub offset: i64,
+}
+
+impl Default for UserSearch {
+    fn default() -> Self {
+        UserSearch {
+            text: None,
+            status: None,
+            deleted_for: None,
+            limit: 50,
+            offset: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NewUser {
+    pub name: String,
+    pub username: String,
+    pub email: String,
+    pub password: Option<String>,
+    pub user_verified: bool,
+    pub totp_secret: String,
+}
+
+/// Accounts. Usernames and emails are matched case-insensitively.
+#[async_trait]
+pub trait UserRepository: Send + Sync {
+    async fn find_by_id(&self, id: i64) -> Result<Option<User>, StoreError>;
+    async fn find_by_username(&self, username: &str) -> Result<Option<User>, StoreError>;
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>, StoreError>;
+    /// Fails with `StoreError::Conflict` when the username or email is taken.
+    async fn create(&self, user: &NewUser) -> Result<i64, StoreError>;
+    /// Fails with `StoreError::Conflict` when the username belongs to someone else.
+    async fn set_username(&self, id: i64, username: &str) -> Result<(), StoreError>;
+    async fn record_login(&self, id: i64) -> Result<(), StoreError>;
+    async fn mark_verified(&self, email: &str) -> Result<(), StoreError>;
+    async fn search(&self, search: &UserSearch) -> Result<Vec<User>, StoreError>;
+    /// Moving to `DELETED` starts the grace period before the account is purged.
+    async fn set_status(&self, id: i64, status: &str) -> Result<(), StoreError>;
+    async fn set_password_reset_required(&self, id: i64, required: bool) -> Result<(), StoreError>;
+    /// Removes the row for good.
+    async fn delete(&self, id: i64) -> Result<(), StoreError>;
+    /// Removes password sign-ups still unverified `older_than` after they were made. Returns
+    /// their ids.
+    async fn delete_unverified(&self, older_than: Duration) -> Result<Vec<i64>, StoreError>;
+    /// Stores the user's activity. Going offline stamps `last_seen`. Returns the new presence,
+    /// or `None` when the activity was that already.
+    async fn set_activity(&self, id: i64, activity: &str) -> Result<Option<Presence>, StoreError>;
+    /// Presence of the users with these ids, ordered by id.
+    async fn presence(&self, ids: &[i64]) -> Result<Vec<Presence>, StoreError>;
+    /// Ids of users who aren't `OFFLINE`.
+    async fn present_ids(&self) -> Result<Vec<i64>, StoreError>;
+}
+
+pub struct PgUserRepository {
+    db: Db,
+}
+
+impl PgUserRepository {
+    pub fn new(db: Db) -> Self {
+        PgUserRepository { db }
+    }
+}
+
+#[async_trait]
+impl UserRepository for PgUserRepository {
+    async fn find_by_id(&self, id: i64) -> Result<Option<User>, StoreError> {
+        Ok(sqlx::query_as!(
+            User,
+            "SELECT id, name, username, email, password, status, user_verified, totp_secret,
+                    provider, provider_user_id, password_reset_required
+             FROM users WHERE id = $1",
+            id,
+        )
+        .fetch_optional(&self.db)
+        .await?)
+    }
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<User>, StoreError> {
+        Ok(sqlx::query_as!(
+            User,
+            "SELECT id, name, username, email, password, status, user_verified, totp_secret,
+                    provider, provider_user_id, password_reset_required
+             FROM users WHERE lower(username) = lower($1)",
+            username,
+        )
+        .fetch_optional(&self.db)
+        .await?)
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>, StoreError> {
+        Ok(sqlx::query_as!(
+            User,
+            "SELECT id, name, username, email, password, status, user_verified, totp_secret,
+                    provider, provider_user_id, password_reset_required
+             FROM users WHERE lower(email) = lower($1)",
+            email,
+        )
+        .fetch_optional(&self.db)
+        .await?)
+    }
+
+    async fn create(&self, user: &NewUser) -> Result<i64, StoreError> {
+        sqlx::query_scalar!(
+            "INSERT INTO users (name, username, email, password, status, activity, user_verified, totp_secret, personalization)
+             VALUES ($1, $2, $3, $4, 'active', 'offline', $5, $6, '{}')
+             RETURNING id",
+            &user.name,
+            &user.username,
+            &user.email,
+            user.password.as_deref(),
+            user.user_verified,
+            &user.totp_secret,
+        )
+        .fetch_one(&self.db)
+        .await
+        .map_err(|err| match err.as_database_error().and_then(|db_err| db_err.constraint()) {
+            Some("users_username_lower_key") => StoreError::Conflict("username"),
+            Some("users_email_lower_key") => StoreError::Conflict("email"),
+            _ => StoreError::Database(err),
+        })
+    }
+
+    async fn set_username(&self, id: i64, username: &str) -> Result<(), StoreError> {
+        sqlx::query!("UPDATE users SET username = $1 WHERE id = $2", username, id,)
+            .execute(&self.db)
+            .await
+            .map_err(|err| {
+                match err
+                    .as_database_error()
+                    .and_then(|db_err| db_err.constraint())
+                {
+                    Some("users_username_lower_key") => StoreError::Conflict("username"),
+                    _ => StoreError::Database(err),
+                }
+            })?;
+        Ok(())
+    }
+
+    async fn record_login(&self, id: i64) -> Result<(), StoreError> {
+        sqlx::query!("UPDATE users SET last_login = now() WHERE id = $1", id)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    async fn mark_verified(&self, email: &str) -> Result<(), StoreError> {
+        sqlx::query!(
+            "UPDATE users SET user_verified = true WHERE lower(email) = lower($1)",
+            email,
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    async fn search(&self, search: &UserSearch) -> Result<Vec<User>, StoreError> {
+        Ok(sqlx::query_as!(
+            User,
+            "SELECT id, name, username, email, password, status, user_verified, totp_secret,
+                    provider, provider_user_id, password_reset_required
+             FROM users
+             WHERE ($1::text IS NULL
+                    OR strpos(lower(name), lower($1)) > 0
+                    OR strpos(lower(username), lower($1)) > 0
+                    OR strpos(lower(email), lower($1)) > 0)
+               AND ($2::text IS NULL OR status = $2)
+               AND ($5::float8 IS NULL OR deleted_at < now() - make_interval(secs => $5))
+             ORDER BY id
+             LIMIT $3 OFFSET $4",
+            search.text.as_deref(),
+            search.status.as_deref(),
+            search.limit,
+            search.offset,
+            search
+                .deleted_for
+                .map(|deleted_for| deleted_for.as_secs_f64()),
+        )
+        .fetch_all(&self.db)
+        .await?)
+    }
+
+    async fn set_status(&self, id: i64, status: &str) -> Result<(), StoreError> {
+        sqlx::query!(
+            "UPDATE users
+             SET status = $1,
+                 deleted_at = CASE WHEN $1 = 'deleted' THEN coalesce(deleted_at, now()) END
+             WHERE id = $2",
+            status,
+            id,
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    async fn set_password_reset_required(&self, id: i64, required: bool) -> Result<(), StoreError> {
+        sqlx::query!(
+            "UPDATE users SET password_reset_required = $1 WHERE id = $2",
+            required,
+            id,
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, id: i64) -> Result<(), StoreError> {
+        sqlx::query!("DELETE FROM users WHERE id = $1", id)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_unverified(&self, older_than: Duration) -> Result<Vec<i64>, StoreError> {
+        // Provider accounts are verified by their provider and have no password.
+        Ok(sqlx::query_scalar!(
+            "DELETE FROM users
+             WHERE NOT user_verified AND password IS NOT NULL AND status = 'active'
+               AND created_at < now() - make_interval(secs => $1)
+             RETURNING id",
+            older_than.as_secs_f64(),
+        )
+        .fetch_all(&self.db)
+        .await?)
+    }
+
+    async fn set_activity(&self, id: i64, activity: &str) -> Result<Option<Presence>, StoreError> {
+        Ok(sqlx::query_as!(
+            Presence,
+            r#"UPDATE users
+               SET activity = $2,
+                   last_seen = CASE WHEN $2 = 'offline' THEN now() ELSE last_seen END
+               WHERE id = $1 AND activity <> $2
+               RETURNING id AS user_id, activity, last_seen AS "last_seen: DateTime<Utc>""#,
+            id,
+            activity,
+        )
+        .fetch_optional(&self.db)
+        .await?)
+    }
+
+    async fn presence(&self, ids: &[i64]) -> Result<Vec<Presence>, StoreError> {
+        Ok(sqlx::query_as!(
+            Presence,
+            r#"SELECT id AS user_id, activity, last_seen AS "last_seen: DateTime<Utc>"
+               FROM users WHERE id = ANY($1) ORDER BY id"#,
+            ids,
+        )
+        .fetch_all(&self.db)
+        .await?)
+    }
+
+    async fn present_ids(&self) -> Result<Vec<i64>, StoreError> {
+        Ok(
+            sqlx::query_scalar!("SELECT id FROM users WHERE activity <> 'offline' ORDER BY id")
+                .fetch_all(&self.db)
+                .await?,
+        )
+    }
+}
@@ -0,0 +1,120 @@
+use async_trait::async_trait;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::StoreError;
+use crate::ephemeral::EphemeralStore;
+
+/// The sign-up waiting for its emailed code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingVerification {
+    pub email: String,
+    pub name: String,
+    /// Code of the group invite the sign-up came through, redeemed once the email is verified.
+    pub invite: Option<String>,
+}
+
+/// What pending sign-ups are kept by, so the same address in any case finds the same one.
+pub fn verification_key(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// Sign-ups waiting for their code, one per email address, so sign-ups running at the same time
+/// each keep their own code and invite.
+#[async_trait]
+pub trait VerificationStore: Send + Sync {
+    /// Stores the hash of a freshly sent code, replacing any earlier one for the same email.
+    async fn start(
+        &self,
+        pending: &PendingVerification,
+        code_hash: &str,
+        expiry: Duration,
+    ) -> Result<(), StoreError>;
+    async fn pending(&self, email: &str) -> Result<Option<PendingVerification>, StoreError>;
+    /// `None` once the code expired.
+    async fn code_hash(&self, email: &str) -> Result<Option<String>, StoreError>;
+    /// Drops the sign-up's code, email and invite. Its name stays around for the personalization
+    /// steps.
+    async fn finish(&self, email: &str) -> Result<(), StoreError>;
+    /// Name given at the sign-up with this email, still there after `finish` until the sign-up
+    /// would have expired.
+    async fn signup_name(&self, email: &str) -> Result<Option<String>, StoreError>;
+}
+
+fn signup_key(email: &str, field: &str) -> String {
+    format!("signup:{}:{}", verification_key(email), field)
+}
+
+/// `VerificationStore` on top of whichever `EphemeralStore` is configured.
+pub struct EphemeralVerificationStore {
+    store: Arc<dyn EphemeralStore>,
+    // Unverified sign-ups are kept as long as `purge_unverified` keeps their account.
+    ttl: Duration,
+}
+
+impl EphemeralVerificationStore {
+    pub fn new(store: Arc<dyn EphemeralStore>, ttl: Duration) -> Self {
+        EphemeralVerificationStore { store, ttl }
+    }
+}
+
+#[async_trait]
+impl VerificationStore for EphemeralVerificationStore {
+    async fn start(
+        &self,
+        pending: &PendingVerification,
+        code_hash: &str,
+        expiry: Duration,
+    ) -> Result<(), StoreError> {
+        let email = &pending.email;
+        let ttl = Some(self.ttl);
+        self.store
+            .set(&signup_key(email, "email"), email, ttl)
+            .await?;
+        self.store
+            .set(&signup_key(email, "name"), &pending.name, ttl)
+            .await?;
+        match &pending.invite {
+            Some(invite) => {
+                self.store
+                    .set(&signup_key(email, "invite"), invite, ttl)
+                    .await?
+            }
+            // Not one left over from an earlier sign-up with this email.
+            None => {
+                self.store.delete(&[signup_key(email, "invite")]).await?;
+            }
+        }
+        self.store
+            .set(&signup_key(email, "code"), code_hash, Some(expiry))
+            .await
+    }
+
+    async fn pending(&self, email: &str) -> Result<Option<PendingVerification>, StoreError> {
+        let stored_email = self.store.get(&signup_key(email, "email")).await?;
+        let name = self.store.get(&signup_key(email, "name")).await?;
+        let invite = self.store.get(&signup_key(email, "invite")).await?;
+        Ok(stored_email
+            .zip(name)
+            .map(|(email, name)| PendingVerification {
+                email,
+                name,
+                invite,
+            }))
+    }
+
+    async fn code_hash(&self, email: &str) -> Result<Option<String>, StoreError> {
+        self.store.get(&signup_key(email, "code")).await
+    }
+
+    async fn finish(&self, email: &str) -> Result<(), StoreError> {
+        // The name expires with the rest of what `start` stored.
+        let keys = ["code", "email", "invite"].map(|field| signup_key(email, field));
+        self.store.delete(&keys).await?;
+        Ok(())
+    }
+
+    async fn signup_name(&self, email: &str) -> Result<Option<String>, StoreError> {
+        self.store.get(&signup_key(email, "name")).await
+    }
+}
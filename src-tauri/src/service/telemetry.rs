@@ -0,0 +1,115 @@
+use axum::{
+    extract::{MatchedPath, Request},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use metrics::{counter, gauge, histogram};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use once_cell::sync::OnceCell;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::auth_handler::AppState;
+
+// Request latencies from a fast cache hit up to a slow OAuth round trip.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+static HANDLE: OnceCell<PrometheusHandle> = OnceCell::new();
+
+/// Installs the global Prometheus recorder on first use and returns the handle that renders it.
+pub fn recorder() -> Result<PrometheusHandle, anyhow::Error> {
+    HANDLE
+        .get_or_try_init(|| {
+            Ok::<_, anyhow::Error>(
+                PrometheusBuilder::new()
+                    .set_buckets_for_metric(
+                        Matcher::Full("http_request_duration_seconds".to_string()),
+                        LATENCY_BUCKETS,
+                    )?
+                    .install_recorder()?,
+            )
+        })
+        .cloned()
+}
+
+/// Counts every routed request and records how long it took, labelled by the route template
+/// rather than the raw path so ids don't blow up the label set.
+pub async fn track_http(request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let method = request.method().to_string();
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let status = response.status().as_u16().to_string();
+    counter!("http_requests_total", "method" => method.clone(), "route" => route.clone(), "status" => status)
+        .increment(1);
+    histogram!("http_request_duration_seconds", "method" => method, "route" => route)
+        .record(elapsed);
+
+    response
+}
+
+pub async fn metrics_handler(Extension(state): Extension<Arc<AppState>>) -> impl IntoResponse {
+    // Pool usage is sampled at scrape time instead of on every checkout.
+    let size = state.db.size() as f64;
+    let idle = state.db.num_idle() as f64;
+    gauge!("db_pool_connections", "state" => "idle").set(idle);
+    gauge!("db_pool_connections", "state" => "active").set(size - idle);
+    gauge!("db_pool_max_connections").set(state.settings.database.max_connections as f64);
+
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(),
+    )
+}
+
+pub fn record_login(method: &'static str, success: bool) {
+    counter!("logins_total", "method" => method, "outcome" => outcome(success)).increment(1);
+}
+
+pub fn record_otp_sent(success: bool) {
+    counter!("otp_sent_total", "outcome" => outcome(success)).increment(1);
+}
+
+/// `result` is one of "valid", "invalid", "expired" or "error".
+pub fn record_otp_verification(result: &'static str) {
+    counter!("otp_verifications_total", "result" => result).increment(1);
+}
+
+pub fn record_oauth(provider: &str, success: bool) {
+    counter!("oauth_sign_ins_total", "provider" => provider.to_lowercase(), "outcome" => outcome(success))
+        .increment(1);
+}
+
+pub fn record_redis_error(operation: &'static str) {
+    counter!("redis_errors_total", "operation" => operation).increment(1);
+}
+
+pub fn record_gateway_connect(success: bool) {
+    counter!("gateway_connections_total", "outcome" => outcome(success)).increment(1);
+}
+
+/// `status` is "succeeded" or "failed".
+pub fn record_job(job: &'static str, status: &'static str, duration: Duration) {
+    counter!("job_runs_total", "job" => job, "status" => status).increment(1);
+    histogram!("job_duration_seconds", "job" => job).record(duration.as_secs_f64());
+}
+
+fn outcome(success: bool) -> &'static str {
+    if success {
+        "success"
+    } else {
+        "failure"
+    }
+}
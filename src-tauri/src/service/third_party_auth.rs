@@ -1,6 +1,6 @@
 #![allow(unused)]
 #![allow(warnings)]
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 #[cfg(test)]
 use do_username::do_username;
 use env_logger::fmt::Timestamp;
@@ -48,9 +48,20 @@ use openidconnect::core::{
 use openidconnect::{AdditionalProviderMetadata, ProviderMetadata, RevocationUrl, UserInfoClaims};
 use serde::{Deserialize, Serialize};
 
+use crate::auth_handler::AppState;
+use crate::settings::Settings;
+use crate::token_vault;
+use crate::username::{self, UsernameError};
+use sqlx::types::time::PrimitiveDateTime;
 use std::env;
-use std::io::{BufRead, BufReader, Write};
-use std::net::TcpListener;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use tauri::AppHandle;
+use tauri_plugin_opener::OpenerExt;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use totp_rs::Secret;
+use tracing::debug;
 #[derive(Clone, Debug, Deserialize, Serialize)]
 
 struct RevocationEndpointProviderMetadata {
@@ -297,6 +308,58 @@ pub const HTML_GH_SUCCESS_RESPONSE: &str = r#"
 </html>
 "#;
 
+pub const HTML_FAILURE_RESPONSE: &str = r#"
+<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>Authentication Failed</title>
+    <style>
+        body {
+            font-family: 'Roboto', -apple-system, BlinkMacSystemFont, 'Segoe UI', Oxygen, Ubuntu, Cantarell, 'Open Sans', 'Helvetica Neue', sans-serif;
+            display: flex;
+            justify-content: center;
+            align-items: center;
+            min-height: 90vh;
+            background-color: #f8f9fa;
+            color: #5f6368;
+            margin: 0;
+            padding: 20px;
+            box-sizing: border-box;
+        }
+        .container {
+            background-color: #ffffff;
+            padding: 40px 30px;
+            border-radius: 8px;
+            box-shadow: 0 1px 3px rgba(0,0,0,0.12), 0 1px 2px rgba(0,0,0,0.24);
+            text-align: center;
+            max-width: 450px;
+            width: 100%;
+        }
+        h1 {
+            color: #202124;
+            font-weight: 500;
+            font-size: 22px;
+            margin-top: 0;
+            margin-bottom: 15px;
+        }
+        p {
+            font-size: 14px;
+            line-height: 1.6;
+            color: #5f6368;
+        }
+    </style>
+</head>
+<body>
+    <div class="container">
+        <h1>Authentication Failed</h1>
+        <p>The sign-in could not be completed. Close this window and try again from Wyrd.</p>
+    </div>
+</body>
+</html>
+"#;
+
 pub struct ThirdPartyAuthInfo {
     first_name: String,
     last_name: Option<String>,
@@ -322,12 +385,14 @@ pub fn handle_error<T: std::error::Error>(fail: &T, msg: &'static str) {
         err_msg += &format!("\n    caused by: {}", cause);
         cur_fail = cause.source();
     }
-    println!("{}", err_msg);
+    tracing::error!("{}", err_msg);
     exit(1);
 }
 
-pub async fn create_client(provider: &str) -> Result<(ClientCredentials), anyhow::Error> {
-    dotenv::dotenv().ok();
+pub async fn create_client(
+    provider: &str,
+    settings: &Settings,
+) -> Result<(ClientCredentials), anyhow::Error> {
     let scopes = Some(vec![
         Scope::new("openid".to_string()),
         Scope::new("email".to_string()),
@@ -348,140 +413,451 @@ pub async fn create_client(provider: &str) -> Result<(ClientCredentials), anyhow
         CoreClaimName::new("locale".to_string()),
     ]);
     let client_creds: ClientCredentials = match provider {
-        "GOOGLE" => ClientCredentials {
-            issuer_url: IssuerUrl::new(String::from("https://accounts.google.com"))?,
-            client_id: ClientId::new(std::env::var("GOOGLE_CLIENT_ID")?),
-            client_secret: ClientSecret::new(std::env::var("GOOGLE_CLIENT_SECRET")?),
-            tenant_id: None,
-            scopes,
-            claims,
-        },
+        "GOOGLE" => {
+            let google = &settings.google;
+            ClientCredentials {
+                issuer_url: IssuerUrl::new(String::from("https://accounts.google.com"))?,
+                client_id: ClientId::new(
+                    google
+                        .client_id
+                        .clone()
+                        .context("google.client_id is not set")?,
+                ),
+                client_secret: ClientSecret::new(
+                    google
+                        .client_secret
+                        .clone()
+                        .context("google.client_secret is not set")?,
+                ),
+                tenant_id: None,
+                scopes,
+                claims,
+            }
+        }
         "MS" => {
-            let tenant_id = std::env::var("MS_TENANT_ID")?;
+            let microsoft = &settings.microsoft;
+            let tenant_id = microsoft.tenant.clone();
             ClientCredentials {
-                issuer_url: IssuerUrl::new(format!("https://sts.windows.net/{}/", tenant_id))?, // Example issuer URL
-                client_id: ClientId::new(std::env::var("MS_CLIENT_ID")?),
-                client_secret: ClientSecret::new(std::env::var("MS_CLIENT_SECRET")?),
+                issuer_url: IssuerUrl::new(format!(
+                    "https://login.microsoftonline.com/{}/v2.0",
+                    tenant_id
+                ))?,
+                client_id: ClientId::new(
+                    microsoft
+                        .client_id
+                        .clone()
+                        .context("microsoft.client_id is not set")?,
+                ),
+                client_secret: ClientSecret::new(
+                    microsoft
+                        .client_secret
+                        .clone()
+                        .context("microsoft.client_secret is not set")?,
+                ),
                 tenant_id: Some(tenant_id.clone()),
                 scopes,
                 claims,
             }
         }
-        _ => ClientCredentials {
-            issuer_url: IssuerUrl::new(String::from("https://accounts.google.com"))?, // Example issuer URL
-            client_id: ClientId::new(std::env::var("MS_CLIENT_ID")?),
-            client_secret: ClientSecret::new(std::env::var("MS_CLIENT_SECRET")?),
-            tenant_id: None,
-            scopes,
-            claims,
-        },
+        other => return Err(anyhow!("Unknown sign-in provider {}", other)),
     };
     return Ok(client_creds);
 }
 
-pub fn auth_code(provider: &str) -> (AuthorizationCode, CsrfToken) {
-    let (code, state) = {
-        // A very naive implementation of the redirect server.
-        let listener = TcpListener::bind("127.0.0.1:8080").unwrap();
+// How long the loopback listener waits for the browser to come back before giving up.
+const LOOPBACK_TIMEOUT: Duration = Duration::from_secs(300);
+
+pub const OAUTH_RESULT_EVENT: &str = "oauth-result";
+
+const EMAIL_TAKEN: &str = "This email address has already been regestred with a different account. Please log in with your password.";
+
+/// Payload emitted to the webview once a desktop sign-in attempt finishes.
+#[derive(Clone, Debug, Serialize)]
+pub struct OAuthResult {
+    pub provider: String,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// Present for new accounts, which may still replace their allocated username.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username_ticket: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
 
-        // Accept one connection
-        let (mut stream, _) = listener.accept().unwrap();
+impl OAuthResult {
+    pub fn from_result(provider: &str, result: Result<SignIn, anyhow::Error>) -> Self {
+        match result {
+            Ok(sign_in) => OAuthResult {
+                provider: provider.to_string(),
+                success: true,
+                username: Some(sign_in.username),
+                username_ticket: sign_in.username_ticket,
+                error: None,
+            },
+            Err(err) => OAuthResult {
+                provider: provider.to_string(),
+                success: false,
+                username: None,
+                username_ticket: None,
+                error: Some(err.to_string()),
+            },
+        }
+    }
+}
 
-        let mut reader = BufReader::new(&stream);
+/// Redirect listener for the native-app flow (RFC 8252 section 7.3). Every attempt binds its
+/// own ephemeral port on the loopback interface, so the redirect URI is only known at runtime.
+pub struct LoopbackRedirect {
+    listener: TcpListener,
+    pub redirect_url: RedirectUrl,
+}
 
-        let mut request_line = String::new();
-        reader.read_line(&mut request_line).unwrap();
+pub async fn bind_loopback() -> Result<LoopbackRedirect, anyhow::Error> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let port = listener.local_addr()?.port();
+    let redirect_url = RedirectUrl::new(format!("http://127.0.0.1:{}", port))?;
+    Ok(LoopbackRedirect {
+        listener,
+        redirect_url,
+    })
+}
 
-        let redirect_url = request_line.split_whitespace().nth(1).unwrap();
-        let url = Url::parse(&("http://localhost".to_string() + redirect_url)).unwrap();
+impl LoopbackRedirect {
+    /// Waits for the provider to redirect the browser back and returns the authorization code.
+    /// Requests whose `state` parameter doesn't match `csrf_token` are turned away and the wait
+    /// goes on.
+    pub async fn wait_for_code(
+        self,
+        provider: &str,
+        csrf_token: &CsrfToken,
+    ) -> Result<AuthorizationCode, anyhow::Error> {
+        tokio::time::timeout(LOOPBACK_TIMEOUT, self.accept_redirect(provider, csrf_token))
+            .await
+            .map_err(|_| anyhow!("Timed out waiting for the sign-in to finish in the browser"))?
+    }
 
-        let code = url
-            .query_pairs()
-            .find(|(key, _)| key == "code")
-            .map(|(_, code)| AuthorizationCode::new(code.into_owned()))
-            .unwrap();
+    // Connections that break off or don't carry this attempt's `state` are answered and
+    // dropped; only the redirect for this attempt, or the timeout, ends the wait.
+    async fn accept_redirect(
+        &self,
+        provider: &str,
+        csrf_token: &CsrfToken,
+    ) -> Result<AuthorizationCode, anyhow::Error> {
+        loop {
+            let (mut stream, _) = self.listener.accept().await?;
+
+            let mut request_line = String::new();
+            if let Err(err) = BufReader::new(&mut stream)
+                .read_line(&mut request_line)
+                .await
+            {
+                debug!("Dropped a connection to the redirect listener: {}", err);
+                continue;
+            }
 
-        let state = url
-            .query_pairs()
-            .find(|(key, _)| key == "state")
-            .map(|(_, state)| CsrfToken::new(state.into_owned()))
-            .unwrap();
+            let (status, body, outcome) = redirect_outcome(&request_line, provider, csrf_token);
+            // The outcome stands even if the browser went away before reading the page.
+            if let Err(err) = write_response(&mut stream, status, body).await {
+                debug!("Failed to answer the redirect: {:?}", err);
+            }
+            if let Some(outcome) = outcome {
+                return outcome;
+            }
+        }
+    }
+}
 
-        let success_response = match provider {
-            "GOOGLE" => HTML_GOOGLE_SUCCESS_RESPONSE,
-            "MS" => HTML_MS_SUCCESS_RESPONSE,
-            _ => HTML_GH_SUCCESS_RESPONSE,
-        };
+// What the redirect listener answers a request with, and how the attempt ends, if it does.
+fn redirect_outcome(
+    request_line: &str,
+    provider: &str,
+    csrf_token: &CsrfToken,
+) -> (
+    &'static str,
+    &'static str,
+    Option<Result<AuthorizationCode, anyhow::Error>>,
+) {
+    // Browsers open speculative connections and ask for favicons, skip anything that isn't the
+    // redirect itself.
+    let url = match request_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|path| Url::parse(&format!("http://127.0.0.1{}", path)).ok())
+    {
+        Some(url) if url.query().is_some() => url,
+        _ => return ("404 Not Found", "", None),
+    };
 
-        let response = format!(
-                "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\n\r\n{}",
-                success_response.len(),
-                success_response
-            );
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
 
-        stream.write_all(response.as_bytes()).unwrap();
-        (code, state)
+    // Likely a tab left over from an earlier attempt, this one may still come.
+    if param("state").as_deref() != Some(csrf_token.secret().as_str()) {
+        return ("400 Bad Request", HTML_FAILURE_RESPONSE, None);
+    }
+
+    if let Some(error) = param("error") {
+        return (
+            "400 Bad Request",
+            HTML_FAILURE_RESPONSE,
+            Some(Err(anyhow!("The provider returned an error: {}", error))),
+        );
+    }
+
+    let Some(code) = param("code") else {
+        return (
+            "400 Bad Request",
+            HTML_FAILURE_RESPONSE,
+            Some(Err(anyhow!(
+                "The provider did not return an authorization code"
+            ))),
+        );
     };
-    (code, state)
+
+    let success_response = match provider {
+        "GOOGLE" => HTML_GOOGLE_SUCCESS_RESPONSE,
+        "MS" => HTML_MS_SUCCESS_RESPONSE,
+        _ => HTML_GH_SUCCESS_RESPONSE,
+    };
+    (
+        "200 OK",
+        success_response,
+        Some(Ok(AuthorizationCode::new(code))),
+    )
 }
 
+async fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    body: &str,
+) -> Result<(), anyhow::Error> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
 
+/// Opens the authorization URL in the user's default browser instead of the webview, so the
+/// provider's cookies and password managers are available.
+pub fn open_in_browser(app: &AppHandle, auth_url: &Url) -> Result<(), anyhow::Error> {
+    app.opener()
+        .open_url(auth_url.as_str(), None::<&str>)
+        .map_err(|err| anyhow!("Failed to open the system browser: {}", err))
+}
 
-pub async fn sign_in_or_sign_up(
-    user_info: CoreUserInfoClaims,
-    pool: &PgPool,
-) -> Result<(String), anyhow::Error> {
-    /*
-    Search through DB for user_id to make sure user isn't already signedup
-    */
+/// Outcome of a provider sign-in, resolved to a Wyrd account.
+pub struct SignIn {
+    pub user_id: i64,
+    pub provider_user_id: String,
+    pub username: String,
+    /// Only set when this sign-in created the account, see `username::choose_username`.
+    pub username_ticket: Option<String>,
+}
 
-    let user_id = user_info.subject().to_string();
-    let email = user_info.email().unwrap().to_string();
-    let first_name = user_info
-        .given_name()
-        .unwrap()
-        .get(None)
-        .unwrap()
+pub async fn sign_in_or_sign_up<AC: AdditionalClaims>(
+    user_info: UserInfoClaims<AC, CoreGenderClaim>,
+    provider: &str,
+    state: &AppState,
+) -> Result<SignIn, anyhow::Error> {
+    let pool = &state.db;
+    let provider_user_id = user_info.subject().to_string();
+
+    // Returning users are matched on the provider identity, never on the email alone.
+    if let Some(existing) = sqlx::query!(
+        "SELECT id, username FROM users WHERE provider = $1 AND provider_user_id = $2",
+        provider,
+        &provider_user_id,
+    )
+    .fetch_optional(pool)
+    .await?
+    {
+        return Ok(SignIn {
+            user_id: existing.id,
+            provider_user_id,
+            username: existing.username,
+            username_ticket: None,
+        });
+    }
+
+    let email = user_info
+        .email()
+        .ok_or_else(|| anyhow!("The provider did not share an email address"))?
         .to_string();
-    let username = email.split('@').collect::<Vec<&str>>()[0].to_string();
-    let lang = LanguageTag::new(String::from("en"));
-    if user_info.locale().is_none() {
-        user_info.clone().set_locale(Some(lang.clone()));
+
+    // Linking onto an existing password account would let whoever controls the provider
+    // account take it over, so that has to go through a regular login first.
+    let existing_email = sqlx::query!(
+        "SELECT id FROM users WHERE lower(email) = lower($1)",
+        &email
+    )
+    .fetch_optional(pool)
+    .await?;
+    if existing_email.is_some() {
+        return Err(anyhow!(EMAIL_TAKEN));
     }
 
-    let mut last_name = user_info
+    let first_name = user_info
+        .given_name()
+        .and_then(|name| name.get(None).map(|s| s.to_string()))
+        .unwrap_or_default();
+
+    let last_name = user_info
         .family_name()
         .and_then(|name| name.get(None).map(|s| s.to_string()));
 
+    let name = match &last_name {
+        Some(last_name) => format!("{} {}", first_name, last_name),
+        None => first_name.clone(),
+    };
+
     let profile_url = user_info
         .picture()
         .and_then(|pic| pic.get(None).map(|s| s.to_string()));
 
-    let provider = "GOOGLE";
-    //let username = generate_username();
-    /*let new_google_user = GoogleUserInfo{
-        google_id,
-        first_name,
-    }*/
-    println!("FIRST NAME: {:?}: ", first_name);
-    println!("LAST NAME: {:?}", last_name);
-    println!("USER ID: {:?}: ", user_id);
-    println!("EMAIL: {:?}", email);
-    println!("USERNAME: {:?}", username);
-    println!("PICTURE URL: {:?}", profile_url);
-
-    let user = ThirdPartyAuthInfo {
-        first_name,
-        last_name,
-        user_id,
-        email,
-        username,
-        profile_url,
-    };
-    //println!("LAST NAME: {:?}", last_name);
-    //println!("PICTURE URL: {}", picture_url);
+    let user_verified = user_info.email_verified().unwrap_or(false);
+
+    //Used for secret key generations for the totp-rs crate.
+    let secret_key = Secret::generate_secret().to_string();
+
+    // The username is derived from the first name rather than the email so it doesn't leak the
+    // address. ON CONFLICT makes the insert itself the reservation: if another sign-up grabbed
+    // the name in the meantime nothing is written and the next candidate is tried. Only the
+    // username index is named, any other conflict is an error of its own.
+    for candidate in username::candidates(&first_name) {
+        let inserted = sqlx::query_scalar!(
+            "INSERT INTO users (name, username, email, password, status, activity, user_verified, totp_secret, personalization, profile_url, provider, provider_user_id)
+             VALUES ($1, $2, $3, NULL, $4, $5, $6, $7, $8, $9, $10, $11)
+             ON CONFLICT (lower(username)) DO NOTHING
+             RETURNING id",
+            &name,
+            &candidate,
+            &email,
+            "active",
+            "offline",
+            user_verified,
+            &secret_key,
+            serde_json::json!({}),
+            profile_url,
+            provider,
+            &provider_user_id,
+        )
+        .fetch_optional(pool)
+        .await
+        .map_err(|err| {
+            match err.as_database_error().and_then(|db_err| db_err.constraint()) {
+                // Signed up with a password since the check above.
+                Some("users_email_lower_key") => anyhow!(EMAIL_TAKEN),
+                _ => err.into(),
+            }
+        })?;
+
+        if let Some(user_id) = inserted {
+            let username_ticket = username::issue_ticket(state.ephemeral.as_ref(), user_id).await?;
+            return Ok(SignIn {
+                user_id,
+                provider_user_id,
+                username: candidate,
+                username_ticket: Some(username_ticket),
+            });
+        }
+    }
 
-    Ok(user.user_id)
+    Err(UsernameError::Exhausted.into())
 }
 
+/// Detaches a provider identity from its account. The stored grant is revoked first so the
+/// provider stops honouring it as soon as the link is gone.
+pub async fn unlink_identity(
+    state: &AppState,
+    provider: &str,
+    provider_user_id: &str,
+) -> Result<(), anyhow::Error> {
+    token_vault::revoke(state, provider, provider_user_id).await?;
+
+    sqlx::query!(
+        "UPDATE users SET provider = NULL, provider_user_id = NULL
+         WHERE provider = $1 AND provider_user_id = $2",
+        provider,
+        provider_user_id,
+    )
+    .execute(&state.db)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn send(redirect: &RedirectUrl, request: &str) -> String {
+        let address = redirect.url().socket_addrs(|| None).unwrap()[0];
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        tokio::io::AsyncReadExt::read_to_string(&mut stream, &mut response)
+            .await
+            .unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn the_listener_waits_for_the_redirect_of_this_attempt() {
+        let loopback = bind_loopback().await.unwrap();
+        let redirect = loopback.redirect_url.clone();
+        let csrf_token = CsrfToken::new("expected".to_string());
+        let waiting = tokio::spawn(async move {
+            loopback
+                .wait_for_code("GOOGLE", &CsrfToken::new("expected".to_string()))
+                .await
+        });
+
+        // A connection that closes without a request line.
+        let address = redirect.url().socket_addrs(|| None).unwrap()[0];
+        drop(TcpStream::connect(address).await.unwrap());
+        assert!(send(&redirect, "GET /favicon.ico HTTP/1.1\r\n\r\n")
+            .await
+            .starts_with("HTTP/1.1 404"));
+        assert!(
+            send(&redirect, "GET /?state=stale&code=old HTTP/1.1\r\n\r\n")
+                .await
+                .starts_with("HTTP/1.1 400")
+        );
+        let request = format!(
+            "GET /?state={}&code=fresh HTTP/1.1\r\n\r\n",
+            csrf_token.secret()
+        );
+        assert!(send(&redirect, &request).await.starts_with("HTTP/1.1 200"));
+
+        let code = waiting.await.unwrap().unwrap();
+        assert_eq!(code.secret(), "fresh");
+    }
 
+    #[test]
+    fn provider_errors_for_this_attempt_end_it() {
+        let csrf_token = CsrfToken::new("expected".to_string());
+        let (status, _, outcome) = redirect_outcome(
+            "GET /?state=expected&error=access_denied HTTP/1.1",
+            "MS",
+            &csrf_token,
+        );
+        assert_eq!(status, "400 Bad Request");
+        assert!(matches!(outcome, Some(Err(_))));
+
+        let (_, _, outcome) = redirect_outcome(
+            "GET /?state=other&error=access_denied HTTP/1.1",
+            "MS",
+            &csrf_token,
+        );
+        assert!(outcome.is_none());
+    }
+}
@@ -0,0 +1,340 @@
+use anyhow::{anyhow, Context};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use openidconnect::{AccessToken, RefreshToken};
+use sqlx::types::time::OffsetDateTime;
+use sqlx::PgPool;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+use crate::auth_handler::AppState;
+use crate::settings::Settings;
+use crate::{google, microsoft};
+
+// XChaCha20 nonces are 24 bytes, which is large enough to pick at random for every seal.
+const NONCE_LEN: usize = 24;
+
+// How close to expiry a token has to be to get refreshed. Wider than the refresh_tokens job's
+// schedule, so no token expires between two runs.
+const REFRESH_WINDOW_SECS: i64 = 10 * 60;
+
+/// Access and refresh tokens for one linked identity, in plaintext. Only ever held in memory.
+pub struct ProviderTokens {
+    pub access_token: AccessToken,
+    pub refresh_token: Option<RefreshToken>,
+    pub expires_at: Option<OffsetDateTime>,
+    pub scopes: Vec<String>,
+}
+
+impl ProviderTokens {
+    pub fn new(
+        access_token: AccessToken,
+        refresh_token: Option<RefreshToken>,
+        expires_in: Option<Duration>,
+        scopes: Vec<String>,
+    ) -> Self {
+        ProviderTokens {
+            access_token,
+            refresh_token,
+            expires_at: expires_in.map(|expires_in| OffsetDateTime::now_utc() + expires_in),
+            scopes,
+        }
+    }
+}
+
+/// Encrypts provider tokens at rest in the `provider_tokens` table.
+///
+/// Every value is sealed with XChaCha20-Poly1305 and bound to its identity through the
+/// associated data, so a ciphertext copied onto another row fails to decrypt.
+pub struct TokenVault {
+    cipher: XChaCha20Poly1305,
+}
+
+impl TokenVault {
+    pub fn new(key: &[u8; 32]) -> Self {
+        TokenVault {
+            cipher: XChaCha20Poly1305::new(key.into()),
+        }
+    }
+
+    /// Uses `token_vault.key`, which has to be set.
+    pub fn from_settings(settings: &Settings) -> Result<Self, anyhow::Error> {
+        let key = settings
+            .token_vault
+            .decoded_key()
+            .context("token_vault.key must be set, e.g. through TOKEN_VAULT_KEY")?;
+        Ok(TokenVault::new(&key))
+    }
+
+    fn seal(&self, plaintext: &str, aad: &str) -> Result<Vec<u8>, anyhow::Error> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext.as_bytes(),
+                    aad: aad.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("Failed to encrypt provider token"))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    fn open(&self, sealed: &[u8], aad: &str) -> Result<String, anyhow::Error> {
+        if sealed.len() < NONCE_LEN {
+            return Err(anyhow!("Stored provider token is truncated"));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let plaintext = self
+            .cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: aad.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("Failed to decrypt provider token"))?;
+        Ok(String::from_utf8(plaintext)?)
+    }
+
+    pub async fn store(
+        &self,
+        pool: &PgPool,
+        provider: &str,
+        provider_user_id: &str,
+        tokens: &ProviderTokens,
+    ) -> Result<(), anyhow::Error> {
+        let aad = identity_aad(provider, provider_user_id);
+        let access_token = self.seal(tokens.access_token.secret(), &aad)?;
+        let refresh_token = tokens
+            .refresh_token
+            .as_ref()
+            .map(|token| self.seal(token.secret(), &aad))
+            .transpose()?;
+
+        // Providers don't always hand out a new refresh token, keep the old one in that case.
+        sqlx::query!(
+            "INSERT INTO provider_tokens (provider, provider_user_id, access_token, refresh_token, expires_at, scopes)
+             VALUES ($1, $2, $3, $4, $5, $6)
+             ON CONFLICT (provider, provider_user_id) DO UPDATE SET
+                access_token = EXCLUDED.access_token,
+                refresh_token = COALESCE(EXCLUDED.refresh_token, provider_tokens.refresh_token),
+                expires_at = EXCLUDED.expires_at,
+                scopes = EXCLUDED.scopes,
+                updated_at = now()",
+            provider,
+            provider_user_id,
+            access_token,
+            refresh_token,
+            tokens.expires_at,
+            &tokens.scopes,
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn load(
+        &self,
+        pool: &PgPool,
+        provider: &str,
+        provider_user_id: &str,
+    ) -> Result<Option<ProviderTokens>, anyhow::Error> {
+        let record = sqlx::query!(
+            "SELECT access_token, refresh_token, expires_at, scopes FROM provider_tokens
+             WHERE provider = $1 AND provider_user_id = $2",
+            provider,
+            provider_user_id,
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        let Some(record) = record else {
+            return Ok(None);
+        };
+
+        let aad = identity_aad(provider, provider_user_id);
+        Ok(Some(ProviderTokens {
+            access_token: AccessToken::new(self.open(&record.access_token, &aad)?),
+            refresh_token: record
+                .refresh_token
+                .map(|sealed| self.open(&sealed, &aad).map(RefreshToken::new))
+                .transpose()?,
+            expires_at: record.expires_at,
+            scopes: record.scopes,
+        }))
+    }
+
+    pub async fn remove(
+        &self,
+        pool: &PgPool,
+        provider: &str,
+        provider_user_id: &str,
+    ) -> Result<(), anyhow::Error> {
+        sqlx::query!(
+            "DELETE FROM provider_tokens WHERE provider = $1 AND provider_user_id = $2",
+            provider,
+            provider_user_id,
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+}
+
+fn identity_aad(provider: &str, provider_user_id: &str) -> String {
+    format!("{}:{}", provider, provider_user_id)
+}
+
+/// Revokes the stored grant at the provider and deletes it from the vault. Used when an
+/// identity gets unlinked, so nothing keeps working on the user's behalf afterwards.
+pub async fn revoke(
+    state: &AppState,
+    provider: &str,
+    provider_user_id: &str,
+) -> Result<(), anyhow::Error> {
+    if let Some(tokens) = state
+        .vault
+        .load(&state.db, provider, provider_user_id)
+        .await?
+    {
+        let result = match provider {
+            "GOOGLE" => google::revoke_tokens(state, &tokens).await,
+            // Microsoft has no revocation endpoint for v2 tokens; dropping them is all we can do.
+            _ => Ok(()),
+        };
+        // A failed revocation shouldn't leave the tokens lying around either.
+        if let Err(err) = result {
+            warn!("Failed to revoke {} tokens: {:?}", provider, err);
+        }
+    }
+
+    state
+        .vault
+        .remove(&state.db, provider, provider_user_id)
+        .await
+}
+
+/// What a `refresh_due_tokens` run did.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RefreshCounts {
+    pub refreshed: usize,
+    pub failed: usize,
+}
+
+/// Refreshes the tokens that expire soon. Failures for one identity, whether at the provider
+/// or reading and writing the vault, are logged and counted and don't stop the others.
+pub async fn refresh_due_tokens(state: &AppState) -> Result<RefreshCounts, anyhow::Error> {
+    let due = sqlx::query!(
+        "SELECT provider, provider_user_id FROM provider_tokens
+         WHERE refresh_token IS NOT NULL AND expires_at < now() + make_interval(secs => $1)",
+        REFRESH_WINDOW_SECS as f64,
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    let mut counts = RefreshCounts::default();
+    for identity in due {
+        let provider = identity.provider.as_str();
+        let provider_user_id = identity.provider_user_id.as_str();
+        match refresh_identity(state, provider, provider_user_id).await {
+            Ok(true) => {
+                debug!("Refreshed {} tokens", provider);
+                counts.refreshed += 1;
+            }
+            Ok(false) => {}
+            Err(err) => {
+                warn!(
+                    "Failed to refresh {} tokens of {}: {:?}",
+                    provider, provider_user_id, err
+                );
+                counts.failed += 1;
+            }
+        }
+    }
+
+    Ok(counts)
+}
+
+// Returns whether there was a refresh token to use.
+async fn refresh_identity(
+    state: &AppState,
+    provider: &str,
+    provider_user_id: &str,
+) -> Result<bool, anyhow::Error> {
+    let Some(refresh_token) = state
+        .vault
+        .load(&state.db, provider, provider_user_id)
+        .await?
+        .and_then(|tokens| tokens.refresh_token)
+    else {
+        return Ok(false);
+    };
+
+    let tokens = match provider {
+        "GOOGLE" => google::refresh_tokens(state, &refresh_token).await?,
+        "MS" => microsoft::refresh_tokens(state, &refresh_token).await?,
+        other => return Err(anyhow!("Unsupported provider: {}", other)),
+    };
+    state
+        .vault
+        .store(&state.db, provider, provider_user_id, &tokens)
+        .await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vault() -> TokenVault {
+        TokenVault::new(&[7; 32])
+    }
+
+    #[test]
+    fn sealed_tokens_open_again() {
+        let vault = vault();
+        let aad = identity_aad("GOOGLE", "1234");
+        let sealed = vault.seal("ya29.access", &aad).unwrap();
+        assert_eq!(vault.open(&sealed, &aad).unwrap(), "ya29.access");
+        // A fresh nonce every time, so equal tokens don't show as equal rows.
+        assert_ne!(vault.seal("ya29.access", &aad).unwrap(), sealed);
+    }
+
+    #[test]
+    fn tokens_only_open_for_their_identity() {
+        let vault = vault();
+        let sealed = vault
+            .seal("ya29.access", &identity_aad("GOOGLE", "1234"))
+            .unwrap();
+        assert!(vault
+            .open(&sealed, &identity_aad("GOOGLE", "5678"))
+            .is_err());
+        assert!(vault.open(&sealed, &identity_aad("MS", "1234")).is_err());
+        assert!(TokenVault::new(&[8; 32])
+            .open(&sealed, &identity_aad("GOOGLE", "1234"))
+            .is_err());
+    }
+
+    #[test]
+    fn tampered_tokens_fail_to_open() {
+        let vault = vault();
+        let aad = identity_aad("GOOGLE", "1234");
+        let sealed = vault.seal("ya29.access", &aad).unwrap();
+
+        // The nonce, the ciphertext and the tag are all covered.
+        for index in [0, NONCE_LEN, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[index] ^= 1;
+            assert!(vault.open(&tampered, &aad).is_err());
+        }
+        assert!(vault.open(&sealed[..sealed.len() - 1], &aad).is_err());
+        assert!(vault.open(&sealed[..NONCE_LEN - 1], &aad).is_err());
+    }
+}
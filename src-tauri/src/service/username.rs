@@ -0,0 +1,369 @@
+use do_username::do_username;
+use rand::Rng;
+use std::collections::HashSet;
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::ephemeral::EphemeralStore;
+use crate::repository::{StoreError, UserRepository};
+
+pub const MIN_LENGTH: usize = 4;
+pub const MAX_LENGTH: usize = 24;
+
+// Names that would read as official accounts or clash with routes.
+const RESERVED: &[&str] = &[
+    "admin",
+    "administrator",
+    "api",
+    "help",
+    "moderator",
+    "null",
+    "root",
+    "staff",
+    "support",
+    "system",
+    "undefined",
+    "wyrd",
+];
+
+// How many numeric suffixes to try on the preferred name before falling back to generated ones.
+const SUFFIX_ATTEMPTS: usize = 5;
+const GENERATED_ATTEMPTS: usize = 10;
+
+// A social sign-up gets this long to pick a different username than the one allocated.
+const TICKET_EXPIRY_TIME: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Debug, Error)]
+pub enum UsernameError {
+    #[error("Usernames must be at least {MIN_LENGTH} characters long.")]
+    TooShort,
+
+    #[error("Usernames can be at most {MAX_LENGTH} characters long.")]
+    TooLong,
+
+    #[error("Usernames have to start with a letter.")]
+    InvalidStart,
+
+    #[error("Usernames can only contain letters, numbers, '-' and '_'.")]
+    InvalidCharacters,
+
+    #[error("This username is reserved. Please try a different one.")]
+    Reserved,
+
+    #[error("Username already taken. Please try a different one.")]
+    Taken,
+
+    #[error("This link to choose a username has expired. Please sign in again.")]
+    TicketExpired,
+
+    #[error("Could not find a free username")]
+    Exhausted,
+
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] sqlx::Error),
+
+    #[error("{0}")]
+    StoreError(#[from] StoreError),
+}
+
+/// Lowercases `raw` and maps it onto the username alphabet: whitespace and dots become
+/// underscores, anything else outside `[a-z0-9_-]` is dropped.
+pub fn normalize(raw: &str) -> String {
+    let mut normalized = String::with_capacity(raw.len());
+    for c in raw.trim().chars().flat_map(char::to_lowercase) {
+        let mapped = match c {
+            'a'..='z' | '0'..='9' | '-' | '_' => c,
+            c if c.is_whitespace() || c == '.' => '_',
+            _ => continue,
+        };
+        // Collapse runs like "a..b" into a single separator.
+        if mapped == '_' && normalized.ends_with('_') {
+            continue;
+        }
+        normalized.push(mapped);
+    }
+    normalized
+        .trim_matches(|c| c == '_' || c == '-')
+        .to_string()
+}
+
+pub fn validate(candidate: &str) -> Result<(), UsernameError> {
+    let length = candidate.chars().count();
+    if length < MIN_LENGTH {
+        return Err(UsernameError::TooShort);
+    }
+    if length > MAX_LENGTH {
+        return Err(UsernameError::TooLong);
+    }
+    if !candidate.starts_with(|c: char| c.is_ascii_lowercase()) {
+        return Err(UsernameError::InvalidStart);
+    }
+    if !candidate
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+    {
+        return Err(UsernameError::InvalidCharacters);
+    }
+    if RESERVED.contains(&candidate) {
+        return Err(UsernameError::Reserved);
+    }
+    Ok(())
+}
+
+/// Usernames to try for a new account, best first: the normalized `hint`, the hint with a few
+/// random numeric suffixes, then generated names. Every yielded name passes `validate`.
+pub fn candidates(hint: &str) -> Vec<String> {
+    let mut candidates = Vec::new();
+    let mut rng = rand::thread_rng();
+
+    // Leave room for a 4 digit suffix.
+    let base: String = normalize(hint).chars().take(MAX_LENGTH - 4).collect();
+    if base.starts_with(|c: char| c.is_ascii_lowercase()) {
+        candidates.push(base.clone());
+        // Short names like "jo" only become valid with a suffix.
+        for _ in 0..SUFFIX_ATTEMPTS {
+            candidates.push(format!("{}{}", base, rng.gen_range(10..10_000)));
+        }
+    }
+
+    for _ in 0..GENERATED_ATTEMPTS {
+        candidates.push(normalize(&do_username(MAX_LENGTH)));
+    }
+
+    // Generated names can repeat anywhere in the list, not only next to each other.
+    let mut seen = HashSet::new();
+    candidates.retain(|candidate| validate(candidate).is_ok() && seen.insert(candidate.clone()));
+    candidates
+}
+
+/// Hands out a one-time ticket that lets a freshly created social account replace its
+/// allocated username through `choose_username`.
+pub async fn issue_ticket(
+    ephemeral: &dyn EphemeralStore,
+    user_id: i64,
+) -> Result<String, UsernameError> {
+    let ticket = hex::encode(rand::thread_rng().gen::<[u8; 32]>());
+    ephemeral
+        .set(
+            &ticket_key(&ticket),
+            &user_id.to_string(),
+            Some(TICKET_EXPIRY_TIME),
+        )
+        .await?;
+    Ok(ticket)
+}
+
+/// Renames the account the ticket was issued for. Usernames compare case-insensitively, so
+/// "Elijah" is taken once "elijah" exists.
+pub async fn choose_username(
+    users: &dyn UserRepository,
+    ephemeral: &dyn EphemeralStore,
+    ticket: &str,
+    requested: &str,
+) -> Result<String, UsernameError> {
+    let username = requested.trim().to_lowercase();
+    validate(&username)?;
+
+    // Taken rather than read, so two requests with the same ticket can't both rename.
+    let key = ticket_key(ticket);
+    let user_id: i64 = ephemeral
+        .take(&key)
+        .await?
+        .and_then(|user_id| user_id.parse().ok())
+        .ok_or(UsernameError::TicketExpired)?;
+
+    // The unique index on lower(username) settles races with other sign-ups.
+    match users.set_username(user_id, &username).await {
+        Ok(()) => Ok(username),
+        Err(err) => {
+            // Only a rename that went through uses the ticket up.
+            ephemeral
+                .set(&key, &user_id.to_string(), Some(TICKET_EXPIRY_TIME))
+                .await?;
+            match err {
+                StoreError::Conflict(_) => Err(UsernameError::Taken),
+                err => Err(err.into()),
+            }
+        }
+    }
+}
+
+fn ticket_key(ticket: &str) -> String {
+    format!("username_ticket:{}", ticket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ApiError;
+    use axum::http::StatusCode;
+
+    #[test]
+    fn normalize_maps_onto_the_username_alphabet() {
+        assert_eq!(normalize("  Ada Lovelace "), "ada_lovelace");
+        assert_eq!(normalize("grace.b..hopper"), "grace_b_hopper");
+        assert_eq!(normalize("Ünïcödé Jösé"), "ncd_js");
+        assert_eq!(normalize("_-linus-_"), "linus");
+        assert_eq!(normalize(""), "");
+        assert_eq!(normalize("   "), "");
+        assert_eq!(normalize("!?*#"), "");
+        assert_eq!(normalize(". . ."), "");
+    }
+
+    #[test]
+    fn usernames_start_with_a_letter() {
+        assert!(validate("ada4").is_ok());
+        assert!(matches!(validate("4ada"), Err(UsernameError::InvalidStart)));
+        assert!(matches!(validate("_ada"), Err(UsernameError::InvalidStart)));
+        assert!(matches!(validate("Adam"), Err(UsernameError::InvalidStart)));
+        assert!(matches!(
+            validate("ada!"),
+            Err(UsernameError::InvalidCharacters)
+        ));
+        assert!(matches!(validate("admin"), Err(UsernameError::Reserved)));
+    }
+
+    #[test]
+    fn usernames_have_length_limits() {
+        assert!(matches!(validate("ada"), Err(UsernameError::TooShort)));
+        assert!(validate(&"a".repeat(MIN_LENGTH)).is_ok());
+        assert!(validate(&"a".repeat(MAX_LENGTH)).is_ok());
+        assert!(matches!(
+            validate(&"a".repeat(MAX_LENGTH + 1)),
+            Err(UsernameError::TooLong)
+        ));
+    }
+
+    #[test]
+    fn candidates_are_valid_unique_and_best_first() {
+        let candidates = candidates("Ada Lovelace");
+        assert_eq!(candidates[0], "ada_lovelace");
+        for suffixed in &candidates[1..] {
+            if !suffixed.starts_with("ada_lovelace") {
+                break;
+            }
+            assert!(suffixed["ada_lovelace".len()..]
+                .chars()
+                .all(|c| c.is_ascii_digit()));
+        }
+        assert!(candidates
+            .iter()
+            .all(|candidate| validate(candidate).is_ok()));
+        let unique: HashSet<_> = candidates.iter().collect();
+        assert_eq!(unique.len(), candidates.len());
+    }
+
+    #[test]
+    fn short_hints_only_come_with_a_suffix() {
+        let candidates = candidates("Jo");
+        assert!(!candidates.contains(&"jo".to_string()));
+        assert!(candidates[0].starts_with("jo"));
+        assert!(candidates
+            .iter()
+            .all(|candidate| validate(candidate).is_ok()));
+    }
+
+    #[test]
+    fn long_hints_leave_room_for_a_suffix() {
+        let candidates = candidates(&"a".repeat(40));
+        assert_eq!(candidates[0], "a".repeat(MAX_LENGTH - 4));
+        assert!(candidates
+            .iter()
+            .all(|candidate| candidate.chars().count() <= MAX_LENGTH));
+    }
+
+    #[test]
+    fn unusable_hints_fall_back_to_generated_names() {
+        for hint in ["", "!?*#", "4242"] {
+            let candidates = candidates(hint);
+            assert!(!candidates.is_empty());
+            assert!(candidates
+                .iter()
+                .all(|candidate| validate(candidate).is_ok()));
+        }
+    }
+
+    use crate::memory_store::{MemoryStore, MemoryUserRepository};
+    use crate::repository::NewUser;
+
+    async fn add_user(users: &MemoryUserRepository, username: &str) -> i64 {
+        users
+            .create(&NewUser {
+                name: format!("{} Example", username),
+                username: username.to_string(),
+                email: format!("{}@example.com", username),
+                password: None,
+                user_verified: true,
+                totp_secret: "secret".to_string(),
+            })
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn tickets_only_rename_once() {
+        let users = MemoryUserRepository::new();
+        let ephemeral = MemoryStore::new();
+        let id = add_user(&users, "ada_lovelace").await;
+        let ticket = issue_ticket(&ephemeral, id).await.unwrap();
+
+        assert_eq!(
+            choose_username(&users, &ephemeral, &ticket, " Countess ")
+                .await
+                .unwrap(),
+            "countess"
+        );
+        assert_eq!(
+            users.find_by_id(id).await.unwrap().unwrap().username,
+            "countess"
+        );
+        assert!(matches!(
+            choose_username(&users, &ephemeral, &ticket, "enchantress").await,
+            Err(UsernameError::TicketExpired)
+        ));
+        assert_eq!(
+            users.find_by_id(id).await.unwrap().unwrap().username,
+            "countess"
+        );
+    }
+
+    #[tokio::test]
+    async fn taken_usernames_leave_the_ticket_usable() {
+        let users = MemoryUserRepository::new();
+        let ephemeral = MemoryStore::new();
+        add_user(&users, "grace").await;
+        let id = add_user(&users, "ada_lovelace").await;
+        let ticket = issue_ticket(&ephemeral, id).await.unwrap();
+
+        assert!(matches!(
+            choose_username(&users, &ephemeral, &ticket, "Grace").await,
+            Err(UsernameError::Taken)
+        ));
+        assert!(choose_username(&users, &ephemeral, &ticket, "countess")
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn expired_tickets_are_rejected_as_unauthenticated() {
+        let users = MemoryUserRepository::new();
+        let ephemeral = MemoryStore::new();
+        let id = add_user(&users, "ada_lovelace").await;
+        ephemeral
+            .set(&ticket_key("stale"), &id.to_string(), Some(Duration::ZERO))
+            .await
+            .unwrap();
+
+        for ticket in ["stale", "made-up"] {
+            let err = choose_username(&users, &ephemeral, ticket, "countess")
+                .await
+                .unwrap_err();
+            assert!(matches!(err, UsernameError::TicketExpired));
+            assert_eq!(ApiError::from(err).status(), StatusCode::UNAUTHORIZED);
+        }
+        assert_eq!(
+            users.find_by_id(id).await.unwrap().unwrap().username,
+            "ada_lovelace"
+        );
+    }
+}
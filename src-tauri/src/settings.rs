@@ -0,0 +1,766 @@
+use config::{Config, Environment, File, FileFormat};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use thiserror::Error;
+use tracing_subscriber::EnvFilter;
+
+// Compiled in so the app starts with sane values even when no config directory ships with it.
+const DEFAULT_CONFIG: &str = include_str!("../config/default.toml");
+const DEV_CONFIG: &str = include_str!("../config/dev.toml");
+const TEST_CONFIG: &str = include_str!("../config/test.toml");
+const PROD_CONFIG: &str = include_str!("../config/prod.toml");
+
+// Environment variables honoured besides the WYRD__ ones: DATABASE_URL since sqlx needs it
+// anyway, the rest because deployments set them before they were settings.
+const PLAIN_VARIABLES: &[(&str, &str)] = &[
+    ("DATABASE_URL", "database.url"),
+    ("TOKEN_VAULT_KEY", "token_vault.key"),
+    ("GOOGLE_CLIENT_ID", "google.client_id"),
+    ("GOOGLE_CLIENT_SECRET", "google.client_secret"),
+    ("MS_CLIENT_ID", "microsoft.client_id"),
+    ("MS_CLIENT_SECRET", "microsoft.client_secret"),
+];
+
+#[derive(Debug, Error)]
+pub enum SettingsError {
+    #[error("Unknown profile '{0}', expected one of dev, test or prod")]
+    UnknownProfile(String),
+
+    #[error("Failed to load settings: {0}")]
+    Load(#[from] config::ConfigError),
+
+    #[error("Invalid setting `{key}`: {reason}")]
+    Invalid { key: &'static str, reason: String },
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    Dev,
+    Test,
+    Prod,
+}
+
+impl Profile {
+    /// `WYRD_PROFILE` if set, otherwise dev for debug builds and prod for release builds.
+    pub fn from_env() -> Result<Self, SettingsError> {
+        match std::env::var("WYRD_PROFILE") {
+            Ok(profile) => profile.parse(),
+            Err(_) if cfg!(debug_assertions) => Ok(Profile::Dev),
+            Err(_) => Ok(Profile::Prod),
+        }
+    }
+
+    fn config(&self) -> &'static str {
+        match self {
+            Profile::Dev => DEV_CONFIG,
+            Profile::Test => TEST_CONFIG,
+            Profile::Prod => PROD_CONFIG,
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = SettingsError;
+
+    fn from_str(profile: &str) -> Result<Self, Self::Err> {
+        match profile.trim().to_lowercase().as_str() {
+            "dev" => Ok(Profile::Dev),
+            "test" => Ok(Profile::Test),
+            "prod" => Ok(Profile::Prod),
+            other => Err(SettingsError::UnknownProfile(other.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Profile::Dev => "dev",
+            Profile::Test => "test",
+            Profile::Prod => "prod",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ServerSettings {
+    pub host: IpAddr,
+    pub port: u16,
+    /// Attempts at reaching Postgres and Redis on startup before giving up.
+    pub startup_attempts: u32,
+    /// How long in-flight requests get to finish once shutdown starts.
+    pub shutdown_grace_secs: u64,
+    /// Port serving the admin endpoints over plain HTTP on 127.0.0.1, unless mutual TLS moves
+    /// them to `tls.admin_port`. 0 disables them.
+    pub admin_port: u16,
+}
+
+impl ServerSettings {
+    pub fn bind_address(&self) -> SocketAddr {
+        SocketAddr::new(self.host, self.port)
+    }
+
+    /// Where a client on this machine reaches the server.
+    pub fn local_url(&self, https: bool) -> String {
+        let host = if self.host.is_unspecified() {
+            IpAddr::V4(Ipv4Addr::LOCALHOST)
+        } else {
+            self.host
+        };
+        let scheme = if https { "https" } else { "http" };
+        format!("{}://{}", scheme, SocketAddr::new(host, self.port))
+    }
+
+    /// `port` on the same interface the API listens on.
+    pub fn address_with_port(&self, port: u16) -> SocketAddr {
+        SocketAddr::new(self.host, port)
+    }
+
+    /// Where the admin endpoints listen without mutual TLS. Loopback only, so only this machine
+    /// can scrape them.
+    pub fn admin_address(&self) -> Option<SocketAddr> {
+        (self.admin_port != 0)
+            .then(|| SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), self.admin_port))
+    }
+
+    pub fn shutdown_grace(&self) -> Duration {
+        Duration::from_secs(self.shutdown_grace_secs)
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct DatabaseSettings {
+    pub url: String,
+    pub max_connections: u32,
+    pub run_migrations: bool,
+}
+
+// Like the Redis URL, the Postgres one usually carries a password.
+impl fmt::Debug for DatabaseSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DatabaseSettings")
+            .field("url", &self.url.split('@').last().unwrap_or_default())
+            .field("max_connections", &self.max_connections)
+            .field("run_migrations", &self.run_migrations)
+            .finish()
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct RedisSettings {
+    /// `redis://`, or `rediss://` for TLS. May carry `user:password@`.
+    pub url: String,
+    /// Override the credentials in `url`, e.g. to keep the password in the environment.
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub connect_timeout_secs: u64,
+    pub response_timeout_secs: u64,
+    /// How often a dropped connection is retried, with exponential backoff, before the
+    /// commands waiting on it fail.
+    pub reconnect_attempts: usize,
+    pub max_reconnect_delay_ms: u64,
+}
+
+impl RedisSettings {
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout_secs)
+    }
+
+    pub fn response_timeout(&self) -> Duration {
+        Duration::from_secs(self.response_timeout_secs)
+    }
+}
+
+// Settings get logged on startup failures; the password shouldn't be.
+impl fmt::Debug for RedisSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedisSettings")
+            .field("url", &self.url.split('@').last().unwrap_or_default())
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .field("connect_timeout_secs", &self.connect_timeout_secs)
+            .field("response_timeout_secs", &self.response_timeout_secs)
+            .field("reconnect_attempts", &self.reconnect_attempts)
+            .field("max_reconnect_delay_ms", &self.max_reconnect_delay_ms)
+            .finish()
+    }
+}
+
+/// Where short-lived state lives: codes, sessions, tickets and rate limit windows.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EphemeralBackend {
+    #[default]
+    Redis,
+    /// Process memory. Enough for a single-user desktop install, lost on restart.
+    Memory,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct EphemeralSettings {
+    pub backend: EphemeralBackend,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct OtpSettings {
+    pub expiry_secs: u64,
+}
+
+impl OtpSettings {
+    pub fn expiry(&self) -> Duration {
+        Duration::from_secs(self.expiry_secs)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CorsSettings {
+    /// Origins allowed to call the API with credentials.
+    pub allowed_origins: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SecuritySettings {
+    /// Strict-Transport-Security max-age, sent on responses over HTTPS only.
+    pub hsts_max_age_secs: u64,
+    /// Count requests carrying `X-Forwarded-Proto: https` as HTTPS. Only safe behind a proxy that
+    /// terminates TLS and sets the header.
+    pub trust_forwarded_proto: bool,
+    /// How long a login stays valid.
+    pub session_ttl_secs: u64,
+}
+
+impl SecuritySettings {
+    pub fn session_ttl(&self) -> Duration {
+        Duration::from_secs(self.session_ttl_secs)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TlsSettings {
+    /// Serve the API over HTTPS on `server.port`.
+    pub enabled: bool,
+    /// PEM certificate chain, leaf first.
+    pub cert_path: Option<PathBuf>,
+    /// PEM private key (PKCS#8, PKCS#1 or SEC1).
+    pub key_path: Option<PathBuf>,
+    /// How often the files above are checked for changes, so renewed certificates are picked
+    /// up without a restart.
+    pub reload_interval_secs: u64,
+    /// Plain HTTP port that redirects everything to HTTPS. Unset disables it.
+    pub redirect_port: Option<u16>,
+    /// PEM bundle of CAs that sign admin client certificates. Setting it moves the admin
+    /// endpoints to `admin_port`, which only accepts clients presenting such a certificate.
+    pub client_ca_path: Option<PathBuf>,
+    pub admin_port: Option<u16>,
+}
+
+impl TlsSettings {
+    pub fn mutual_tls(&self) -> bool {
+        self.enabled && self.client_ca_path.is_some()
+    }
+
+    pub fn reload_interval(&self) -> Duration {
+        Duration::from_secs(self.reload_interval_secs)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct OidcSettings {
+    pub discovery_ttl_secs: u64,
+    /// Least time between two key set fetches for one provider, however many ID tokens name
+    /// keys we don't know.
+    pub jwks_min_refetch_secs: u64,
+}
+
+impl OidcSettings {
+    pub fn discovery_ttl(&self) -> Duration {
+        Duration::from_secs(self.discovery_ttl_secs)
+    }
+
+    pub fn jwks_min_refetch(&self) -> Duration {
+        Duration::from_secs(self.jwks_min_refetch_secs)
+    }
+}
+
+#[derive(Clone, Default, Deserialize)]
+pub struct TokenVaultSettings {
+    /// 32 random bytes, hex encoded, e.g. `openssl rand -hex 32`. Encrypts stored provider
+    /// tokens. `TOKEN_VAULT_KEY` sets it as well.
+    pub key: Option<String>,
+}
+
+impl TokenVaultSettings {
+    /// `None` while unset, or when malformed, which `validate` rules out.
+    pub fn decoded_key(&self) -> Option<[u8; 32]> {
+        hex::decode(self.key.as_deref()?.trim())
+            .ok()?
+            .try_into()
+            .ok()
+    }
+}
+
+// Anyone holding the key can read every stored provider token.
+impl fmt::Debug for TokenVaultSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenVaultSettings")
+            .field("key", &self.key.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+/// The OAuth client registered with Google. Google sign-in needs both halves.
+#[derive(Clone, Default, Deserialize)]
+pub struct GoogleSettings {
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+impl fmt::Debug for GoogleSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GoogleSettings")
+            .field("client_id", &self.client_id)
+            .field(
+                "client_secret",
+                &self.client_secret.as_ref().map(|_| "<redacted>"),
+            )
+            .finish()
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct MicrosoftSettings {
+    /// "common", "organizations", "consumers" or a tenant id.
+    pub tenant: String,
+    /// Tenant ids allowed to sign in. Empty allows every tenant the authority accepts.
+    pub allowed_tenants: Vec<String>,
+    /// The app registration's client. Microsoft sign-in needs both halves.
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+impl fmt::Debug for MicrosoftSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MicrosoftSettings")
+            .field("tenant", &self.tenant)
+            .field("allowed_tenants", &self.allowed_tenants)
+            .field("client_id", &self.client_id)
+            .field(
+                "client_secret",
+                &self.client_secret.as_ref().map(|_| "<redacted>"),
+            )
+            .finish()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct LoggingSettings {
+    /// Filter directives in `RUST_LOG` syntax, e.g. "info,wyrd_lib=debug".
+    pub level: String,
+    /// Where the rotated log files go. Unset uses the app data dir, or `./logs` for wyrd-server.
+    pub directory: Option<PathBuf>,
+    /// Daily files kept before the oldest is deleted.
+    pub max_files: usize,
+    /// Also print human readable logs to stdout.
+    pub stdout: bool,
+}
+
+/// What requests are counted together under a rate limit rule.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitKey {
+    #[default]
+    Ip,
+    User,
+    /// Everyone shares one budget.
+    Route,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RateLimitRule {
+    /// Route template the rule applies to, e.g. "/api/v1/signup". Rules without one are
+    /// checked explicitly, like the ones for Tauri commands.
+    pub route: Option<String>,
+    #[serde(default)]
+    pub key: RateLimitKey,
+    /// Requests allowed per window.
+    pub limit: u32,
+    pub window_secs: u64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RateLimitSettings {
+    pub enabled: bool,
+    /// Take the client IP from X-Forwarded-For. Only safe behind a proxy that sets it.
+    pub trust_forwarded_for: bool,
+    #[serde(default)]
+    pub rules: HashMap<String, RateLimitRule>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PresenceSettings {
+    /// How long a gateway connection counts without a heartbeat.
+    pub heartbeat_timeout_secs: u64,
+    /// How long every device of a user has to go without activity before they show as away.
+    pub idle_after_secs: u64,
+}
+
+impl PresenceSettings {
+    pub fn heartbeat_timeout(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_timeout_secs)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct JobsSettings {
+    /// Run scheduled jobs in this process. Instances sharing a database take turns per job.
+    pub enabled: bool,
+    /// Cron expressions in UTC by job name, with a leading seconds field. Jobs left out or set
+    /// to "" only run when started through wyrd-admin.
+    #[serde(default)]
+    pub schedules: HashMap<String, String>,
+    /// How long a password sign-up may stay unverified before it's removed.
+    pub unverified_grace_hours: u64,
+    /// How long deleted accounts are kept before they're purged.
+    pub deletion_grace_days: u64,
+    /// How long job run history is kept.
+    pub history_days: u64,
+}
+
+impl JobsSettings {
+    pub fn schedule(&self, job: &str) -> Option<cron::Schedule> {
+        self.schedules
+            .get(job)
+            .and_then(|expression| cron::Schedule::from_str(expression).ok())
+    }
+
+    pub fn unverified_grace(&self) -> Duration {
+        Duration::from_secs(self.unverified_grace_hours * 60 * 60)
+    }
+
+    pub fn deletion_grace(&self) -> Duration {
+        Duration::from_secs(self.deletion_grace_days * 24 * 60 * 60)
+    }
+
+    pub fn history(&self) -> Duration {
+        Duration::from_secs(self.history_days * 24 * 60 * 60)
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct DesktopSettings {
+    /// Backend the desktop app talks to. Unset runs the server inside the app.
+    pub server_url: Option<String>,
+}
+
+/// Everything the backend can be configured with.
+///
+/// Layers, later ones winning: `config/default.toml`, the profile's `config/<profile>.toml`,
+/// the file at `WYRD_CONFIG` if set, then `WYRD__<SECTION>__<KEY>` environment variables
+/// (e.g. `WYRD__SERVER__PORT=4000`), then the plain variables in `PLAIN_VARIABLES`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Settings {
+    pub profile: Profile,
+    pub server: ServerSettings,
+    pub database: DatabaseSettings,
+    pub redis: RedisSettings,
+    #[serde(default)]
+    pub ephemeral: EphemeralSettings,
+    pub otp: OtpSettings,
+    pub cors: CorsSettings,
+    pub security: SecuritySettings,
+    pub tls: TlsSettings,
+    pub oidc: OidcSettings,
+    #[serde(default)]
+    pub token_vault: TokenVaultSettings,
+    #[serde(default)]
+    pub google: GoogleSettings,
+    pub microsoft: MicrosoftSettings,
+    pub logging: LoggingSettings,
+    pub rate_limit: RateLimitSettings,
+    pub presence: PresenceSettings,
+    pub jobs: JobsSettings,
+    #[serde(default)]
+    pub desktop: DesktopSettings,
+}
+
+impl Settings {
+    pub fn load() -> Result<Self, SettingsError> {
+        dotenvy::dotenv().ok();
+        Settings::load_profile(Profile::from_env()?)
+    }
+
+    pub fn load_profile(profile: Profile) -> Result<Self, SettingsError> {
+        let mut builder = Config::builder()
+            .add_source(File::from_str(DEFAULT_CONFIG, FileFormat::Toml))
+            .add_source(File::from_str(profile.config(), FileFormat::Toml));
+
+        if let Ok(path) = std::env::var("WYRD_CONFIG") {
+            builder = builder.add_source(File::with_name(&path));
+        }
+
+        let mut builder = builder
+            .add_source(
+                Environment::with_prefix("WYRD")
+                    .prefix_separator("__")
+                    .separator("__")
+                    .list_separator(",")
+                    .with_list_parse_key("cors.allowed_origins")
+                    .with_list_parse_key("microsoft.allowed_tenants")
+                    .try_parsing(true),
+            )
+            .set_override("profile", profile.to_string())?;
+        for (variable, key) in PLAIN_VARIABLES {
+            let value = std::env::var(variable)
+                .ok()
+                .filter(|value| !value.is_empty());
+            builder = builder.set_override_option(*key, value)?;
+        }
+        let settings: Settings = builder.build()?.try_deserialize()?;
+
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    pub fn validate(&self) -> Result<(), SettingsError> {
+        if self.server.port == 0 {
+            return Err(invalid("server.port", "must not be 0"));
+        }
+        if self.server.startup_attempts == 0 {
+            return Err(invalid("server.startup_attempts", "must be at least 1"));
+        }
+        if self.ephemeral.backend == EphemeralBackend::Redis {
+            let scheme = self.redis.url.split("://").next().unwrap_or_default();
+            if !["redis", "rediss", "redis+unix", "unix"].contains(&scheme) {
+                return Err(invalid(
+                    "redis.url",
+                    "must start with redis://, rediss:// or redis+unix://",
+                ));
+            }
+            if self.redis.connect_timeout_secs == 0 || self.redis.response_timeout_secs == 0 {
+                return Err(invalid(
+                    "redis.connect_timeout_secs",
+                    "timeouts must be at least 1",
+                ));
+            }
+        }
+        if self.security.session_ttl_secs == 0 {
+            return Err(invalid("security.session_ttl_secs", "must be at least 1"));
+        }
+
+        if let Some(admin) = self.server.admin_address() {
+            let port = admin.port();
+            if port == self.server.port || Some(port) == self.tls.redirect_port {
+                return Err(invalid(
+                    "server.admin_port",
+                    "must differ from server.port and tls.redirect_port",
+                ));
+            }
+        }
+
+        if self.tls.enabled {
+            if self.tls.cert_path.is_none() || self.tls.key_path.is_none() {
+                return Err(invalid(
+                    "tls.cert_path",
+                    "tls.cert_path and tls.key_path are required when TLS is enabled",
+                ));
+            }
+            if self.tls.reload_interval_secs == 0 {
+                return Err(invalid("tls.reload_interval_secs", "must be at least 1"));
+            }
+            if self.tls.redirect_port == Some(self.server.port) {
+                return Err(invalid("tls.redirect_port", "must differ from server.port"));
+            }
+        } else if self.tls.redirect_port.is_some() || self.tls.client_ca_path.is_some() {
+            return Err(invalid(
+                "tls.enabled",
+                "must be true to use tls.redirect_port or tls.client_ca_path",
+            ));
+        }
+        if self.tls.client_ca_path.is_some() {
+            match self.tls.admin_port {
+                None => {
+                    return Err(invalid(
+                        "tls.admin_port",
+                        "is required when tls.client_ca_path is set",
+                    ))
+                }
+                Some(port) if port == self.server.port || Some(port) == self.tls.redirect_port => {
+                    return Err(invalid(
+                        "tls.admin_port",
+                        "must differ from server.port and tls.redirect_port",
+                    ))
+                }
+                Some(_) => {}
+            }
+        }
+
+        if !(self.database.url.starts_with("postgres://")
+            || self.database.url.starts_with("postgresql://"))
+        {
+            return Err(invalid(
+                "database.url",
+                "must be a postgres:// URL, set it in the config file or through DATABASE_URL",
+            ));
+        }
+        if self.database.max_connections == 0 {
+            return Err(invalid("database.max_connections", "must be at least 1"));
+        }
+
+        if !(30..=900).contains(&self.otp.expiry_secs) {
+            return Err(invalid(
+                "otp.expiry_secs",
+                "must be between 30 and 900 seconds",
+            ));
+        }
+
+        if self.cors.allowed_origins.is_empty() {
+            return Err(invalid(
+                "cors.allowed_origins",
+                "must list at least one origin",
+            ));
+        }
+        for origin in &self.cors.allowed_origins {
+            if origin == "*" {
+                return Err(invalid(
+                    "cors.allowed_origins",
+                    "\"*\" can't be used, the session cookie needs credentialed requests",
+                ));
+            }
+            if url::Url::parse(origin).is_err() {
+                return Err(invalid(
+                    "cors.allowed_origins",
+                    format!("'{}' is not a valid origin", origin),
+                ));
+            }
+        }
+
+        if self.oidc.discovery_ttl_secs < 60 {
+            return Err(invalid(
+                "oidc.discovery_ttl_secs",
+                "must be at least 60 seconds",
+            ));
+        }
+        if self.oidc.jwks_min_refetch_secs > self.oidc.discovery_ttl_secs {
+            return Err(invalid(
+                "oidc.jwks_min_refetch_secs",
+                "must not exceed oidc.discovery_ttl_secs",
+            ));
+        }
+
+        if self.token_vault.key.is_some() && self.token_vault.decoded_key().is_none() {
+            return Err(invalid(
+                "token_vault.key",
+                "must be 32 bytes, hex encoded (64 characters)",
+            ));
+        }
+
+        if let Err(err) = EnvFilter::try_new(&self.logging.level) {
+            return Err(invalid("logging.level", err.to_string()));
+        }
+        if self.logging.max_files == 0 {
+            return Err(invalid("logging.max_files", "must be at least 1"));
+        }
+
+        for rule in self.rate_limit.rules.values() {
+            if rule.limit == 0 {
+                return Err(invalid("rate_limit.rules.limit", "must be at least 1"));
+            }
+            if rule.window_secs == 0 {
+                return Err(invalid(
+                    "rate_limit.rules.window_secs",
+                    "must be at least 1 second",
+                ));
+            }
+            if let Some(route) = &rule.route {
+                if !route.starts_with('/') {
+                    return Err(invalid(
+                        "rate_limit.rules.route",
+                        format!("'{}' must start with '/'", route),
+                    ));
+                }
+            }
+        }
+
+        if self.presence.heartbeat_timeout_secs == 0 {
+            return Err(invalid(
+                "presence.heartbeat_timeout_secs",
+                "must be at least 1",
+            ));
+        }
+        if self.presence.idle_after_secs == 0 {
+            return Err(invalid("presence.idle_after_secs", "must be at least 1"));
+        }
+
+        for (job, expression) in &self.jobs.schedules {
+            if crate::jobs::find(job).is_none() {
+                return Err(invalid(
+                    "jobs.schedules",
+                    format!("there is no job called '{}'", job),
+                ));
+            }
+            if expression.is_empty() {
+                continue;
+            }
+            if let Err(err) = cron::Schedule::from_str(expression) {
+                return Err(invalid(
+                    "jobs.schedules",
+                    format!("'{}' for {}: {}", expression, job, err),
+                ));
+            }
+        }
+        // Shorter than the emailed code's lifetime would remove sign-ups mid-verification.
+        if self.jobs.unverified_grace_hours == 0 {
+            return Err(invalid("jobs.unverified_grace_hours", "must be at least 1"));
+        }
+        if self.jobs.history_days == 0 {
+            return Err(invalid("jobs.history_days", "must be at least 1"));
+        }
+
+        if let Some(server_url) = &self.desktop.server_url {
+            let is_http = url::Url::parse(server_url)
+                .map(|url| url.scheme() == "http" || url.scheme() == "https")
+                .unwrap_or(false);
+            if !is_http {
+                return Err(invalid(
+                    "desktop.server_url",
+                    format!("'{}' is not an http(s) URL", server_url),
+                ));
+            }
+        }
+
+        if self.google.client_id.is_some() != self.google.client_secret.is_some() {
+            return Err(invalid(
+                "google.client_secret",
+                "google.client_id and google.client_secret are set together",
+            ));
+        }
+        if self.microsoft.client_id.is_some() != self.microsoft.client_secret.is_some() {
+            return Err(invalid(
+                "microsoft.client_secret",
+                "microsoft.client_id and microsoft.client_secret are set together",
+            ));
+        }
+        if self.microsoft.tenant.trim().is_empty() {
+            return Err(invalid(
+                "microsoft.tenant",
+                "must be common, organizations, consumers or a tenant id",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+fn invalid(key: &'static str, reason: impl Into<String>) -> SettingsError {
+    SettingsError::Invalid {
+        key,
+        reason: reason.into(),
+    }
+}
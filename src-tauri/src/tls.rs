@@ -0,0 +1,168 @@
+use anyhow::Context;
+use axum::{
+    extract::Request,
+    http::{header, uri::Authority, StatusCode, Uri},
+    response::{IntoResponse, Redirect, Response},
+    Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::settings::TlsSettings;
+
+/// Which client certificates a listener asks for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClientAuth {
+    None,
+    /// Only clients with a certificate signed by `tls.client_ca_path` get through the handshake.
+    Required,
+}
+
+/// Builds the rustls config for the certificate and key in `settings`.
+pub fn server_config(
+    settings: &TlsSettings,
+    client_auth: ClientAuth,
+) -> Result<ServerConfig, anyhow::Error> {
+    let cert_path = required(&settings.cert_path, "tls.cert_path")?;
+    let key_path = required(&settings.key_path, "tls.key_path")?;
+
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read certificates from {}", cert_path.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates found in {}", cert_path.display());
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .with_context(|| format!("Failed to read the private key from {}", key_path.display()))?;
+
+    let builder = ServerConfig::builder();
+    let builder = match client_auth {
+        ClientAuth::None => builder.with_no_client_auth(),
+        ClientAuth::Required => {
+            let ca_path = required(&settings.client_ca_path, "tls.client_ca_path")?;
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(ca_path)
+                .with_context(|| format!("Failed to read client CAs from {}", ca_path.display()))?
+            {
+                roots.add(cert?)?;
+            }
+            builder
+                .with_client_cert_verifier(WebPkiClientVerifier::builder(Arc::new(roots)).build()?)
+        }
+    };
+
+    let mut config = builder.with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+pub fn rustls_config(
+    settings: &TlsSettings,
+    client_auth: ClientAuth,
+) -> Result<RustlsConfig, anyhow::Error> {
+    Ok(RustlsConfig::from_config(Arc::new(server_config(
+        settings,
+        client_auth,
+    )?)))
+}
+
+fn required<'a>(path: &'a Option<PathBuf>, key: &str) -> Result<&'a Path, anyhow::Error> {
+    path.as_deref()
+        .ok_or_else(|| anyhow::anyhow!("{} is not set", key))
+}
+
+/// Re-reads the certificate files every `tls.reload_interval_secs` and swaps them in when one
+/// changed, so renewals don't need a restart. Connections already open keep their certificate.
+pub fn spawn_reloader(
+    config: RustlsConfig,
+    settings: TlsSettings,
+    client_auth: ClientAuth,
+    shutdown: CancellationToken,
+) {
+    tokio::spawn(async move {
+        let mut last_modified = modified(&settings);
+        let mut interval = tokio::time::interval(settings.reload_interval());
+        interval.tick().await;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {},
+                _ = shutdown.cancelled() => return,
+            }
+
+            let current = modified(&settings);
+            if current == last_modified {
+                continue;
+            }
+            match server_config(&settings, client_auth) {
+                Ok(server_config) => {
+                    config.reload_from_config(Arc::new(server_config));
+                    last_modified = current;
+                    info!("Reloaded TLS certificates");
+                }
+                // Most likely caught halfway through a renewal; try again next tick.
+                Err(err) => warn!("Keeping the current TLS certificates: {:#}", err),
+            }
+        }
+    });
+}
+
+fn modified(settings: &TlsSettings) -> Vec<Option<SystemTime>> {
+    [
+        &settings.cert_path,
+        &settings.key_path,
+        &settings.client_ca_path,
+    ]
+    .into_iter()
+    .flatten()
+    .map(|path| {
+        std::fs::metadata(path)
+            .and_then(|meta| meta.modified())
+            .ok()
+    })
+    .collect()
+}
+
+/// Answers every plain HTTP request with a permanent redirect to the same URL over HTTPS.
+pub fn redirect_router(https_port: u16) -> Router {
+    Router::new()
+        .fallback(move |request: Request| async move { redirect_to_https(&request, https_port) })
+}
+
+fn redirect_to_https(request: &Request, https_port: u16) -> Response {
+    let host = request
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<Authority>().ok());
+    let Some(host) = host else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    let authority = if https_port == 443 {
+        host.host().to_string()
+    } else {
+        format!("{}:{}", host.host(), https_port)
+    };
+    let path_and_query = request
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+
+    match Uri::builder()
+        .scheme("https")
+        .authority(authority)
+        .path_and_query(path_and_query)
+        .build()
+    {
+        Ok(uri) => Redirect::permanent(&uri.to_string()).into_response(),
+        Err(_) => StatusCode::BAD_REQUEST.into_response(),
+    }
+}
@@ -26,7 +26,7 @@ pub async fn hash(password: String) -> anyhow::Result<String> {
     .context("panic in hash()")?
 }
 
-pub async fn verify(pool: &PgPool, password: String, hash: String) -> anyhow::Result<bool> {
+pub async fn verify(password: String, hash: String) -> anyhow::Result<bool> {
     task::spawn_blocking(move || {
         let hash = PasswordHash::new(&hash)
             .map_err(|e| anyhow!(e).context("BUG: password hash invalid"))?;
@@ -0,0 +1,243 @@
+use std::time::Duration;
+use wyrd_lib::accounts::{self, AccountError};
+use wyrd_lib::auth_handler::LoginReq;
+use wyrd_lib::auth_service::{login, AuthenticationErrors};
+use wyrd_lib::memory_store::{MemorySessionStore, MemoryUserRepository};
+use wyrd_lib::password;
+use wyrd_lib::repository::{
+    NewUser, SessionStore, UserRepository, UserSearch, ACTIVE, DELETED, SUSPENDED,
+};
+
+mod common;
+
+const PASSWORD: &str = "correct horse battery";
+const SESSION_TTL: Duration = Duration::from_secs(60);
+
+// A password sign-up that hasn't verified its email yet.
+async fn add_user(users: &MemoryUserRepository, username: &str) -> i64 {
+    users
+        .create(&NewUser {
+            password: Some(password::hash(PASSWORD.to_string()).await.unwrap()),
+            user_verified: false,
+            ..common::new_user(username)
+        })
+        .await
+        .unwrap()
+}
+
+async fn log_in(
+    users: &MemoryUserRepository,
+    sessions: &MemorySessionStore,
+    username: &str,
+) -> Result<String, AuthenticationErrors> {
+    let request = LoginReq {
+        username: Some(username.to_string()),
+        email: None,
+        password: PASSWORD.to_string(),
+    };
+    login(users, sessions, request, SESSION_TTL).await
+}
+
+#[tokio::test]
+async fn finds_accounts_by_id_email_or_username() {
+    let users = MemoryUserRepository::new();
+    let id = add_user(&users, "ada").await;
+
+    for who in [
+        id.to_string(),
+        format!("#{}", id),
+        "ADA@example.com".to_string(),
+        "Ada".to_string(),
+    ] {
+        assert_eq!(accounts::find(&users, &who).await.unwrap().id, id);
+    }
+    assert!(matches!(
+        accounts::find(&users, "grace").await,
+        Err(AccountError::NotFound(_))
+    ));
+}
+
+#[tokio::test]
+async fn search_filters_by_text_and_status() {
+    let users = MemoryUserRepository::new();
+    add_user(&users, "ada").await;
+    let grace = add_user(&users, "grace").await;
+    add_user(&users, "gracie").await;
+    users.set_status(grace, SUSPENDED).await.unwrap();
+
+    let found = users
+        .search(&UserSearch {
+            text: Some("GRAC".to_string()),
+            ..UserSearch::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(found.len(), 2);
+
+    let suspended = users
+        .search(&UserSearch {
+            status: Some(SUSPENDED.to_string()),
+            ..UserSearch::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(suspended.len(), 1);
+    assert_eq!(suspended[0].id, grace);
+
+    let page = users
+        .search(&UserSearch {
+            limit: 1,
+            offset: 1,
+            ..UserSearch::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(page[0].id, grace);
+}
+
+#[tokio::test]
+async fn suspension_ends_sessions_and_blocks_login() {
+    let users = MemoryUserRepository::new();
+    let sessions = MemorySessionStore::new();
+    add_user(&users, "ada").await;
+    let session = log_in(&users, &sessions, "ada").await.unwrap();
+
+    let user = accounts::find(&users, "ada").await.unwrap();
+    assert_eq!(
+        accounts::suspend(&users, &sessions, &user).await.unwrap(),
+        1
+    );
+    assert_eq!(sessions.user_id(&session).await.unwrap(), None);
+    assert!(matches!(
+        log_in(&users, &sessions, "ada").await,
+        Err(AuthenticationErrors::AccountSuspended)
+    ));
+
+    let user = accounts::find(&users, "ada").await.unwrap();
+    assert!(matches!(
+        accounts::suspend(&users, &sessions, &user).await,
+        Err(AccountError::WrongStatus { .. })
+    ));
+    accounts::unsuspend(&users, &user).await.unwrap();
+    assert!(log_in(&users, &sessions, "ada").await.is_ok());
+}
+
+#[tokio::test]
+async fn forced_password_reset_blocks_login() {
+    let users = MemoryUserRepository::new();
+    let sessions = MemorySessionStore::new();
+    add_user(&users, "ada").await;
+    log_in(&users, &sessions, "ada").await.unwrap();
+
+    let user = accounts::find(&users, "ada").await.unwrap();
+    assert_eq!(
+        accounts::force_password_reset(&users, &sessions, &user)
+            .await
+            .unwrap(),
+        1
+    );
+    assert!(matches!(
+        log_in(&users, &sessions, "ada").await,
+        Err(AuthenticationErrors::PasswordResetRequired)
+    ));
+}
+
+#[tokio::test]
+async fn marks_accounts_verified() {
+    let users = MemoryUserRepository::new();
+    add_user(&users, "ada").await;
+
+    let user = accounts::find(&users, "ada").await.unwrap();
+    accounts::mark_verified(&users, &user).await.unwrap();
+    assert!(accounts::find(&users, "ada").await.unwrap().user_verified);
+}
+
+#[tokio::test]
+async fn purge_only_removes_deleted_accounts_when_confirmed() {
+    let users = MemoryUserRepository::new();
+    let sessions = MemorySessionStore::new();
+    let ada = add_user(&users, "ada").await;
+    let grace = add_user(&users, "grace").await;
+    users.set_status(grace, DELETED).await.unwrap();
+    assert!(matches!(
+        log_in(&users, &sessions, "grace").await,
+        Err(AuthenticationErrors::LoginError(_))
+    ));
+
+    let dry_run = accounts::purge_deleted(&users, &sessions, None, true)
+        .await
+        .unwrap();
+    assert_eq!(dry_run.len(), 1);
+    assert_eq!(dry_run[0].id, grace);
+    assert!(users.find_by_id(grace).await.unwrap().is_some());
+
+    accounts::purge_deleted(&users, &sessions, None, false)
+        .await
+        .unwrap();
+    assert!(users.find_by_id(grace).await.unwrap().is_none());
+    assert_eq!(users.find_by_id(ada).await.unwrap().unwrap().status, ACTIVE);
+    assert!(accounts::purge_deleted(&users, &sessions, None, true)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn purge_waits_out_the_deletion_grace_period() {
+    let users = MemoryUserRepository::new();
+    let sessions = MemorySessionStore::new();
+    let ada = add_user(&users, "ada").await;
+    users.set_status(ada, DELETED).await.unwrap();
+
+    let grace = Some(Duration::from_secs(60 * 60));
+    assert!(accounts::purge_deleted(&users, &sessions, grace, false)
+        .await
+        .unwrap()
+        .is_empty());
+
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    let purged = accounts::purge_deleted(&users, &sessions, Some(Duration::ZERO), false)
+        .await
+        .unwrap();
+    assert_eq!(purged.len(), 1);
+    assert!(users.find_by_id(ada).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn purges_only_stale_unverified_password_sign_ups() {
+    let users = MemoryUserRepository::new();
+    let sessions = MemorySessionStore::new();
+    let unverified = add_user(&users, "ada").await;
+    let verified = add_user(&users, "grace").await;
+    users.mark_verified("grace@example.com").await.unwrap();
+    let social = users
+        .create(&NewUser {
+            name: "Alan Example".to_string(),
+            username: "alan".to_string(),
+            email: "alan@example.com".to_string(),
+            password: None,
+            user_verified: false,
+            totp_secret: "secret".to_string(),
+        })
+        .await
+        .unwrap();
+
+    let grace = Duration::from_secs(60 * 60);
+    assert_eq!(
+        accounts::purge_unverified(&users, &sessions, grace)
+            .await
+            .unwrap(),
+        0
+    );
+
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    assert_eq!(
+        accounts::purge_unverified(&users, &sessions, Duration::ZERO)
+            .await
+            .unwrap(),
+        1
+    );
+    assert!(users.find_by_id(unverified).await.unwrap().is_none());
+    assert!(users.find_by_id(verified).await.unwrap().is_some());
+    assert!(users.find_by_id(social).await.unwrap().is_some());
+}
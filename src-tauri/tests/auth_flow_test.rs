@@ -0,0 +1,270 @@
+// Signup, email verification and login against the in-memory stores: no Postgres, Redis or SMTP.
+use std::time::Duration;
+use wyrd_lib::auth_handler::{LoginReq, SignupReq};
+use wyrd_lib::auth_service::{login, signup, AuthenticationErrors};
+use wyrd_lib::memory_store::{MemorySessionStore, MemoryUserRepository, MemoryVerificationStore};
+use wyrd_lib::otp::{start_verification, verify_otp, OTPErrors};
+use wyrd_lib::repository::{PendingVerification, SessionStore, UserRepository, VerificationStore};
+
+const EXPIRY: Duration = Duration::from_secs(90);
+const SESSION_TTL: Duration = Duration::from_secs(60);
+
+fn signup_req(username: &str, email: &str) -> SignupReq {
+    SignupReq {
+        name: "Ada Lovelace".to_string(),
+        username: username.to_string(),
+        email: email.to_string(),
+        password: "correct horse battery".to_string(),
+        invite: None,
+    }
+}
+
+fn login_req(username: Option<&str>, email: Option<&str>, password: &str) -> LoginReq {
+    LoginReq {
+        username: username.map(str::to_string),
+        email: email.map(str::to_string),
+        password: password.to_string(),
+    }
+}
+
+fn pending(email: &str) -> PendingVerification {
+    PendingVerification {
+        email: email.to_string(),
+        name: "Ada Lovelace".to_string(),
+        invite: None,
+    }
+}
+
+#[tokio::test]
+async fn signup_creates_an_unverified_account() {
+    let users = MemoryUserRepository::new();
+    signup(&users, &signup_req("ada", "ada@example.com"))
+        .await
+        .unwrap();
+
+    let user = users
+        .find_by_email("ADA@example.com")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(user.username, "ada");
+    assert!(!user.user_verified);
+    assert_ne!(user.password.as_deref(), Some("correct horse battery"));
+}
+
+#[tokio::test]
+async fn signup_reports_every_bad_field() {
+    let users = MemoryUserRepository::new();
+    let errors = signup(&users, &signup_req("1a", "not-an-email"))
+        .await
+        .unwrap_err();
+
+    assert_eq!(errors.len(), 2);
+    assert!(matches!(
+        errors[0],
+        AuthenticationErrors::InvalidUsername(_)
+    ));
+    assert!(matches!(
+        errors[1],
+        AuthenticationErrors::SignupInvalidEmail
+    ));
+}
+
+#[tokio::test]
+async fn signup_rejects_taken_names_regardless_of_case() {
+    let users = MemoryUserRepository::new();
+    signup(&users, &signup_req("ada", "ada@example.com"))
+        .await
+        .unwrap();
+
+    let errors = signup(&users, &signup_req("ADA", "Ada@Example.com"))
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        errors[..],
+        [
+            AuthenticationErrors::SignupErrorUsername(_),
+            AuthenticationErrors::SignupErrorEmail(_)
+        ]
+    ));
+}
+
+#[tokio::test]
+async fn emailed_code_verifies_the_account() {
+    let users = MemoryUserRepository::new();
+    let verifications = MemoryVerificationStore::new();
+    signup(&users, &signup_req("ada", "ada@example.com"))
+        .await
+        .unwrap();
+
+    let code = start_verification(&users, &verifications, &pending("ada@example.com"), EXPIRY)
+        .await
+        .unwrap();
+    assert_eq!(code.len(), 6);
+    assert!(!verify_otp(&verifications, "ada@example.com", "not it")
+        .await
+        .unwrap());
+    assert!(verify_otp(&verifications, "Ada@Example.com", &code)
+        .await
+        .unwrap());
+
+    let pending = verifications
+        .pending("ada@example.com")
+        .await
+        .unwrap()
+        .unwrap();
+    users.mark_verified(&pending.email).await.unwrap();
+    verifications.finish("ada@example.com").await.unwrap();
+
+    let user = users.find_by_username("ada").await.unwrap().unwrap();
+    assert!(user.user_verified);
+    assert!(verifications
+        .pending("ada@example.com")
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+async fn overlapping_sign_ups_keep_their_own_code_and_invite() {
+    let users = MemoryUserRepository::new();
+    let verifications = MemoryVerificationStore::new();
+    signup(&users, &signup_req("ada", "ada@example.com"))
+        .await
+        .unwrap();
+    signup(&users, &signup_req("grace", "grace@example.com"))
+        .await
+        .unwrap();
+
+    let invited = PendingVerification {
+        invite: Some("c0ffee".to_string()),
+        ..pending("ada@example.com")
+    };
+    let ada_code = start_verification(&users, &verifications, &invited, EXPIRY)
+        .await
+        .unwrap();
+    // Grace signs up before Ada enters her code.
+    let grace_code = start_verification(
+        &users,
+        &verifications,
+        &pending("grace@example.com"),
+        EXPIRY,
+    )
+    .await
+    .unwrap();
+
+    assert!(verify_otp(&verifications, "ada@example.com", &ada_code)
+        .await
+        .unwrap());
+    assert_eq!(
+        verifications.pending("ada@example.com").await.unwrap(),
+        Some(invited)
+    );
+    verifications.finish("ada@example.com").await.unwrap();
+
+    assert!(verify_otp(&verifications, "grace@example.com", &grace_code)
+        .await
+        .unwrap());
+    let grace = verifications
+        .pending("grace@example.com")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(grace.email, "grace@example.com");
+    assert_eq!(grace.invite, None);
+}
+
+#[tokio::test]
+async fn expired_codes_are_rejected() {
+    let users = MemoryUserRepository::new();
+    let verifications = MemoryVerificationStore::new();
+    signup(&users, &signup_req("ada", "ada@example.com"))
+        .await
+        .unwrap();
+
+    let code = start_verification(
+        &users,
+        &verifications,
+        &pending("ada@example.com"),
+        Duration::ZERO,
+    )
+    .await
+    .unwrap();
+    assert!(matches!(
+        verify_otp(&verifications, "ada@example.com", &code).await,
+        Err(OTPErrors::Expired)
+    ));
+}
+
+#[tokio::test]
+async fn codes_need_an_account() {
+    let users = MemoryUserRepository::new();
+    let verifications = MemoryVerificationStore::new();
+    let result = start_verification(
+        &users,
+        &verifications,
+        &pending("nobody@example.com"),
+        EXPIRY,
+    )
+    .await;
+    assert!(matches!(result, Err(OTPErrors::GenerateOTPError(_))));
+}
+
+#[tokio::test]
+async fn login_opens_a_session() {
+    let users = MemoryUserRepository::new();
+    let sessions = MemorySessionStore::new();
+    signup(&users, &signup_req("ada", "ada@example.com"))
+        .await
+        .unwrap();
+    let id = users.find_by_username("ada").await.unwrap().unwrap().id;
+    assert!(users.last_login(id).is_none());
+
+    let by_username = login(
+        &users,
+        &sessions,
+        login_req(Some("Ada"), None, "correct horse battery"),
+        SESSION_TTL,
+    )
+    .await
+    .unwrap();
+    let by_email = login(
+        &users,
+        &sessions,
+        login_req(None, Some("ada@example.com"), "correct horse battery"),
+        SESSION_TTL,
+    )
+    .await
+    .unwrap();
+
+    assert_ne!(by_username, by_email);
+    assert_eq!(sessions.user_id(&by_username).await.unwrap(), Some(id));
+    assert!(users.last_login(id).is_some());
+
+    assert_eq!(sessions.revoke_all(id).await.unwrap(), 2);
+    assert_eq!(sessions.user_id(&by_email).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn login_rejects_bad_credentials() {
+    let users = MemoryUserRepository::new();
+    let sessions = MemorySessionStore::new();
+    signup(&users, &signup_req("ada", "ada@example.com"))
+        .await
+        .unwrap();
+
+    for request in [
+        login_req(Some("ada"), None, "wrong"),
+        login_req(Some("grace"), None, "correct horse battery"),
+        login_req(
+            Some("ada"),
+            Some("ada@example.com"),
+            "correct horse battery",
+        ),
+    ] {
+        assert!(matches!(
+            login(&users, &sessions, request, SESSION_TTL).await,
+            Err(AuthenticationErrors::LoginError(_))
+        ));
+    }
+}
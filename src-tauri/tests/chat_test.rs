@@ -0,0 +1,687 @@
+// Conversations and their history, against the in-memory stores.
+use chrono::{Duration, Utc};
+use wyrd_lib::chat::{
+    self, ChatError, HistoryPage, MAX_GROUP_NAME_CHARS, MAX_INVITE_EXPIRY_SECS, MAX_PAGE,
+};
+use wyrd_lib::memory_store::{MemoryConversationStore, MemoryUserRepository};
+use wyrd_lib::repository::{
+    ConversationStore, GroupEvent, HistoryCursor, Invite, User, ADMIN, MEMBER, OWNER, SYSTEM,
+};
+
+mod common;
+
+fn seqs(page: &HistoryPage) -> Vec<i64> {
+    page.messages.iter().map(|message| message.seq).collect()
+}
+
+#[tokio::test]
+async fn either_side_opens_the_same_direct_conversation() {
+    let users = MemoryUserRepository::new();
+    let conversations = MemoryConversationStore::new();
+    let ada = common::add_user(&users, "ada").await;
+    let grace = common::add_user(&users, "grace").await;
+    common::add_user(&users, "linus").await;
+
+    let first = chat::open_direct(&users, &conversations, &ada, "grace")
+        .await
+        .unwrap();
+    let second = chat::open_direct(&users, &conversations, &grace, "ada")
+        .await
+        .unwrap();
+    assert_eq!(first.id, second.id);
+    assert_eq!(first.last_seq, 0);
+
+    let other = chat::open_direct(&users, &conversations, &ada, "linus")
+        .await
+        .unwrap();
+    assert_ne!(other.id, first.id);
+}
+
+#[tokio::test]
+async fn messages_are_numbered_per_conversation() {
+    let users = MemoryUserRepository::new();
+    let conversations = MemoryConversationStore::new();
+    let ada = common::add_user(&users, "ada").await;
+    let grace = common::add_user(&users, "grace").await;
+    common::add_user(&users, "linus").await;
+    let with_grace = chat::open_direct(&users, &conversations, &ada, "grace")
+        .await
+        .unwrap();
+    let with_linus = chat::open_direct(&users, &conversations, &ada, "linus")
+        .await
+        .unwrap();
+
+    let (first, members) = chat::post_message(
+        &conversations,
+        &ada,
+        with_grace.id,
+        " hi ",
+        Some("n1".to_string()),
+    )
+    .await
+    .unwrap();
+    assert_eq!(members, [ada.id, grace.id]);
+    assert_eq!(first.body, "hi");
+    assert_eq!(first.sender_id, Some(ada.id));
+    assert_eq!(first.nonce.as_deref(), Some("n1"));
+
+    let (reply, _) = chat::post_message(&conversations, &grace, with_grace.id, "hey", None)
+        .await
+        .unwrap();
+    let (elsewhere, _) = chat::post_message(&conversations, &ada, with_linus.id, "yo", None)
+        .await
+        .unwrap();
+    assert_eq!((first.seq, reply.seq, elsewhere.seq), (1, 2, 1));
+    assert_eq!(
+        conversations
+            .find(with_grace.id)
+            .await
+            .unwrap()
+            .unwrap()
+            .last_seq,
+        2
+    );
+}
+
+#[tokio::test]
+async fn only_members_see_a_conversation() {
+    let users = MemoryUserRepository::new();
+    let conversations = MemoryConversationStore::new();
+    let ada = common::add_user(&users, "ada").await;
+    common::add_user(&users, "grace").await;
+    let linus = common::add_user(&users, "linus").await;
+    let conversation = chat::open_direct(&users, &conversations, &ada, "grace")
+        .await
+        .unwrap();
+
+    assert!(matches!(
+        chat::post_message(&conversations, &linus, conversation.id, "hi", None).await,
+        Err(ChatError::ConversationNotFound(_))
+    ));
+    assert!(matches!(
+        chat::history(
+            &conversations,
+            &linus,
+            conversation.id,
+            HistoryCursor::Latest,
+            None
+        )
+        .await,
+        Err(ChatError::ConversationNotFound(_))
+    ));
+    assert!(matches!(
+        chat::history(&conversations, &ada, 999, HistoryCursor::Latest, None).await,
+        Err(ChatError::ConversationNotFound(_))
+    ));
+    assert!(matches!(
+        chat::post_message(&conversations, &ada, conversation.id, "  ", None).await,
+        Err(ChatError::EmptyBody)
+    ));
+}
+
+#[tokio::test]
+async fn history_pages_in_both_directions() {
+    let users = MemoryUserRepository::new();
+    let conversations = MemoryConversationStore::new();
+    let ada = common::add_user(&users, "ada").await;
+    common::add_user(&users, "grace").await;
+    let id = chat::open_direct(&users, &conversations, &ada, "grace")
+        .await
+        .unwrap()
+        .id;
+    for n in 1..=7 {
+        chat::post_message(&conversations, &ada, id, &format!("message {}", n), None)
+            .await
+            .unwrap();
+    }
+
+    let latest = chat::history(&conversations, &ada, id, HistoryCursor::Latest, Some(3))
+        .await
+        .unwrap();
+    assert_eq!(seqs(&latest), [5, 6, 7]);
+    assert_eq!((latest.older, latest.newer), (Some(5), None));
+
+    let older = chat::history(&conversations, &ada, id, HistoryCursor::Before(5), Some(3))
+        .await
+        .unwrap();
+    assert_eq!(seqs(&older), [2, 3, 4]);
+    assert_eq!((older.older, older.newer), (Some(2), Some(4)));
+
+    let oldest = chat::history(&conversations, &ada, id, HistoryCursor::Before(2), Some(3))
+        .await
+        .unwrap();
+    assert_eq!(seqs(&oldest), [1]);
+    assert_eq!((oldest.older, oldest.newer), (None, Some(1)));
+
+    let newer = chat::history(&conversations, &ada, id, HistoryCursor::After(1), Some(3))
+        .await
+        .unwrap();
+    assert_eq!(seqs(&newer), [2, 3, 4]);
+    assert_eq!((newer.older, newer.newer), (Some(2), Some(4)));
+
+    let caught_up = chat::history(&conversations, &ada, id, HistoryCursor::After(4), Some(3))
+        .await
+        .unwrap();
+    assert_eq!(seqs(&caught_up), [5, 6, 7]);
+    assert_eq!(caught_up.newer, None);
+
+    let from_start = chat::history(&conversations, &ada, id, HistoryCursor::After(0), Some(2))
+        .await
+        .unwrap();
+    assert_eq!(seqs(&from_start), [1, 2]);
+    assert_eq!(from_start.older, None);
+}
+
+#[tokio::test]
+async fn page_size_is_bounded() {
+    let users = MemoryUserRepository::new();
+    let conversations = MemoryConversationStore::new();
+    let ada = common::add_user(&users, "ada").await;
+    common::add_user(&users, "grace").await;
+    let id = chat::open_direct(&users, &conversations, &ada, "grace")
+        .await
+        .unwrap()
+        .id;
+    for _ in 0..MAX_PAGE + 5 {
+        chat::post_message(&conversations, &ada, id, "hi", None)
+            .await
+            .unwrap();
+    }
+
+    let page = chat::history(&conversations, &ada, id, HistoryCursor::Latest, Some(1000))
+        .await
+        .unwrap();
+    assert_eq!(page.messages.len() as i64, MAX_PAGE);
+    let page = chat::history(&conversations, &ada, id, HistoryCursor::Latest, Some(0))
+        .await
+        .unwrap();
+    assert_eq!(seqs(&page), [MAX_PAGE + 5]);
+}
+
+async fn roles(conversations: &MemoryConversationStore, id: i64) -> Vec<(i64, String)> {
+    conversations
+        .members(id)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|member| (member.user_id, member.role))
+        .collect()
+}
+
+async fn events(conversations: &MemoryConversationStore, owner: &User, id: i64) -> Vec<GroupEvent> {
+    chat::history(conversations, owner, id, HistoryCursor::Latest, None)
+        .await
+        .unwrap()
+        .messages
+        .into_iter()
+        .filter_map(|message| message.event)
+        .collect()
+}
+
+#[test]
+fn group_details_are_trimmed_and_checked() {
+    let details =
+        chat::validate_group(" Crew ", Some(" "), Some("https://example.com/a.png")).unwrap();
+    assert_eq!(details.name, "Crew");
+    assert_eq!(details.topic, None);
+    assert!(matches!(
+        chat::validate_group("  ", None, None),
+        Err(ChatError::InvalidGroupName)
+    ));
+    assert!(matches!(
+        chat::validate_group(&"a".repeat(MAX_GROUP_NAME_CHARS + 1), None, None),
+        Err(ChatError::InvalidGroupName)
+    ));
+    assert!(matches!(
+        chat::validate_group("Crew", None, Some("http://example.com/a.png")),
+        Err(ChatError::InvalidAvatarUrl)
+    ));
+}
+
+#[tokio::test]
+async fn groups_start_with_their_owner_and_a_system_message() {
+    let users = MemoryUserRepository::new();
+    let conversations = MemoryConversationStore::new();
+    let ada = common::add_user(&users, "ada").await;
+    let grace = common::add_user(&users, "grace").await;
+    let details = chat::validate_group("Crew", Some("Launch"), None).unwrap();
+
+    let (group, change) = chat::create_group(
+        &users,
+        &conversations,
+        &ada,
+        &details,
+        &["grace".to_string(), "Grace".to_string(), "ada".to_string()],
+    )
+    .await
+    .unwrap();
+    assert_eq!(group.name.as_deref(), Some("Crew"));
+    assert_eq!(
+        roles(&conversations, group.id).await,
+        [(ada.id, OWNER.to_string()), (grace.id, MEMBER.to_string())]
+    );
+    assert_eq!(change.recipients, [ada.id, grace.id]);
+    assert_eq!(change.message.kind, SYSTEM);
+    assert_eq!(change.message.sender_id, Some(ada.id));
+    assert_eq!(
+        change.message.event,
+        Some(GroupEvent::Created {
+            name: "Crew".to_string()
+        })
+    );
+
+    assert!(matches!(
+        chat::create_group(
+            &users,
+            &conversations,
+            &ada,
+            &details,
+            &["nobody".to_string()]
+        )
+        .await,
+        Err(ChatError::UnknownRecipient(_))
+    ));
+}
+
+#[tokio::test]
+async fn admins_manage_members_and_owners_manage_admins() {
+    let users = MemoryUserRepository::new();
+    let conversations = MemoryConversationStore::new();
+    let ada = common::add_user(&users, "ada").await;
+    let grace = common::add_user(&users, "grace").await;
+    let linus = common::add_user(&users, "linus").await;
+    let ken = common::add_user(&users, "ken").await;
+    let details = chat::validate_group("Crew", None, None).unwrap();
+    let (group, _) = chat::create_group(
+        &users,
+        &conversations,
+        &ada,
+        &details,
+        &["grace".to_string()],
+    )
+    .await
+    .unwrap();
+    let id = group.id;
+
+    // Plain members can't add anyone.
+    assert!(matches!(
+        chat::add_members(&users, &conversations, &grace, id, &["linus".to_string()]).await,
+        Err(ChatError::NotPermitted)
+    ));
+    assert!(matches!(
+        chat::set_role(&conversations, &ada, id, grace.id, OWNER).await,
+        Err(ChatError::InvalidRole)
+    ));
+    chat::set_role(&conversations, &ada, id, grace.id, ADMIN)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(chat::set_role(&conversations, &ada, id, grace.id, ADMIN)
+        .await
+        .unwrap()
+        .is_none());
+
+    let added = chat::add_members(
+        &users,
+        &conversations,
+        &grace,
+        id,
+        &["linus".to_string(), "ken".to_string(), "ada".to_string()],
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(
+        added.message.event,
+        Some(GroupEvent::MembersAdded {
+            user_ids: vec![linus.id, ken.id]
+        })
+    );
+    assert!(
+        chat::add_members(&users, &conversations, &grace, id, &["ken".to_string()])
+            .await
+            .unwrap()
+            .is_none()
+    );
+
+    // Admins remove plain members, not other admins or the owner, and only the owner hands out
+    // roles.
+    let removed = chat::remove_member(&conversations, &grace, id, ken.id)
+        .await
+        .unwrap();
+    assert!(removed.recipients.contains(&ken.id));
+    assert!(matches!(
+        chat::remove_member(&conversations, &grace, id, ada.id).await,
+        Err(ChatError::NotPermitted)
+    ));
+    assert!(matches!(
+        chat::set_role(&conversations, &grace, id, linus.id, ADMIN).await,
+        Err(ChatError::NotPermitted)
+    ));
+    assert!(matches!(
+        chat::remove_member(&conversations, &grace, id, ken.id).await,
+        Err(ChatError::NotAMember(_))
+    ));
+    // Someone no longer in the group can't even see it.
+    assert!(matches!(
+        chat::history(&conversations, &ken, id, HistoryCursor::Latest, None).await,
+        Err(ChatError::ConversationNotFound(_))
+    ));
+
+    chat::remove_member(&conversations, &ada, id, grace.id)
+        .await
+        .unwrap();
+    assert_eq!(
+        roles(&conversations, id).await,
+        [(ada.id, OWNER.to_string()), (linus.id, MEMBER.to_string())]
+    );
+}
+
+#[tokio::test]
+async fn owners_transfer_before_leaving() {
+    let users = MemoryUserRepository::new();
+    let conversations = MemoryConversationStore::new();
+    let ada = common::add_user(&users, "ada").await;
+    let grace = common::add_user(&users, "grace").await;
+    let linus = common::add_user(&users, "linus").await;
+    let details = chat::validate_group("Crew", None, None).unwrap();
+    let members = ["grace".to_string(), "linus".to_string()];
+    let (group, _) = chat::create_group(&users, &conversations, &ada, &details, &members)
+        .await
+        .unwrap();
+    let id = group.id;
+
+    assert!(matches!(
+        chat::leave(&conversations, &ada, id).await,
+        Err(ChatError::OwnerMustTransfer)
+    ));
+    chat::transfer_ownership(&conversations, &ada, id, grace.id)
+        .await
+        .unwrap();
+    assert_eq!(
+        roles(&conversations, id).await,
+        [
+            (ada.id, ADMIN.to_string()),
+            (grace.id, OWNER.to_string()),
+            (linus.id, MEMBER.to_string())
+        ]
+    );
+    assert!(matches!(
+        chat::transfer_ownership(&conversations, &ada, id, linus.id).await,
+        Err(ChatError::NotPermitted)
+    ));
+
+    let left = chat::leave(&conversations, &ada, id).await.unwrap();
+    assert!(left.recipients.contains(&ada.id));
+    assert_eq!(
+        events(&conversations, &grace, id).await,
+        [
+            GroupEvent::Created {
+                name: "Crew".to_string()
+            },
+            GroupEvent::OwnershipTransferred {
+                from: ada.id,
+                to: grace.id
+            },
+            GroupEvent::MemberLeft { user_id: ada.id },
+        ]
+    );
+
+    assert!(matches!(
+        chat::delete_group(&conversations, &linus, id).await,
+        Err(ChatError::NotPermitted)
+    ));
+    let former = chat::delete_group(&conversations, &grace, id)
+        .await
+        .unwrap();
+    assert_eq!(former, [grace.id, linus.id]);
+    assert_eq!(conversations.find(id).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn group_details_change_through_admins() {
+    let users = MemoryUserRepository::new();
+    let conversations = MemoryConversationStore::new();
+    let ada = common::add_user(&users, "ada").await;
+    let grace = common::add_user(&users, "grace").await;
+    let details = chat::validate_group("Crew", Some("Launch"), None).unwrap();
+    let (group, _) = chat::create_group(
+        &users,
+        &conversations,
+        &ada,
+        &details,
+        &["grace".to_string()],
+    )
+    .await
+    .unwrap();
+
+    let change = chat::update_group(&conversations, &ada, group.id, Some("Team"), None, None)
+        .await
+        .unwrap();
+    assert_eq!(
+        change.message.event,
+        Some(GroupEvent::Updated {
+            name: "Team".to_string(),
+            topic: Some("Launch".to_string()),
+            avatar_url: None
+        })
+    );
+    chat::update_group(&conversations, &ada, group.id, None, Some(""), None)
+        .await
+        .unwrap();
+    let group = conversations.find(group.id).await.unwrap().unwrap();
+    assert_eq!((group.name.as_deref(), group.topic), (Some("Team"), None));
+
+    assert!(matches!(
+        chat::update_group(&conversations, &grace, group.id, Some("Mine"), None, None).await,
+        Err(ChatError::NotPermitted)
+    ));
+    let direct = chat::open_direct(&users, &conversations, &ada, "grace")
+        .await
+        .unwrap();
+    assert!(matches!(
+        chat::update_group(&conversations, &ada, direct.id, Some("Pair"), None, None).await,
+        Err(ChatError::NotPermitted)
+    ));
+    assert!(matches!(
+        chat::leave(&conversations, &ada, direct.id).await,
+        Err(ChatError::NotPermitted)
+    ));
+}
+
+#[tokio::test]
+async fn invite_links_add_members_with_their_role() {
+    let users = MemoryUserRepository::new();
+    let conversations = MemoryConversationStore::new();
+    let ada = common::add_user(&users, "ada").await;
+    let grace = common::add_user(&users, "grace").await;
+    let linus = common::add_user(&users, "linus").await;
+    let ken = common::add_user(&users, "ken").await;
+    let details = chat::validate_group("Crew", None, None).unwrap();
+    let (group, _) = chat::create_group(
+        &users,
+        &conversations,
+        &ada,
+        &details,
+        &["grace".to_string()],
+    )
+    .await
+    .unwrap();
+
+    // Plain members can't hand out invites, admins only member ones.
+    assert!(matches!(
+        chat::create_invite(&conversations, &grace, group.id, None, None, None).await,
+        Err(ChatError::NotPermitted)
+    ));
+    chat::set_role(&conversations, &ada, group.id, grace.id, ADMIN)
+        .await
+        .unwrap();
+    assert!(matches!(
+        chat::create_invite(&conversations, &grace, group.id, Some(ADMIN), None, None).await,
+        Err(ChatError::NotPermitted)
+    ));
+    let invite = chat::create_invite(&conversations, &grace, group.id, None, Some(1), None)
+        .await
+        .unwrap();
+    assert_eq!((invite.role.as_str(), invite.uses), (MEMBER, 0));
+
+    let preview = chat::preview_invite(&conversations, &invite.code)
+        .await
+        .unwrap();
+    assert_eq!((preview.group.id, preview.member_count), (group.id, 2));
+
+    let (joined, change) = chat::accept_invite(&conversations, &linus, &invite.code)
+        .await
+        .unwrap();
+    assert_eq!(joined.id, group.id);
+    let change = change.unwrap();
+    assert_eq!(change.message.sender_id, Some(linus.id));
+    assert_eq!(
+        change.message.event,
+        Some(GroupEvent::MemberJoined {
+            user_id: linus.id,
+            invite_id: invite.id
+        })
+    );
+    assert_eq!(change.recipients, [ada.id, grace.id, linus.id]);
+    assert_eq!(
+        roles(&conversations, group.id).await[2],
+        (linus.id, MEMBER.to_string())
+    );
+
+    // Joining twice changes nothing, and the one use is gone.
+    let (_, again) = chat::accept_invite(&conversations, &linus, &invite.code)
+        .await
+        .unwrap();
+    assert!(again.is_none());
+    assert!(matches!(
+        chat::accept_invite(&conversations, &ken, &invite.code).await,
+        Err(ChatError::InviteExpired)
+    ));
+    let uses = chat::invite_uses(&conversations, &ada, group.id, invite.id)
+        .await
+        .unwrap();
+    assert_eq!(uses.len(), 1);
+    assert_eq!(uses[0].user_id, Some(linus.id));
+
+    let admins = chat::create_invite(&conversations, &ada, group.id, Some(ADMIN), None, None)
+        .await
+        .unwrap();
+    chat::accept_invite(&conversations, &ken, &admins.code)
+        .await
+        .unwrap();
+    assert_eq!(
+        roles(&conversations, group.id).await[3],
+        (ken.id, ADMIN.to_string())
+    );
+    let listed = chat::invites(&conversations, &grace, group.id)
+        .await
+        .unwrap();
+    assert_eq!(
+        listed.iter().map(|invite| invite.id).collect::<Vec<_>>(),
+        [admins.id, invite.id]
+    );
+}
+
+#[tokio::test]
+async fn revoked_invites_stop_working() {
+    let users = MemoryUserRepository::new();
+    let conversations = MemoryConversationStore::new();
+    let ada = common::add_user(&users, "ada").await;
+    let grace = common::add_user(&users, "grace").await;
+    let details = chat::validate_group("Crew", None, None).unwrap();
+    let (group, _) = chat::create_group(&users, &conversations, &ada, &details, &[])
+        .await
+        .unwrap();
+    let (other, _) = chat::create_group(&users, &conversations, &ada, &details, &[])
+        .await
+        .unwrap();
+    let invite = chat::create_invite(&conversations, &ada, group.id, None, None, Some(3600))
+        .await
+        .unwrap();
+    assert!(invite.expires_at.unwrap() > Utc::now());
+
+    // Invites are managed through their own group only.
+    assert!(matches!(
+        chat::revoke_invite(&conversations, &ada, other.id, invite.id).await,
+        Err(ChatError::InviteNotFound)
+    ));
+    chat::revoke_invite(&conversations, &ada, group.id, invite.id)
+        .await
+        .unwrap();
+    assert!(matches!(
+        chat::preview_invite(&conversations, &invite.code).await,
+        Err(ChatError::InviteExpired)
+    ));
+    assert!(matches!(
+        chat::accept_invite(&conversations, &grace, &invite.code).await,
+        Err(ChatError::InviteExpired)
+    ));
+    assert!(matches!(
+        chat::accept_invite(&conversations, &grace, "nope").await,
+        Err(ChatError::InviteNotFound)
+    ));
+    assert_eq!(conversations.member_ids(group.id).await.unwrap(), [ada.id]);
+}
+
+#[tokio::test]
+async fn invite_settings_are_checked() {
+    let users = MemoryUserRepository::new();
+    let conversations = MemoryConversationStore::new();
+    let ada = common::add_user(&users, "ada").await;
+    let details = chat::validate_group("Crew", None, None).unwrap();
+    let (group, _) = chat::create_group(&users, &conversations, &ada, &details, &[])
+        .await
+        .unwrap();
+
+    assert!(matches!(
+        chat::create_invite(&conversations, &ada, group.id, Some(OWNER), None, None).await,
+        Err(ChatError::InvalidRole)
+    ));
+    assert!(matches!(
+        chat::create_invite(&conversations, &ada, group.id, None, Some(0), None).await,
+        Err(ChatError::InvalidInviteMaxUses)
+    ));
+    for expires_in in [0, MAX_INVITE_EXPIRY_SECS + 1] {
+        assert!(matches!(
+            chat::create_invite(&conversations, &ada, group.id, None, None, Some(expires_in)).await,
+            Err(ChatError::InvalidInviteExpiry)
+        ));
+    }
+}
+
+#[test]
+fn invites_are_usable_until_revoked_expired_or_used_up() {
+    let now = Utc::now();
+    let invite = Invite {
+        id: 1,
+        conversation_id: 1,
+        code: "code".to_string(),
+        created_by: Some(1),
+        role: MEMBER.to_string(),
+        max_uses: Some(2),
+        uses: 1,
+        expires_at: Some(now + Duration::minutes(5)),
+        revoked_at: None,
+        created_at: now,
+    };
+    assert!(invite.usable(now));
+    assert!(!invite.usable(now + Duration::minutes(5)));
+    assert!(!Invite {
+        uses: 2,
+        ..invite.clone()
+    }
+    .usable(now));
+    assert!(!Invite {
+        revoked_at: Some(now),
+        ..invite.clone()
+    }
+    .usable(now));
+    assert!(Invite {
+        max_uses: None,
+        expires_at: None,
+        uses: 100,
+        ..invite
+    }
+    .usable(now + Duration::days(365)));
+}
@@ -0,0 +1,69 @@
+// Fixtures shared by the integration tests, on top of the in-memory stores. Each test binary
+// only uses some of them.
+#![allow(dead_code)]
+
+use sqlx_postgres::PgPoolOptions;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use wyrd_lib::auth_handler::AppState;
+use wyrd_lib::ephemeral::EphemeralStore;
+use wyrd_lib::memory_store::{
+    MemoryConversationStore, MemoryEmailOutbox, MemorySessionStore, MemoryUserRepository,
+    MemoryVerificationStore,
+};
+use wyrd_lib::oidc_cache::{self, OidcCache};
+use wyrd_lib::rate_limit::RateLimiter;
+use wyrd_lib::repository::{EphemeralPresenceStore, NewUser, User, UserRepository};
+use wyrd_lib::settings::Settings;
+use wyrd_lib::telemetry;
+use wyrd_lib::token_vault::TokenVault;
+
+/// A verified account without a password, named after `username`.
+pub fn new_user(username: &str) -> NewUser {
+    NewUser {
+        name: format!("{} Example", username),
+        username: username.to_string(),
+        email: format!("{}@example.com", username),
+        password: None,
+        user_verified: true,
+        totp_secret: "secret".to_string(),
+    }
+}
+
+pub async fn add_user(users: &dyn UserRepository, username: &str) -> User {
+    let id = users.create(&new_user(username)).await.unwrap();
+    users.find_by_id(id).await.unwrap().unwrap()
+}
+
+/// State on the in-memory stores and `ephemeral`. The database is never connected to.
+pub fn state(settings: Settings, ephemeral: Arc<dyn EphemeralStore>) -> Arc<AppState> {
+    Arc::new(AppState {
+        db: PgPoolOptions::new()
+            .connect_lazy(&settings.database.url)
+            .unwrap(),
+        ephemeral: ephemeral.clone(),
+        users: Arc::new(MemoryUserRepository::new()),
+        verifications: Arc::new(MemoryVerificationStore::new()),
+        sessions: Arc::new(MemorySessionStore::new()),
+        conversations: Arc::new(MemoryConversationStore::new()),
+        presence: Arc::new(EphemeralPresenceStore::new(ephemeral.clone())),
+        outbox: Arc::new(MemoryEmailOutbox::new()),
+        vault: TokenVault::new(&[7; 32]),
+        http: oidc_cache::build_http_client().unwrap(),
+        oidc: OidcCache::new(&settings.oidc),
+        metrics: telemetry::recorder().unwrap(),
+        rate_limit: RateLimiter::new(ephemeral, settings.rate_limit.clone()),
+        settings,
+        gateway: OnceLock::new(),
+    })
+}
+
+/// Signs up `username` and returns a session token for them.
+pub async fn sign_in(state: &AppState, username: &str) -> String {
+    let user = add_user(state.users.as_ref(), username).await;
+    state
+        .sessions
+        .create(user.id, Duration::from_secs(60))
+        .await
+        .unwrap()
+}
@@ -0,0 +1,186 @@
+// The in-process backend, and the verification and session stores built on top of it.
+use std::sync::Arc;
+use std::time::Duration;
+use wyrd_lib::ephemeral::EphemeralStore;
+use wyrd_lib::memory_store::MemoryStore;
+use wyrd_lib::repository::{
+    EphemeralSessionStore, EphemeralVerificationStore, PendingVerification, SessionStore,
+    VerificationStore,
+};
+
+const WINDOW: Duration = Duration::from_secs(60);
+const PENDING: Duration = Duration::from_secs(72 * 60 * 60);
+
+#[tokio::test]
+async fn values_expire() {
+    let store = MemoryStore::new();
+    store.set("kept", "1", None).await.unwrap();
+    store.set("gone", "2", Some(Duration::ZERO)).await.unwrap();
+
+    assert_eq!(store.get("kept").await.unwrap().as_deref(), Some("1"));
+    assert_eq!(store.get("gone").await.unwrap(), None);
+    assert_eq!(store.take("kept").await.unwrap().as_deref(), Some("1"));
+    assert_eq!(store.get("kept").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn delete_counts_existing_keys() {
+    let store = MemoryStore::new();
+    store.set("a", "1", None).await.unwrap();
+    store.set_add("b", "x").await.unwrap();
+
+    let keys = ["a", "b", "c"].map(String::from);
+    assert_eq!(store.delete(&keys).await.unwrap(), 2);
+    assert!(store.set_members("b").await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn sliding_window_limits_hits() {
+    let store = MemoryStore::new();
+    for _ in 0..3 {
+        assert_eq!(store.hit("login:ada", WINDOW, 3).await.unwrap(), None);
+    }
+    let wait = store.hit("login:ada", WINDOW, 3).await.unwrap().unwrap();
+    assert!(wait > Duration::ZERO && wait <= WINDOW);
+
+    // Other keys have their own budget.
+    assert_eq!(store.hit("login:grace", WINDOW, 3).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn verification_keeps_the_name_after_finishing() {
+    let verifications = EphemeralVerificationStore::new(Arc::new(MemoryStore::new()), PENDING);
+    let pending = PendingVerification {
+        email: "ada@example.com".to_string(),
+        name: "Ada".to_string(),
+        invite: Some("c0ffee".to_string()),
+    };
+    verifications.start(&pending, "hash", WINDOW).await.unwrap();
+    assert_eq!(
+        verifications.pending("ADA@example.com").await.unwrap(),
+        Some(pending)
+    );
+    assert_eq!(
+        verifications
+            .code_hash("ada@example.com")
+            .await
+            .unwrap()
+            .as_deref(),
+        Some("hash")
+    );
+
+    verifications.finish("ada@example.com").await.unwrap();
+    assert_eq!(
+        verifications.pending("ada@example.com").await.unwrap(),
+        None
+    );
+    assert_eq!(
+        verifications.code_hash("ada@example.com").await.unwrap(),
+        None
+    );
+    assert_eq!(
+        verifications
+            .signup_name("ada@example.com")
+            .await
+            .unwrap()
+            .as_deref(),
+        Some("Ada")
+    );
+}
+
+#[tokio::test]
+async fn verifications_are_kept_per_email() {
+    let verifications = EphemeralVerificationStore::new(Arc::new(MemoryStore::new()), PENDING);
+    let ada = PendingVerification {
+        email: "ada@example.com".to_string(),
+        name: "Ada".to_string(),
+        invite: Some("c0ffee".to_string()),
+    };
+    let grace = PendingVerification {
+        email: "grace@example.com".to_string(),
+        name: "Grace".to_string(),
+        invite: None,
+    };
+    verifications.start(&ada, "ada-hash", WINDOW).await.unwrap();
+    verifications
+        .start(&grace, "grace-hash", WINDOW)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        verifications.pending("ada@example.com").await.unwrap(),
+        Some(ada)
+    );
+    assert_eq!(
+        verifications
+            .code_hash("ada@example.com")
+            .await
+            .unwrap()
+            .as_deref(),
+        Some("ada-hash")
+    );
+    verifications.finish("ada@example.com").await.unwrap();
+    assert_eq!(
+        verifications.pending("grace@example.com").await.unwrap(),
+        Some(grace)
+    );
+    // Each finished sign-up personalizes its own account.
+    for (email, name) in [("ada@example.com", "Ada"), ("grace@example.com", "Grace")] {
+        assert_eq!(
+            verifications.signup_name(email).await.unwrap().as_deref(),
+            Some(name)
+        );
+    }
+}
+
+#[tokio::test]
+async fn sessions_can_be_revoked_one_by_one_or_all_at_once() {
+    let sessions = EphemeralSessionStore::new(Arc::new(MemoryStore::new()));
+    let first = sessions.create(1, WINDOW).await.unwrap();
+    let second = sessions.create(1, WINDOW).await.unwrap();
+    let other = sessions.create(2, WINDOW).await.unwrap();
+    assert_eq!(sessions.user_id(&first).await.unwrap(), Some(1));
+
+    sessions.revoke(&first).await.unwrap();
+    assert_eq!(sessions.user_id(&first).await.unwrap(), None);
+
+    assert_eq!(sessions.revoke_all(1).await.unwrap(), 1);
+    assert_eq!(sessions.user_id(&second).await.unwrap(), None);
+    assert_eq!(sessions.user_id(&other).await.unwrap(), Some(2));
+}
+
+#[tokio::test]
+async fn expired_sessions_are_gone() {
+    let sessions = EphemeralSessionStore::new(Arc::new(MemoryStore::new()));
+    let token = sessions.create(1, Duration::ZERO).await.unwrap();
+    assert_eq!(sessions.user_id(&token).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn scan_finds_keys_by_prefix() {
+    let store = MemoryStore::new();
+    store.set("session:a", "1", None).await.unwrap();
+    store
+        .set("session:b", "2", Some(Duration::ZERO))
+        .await
+        .unwrap();
+    store.set_add("sessions:c", "x").await.unwrap();
+    store.set("token", "3", None).await.unwrap();
+
+    let mut keys = store.scan("session").await.unwrap();
+    keys.sort();
+    assert_eq!(keys, ["session:a", "sessions:c"]);
+}
+
+#[tokio::test]
+async fn pruning_clears_expired_sessions_from_the_index() {
+    let sessions = EphemeralSessionStore::new(Arc::new(MemoryStore::new()));
+    sessions.create(1, Duration::ZERO).await.unwrap();
+    sessions.create(1, Duration::ZERO).await.unwrap();
+    let kept = sessions.create(1, WINDOW).await.unwrap();
+
+    assert_eq!(sessions.prune_expired().await.unwrap(), 2);
+    assert_eq!(sessions.prune_expired().await.unwrap(), 0);
+    assert_eq!(sessions.user_id(&kept).await.unwrap(), Some(1));
+    assert_eq!(sessions.revoke_all(1).await.unwrap(), 1);
+}
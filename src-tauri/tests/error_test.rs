@@ -0,0 +1,110 @@
+use axum::body::{self, Body};
+use axum::extract::{FromRequest, FromRequestParts};
+use axum::http::{header, Request, StatusCode};
+use axum::response::IntoResponse;
+use std::collections::HashMap;
+use wyrd_lib::auth_service::AuthenticationErrors;
+use wyrd_lib::error::{ApiError, ErrorCode, FieldError, Locale};
+use wyrd_lib::extract::{Json, Query};
+use wyrd_lib::username::UsernameError;
+
+#[test]
+fn signup_reports_every_field() {
+    let err = ApiError::from(vec![
+        AuthenticationErrors::InvalidUsername(UsernameError::TooShort),
+        AuthenticationErrors::SignupInvalidEmail,
+    ]);
+    assert_eq!(err.code, ErrorCode::ValidationFailed);
+    assert_eq!(err.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(
+        err.fields,
+        [
+            FieldError::new("username", ErrorCode::UsernameTooShort),
+            FieldError::new("email", ErrorCode::EmailInvalid),
+        ]
+    );
+}
+
+#[test]
+fn internal_errors_hide_the_cause() {
+    let err = ApiError::from(vec![
+        AuthenticationErrors::SignupInvalidEmail,
+        AuthenticationErrors::HashError("argon2 exploded".to_string()),
+    ]);
+    assert_eq!(err.code, ErrorCode::Internal);
+
+    let body = serde_json::to_value(&err).unwrap();
+    assert_eq!(body["code"], "internal");
+    assert!(!body.to_string().contains("argon2"));
+    assert!(body.get("fields").is_none());
+}
+
+#[test]
+fn bodies_carry_code_message_and_fields() {
+    let err = ApiError::validation(vec![FieldError::new("username", ErrorCode::UsernameTaken)]);
+    let body = serde_json::to_value(err.body(Locale::Es)).unwrap();
+    assert_eq!(body["code"], "validation_failed");
+    assert_eq!(body["fields"][0]["field"], "username");
+    assert_eq!(body["fields"][0]["code"], "username_taken");
+    assert_eq!(
+        body["fields"][0]["message"],
+        "Este nombre de usuario ya existe. Prueba con otro."
+    );
+}
+
+#[test]
+fn accept_language_picks_the_heaviest_supported_locale() {
+    assert_eq!(
+        Locale::from_accept_language("es-ES,es;q=0.9,en;q=0.8"),
+        Locale::Es
+    );
+    assert_eq!(
+        Locale::from_accept_language("fr-FR,en;q=0.5,es;q=0.7"),
+        Locale::Es
+    );
+    assert_eq!(Locale::from_accept_language("de-DE"), Locale::En);
+    assert_eq!(Locale::from_accept_language(""), Locale::En);
+}
+
+#[tokio::test]
+async fn malformed_bodies_get_an_error_body() {
+    let request = Request::post("/api/v1/signup")
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(r#"{"name": "Ada","#))
+        .unwrap();
+    let err = Json::<serde_json::Value>::from_request(request, &())
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(err.code, ErrorCode::ValidationFailed);
+
+    let response = err.into_response();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body = body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["error"]["code"], "validation_failed");
+}
+
+#[tokio::test]
+async fn missing_content_types_and_bad_queries_are_rejected_the_same_way() {
+    let request = Request::post("/api/v1/signup")
+        .body(Body::from("{}"))
+        .unwrap();
+    let err = Json::<serde_json::Value>::from_request(request, &())
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(err.code, ErrorCode::ValidationFailed);
+
+    let (mut parts, _) = Request::get("/history?limit=lots")
+        .body(())
+        .unwrap()
+        .into_parts();
+    let err = Query::<HashMap<String, u32>>::from_request_parts(&mut parts, &())
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(err.code, ErrorCode::ValidationFailed);
+}
@@ -0,0 +1,128 @@
+// The parts of the real-time gateway that don't need a socket: who is connecting and what they
+// may send.
+use axum::http::{header, HeaderMap, HeaderValue};
+use std::time::Duration;
+use wyrd_lib::auth_service::{authenticate, AuthenticationErrors};
+use wyrd_lib::chat::{self, ChatError, MAX_BODY_CHARS};
+use wyrd_lib::gateway::{handshake_token, user_room, Handshake};
+use wyrd_lib::memory_store::{MemoryConversationStore, MemorySessionStore, MemoryUserRepository};
+use wyrd_lib::repository::{ConversationStore, SessionStore, UserRepository, DELETED, SUSPENDED};
+
+mod common;
+
+const SESSION_TTL: Duration = Duration::from_secs(60);
+
+fn bearer(token: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::AUTHORIZATION,
+        HeaderValue::from_str(&format!("Bearer {}", token)).unwrap(),
+    );
+    headers
+}
+
+#[test]
+fn handshake_token_prefers_the_auth_object() {
+    let auth = Handshake {
+        token: Some("from-auth".to_string()),
+    };
+    assert_eq!(
+        handshake_token(&auth, &bearer("from-header")).as_deref(),
+        Some("from-auth")
+    );
+    assert_eq!(
+        handshake_token(&Handshake::default(), &bearer("from-header")).as_deref(),
+        Some("from-header")
+    );
+
+    let empty = Handshake {
+        token: Some(String::new()),
+    };
+    assert_eq!(handshake_token(&empty, &HeaderMap::new()), None);
+    let mut basic = HeaderMap::new();
+    basic.insert(header::AUTHORIZATION, HeaderValue::from_static("Basic abc"));
+    assert_eq!(handshake_token(&Handshake::default(), &basic), None);
+}
+
+#[tokio::test]
+async fn sessions_authenticate_active_users_only() {
+    let users = MemoryUserRepository::new();
+    let sessions = MemorySessionStore::new();
+    let ada = common::add_user(&users, "ada").await;
+    let token = sessions.create(ada.id, SESSION_TTL).await.unwrap();
+
+    assert_eq!(authenticate(&users, &sessions, &token).await.unwrap(), ada);
+    assert!(matches!(
+        authenticate(&users, &sessions, "made-up").await,
+        Err(AuthenticationErrors::Unauthenticated)
+    ));
+
+    users.set_status(ada.id, SUSPENDED).await.unwrap();
+    assert!(matches!(
+        authenticate(&users, &sessions, &token).await,
+        Err(AuthenticationErrors::AccountSuspended)
+    ));
+    users.set_status(ada.id, DELETED).await.unwrap();
+    assert!(matches!(
+        authenticate(&users, &sessions, &token).await,
+        Err(AuthenticationErrors::Unauthenticated)
+    ));
+}
+
+#[tokio::test]
+async fn revoked_sessions_stop_authenticating() {
+    let users = MemoryUserRepository::new();
+    let sessions = MemorySessionStore::new();
+    let ada = common::add_user(&users, "ada").await;
+    let token = sessions.create(ada.id, SESSION_TTL).await.unwrap();
+
+    sessions.revoke(&token).await.unwrap();
+    assert!(matches!(
+        authenticate(&users, &sessions, &token).await,
+        Err(AuthenticationErrors::Unauthenticated)
+    ));
+}
+
+#[tokio::test]
+async fn direct_conversations_need_an_active_recipient() {
+    let users = MemoryUserRepository::new();
+    let conversations = MemoryConversationStore::new();
+    let ada = common::add_user(&users, "ada").await;
+    let grace = common::add_user(&users, "grace").await;
+
+    let conversation = chat::open_direct(&users, &conversations, &ada, "Grace")
+        .await
+        .unwrap();
+    assert_eq!(
+        conversations.member_ids(conversation.id).await.unwrap(),
+        [ada.id, grace.id]
+    );
+    assert_ne!(user_room(ada.id), user_room(grace.id));
+
+    assert!(matches!(
+        chat::open_direct(&users, &conversations, &ada, "ada").await,
+        Err(ChatError::MessagingSelf)
+    ));
+    users.set_status(grace.id, SUSPENDED).await.unwrap();
+    assert!(matches!(
+        chat::open_direct(&users, &conversations, &ada, "grace").await,
+        Err(ChatError::UnknownRecipient(_))
+    ));
+    assert!(matches!(
+        chat::open_direct(&users, &conversations, &ada, "nobody").await,
+        Err(ChatError::UnknownRecipient(_))
+    ));
+}
+
+#[test]
+fn message_bodies_are_trimmed_and_bounded() {
+    assert!(matches!(
+        chat::validate_body(" \n "),
+        Err(ChatError::EmptyBody)
+    ));
+    assert!(chat::validate_body(&"é".repeat(MAX_BODY_CHARS)).is_ok());
+    assert!(matches!(
+        chat::validate_body(&"a".repeat(MAX_BODY_CHARS + 1)),
+        Err(ChatError::BodyTooLong)
+    ));
+}
@@ -0,0 +1,36 @@
+use chrono::{Timelike, Utc};
+use wyrd_lib::jobs::{self, JOBS};
+use wyrd_lib::settings::{Profile, Settings};
+
+#[test]
+fn every_job_is_scheduled_by_default() {
+    let settings = Settings::load_profile(Profile::Test).unwrap();
+    for job in JOBS {
+        assert!(
+            jobs::next_run(&settings.jobs, job).is_some(),
+            "{} has no schedule",
+            job.name
+        );
+    }
+}
+
+#[test]
+fn next_run_follows_the_schedule() {
+    let mut settings = Settings::load_profile(Profile::Test).unwrap();
+    let job = jobs::find("purge_deleted").unwrap();
+    settings
+        .jobs
+        .schedules
+        .insert(job.name.to_string(), "0 30 3 * * *".to_string());
+
+    let next = jobs::next_run(&settings.jobs, job).unwrap();
+    assert!(next > Utc::now());
+    assert_eq!((next.hour(), next.minute(), next.second()), (3, 30, 0));
+
+    settings
+        .jobs
+        .schedules
+        .insert(job.name.to_string(), String::new());
+    assert_eq!(jobs::next_run(&settings.jobs, job), None);
+    assert!(jobs::find("vacuum").is_none());
+}
@@ -0,0 +1,15 @@
+use wyrd_lib::logging::{redact_email, Redacted};
+
+#[test]
+fn emails_keep_only_the_first_letter_and_domain() {
+    assert_eq!(redact_email("jane.doe@example.com"), "j***@example.com");
+    assert_eq!(redact_email("@example.com"), "***@example.com");
+    assert_eq!(redact_email("not an email"), "***");
+}
+
+#[test]
+fn redacted_values_never_format() {
+    let code = Redacted("123456");
+    assert_eq!(format!("{}", code), "[redacted]");
+    assert_eq!(format!("{:?}", code), "[redacted]");
+}
@@ -0,0 +1,45 @@
+use wyrd_lib::migrations::MIGRATOR;
+
+#[test]
+fn every_migration_can_be_reverted() {
+    let ups: Vec<i64> = MIGRATOR
+        .iter()
+        .filter(|m| m.migration_type.is_up_migration())
+        .map(|m| m.version)
+        .collect();
+    let downs: Vec<i64> = MIGRATOR
+        .iter()
+        .filter(|m| m.migration_type.is_down_migration())
+        .map(|m| m.version)
+        .collect();
+
+    assert!(!ups.is_empty());
+    assert_eq!(ups, downs);
+}
+
+#[test]
+fn users_table_comes_first() {
+    let first = MIGRATOR
+        .iter()
+        .find(|m| m.migration_type.is_up_migration())
+        .unwrap();
+    assert_eq!(first.description, "users");
+    assert!(first.sql.contains("lower(email)"));
+    assert!(first.sql.contains("totp_secret TEXT NOT NULL"));
+    // Tables from before the schema was checked in are converted, not just left alone.
+    assert!(first
+        .sql
+        .contains("ALTER COLUMN last_login TYPE TIMESTAMPTZ"));
+    assert!(first.sql.contains("ALTER COLUMN totp_secret SET NOT NULL"));
+}
+
+#[test]
+fn usernames_are_unique_regardless_of_case() {
+    let index = MIGRATOR
+        .iter()
+        .find(|m| m.migration_type.is_up_migration() && m.description == "username lower key")
+        .unwrap();
+    assert!(index
+        .sql
+        .contains("users_username_lower_key ON users (lower(username))"));
+}
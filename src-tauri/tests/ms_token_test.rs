@@ -0,0 +1,45 @@
+use openidconnect::OAuth2TokenResponse;
+use std::time::Duration;
+use wyrd_lib::microsoft::AzureCoreTokenResponse;
+
+fn token_response(expires_in: serde_json::Value) -> AzureCoreTokenResponse {
+    serde_json::from_value(serde_json::json!({
+        "access_token": "access",
+        "token_type": "Bearer",
+        "expires_in": expires_in,
+        "scope": "openid email profile offline_access",
+    }))
+    .unwrap()
+}
+
+#[test]
+fn expires_in_accepts_numbers_and_strings() {
+    assert_eq!(
+        token_response(serde_json::json!(3599)).expires_in(),
+        Some(Duration::from_secs(3599))
+    );
+    assert_eq!(
+        token_response(serde_json::json!("3599")).expires_in(),
+        Some(Duration::from_secs(3599))
+    );
+}
+
+#[test]
+fn expires_in_rejects_garbage() {
+    let parsed = serde_json::from_value::<AzureCoreTokenResponse>(serde_json::json!({
+        "access_token": "access",
+        "token_type": "Bearer",
+        "expires_in": "soon",
+    }));
+    assert!(parsed.is_err());
+}
+
+#[test]
+fn expires_in_is_optional() {
+    let parsed: AzureCoreTokenResponse = serde_json::from_value(serde_json::json!({
+        "access_token": "access",
+        "token_type": "Bearer",
+    }))
+    .unwrap();
+    assert_eq!(parsed.expires_in(), None);
+}
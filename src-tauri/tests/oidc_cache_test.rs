@@ -0,0 +1,174 @@
+// Discovery caching against a stub issuer served on a local port.
+use axum::{extract::State, http::StatusCode, response::IntoResponse, routing::get, Json, Router};
+use openidconnect::{EmptyAdditionalProviderMetadata, IssuerUrl, JsonWebKey};
+use serde_json::json;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use wyrd_lib::oidc_cache::{build_http_client, CachedProviderMetadata, MetadataCache};
+
+type Cache = MetadataCache<EmptyAdditionalProviderMetadata>;
+
+const HOUR: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Default)]
+struct Issuer {
+    base: String,
+    /// Issuer the document advertises, the real one when unset.
+    advertised: Option<String>,
+    kids: Vec<&'static str>,
+    down: bool,
+    discoveries: usize,
+    key_fetches: usize,
+}
+
+type Stub = Arc<Mutex<Issuer>>;
+
+async fn discovery(State(stub): State<Stub>) -> impl IntoResponse {
+    let mut issuer = stub.lock().unwrap();
+    if issuer.down {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    issuer.discoveries += 1;
+    let base = issuer.base.clone();
+    Json(json!({
+        "issuer": issuer.advertised.clone().unwrap_or_else(|| base.clone()),
+        "authorization_endpoint": format!("{}/authorize", base),
+        "token_endpoint": format!("{}/token", base),
+        "jwks_uri": format!("{}/keys", base),
+        "response_types_supported": ["code"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["RS256"],
+    }))
+    .into_response()
+}
+
+async fn keys(State(stub): State<Stub>) -> impl IntoResponse {
+    let mut issuer = stub.lock().unwrap();
+    if issuer.down {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    issuer.key_fetches += 1;
+    let keys: Vec<_> = issuer
+        .kids
+        .iter()
+        .map(|kid| json!({"kty": "RSA", "use": "sig", "kid": kid, "n": "AQAB", "e": "AQAB"}))
+        .collect();
+    Json(json!({ "keys": keys })).into_response()
+}
+
+/// Serves an issuer at `http://127.0.0.1:<port>/tenant`.
+async fn stub_issuer() -> (IssuerUrl, Stub) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base = format!("http://{}/tenant", listener.local_addr().unwrap());
+    let stub = Arc::new(Mutex::new(Issuer {
+        base: base.clone(),
+        kids: vec!["first"],
+        ..Issuer::default()
+    }));
+    let app = Router::new()
+        .route("/tenant/.well-known/openid-configuration", get(discovery))
+        .route("/tenant/keys", get(keys))
+        .with_state(stub.clone());
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    (IssuerUrl::new(base).unwrap(), stub)
+}
+
+fn kids(metadata: &CachedProviderMetadata<EmptyAdditionalProviderMetadata>) -> Vec<String> {
+    metadata
+        .jwks()
+        .keys()
+        .iter()
+        .filter_map(|key| key.key_id().map(|kid| kid.as_str().to_owned()))
+        .collect()
+}
+
+#[tokio::test]
+async fn documents_are_served_from_cache_until_they_expire() {
+    let http = build_http_client().unwrap();
+    let (issuer, stub) = stub_issuer().await;
+
+    let cache = Cache::new(HOUR);
+    cache.get(&http, &issuer).await.unwrap();
+    cache.get(&http, &issuer).await.unwrap();
+    assert_eq!(stub.lock().unwrap().discoveries, 1);
+
+    let expired = Cache::new(Duration::ZERO);
+    expired.get(&http, &issuer).await.unwrap();
+    expired.get(&http, &issuer).await.unwrap();
+    assert_eq!(stub.lock().unwrap().discoveries, 3);
+}
+
+#[tokio::test]
+async fn stale_documents_are_served_while_the_issuer_is_down() {
+    let http = build_http_client().unwrap();
+    let (issuer, stub) = stub_issuer().await;
+
+    let cache = Cache::new(Duration::ZERO);
+    let fetched = cache.get(&http, &issuer).await.unwrap();
+    stub.lock().unwrap().down = true;
+    let stale = cache.get(&http, &issuer).await.unwrap();
+    assert!(Arc::ptr_eq(&fetched, &stale));
+
+    // Nothing to fall back on without an earlier fetch.
+    assert!(Cache::new(HOUR).get(&http, &issuer).await.is_err());
+}
+
+#[tokio::test]
+async fn unknown_signing_keys_refetch_only_the_key_set() {
+    let http = build_http_client().unwrap();
+    let (issuer, stub) = stub_issuer().await;
+
+    let cache = Cache::new(HOUR);
+    assert!(cache.refresh_jwks(&http).await.is_err());
+    let metadata = cache.get(&http, &issuer).await.unwrap();
+    assert_eq!(kids(&metadata), ["first"]);
+
+    // The issuer rotated its keys.
+    stub.lock().unwrap().kids = vec!["first", "second"];
+    let refreshed = cache.refresh_jwks(&http).await.unwrap();
+    assert_eq!(kids(&refreshed), ["first", "second"]);
+    // Later logins see the new keys without another discovery.
+    let cached = cache.get(&http, &issuer).await.unwrap();
+    assert_eq!(kids(&cached), ["first", "second"]);
+
+    let stub = stub.lock().unwrap();
+    assert_eq!((stub.discoveries, stub.key_fetches), (1, 2));
+}
+
+#[tokio::test]
+async fn only_templated_caches_accept_another_issuer() {
+    let http = build_http_client().unwrap();
+    let (issuer, stub) = stub_issuer().await;
+    stub.lock().unwrap().advertised =
+        Some("https://login.microsoftonline.com/{tenantid}/v2.0".to_string());
+
+    assert!(Cache::new(HOUR).get(&http, &issuer).await.is_err());
+
+    let metadata = Cache::with_templated_issuer(HOUR)
+        .get(&http, &issuer)
+        .await
+        .unwrap();
+    assert_eq!(
+        metadata.issuer().as_str(),
+        "https://login.microsoftonline.com/{tenantid}/v2.0"
+    );
+    assert_eq!(kids(&metadata), ["first"]);
+}
+
+#[tokio::test]
+async fn unknown_signing_keys_refetch_at_most_once_per_interval() {
+    let http = build_http_client().unwrap();
+    let (issuer, stub) = stub_issuer().await;
+
+    let cache = Cache::new(HOUR).with_min_jwks_refetch(HOUR);
+    cache.get(&http, &issuer).await.unwrap();
+
+    // Discovery just fetched the keys, so made-up kids can't trigger another request.
+    stub.lock().unwrap().kids = vec!["first", "second"];
+    for _ in 0..3 {
+        let metadata = cache.refresh_jwks(&http).await.unwrap();
+        assert_eq!(kids(&metadata), ["first"]);
+    }
+    assert_eq!(stub.lock().unwrap().key_fetches, 1);
+}
@@ -0,0 +1,284 @@
+use axum::body::Body;
+use axum::http::{Method, Request, StatusCode};
+use axum::Router;
+use serde_json::Value;
+use tower::ServiceExt;
+use utoipa::OpenApi;
+use wyrd_lib::api::{self, ApiDoc};
+
+fn spec() -> Value {
+    serde_json::to_value(ApiDoc::openapi()).unwrap()
+}
+
+fn properties(spec: &Value, schema: &str) -> Vec<String> {
+    let mut names: Vec<String> = spec["components"]["schemas"][schema]["properties"]
+        .as_object()
+        .unwrap_or_else(|| panic!("missing schema {}", schema))
+        .keys()
+        .cloned()
+        .collect();
+    names.sort();
+    names
+}
+
+// Renaming or dropping a field here breaks clients built against v1, so these fail loudly.
+#[test]
+fn v1_paths_are_stable() {
+    let spec = spec();
+    assert_eq!(spec["servers"][0]["url"], "/api/v1");
+
+    let mut paths: Vec<&String> = spec["paths"].as_object().unwrap().keys().collect();
+    paths.sort();
+    assert_eq!(
+        paths,
+        [
+            "/conversations/direct",
+            "/conversations/{id}",
+            "/conversations/{id}/invites",
+            "/conversations/{id}/invites/{invite_id}",
+            "/conversations/{id}/invites/{invite_id}/uses",
+            "/conversations/{id}/leave",
+            "/conversations/{id}/members",
+            "/conversations/{id}/members/{user_id}",
+            "/conversations/{id}/messages",
+            "/conversations/{id}/owner",
+            "/groups",
+            "/invites/{code}",
+            "/invites/{code}/accept",
+            "/login",
+            "/otp",
+            "/otp/resend",
+            "/presence",
+            "/signup",
+            "/username"
+        ]
+    );
+    for path in [
+        "/conversations/direct",
+        "/conversations/{id}/leave",
+        "/conversations/{id}/members",
+        "/conversations/{id}/owner",
+        "/groups",
+        "/invites/{code}/accept",
+        "/login",
+        "/otp",
+        "/otp/resend",
+        "/signup",
+        "/username",
+    ] {
+        assert!(
+            spec["paths"][path]["post"].is_object(),
+            "{} is not a POST",
+            path
+        );
+    }
+    let messages = &spec["paths"]["/conversations/{id}/messages"];
+    assert!(messages["get"].is_object() && messages["post"].is_object());
+    let group = &spec["paths"]["/conversations/{id}"];
+    assert!(group["get"].is_object() && group["patch"].is_object() && group["delete"].is_object());
+    let member = &spec["paths"]["/conversations/{id}/members/{user_id}"];
+    assert!(member["patch"].is_object() && member["delete"].is_object());
+    let invites = &spec["paths"]["/conversations/{id}/invites"];
+    assert!(invites["get"].is_object() && invites["post"].is_object());
+    assert!(spec["paths"]["/conversations/{id}/invites/{invite_id}"]["delete"].is_object());
+    assert!(spec["paths"]["/conversations/{id}/invites/{invite_id}/uses"]["get"].is_object());
+    assert!(spec["paths"]["/invites/{code}"]["get"].is_object());
+    let presence = &spec["paths"]["/presence"];
+    assert!(presence["get"].is_object() && presence["put"].is_object());
+}
+
+#[test]
+fn v1_shapes_are_stable() {
+    let spec = spec();
+    assert_eq!(
+        properties(&spec, "SignupReq"),
+        ["email", "invite", "name", "password", "username"]
+    );
+    assert_eq!(properties(&spec, "OTPVerReq"), ["email", "entered_code"]);
+    assert_eq!(properties(&spec, "ResendOtpReq"), ["email"]);
+    assert_eq!(
+        properties(&spec, "OTPVerResp"),
+        ["conversation_id", "message", "valid"]
+    );
+    assert_eq!(
+        properties(&spec, "LoginReq"),
+        ["email", "password", "username"]
+    );
+    assert_eq!(
+        properties(&spec, "ChooseUsernameReq"),
+        ["ticket", "username"]
+    );
+    assert_eq!(properties(&spec, "UsernameResp"), ["username"]);
+    assert_eq!(properties(&spec, "MessageResp"), ["message"]);
+    assert_eq!(properties(&spec, "LoginResp"), ["message", "session"]);
+    assert_eq!(properties(&spec, "ErrorResp"), ["error"]);
+    assert_eq!(
+        properties(&spec, "ErrorBody"),
+        ["code", "fields", "message"]
+    );
+    assert_eq!(
+        properties(&spec, "FieldErrorBody"),
+        ["code", "field", "message"]
+    );
+    assert_eq!(properties(&spec, "OpenDirectReq"), ["username"]);
+    assert_eq!(
+        properties(&spec, "ConversationResp"),
+        [
+            "avatar_url",
+            "created_at",
+            "id",
+            "kind",
+            "last_seq",
+            "members",
+            "name",
+            "topic"
+        ]
+    );
+    assert_eq!(properties(&spec, "PostMessageReq"), ["body", "nonce"]);
+    assert_eq!(
+        properties(&spec, "ChatMessage"),
+        [
+            "body",
+            "conversation_id",
+            "created_at",
+            "edited_at",
+            "event",
+            "id",
+            "kind",
+            "nonce",
+            "sender_id",
+            "seq"
+        ]
+    );
+    assert_eq!(
+        properties(&spec, "MemberResp"),
+        ["id", "name", "role", "username"]
+    );
+    assert_eq!(
+        properties(&spec, "CreateGroupReq"),
+        ["avatar_url", "members", "name", "topic"]
+    );
+    assert_eq!(
+        properties(&spec, "UpdateGroupReq"),
+        ["avatar_url", "name", "topic"]
+    );
+    assert_eq!(properties(&spec, "AddMembersReq"), ["usernames"]);
+    assert_eq!(properties(&spec, "SetRoleReq"), ["role"]);
+    assert_eq!(properties(&spec, "TransferOwnershipReq"), ["user_id"]);
+    assert_eq!(
+        properties(&spec, "HistoryPage"),
+        ["messages", "newer", "older"]
+    );
+    assert_eq!(
+        properties(&spec, "CreateInviteReq"),
+        ["expires_in", "max_uses", "role"]
+    );
+    assert_eq!(
+        properties(&spec, "InviteResp"),
+        [
+            "code",
+            "created_at",
+            "created_by",
+            "expires_at",
+            "id",
+            "max_uses",
+            "revoked_at",
+            "role",
+            "usable",
+            "uses"
+        ]
+    );
+    assert_eq!(
+        properties(&spec, "InviteUseResp"),
+        ["used_at", "user_id", "username"]
+    );
+    assert_eq!(
+        properties(&spec, "InvitePreviewResp"),
+        [
+            "avatar_url",
+            "conversation_id",
+            "expires_at",
+            "member_count",
+            "name",
+            "role",
+            "topic"
+        ]
+    );
+    assert_eq!(
+        properties(&spec, "PresenceUpdate"),
+        ["last_seen", "state", "user_id"]
+    );
+    assert_eq!(properties(&spec, "SetPresenceReq"), ["state"]);
+}
+
+#[test]
+fn chat_routes_need_a_session() {
+    let spec = spec();
+    assert_eq!(
+        spec["components"]["securitySchemes"]["session"]["scheme"],
+        "bearer"
+    );
+    let history = &spec["paths"]["/conversations/{id}/messages"]["get"];
+    assert!(history["security"][0]["session"].is_array());
+    assert!(spec["paths"]["/login"]["post"]["security"].is_null());
+    // Anyone holding the link can look at it, joining takes an account.
+    assert!(spec["paths"]["/invites/{code}"]["get"]["security"].is_null());
+    let accept = &spec["paths"]["/invites/{code}/accept"]["post"];
+    assert!(accept["security"][0]["session"].is_array());
+}
+
+#[test]
+fn error_responses_share_one_shape() {
+    let spec = spec();
+    let signup_error = &spec["paths"]["/signup"]["post"]["responses"]["400"]["content"]
+        ["application/json"]["schema"]["$ref"];
+    assert_eq!(signup_error, "#/components/schemas/ErrorResp");
+}
+
+// A path in the spec that the router doesn't serve is documentation for nothing. Handlers
+// don't get to run without the app state, so anything but 404 and 405 means the route exists.
+#[tokio::test]
+async fn every_documented_operation_is_routed() {
+    let spec = spec();
+    let app = Router::new().nest(api::V1_PREFIX, api::v1_routes());
+
+    for (path, operations) in spec["paths"].as_object().unwrap() {
+        // Path parameters are ids, or invite codes that happen to look like one.
+        let uri = format!(
+            "{}{}",
+            api::V1_PREFIX,
+            path.split('/')
+                .map(|segment| if segment.starts_with('{') {
+                    "1"
+                } else {
+                    segment
+                })
+                .collect::<Vec<_>>()
+                .join("/")
+        );
+        for (method, _) in operations.as_object().unwrap() {
+            // Path items can carry a summary or shared parameters next to the operations.
+            let method = match method.as_str() {
+                "get" => Method::GET,
+                "post" => Method::POST,
+                "put" => Method::PUT,
+                "patch" => Method::PATCH,
+                "delete" => Method::DELETE,
+                _ => continue,
+            };
+            let request = Request::builder()
+                .method(method.clone())
+                .uri(&uri)
+                .body(Body::empty())
+                .unwrap();
+            let status = app.clone().oneshot(request).await.unwrap().status();
+            assert!(
+                status != StatusCode::NOT_FOUND && status != StatusCode::METHOD_NOT_ALLOWED,
+                "{} {} answered {}",
+                method,
+                uri,
+                status
+            );
+        }
+    }
+}
@@ -0,0 +1,87 @@
+// Email outbox retries against the in-memory outbox.
+use chrono::{Duration, Utc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use wyrd_lib::memory_store::MemoryEmailOutbox;
+use wyrd_lib::otp::{retry_emails, DeliveryCounts};
+use wyrd_lib::repository::{EmailOutbox, NewEmail, QueuedEmail};
+
+fn email(recipient: &str) -> NewEmail {
+    NewEmail {
+        recipient: format!("{} <{}@example.com>", recipient, recipient.to_lowercase()),
+        subject: "Please verify your account".to_string(),
+        body: "Please enter the following code shown: 123456".to_string(),
+    }
+}
+
+fn sent(_: &QueuedEmail) -> Result<(), String> {
+    Ok(())
+}
+
+fn refused(_: &QueuedEmail) -> Result<(), String> {
+    Err("connection refused".to_string())
+}
+
+#[tokio::test]
+async fn queued_emails_are_sent_once() {
+    let outbox = MemoryEmailOutbox::new();
+    let expires = Utc::now() + Duration::minutes(5);
+    let ada = outbox.enqueue(&email("Ada"), expires).await.unwrap();
+    let grace = outbox.enqueue(&email("Grace"), expires).await.unwrap();
+    assert_eq!(outbox.due(10).await.unwrap(), [ada, grace]);
+
+    let calls = AtomicUsize::new(0);
+    let counts = retry_emails(&outbox, |_| {
+        calls.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    })
+    .await
+    .unwrap();
+    assert_eq!(
+        counts,
+        DeliveryCounts {
+            sent: 2,
+            failed: 0,
+            expired: 0
+        }
+    );
+    assert_eq!(retry_emails(&outbox, sent).await.unwrap().sent, 0);
+    assert_eq!(calls.load(Ordering::Relaxed), 2);
+}
+
+#[tokio::test]
+async fn failed_emails_wait_before_the_next_attempt() {
+    let outbox = MemoryEmailOutbox::new();
+    let queued = outbox
+        .enqueue(&email("Ada"), Utc::now() + Duration::minutes(5))
+        .await
+        .unwrap();
+
+    assert_eq!(retry_emails(&outbox, refused).await.unwrap().failed, 1);
+    // Backed off, so the next run leaves it alone.
+    assert!(outbox.due(10).await.unwrap().is_empty());
+    assert_eq!(retry_emails(&outbox, sent).await.unwrap().sent, 0);
+
+    // Once it's due again it goes out with its attempts counted.
+    outbox
+        .failed(queued.id, "connection refused", Utc::now())
+        .await
+        .unwrap();
+    let due = outbox.due(10).await.unwrap();
+    assert_eq!(due[0].attempts, 2);
+    assert_eq!(retry_emails(&outbox, sent).await.unwrap().sent, 1);
+    assert!(outbox.due(10).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn expired_emails_are_dropped_unsent() {
+    let outbox = MemoryEmailOutbox::new();
+    outbox
+        .enqueue(&email("Ada"), Utc::now() - Duration::seconds(1))
+        .await
+        .unwrap();
+    assert!(outbox.due(10).await.unwrap().is_empty());
+
+    let counts = retry_emails(&outbox, sent).await.unwrap();
+    assert_eq!((counts.sent, counts.expired), (0, 1));
+    assert_eq!(outbox.prune_expired().await.unwrap(), 0);
+}
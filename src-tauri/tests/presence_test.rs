@@ -0,0 +1,327 @@
+// Presence from gateway devices and picked states, against the in-memory stores.
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use std::sync::{Arc, Mutex};
+use wyrd_lib::memory_store::{MemoryConversationStore, MemoryStore, MemoryUserRepository};
+use wyrd_lib::presence::{self, PresenceError};
+use wyrd_lib::repository::{
+    ConversationStore, EphemeralPresenceStore, PresenceStore, StoreError, UserRepository, AWAY,
+    DND, INVISIBLE, OFFLINE, ONLINE,
+};
+use wyrd_lib::settings::PresenceSettings;
+
+mod common;
+
+const SETTINGS: PresenceSettings = PresenceSettings {
+    heartbeat_timeout_secs: 60,
+    idle_after_secs: 300,
+};
+
+fn presence_store() -> EphemeralPresenceStore {
+    EphemeralPresenceStore::new(Arc::new(MemoryStore::new()))
+}
+
+#[test]
+fn devices_and_choices_add_up_to_one_state() {
+    let now = Utc::now();
+    let idle_after = Duration::minutes(5);
+    let active = now - Duration::seconds(10);
+    let idle = now - Duration::minutes(10);
+
+    assert_eq!(presence::aggregate(None, &[], now, idle_after), OFFLINE);
+    assert_eq!(
+        presence::aggregate(Some(DND), &[], now, idle_after),
+        OFFLINE
+    );
+    assert_eq!(
+        presence::aggregate(None, &[active], now, idle_after),
+        ONLINE
+    );
+    assert_eq!(presence::aggregate(None, &[idle], now, idle_after), AWAY);
+    // One device in use is enough.
+    assert_eq!(
+        presence::aggregate(Some(ONLINE), &[idle, active], now, idle_after),
+        ONLINE
+    );
+    assert_eq!(
+        presence::aggregate(Some(AWAY), &[active], now, idle_after),
+        AWAY
+    );
+    assert_eq!(
+        presence::aggregate(Some(DND), &[idle], now, idle_after),
+        DND
+    );
+    assert_eq!(
+        presence::aggregate(Some(INVISIBLE), &[active], now, idle_after),
+        OFFLINE
+    );
+}
+
+#[tokio::test]
+async fn users_stay_online_until_their_last_device_leaves() {
+    let users = MemoryUserRepository::new();
+    let store = presence_store();
+    let ada = common::add_user(&users, "ada").await;
+
+    let update = presence::connected(&users, &store, &SETTINGS, ada.id, "laptop")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(update.state, ONLINE);
+    assert_eq!(update.last_seen, None);
+    // Nothing changed for anyone else.
+    assert!(
+        presence::connected(&users, &store, &SETTINGS, ada.id, "phone")
+            .await
+            .unwrap()
+            .is_none()
+    );
+    assert!(
+        presence::heartbeat(&users, &store, &SETTINGS, ada.id, "phone", false)
+            .await
+            .unwrap()
+            .is_none()
+    );
+
+    assert!(
+        presence::disconnected(&users, &store, &SETTINGS, ada.id, "laptop")
+            .await
+            .unwrap()
+            .is_none()
+    );
+    let update = presence::disconnected(&users, &store, &SETTINGS, ada.id, "phone")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(update.state, OFFLINE);
+    assert!(update.last_seen.is_some());
+    assert!(users.present_ids().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn picked_states_stick_across_reconnects() {
+    let users = MemoryUserRepository::new();
+    let store = presence_store();
+    let ada = common::add_user(&users, "ada").await;
+
+    // Picked while offline, it shows once a device connects.
+    assert!(presence::set_state(&users, &store, &SETTINGS, ada.id, DND)
+        .await
+        .unwrap()
+        .is_none());
+    let update = presence::connected(&users, &store, &SETTINGS, ada.id, "laptop")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(update.state, DND);
+
+    let update = presence::set_state(&users, &store, &SETTINGS, ada.id, INVISIBLE)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(update.state, OFFLINE);
+    presence::disconnected(&users, &store, &SETTINGS, ada.id, "laptop")
+        .await
+        .unwrap();
+    presence::connected(&users, &store, &SETTINGS, ada.id, "laptop")
+        .await
+        .unwrap();
+    assert_eq!(
+        store.choice(ada.id).await.unwrap().as_deref(),
+        Some(INVISIBLE)
+    );
+    assert_eq!(
+        users.presence(&[ada.id]).await.unwrap()[0].activity,
+        OFFLINE
+    );
+
+    let update = presence::set_state(&users, &store, &SETTINGS, ada.id, ONLINE)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(update.state, ONLINE);
+}
+
+#[tokio::test]
+async fn only_selectable_states_can_be_picked() {
+    let users = MemoryUserRepository::new();
+    let store = presence_store();
+    let ada = common::add_user(&users, "ada").await;
+
+    for state in [OFFLINE, "busy", ""] {
+        assert!(matches!(
+            presence::set_state(&users, &store, &SETTINGS, ada.id, state).await,
+            Err(PresenceError::InvalidState)
+        ));
+    }
+    assert_eq!(store.choice(ada.id).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn sweeping_catches_devices_that_stopped_sending_heartbeats() {
+    let users = MemoryUserRepository::new();
+    let store = presence_store();
+    let ada = common::add_user(&users, "ada").await;
+    let grace = common::add_user(&users, "grace").await;
+
+    presence::connected(&users, &store, &SETTINGS, ada.id, "laptop")
+        .await
+        .unwrap();
+    // Last heard of by a server that went away since.
+    store
+        .touch(ada.id, "laptop", false, std::time::Duration::ZERO)
+        .await
+        .unwrap();
+    presence::connected(&users, &store, &SETTINGS, grace.id, "laptop")
+        .await
+        .unwrap();
+    assert_eq!(users.present_ids().await.unwrap(), [ada.id, grace.id]);
+
+    let changed = presence::sweep(&users, &store, &SETTINGS).await.unwrap();
+    assert_eq!(changed.len(), 1);
+    assert_eq!(changed[0].user_id, ada.id);
+    assert_eq!(changed[0].state, OFFLINE);
+    assert_eq!(users.present_ids().await.unwrap(), [grace.id]);
+    assert!(store.devices(ada.id).await.unwrap().is_empty());
+}
+
+/// Loses track of one user's devices, like a Redis shard that went away.
+struct FlakyPresenceStore {
+    inner: EphemeralPresenceStore,
+    broken: i64,
+}
+
+impl FlakyPresenceStore {
+    fn check(&self, user_id: i64) -> Result<(), StoreError> {
+        if user_id == self.broken {
+            return Err(StoreError::Database(sqlx::Error::PoolTimedOut));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PresenceStore for FlakyPresenceStore {
+    async fn touch(
+        &self,
+        user_id: i64,
+        device: &str,
+        active: bool,
+        ttl: std::time::Duration,
+    ) -> Result<(), StoreError> {
+        self.inner.touch(user_id, device, active, ttl).await
+    }
+    async fn remove(&self, user_id: i64, device: &str) -> Result<(), StoreError> {
+        self.inner.remove(user_id, device).await
+    }
+    async fn devices(&self, user_id: i64) -> Result<Vec<DateTime<Utc>>, StoreError> {
+        self.check(user_id)?;
+        self.inner.devices(user_id).await
+    }
+    async fn choice(&self, user_id: i64) -> Result<Option<String>, StoreError> {
+        self.check(user_id)?;
+        self.inner.choice(user_id).await
+    }
+    async fn set_choice(&self, user_id: i64, state: &str) -> Result<(), StoreError> {
+        self.inner.set_choice(user_id, state).await
+    }
+}
+
+#[tokio::test]
+async fn sweeping_goes_on_past_users_it_cant_refresh() {
+    let users = MemoryUserRepository::new();
+    let ada = common::add_user(&users, "ada").await;
+    let grace = common::add_user(&users, "grace").await;
+    let store = FlakyPresenceStore {
+        inner: presence_store(),
+        broken: ada.id,
+    };
+
+    for user in [&ada, &grace] {
+        store
+            .inner
+            .touch(user.id, "laptop", true, SETTINGS.heartbeat_timeout())
+            .await
+            .unwrap();
+        presence::refresh(&users, &store.inner, &SETTINGS, user.id)
+            .await
+            .unwrap();
+    }
+    for user in [&ada, &grace] {
+        store
+            .touch(user.id, "laptop", false, std::time::Duration::ZERO)
+            .await
+            .unwrap();
+    }
+
+    let changed = presence::sweep(&users, &store, &SETTINGS).await.unwrap();
+    assert_eq!(changed.len(), 1);
+    assert_eq!(changed[0].user_id, grace.id);
+    // Ada is still counted as present and gets another go on the next sweep.
+    assert_eq!(users.present_ids().await.unwrap(), [ada.id]);
+}
+
+#[tokio::test]
+async fn presence_reaches_only_people_sharing_a_conversation() {
+    let users = MemoryUserRepository::new();
+    let conversations = MemoryConversationStore::new();
+    let store = presence_store();
+    let ada = common::add_user(&users, "ada").await;
+    let grace = common::add_user(&users, "grace").await;
+    let linus = common::add_user(&users, "linus").await;
+    common::add_user(&users, "stranger").await;
+    conversations.open_direct(ada.id, grace.id).await.unwrap();
+    conversations.open_direct(ada.id, linus.id).await.unwrap();
+
+    assert_eq!(
+        presence::recipients(&conversations, ada.id).await.unwrap(),
+        [grace.id, linus.id, ada.id]
+    );
+    assert_eq!(
+        presence::recipients(&conversations, grace.id)
+            .await
+            .unwrap(),
+        [ada.id, grace.id]
+    );
+
+    presence::connected(&users, &store, &SETTINGS, ada.id, "laptop")
+        .await
+        .unwrap();
+    let seen = presence::contacts(&users, &conversations, grace.id)
+        .await
+        .unwrap();
+    assert_eq!(seen.len(), 1);
+    assert_eq!((seen[0].user_id, seen[0].state.as_str()), (ada.id, ONLINE));
+}
+
+#[tokio::test]
+async fn swept_changes_reach_contacts() {
+    let users = MemoryUserRepository::new();
+    let conversations = MemoryConversationStore::new();
+    let store = presence_store();
+    let ada = common::add_user(&users, "ada").await;
+    let grace = common::add_user(&users, "grace").await;
+    conversations.open_direct(ada.id, grace.id).await.unwrap();
+
+    presence::connected(&users, &store, &SETTINGS, ada.id, "laptop")
+        .await
+        .unwrap();
+    store
+        .touch(ada.id, "laptop", false, std::time::Duration::ZERO)
+        .await
+        .unwrap();
+    let changed = presence::sweep(&users, &store, &SETTINGS).await.unwrap();
+
+    let published = Mutex::new(Vec::new());
+    presence::announce(&conversations, &changed, |recipients, update| {
+        published
+            .lock()
+            .unwrap()
+            .push((recipients.to_vec(), update.user_id, update.state.clone()));
+    })
+    .await;
+    assert_eq!(
+        published.into_inner().unwrap(),
+        [(vec![grace.id, ada.id], ada.id, OFFLINE.to_string())]
+    );
+}
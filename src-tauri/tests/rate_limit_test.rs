@@ -0,0 +1,174 @@
+// The rate limiting middleware, with a session looked up first, against the in-memory stores.
+use async_trait::async_trait;
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    http::{header, Request, StatusCode},
+    middleware,
+    routing::get,
+    Extension, Router,
+};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tower::ServiceExt;
+use wyrd_lib::auth_handler::{authenticate_request, AppState};
+use wyrd_lib::ephemeral::EphemeralStore;
+use wyrd_lib::memory_store::MemoryStore;
+use wyrd_lib::rate_limit::limit_requests;
+use wyrd_lib::repository::StoreError;
+use wyrd_lib::settings::{Profile, RateLimitKey, RateLimitRule, Settings};
+
+mod common;
+
+const ROUTE: &str = "/limited";
+
+/// Fails every call, like Redis while it's down.
+struct BrokenStore;
+
+#[async_trait]
+impl EphemeralStore for BrokenStore {
+    async fn get(&self, _: &str) -> Result<Option<String>, StoreError> {
+        Err(StoreError::Database(sqlx::Error::PoolTimedOut))
+    }
+    async fn set(&self, _: &str, _: &str, _: Option<Duration>) -> Result<(), StoreError> {
+        Err(StoreError::Database(sqlx::Error::PoolTimedOut))
+    }
+    async fn take(&self, _: &str) -> Result<Option<String>, StoreError> {
+        Err(StoreError::Database(sqlx::Error::PoolTimedOut))
+    }
+    async fn delete(&self, _: &[String]) -> Result<u64, StoreError> {
+        Err(StoreError::Database(sqlx::Error::PoolTimedOut))
+    }
+    async fn set_add(&self, _: &str, _: &str) -> Result<(), StoreError> {
+        Err(StoreError::Database(sqlx::Error::PoolTimedOut))
+    }
+    async fn set_remove(&self, _: &str, _: &str) -> Result<(), StoreError> {
+        Err(StoreError::Database(sqlx::Error::PoolTimedOut))
+    }
+    async fn set_members(&self, _: &str) -> Result<Vec<String>, StoreError> {
+        Err(StoreError::Database(sqlx::Error::PoolTimedOut))
+    }
+    async fn scan(&self, _: &str) -> Result<Vec<String>, StoreError> {
+        Err(StoreError::Database(sqlx::Error::PoolTimedOut))
+    }
+    async fn hit(&self, _: &str, _: Duration, _: u64) -> Result<Option<Duration>, StoreError> {
+        Err(StoreError::Database(sqlx::Error::PoolTimedOut))
+    }
+    async fn ping(&self) -> Result<(), StoreError> {
+        Err(StoreError::Database(sqlx::Error::PoolTimedOut))
+    }
+}
+
+fn state(key: RateLimitKey, ephemeral: Arc<dyn EphemeralStore>) -> Arc<AppState> {
+    let mut settings = Settings::load_profile(Profile::Test).unwrap();
+    settings.rate_limit.enabled = true;
+    settings.rate_limit.trust_forwarded_for = false;
+    settings.rate_limit.rules = HashMap::from([(
+        "limited".to_string(),
+        RateLimitRule {
+            route: Some(ROUTE.to_string()),
+            key,
+            limit: 2,
+            window_secs: 60,
+        },
+    )]);
+    common::state(settings, ephemeral)
+}
+
+fn app(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route(ROUTE, get(|| async { "ok" }))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            limit_requests,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            authenticate_request,
+        ))
+        .layer(Extension(state))
+}
+
+async fn call(app: &Router, ip: &str, token: Option<&str>) -> axum::response::Response {
+    let mut request = Request::get(ROUTE);
+    if let Some(token) = token {
+        request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+    }
+    let mut request = request.body(Body::empty()).unwrap();
+    let address: SocketAddr = format!("{}:4000", ip).parse().unwrap();
+    request.extensions_mut().insert(ConnectInfo(address));
+    app.clone().oneshot(request).await.unwrap()
+}
+
+#[tokio::test]
+async fn requests_over_the_limit_get_429_with_retry_after() {
+    let app = app(state(RateLimitKey::Ip, Arc::new(MemoryStore::new())));
+
+    for _ in 0..2 {
+        assert_eq!(call(&app, "10.0.0.1", None).await.status(), StatusCode::OK);
+    }
+    let limited = call(&app, "10.0.0.1", None).await;
+    assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = limited.headers()[header::RETRY_AFTER]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((1..=60).contains(&retry_after));
+}
+
+#[tokio::test]
+async fn ip_rules_count_each_address() {
+    let app = app(state(RateLimitKey::Ip, Arc::new(MemoryStore::new())));
+
+    for _ in 0..2 {
+        call(&app, "10.0.0.1", None).await;
+    }
+    assert_eq!(
+        call(&app, "10.0.0.1", None).await.status(),
+        StatusCode::TOO_MANY_REQUESTS
+    );
+    assert_eq!(call(&app, "10.0.0.2", None).await.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn user_rules_count_signed_in_requests_per_account() {
+    let state = state(RateLimitKey::User, Arc::new(MemoryStore::new()));
+    let ada = common::sign_in(&state, "ada").await;
+    let grace = common::sign_in(&state, "grace").await;
+    let app = app(state);
+
+    // One account across addresses shares a budget.
+    call(&app, "10.0.0.1", Some(&ada)).await;
+    call(&app, "10.0.0.2", Some(&ada)).await;
+    assert_eq!(
+        call(&app, "10.0.0.3", Some(&ada)).await.status(),
+        StatusCode::TOO_MANY_REQUESTS
+    );
+    // Another account on the same address has its own.
+    assert_eq!(
+        call(&app, "10.0.0.1", Some(&grace)).await.status(),
+        StatusCode::OK
+    );
+    // Without a session, or with a stale one, the address is what counts.
+    assert_eq!(call(&app, "10.0.0.1", None).await.status(), StatusCode::OK);
+    assert_eq!(
+        call(&app, "10.0.0.1", Some("expired")).await.status(),
+        StatusCode::OK
+    );
+    assert_eq!(
+        call(&app, "10.0.0.1", None).await.status(),
+        StatusCode::TOO_MANY_REQUESTS
+    );
+}
+
+#[tokio::test]
+async fn store_failures_let_requests_through() {
+    let app = app(state(RateLimitKey::Ip, Arc::new(BrokenStore)));
+
+    for _ in 0..5 {
+        assert_eq!(call(&app, "10.0.0.1", None).await.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,76 @@
+// Headers the API router adds to every response, against the in-memory stores.
+use axum::{
+    body::Body,
+    http::{header, Request, StatusCode},
+    Router,
+};
+use std::sync::Arc;
+use tower::ServiceExt;
+use wyrd_lib::memory_store::MemoryStore;
+use wyrd_lib::server;
+use wyrd_lib::settings::{Profile, Settings};
+
+mod common;
+
+fn app(settings: Settings) -> Router {
+    let state = common::state(settings, Arc::new(MemoryStore::new()));
+    server::router(state).unwrap().0
+}
+
+async fn hsts(app: &Router, forwarded_proto: Option<&str>) -> Option<String> {
+    let mut request = Request::get("/healthz");
+    if let Some(proto) = forwarded_proto {
+        request = request.header("x-forwarded-proto", proto);
+    }
+    let response = app
+        .clone()
+        .oneshot(request.body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    response
+        .headers()
+        .get(header::STRICT_TRANSPORT_SECURITY)
+        .map(|value| value.to_str().unwrap().to_string())
+}
+
+fn with_hsts() -> Settings {
+    let mut settings = Settings::load_profile(Profile::Test).unwrap();
+    settings.security.hsts_max_age_secs = 600;
+    settings
+}
+
+#[tokio::test]
+async fn hsts_is_left_off_plain_http() {
+    let app = app(with_hsts());
+    assert_eq!(hsts(&app, None).await, None);
+    // Anyone can send the header, it only counts from a trusted proxy.
+    assert_eq!(hsts(&app, Some("https")).await, None);
+}
+
+#[tokio::test]
+async fn hsts_is_sent_over_tls() {
+    let mut settings = with_hsts();
+    settings.tls.enabled = true;
+    let app = app(settings);
+    assert_eq!(
+        hsts(&app, None).await.as_deref(),
+        Some("max-age=600; includeSubDomains")
+    );
+}
+
+#[tokio::test]
+async fn hsts_follows_the_scheme_a_trusted_proxy_reports() {
+    let mut settings = with_hsts();
+    settings.security.trust_forwarded_proto = true;
+    let proxied = app(settings);
+    assert!(hsts(&proxied, Some("https")).await.is_some());
+    assert!(hsts(&proxied, Some("HTTPS, http")).await.is_some());
+    assert_eq!(hsts(&proxied, Some("http")).await, None);
+    assert_eq!(hsts(&proxied, None).await, None);
+
+    // Nothing to send with a max-age of 0.
+    let mut settings = Settings::load_profile(Profile::Test).unwrap();
+    settings.security.trust_forwarded_proto = true;
+    assert_eq!(hsts(&app(settings), Some("https")).await, None);
+}
@@ -0,0 +1,21 @@
+import { invoke } from "@tauri-apps/api/core";
+
+// Matches the prefix the backend serves its versioned routes under, see src-tauri/src/api.rs.
+export const API_V1 = "/api/v1";
+
+// Error body every API route and Tauri command fails with, see src-tauri/src/error.rs.
+export interface ApiError {
+  code: string;
+  message: string;
+  fields?: { field: string; code: string; message: string }[];
+}
+
+let serverUrl: Promise<string> | null = null;
+
+// The backend is either started by the desktop app or a remote wyrd-server; ask Tauri which.
+export function apiUrl(path: string): Promise<string> {
+  if (!serverUrl) {
+    serverUrl = invoke<string>("server_url");
+  }
+  return serverUrl.then((base) => `${base.replace(/\/$/, "")}${path}`);
+}
@@ -1,9 +1,9 @@
-import React, { useState, useEffect } from "react";
+import React, { useState } from "react";
 import OtpInput from "react-otp-input";
 import axios from "axios";
 import { useNavigate, useLocation } from "react-router-dom";
-import { invoke } from "@tauri-apps/api/core";
 import { maskEmail } from "react-email-mask";
+import { API_V1, apiUrl } from "../api";
 import "../styles/OTP.css";
 
 function OTP1() {
@@ -20,8 +20,8 @@ function OTP1() {
     inputTypeConf: "isInputNum" as const,
   });
 
-  const OTP_URL = "http://localhost:3000/otp";
-  const RESEND_OTP_URL = "http://localhost:3000/resend-otp";
+  const OTP_PATH = `${API_V1}/otp`;
+  const RESEND_PATH = `${API_V1}/otp/resend`;
   const navigate = useNavigate();
   const location = useLocation();
   const [isDisabled, setDisabled] = useState(true);
@@ -34,31 +34,34 @@ function OTP1() {
   };
 
   interface OTP {
+    email: string;
     entered_code: string;
   }
 
-  const [isResending, setIsResending] = useState(false);
+  interface OTPVerResp {
+    valid: boolean;
+    message: string;
+  }
 
-  const [userEmail, setUserEmail] = useState<string | null>(null);
-  let userName;
+  const [isResending, setIsResending] = useState(false);
 
-  useEffect(() => {
-    invoke("client_info_otp")
-      .then((message: any) => {
-        setUserEmail(message.email);
-      })
-      .catch((error) => console.error(error));
-  }, []); // Runs only once when the component mounts
+  // Handed over by the sign-up form.
+  const userEmail: string | null = location.state?.email ?? null;
 
   const onSubmit = async (code: OTP) => {
     try {
-      const response = await axios.post(OTP_URL, code, {
-        headers: {
-          "Content-Type": "application/json",
+      const response = await axios.post<OTPVerResp>(
+        await apiUrl(OTP_PATH),
+        code,
+        {
+          headers: {
+            "Content-Type": "application/json",
+          },
         },
-      });
-      console.log("OTP verified successfully:", response.data);
-      navigate("/personalize");
+      );
+      if (response.data.valid) {
+        navigate("/personalize");
+      }
     } catch (error) {
       console.error("Error with verification:", error);
       if (axios.isAxiosError(error)) {
@@ -69,15 +72,16 @@ function OTP1() {
 
   const handleOTPChange = (otpValue: string): void => {
     setConfig((prevConfig) => ({ ...prevConfig, otp: otpValue }));
-    if (otpValue.length === 6) {
-      const codeObject: OTP = { entered_code: otpValue };
+    if (otpValue.length === 6 && userEmail) {
+      const codeObject: OTP = { email: userEmail, entered_code: otpValue };
       onSubmit(codeObject); // Pass the OTP to onSubmit
     }
   };
 
   const handleResendOTP = async () => {
     try {
-      await invoke("resend_otp_handler");
+      // Over HTTP, so it works against a remote server as well.
+      await axios.post(await apiUrl(RESEND_PATH), { email: userEmail });
       handleDisable();
     } catch (error) {
       console.error("Error with sending:", error);
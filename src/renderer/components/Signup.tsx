@@ -3,6 +3,9 @@ import React, { useState, useEffect, useRef, CSSProperties } from "react";
 import PasswordStrengthBar from "react-password-strength-bar";
 import { yupResolver } from "@hookform/resolvers/yup";
 import axios from "axios";
+import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
+import { API_V1, ApiError, apiUrl } from "../api";
 import "../styles/Form.css";
 import { useForm } from "react-hook-form";
 import * as yup from "yup";
@@ -17,7 +20,18 @@ interface SignupForm {
   password: string;
 }
 
-const REGISTER_URL = "http://localhost:3000/signup";
+const REGISTER_PATH = `${API_V1}/signup`;
+
+const SOCIAL_UNAVAILABLE =
+  "Google and Microsoft sign-in aren't available with a remote server yet.";
+
+interface OAuthResult {
+  provider: string;
+  success: boolean;
+  username?: string;
+  username_ticket?: string;
+  error?: string;
+}
 
 const schema = yup.object().shape({
   name: yup.string().required("Name is a required field"),
@@ -42,11 +56,46 @@ export function Form(): React.JSX.Element {
   const [email, setEmail] = useState("");
   const [username, setUsername] = useState("");
   const [inputValue, setInputValue] = useState(""); // State for password input
+  const [socialSignIn, setSocialSignIn] = useState(false);
+
+  useEffect(() => {
+    invoke<boolean>("social_sign_in_available")
+      .then(setSocialSignIn)
+      .catch((error) => console.error(error));
+  }, []);
+
+  // The desktop sign-in finishes in the system browser, the backend reports back with an event.
+  useEffect(() => {
+    const unlisten = listen<OAuthResult>("oauth-result", (event) => {
+      if (event.payload.success) {
+        navigate("/personalize");
+      } else {
+        setEmailError(event.payload.error ?? "Sign-in failed. Please try again.");
+      }
+    });
+    return () => {
+      unlisten.then((stop) => stop());
+    };
+  }, []);
+
+  const handleSocialSignIn =
+    (provider: string) => async (event: React.FormEvent<HTMLFormElement>) => {
+      event.preventDefault();
+      try {
+        await invoke("oauth_sign_in", { provider });
+      } catch (error) {
+        console.error("Error starting sign-in:", error);
+        // Commands fail with the same body as the API routes.
+        setEmailError(
+          (error as ApiError)?.message ?? "Sign-in failed. Please try again.",
+        );
+      }
+    };
 
   const onSubmit = async (formData: SignupForm) => {
     try {
       const response = await axios.post(
-        REGISTER_URL,
+        await apiUrl(REGISTER_PATH),
         formData, // Send formData as JSON in the request body
         {
           headers: {
@@ -56,20 +105,24 @@ export function Form(): React.JSX.Element {
       );
       console.log("Registration successful:", response.data);
 
-      navigate("/otp");
+      // The code is checked against this sign-up's email, not whichever one came last.
+      navigate("/otp", { state: { email: formData.email } });
     } catch (error) {
       console.error("Error creating account:", error);
       if (axios.isAxiosError(error)) {
         if (error.response) {
-          // Extract the specific error message from the server response
-          const errorMessage = error.response.data.error || "An error occurred"; // Adjust key as needed
-          const errorType = errorMessage.split(":")[0];
-          const startIndex = errorMessage.indexOf('("') + 2;
-          const endIndex = errorMessage.lastIndexOf('")');
-          if (errorType === "Username") {
-            setUsernameError(errorMessage.slice(startIndex, endIndex));
+          const apiError: ApiError | undefined = error.response.data?.error;
+          if (apiError?.fields?.length) {
+            // Every rejected field gets its own message.
+            for (const field of apiError.fields) {
+              if (field.field === "username") {
+                setUsernameError(field.message);
+              } else if (field.field === "email") {
+                setEmailError(field.message);
+              }
+            }
           } else {
-            setEmailError(errorMessage.slice(startIndex, endIndex));
+            setEmailError(apiError?.message ?? "An error occurred");
           }
         } else if (error.request) {
           setEmailError(
@@ -110,93 +163,96 @@ export function Form(): React.JSX.Element {
               <div className="page_socialButtonsWrapper__xOb3F">
                 {" "}
                 {/* Add a wrapping parent element */}
-                <form
-                  className="SocialButton_form__9yToE"
-                  action="auth/microsoft"
-                  method="post"
-                >
-                  <input
-                    type="hidden"
-                    name="authenticity_token"
-                    value="u6kA6til4majs0slTA_9HFjXefF7OB3Ik7VPD_KgrNrqXul6IWGZlbC8BJ8BA8XhPGgZR9FZie8RjSlR_aJnfA"
-                  />
-                  <button
-                    type="submit"
-                    className="SocialButton_socialButton__S_le7"
-                    aria-label="Sign in with Microsoft"
+                {/* Social sign-in runs against the app's own server, with a remote one it's disabled. */}
+                  <form
+                    className="SocialButton_form__9yToE"
+                    onSubmit={handleSocialSignIn("MS")}
                   >
-                    <svg
-                      xmlns="http://www.w3.org/2000/svg"
-                      viewBox="0 0 32 32"
-                      width="24"
-                      height="24"
-                      className="page_socialButtonIcon__kPbNF"
+                    <input
+                      type="hidden"
+                      name="authenticity_token"
+                      value="u6kA6til4majs0slTA_9HFjXefF7OB3Ik7VPD_KgrNrqXul6IWGZlbC8BJ8BA8XhPGgZR9FZie8RjSlR_aJnfA"
+                    />
+                    <button
+                      type="submit"
+                      className="SocialButton_socialButton__S_le7"
+                      aria-label="Sign in with Microsoft"
+                      disabled={!socialSignIn}
+                      title={socialSignIn ? undefined : SOCIAL_UNAVAILABLE}
                     >
-                      <path d="M0 0h15.206v15.206H0z" fill="#f25022" />
-                      <path d="M16.794 0H32v15.206H16.794z" fill="#7fba00" />
-                      <path d="M0 16.794h15.206V32H0z" fill="#00a4ef" />
-                      <path d="M16.794 16.794H32V32H16.794z" fill="#ffb900" />
-                    </svg>
-                  </button>
-                </form>
-                <form
-                  className="SocialButton_form__9yToE"
-                  action="auth/google"
-                  method="post"
-                >
-                  <input
-                    type="hidden"
-                    name="authenticity_token"
-                    value="u6kA6til4majs0slTA_9HFjXefF7OB3Ik7VPD_KgrNrqXul6IWGZlbC8BJ8BA8XhPGgZR9FZie8RjSlR_aJnfA"
-                  />
-                  <button
-                    type="submit"
-                    className="SocialButton_socialButton__S_le7"
-                    aria-label="Sign in with Google"
+                      <svg
+                        xmlns="http://www.w3.org/2000/svg"
+                        viewBox="0 0 32 32"
+                        width="24"
+                        height="24"
+                        className="page_socialButtonIcon__kPbNF"
+                      >
+                        <path d="M0 0h15.206v15.206H0z" fill="#f25022" />
+                        <path d="M16.794 0H32v15.206H16.794z" fill="#7fba00" />
+                        <path d="M0 16.794h15.206V32H0z" fill="#00a4ef" />
+                        <path d="M16.794 16.794H32V32H16.794z" fill="#ffb900" />
+                      </svg>
+                    </button>
+                  </form>
+                  <form
+                    className="SocialButton_form__9yToE"
+                    onSubmit={handleSocialSignIn("GOOGLE")}
                   >
-                    <svg
-                      xmlns="http://www.w3.org/2000/svg"
-                      xmlnsXlink="http://www.w3.org/1999/xlink"
-                      viewBox="0 0 32 32"
-                      width="24"
-                      height="24"
+                    <input
+                      type="hidden"
+                      name="authenticity_token"
+                      value="u6kA6til4majs0slTA_9HFjXefF7OB3Ik7VPD_KgrNrqXul6IWGZlbC8BJ8BA8XhPGgZR9FZie8RjSlR_aJnfA"
+                    />
+                    <button
+                      type="submit"
+                      className="SocialButton_socialButton__S_le7"
+                      aria-label="Sign in with Google"
+                      disabled={!socialSignIn}
+                      title={socialSignIn ? undefined : SOCIAL_UNAVAILABLE}
                     >
-                      <defs>
-                        <path
-                          id="A"
-                          d="M44.5 20H24v8.5h11.8C34.7 33.9 30.1 37 24 37c-7.2 0-13-5.8-13-13s5.8-13 13-13c3.1 0 5.9 1.1 8.1 2.9l6.4-6.4C34.6 4.1 29.6 2 24 2 11.8 2 2 11.8 2 24s9.8 22 22 22c11 0 21-8 21-22 0-1.3-.2-2.7-.5-4z"
-                        />
-                      </defs>
-                      <clipPath id="B">
-                        <use xlinkHref="#A" />
-                      </clipPath>
-                      <g transform="matrix(.727273 0 0 .727273 -.954545 -1.45455)">
-                        <path
-                          d="M0 37V11l17 13z"
-                          clipPath="url(#B)"
-                          fill="#fbbc05"
-                        />
-                        <path
-                          d="M0 11l17 13 7-6.1L48 14V0H0z"
-                          clipPath="url(#B)"
-                          fill="#ea4335"
-                        />
-                        <path
-                          d="M0 37l30-23 7.9 1L48 0v48H0z"
-                          clipPath="url(#B)"
-                          fill="#34a853"
-                        />
-                        <path
-                          d="M48 48L17 24l-4-3 35-10z"
-                          clipPath="url(#B)"
-                          fill="#4285f4"
-                        />
-                      </g>
-                    </svg>
+                      <svg
+                        xmlns="http://www.w3.org/2000/svg"
+                        xmlnsXlink="http://www.w3.org/1999/xlink"
+                        viewBox="0 0 32 32"
+                        width="24"
+                        height="24"
+                      >
+                        <defs>
+                          <path
+                            id="A"
+                            d="M44.5 20H24v8.5h11.8C34.7 33.9 30.1 37 24 37c-7.2 0-13-5.8-13-13s5.8-13 13-13c3.1 0 5.9 1.1 8.1 2.9l6.4-6.4C34.6 4.1 29.6 2 24 2 11.8 2 2 11.8 2 24s9.8 22 22 22c11 0 21-8 21-22 0-1.3-.2-2.7-.5-4z"
+                          />
+                        </defs>
+                        <clipPath id="B">
+                          <use xlinkHref="#A" />
+                        </clipPath>
+                        <g transform="matrix(.727273 0 0 .727273 -.954545 -1.45455)">
+                          <path
+                            d="M0 37V11l17 13z"
+                            clipPath="url(#B)"
+                            fill="#fbbc05"
+                          />
+                          <path
+                            d="M0 11l17 13 7-6.1L48 14V0H0z"
+                            clipPath="url(#B)"
+                            fill="#ea4335"
+                          />
+                          <path
+                            d="M0 37l30-23 7.9 1L48 0v48H0z"
+                            clipPath="url(#B)"
+                            fill="#34a853"
+                          />
+                          <path
+                            d="M48 48L17 24l-4-3 35-10z"
+                            clipPath="url(#B)"
+                            fill="#4285f4"
+                          />
+                        </g>
+                      </svg>
 
-                    <path d="M16.794 16.794H32V32H16.794z" fill="#ffb900" />
-                  </button>
-                </form>
+                      <path d="M16.794 16.794H32V32H16.794z" fill="#ffb900" />
+                    </button>
+                  </form>
                 <form
                   className="SocialButton_form__9yToE"
                   action="auth/github"
@@ -250,6 +306,9 @@ export function Form(): React.JSX.Element {
                     </svg>
                   </button>
                 </form>
+                {!socialSignIn && (
+                  <p className="page_orText__GB5K8">{SOCIAL_UNAVAILABLE}</p>
+                )}
                 <p className="page_orText__GB5K8">or</p>
               </div>
 